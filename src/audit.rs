@@ -0,0 +1,92 @@
+use crate::error;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, ExitStatus};
+use std::sync::Mutex;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// Audit trail of an image command: a json line per external command and per
+/// high-level operation applied to the image. Disabled by default, i.e.
+/// nothing is recorded.
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    /// Appends the audit trail to `path`.
+    pub fn open(path: &Path) -> Result<AuditLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(format!("audit: cannot open {}", path.to_string_lossy()))?;
+
+        Ok(AuditLog {
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    fn append(&self, mut entry: Value) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        entry["timestamp"] = json!(OffsetDateTime::now_utc().format(&Rfc3339)?);
+
+        let mut file = file
+            .lock()
+            .map_err(|_| anyhow::anyhow!("audit: audit log poisoned"))?;
+
+        writeln!(file, "{entry}").context("audit: cannot write audit log")
+    }
+
+    /// Records an external command invoked by `function`.
+    pub fn command(
+        &self,
+        function: &str,
+        cmd: &Command,
+        status: &ExitStatus,
+        duration: Duration,
+    ) -> Result<()> {
+        if !self.enabled() {
+            return Ok(());
+        }
+
+        self.append(json!({
+            "type": "command",
+            "function": function,
+            "argv": error::argv(cmd),
+            "exitStatus": status.code(),
+            "durationMs": duration.as_millis() as u64,
+        }))
+    }
+
+    /// Records a high-level operation, e.g. a file copied into an image, with
+    /// operation specific `details`.
+    pub fn operation(&self, operation: &str, details: Value) -> Result<()> {
+        if !self.enabled() {
+            return Ok(());
+        }
+
+        let mut entry = json!({
+            "type": "operation",
+            "operation": operation,
+        });
+
+        if let (Some(entry), Value::Object(details)) = (entry.as_object_mut(), details) {
+            entry.extend(details);
+        }
+
+        self.append(entry)
+    }
+}
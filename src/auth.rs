@@ -5,13 +5,14 @@ use tokio::sync::{mpsc, oneshot};
 use anyhow::Result;
 
 use actix_web::{error, get, web, App, HttpServer};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
 use oauth2::basic::BasicClient;
 use oauth2::reqwest::async_http_client;
 use oauth2::{
-    AuthUrl, AuthorizationCode, ClientId, CsrfToken, PkceCodeChallenge, RedirectUrl, TokenResponse,
-    TokenUrl,
+    AuthUrl, AuthorizationCode, ClientId, CsrfToken, PkceCodeChallenge, RedirectUrl, Scope,
+    TokenResponse, TokenUrl,
 };
 
 #[derive(Deserialize)]
@@ -113,8 +114,8 @@ async fn redirect_server<A: ToSocketAddrs>(
     }
 }
 
-fn get_refresh_token_from_key_ring(auth_info: &AuthInfo) -> Option<String> {
-    let entry = match keyring::Entry::new("omnect-cli", &auth_info.client_id) {
+fn get_refresh_token_from_key_ring(client_id: &str) -> Option<String> {
+    let entry = match keyring::Entry::new("omnect-cli", client_id) {
         Ok(entry) => entry,
         Err(err) => {
             log::warn!("Failed to get entry from key ring: {}", err);
@@ -153,6 +154,7 @@ async fn request_access_token(auth_info: &AuthInfo) -> Result<Token> {
 
     let (auth_url, _csrf_token) = client
         .authorize_url(CsrfToken::new_random)
+        .add_scopes(auth_info.scopes.iter().cloned().map(Scope::new))
         .set_pkce_challenge(pkce_challenge)
         .url();
 
@@ -190,7 +192,7 @@ async fn request_access_token(auth_info: &AuthInfo) -> Result<Token> {
 }
 
 async fn refresh_access_token(auth_info: &AuthInfo) -> Option<Token> {
-    let refresh_token = get_refresh_token_from_key_ring(auth_info)?;
+    let refresh_token = get_refresh_token_from_key_ring(&auth_info.client_id)?;
     log::debug!("Found refresh token in key ring.");
 
     let client = BasicClient::new(
@@ -236,10 +238,117 @@ where
     Ok(token.access_token().clone())
 }
 
+/// Login state as derived from the refresh token cached in the key ring.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthStatus {
+    pub backend: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub token_cached: bool,
+    pub logged_in: bool,
+    pub subject: Option<String>,
+    pub username: Option<String>,
+    pub expires: Option<String>,
+}
+
+impl std::fmt::Display for AuthStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "environment: {}", self.backend)?;
+        writeln!(f, "issuer:      {}", self.issuer)?;
+        writeln!(f, "client id:   {}", self.client_id)?;
+        write!(
+            f,
+            "status:      {}",
+            match (self.token_cached, self.logged_in) {
+                (_, true) => "logged in",
+                (true, false) => "login expired",
+                (false, false) => "not logged in",
+            }
+        )?;
+        if let Some(subject) = &self.subject {
+            write!(f, "\nsubject:     {subject}")?;
+        }
+        if let Some(username) = &self.username {
+            write!(f, "\nusername:    {username}")?;
+        }
+        if self.token_cached {
+            write!(
+                f,
+                "\nexpires:     {}",
+                self.expires.as_deref().unwrap_or("never")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// claims of a refresh token, if it is a JWT as e.g. issued by keycloak
+#[derive(Default, Deserialize)]
+struct Claims {
+    sub: Option<String>,
+    preferred_username: Option<String>,
+    exp: Option<i64>,
+}
+
+/// Decodes the claims of `token` without verifying it. Opaque tokens have none.
+fn decode_claims(token: &str) -> Option<Claims> {
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+
+    serde_json::from_slice(&payload).ok()
+}
+
+/// Returns the login state for `provider` of `backend`. Only the refresh token
+/// cached in the key ring is inspected, no request is sent to the provider.
+pub fn status(backend: &url::Url, provider: &crate::config::AuthProvider) -> AuthStatus {
+    let token = get_refresh_token_from_key_ring(provider.client_id());
+    let claims = token
+        .as_deref()
+        .map(|t| decode_claims(t).unwrap_or_default());
+    let exp = claims
+        .as_ref()
+        .and_then(|c| c.exp)
+        .and_then(|exp| OffsetDateTime::from_unix_timestamp(exp).ok());
+
+    AuthStatus {
+        backend: backend.to_string(),
+        issuer: provider.issuer(),
+        client_id: provider.client_id().to_string(),
+        token_cached: token.is_some(),
+        logged_in: token.is_some() && !exp.is_some_and(|exp| exp <= OffsetDateTime::now_utc()),
+        subject: claims.as_ref().and_then(|c| c.sub.clone()),
+        username: claims.as_ref().and_then(|c| c.preferred_username.clone()),
+        expires: exp.and_then(|exp| exp.format(&Rfc3339).ok()),
+    }
+}
+
 pub struct AuthInfo {
     pub auth_url: String,
     pub token_url: String,
     pub bind_addrs: Vec<String>,
     pub redirect_addr: url::Url,
     pub client_id: String,
+    pub scopes: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_token_claims() {
+        let payload = base64::encode_config(
+            r#"{"sub":"1234","preferred_username":"jane","exp":1700000000,"typ":"Refresh"}"#,
+            base64::URL_SAFE_NO_PAD,
+        );
+        let claims = decode_claims(&format!("eyJhbGciOiJIUzI1NiJ9.{payload}.c2ln")).unwrap();
+
+        assert_eq!(claims.sub.as_deref(), Some("1234"));
+        assert_eq!(claims.preferred_username.as_deref(), Some("jane"));
+        assert_eq!(claims.exp, Some(1700000000));
+
+        assert!(decode_claims("opaque-token").is_none());
+        assert!(decode_claims("a.no-base64!.c").is_none());
+    }
 }
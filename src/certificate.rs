@@ -0,0 +1,707 @@
+use crate::file::functions::{list_dir, paths_exist, read_file_from_image, FileOptions, Partition};
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::Serialize;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use time::format_description::well_known::Rfc3339;
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
+
+// certificates injected by omnect-cli into the cert partition
+const KNOWN_CERTS: [&str; 5] = [
+    DEVICE_CERT,
+    "/priv/ca.crt.pem",
+    "/priv/edge-ca.pem",
+    "/ca/ca.crt",
+    "/ca/trust-bundle.pem.crt",
+];
+
+// CA certificates added to the system trust store of rootA
+const ROOT_A_CERT_DIR: &str = "/usr/local/share/ca-certificates";
+
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+const DEVICE_CERT: &str = "/priv/device_id_cert.pem";
+
+// extensions of device certificates issued via openssl
+const DEVICE_CERT_EXTENSIONS: &str = "basicConstraints=critical,CA:FALSE
+keyUsage=critical,digitalSignature,keyEncipherment
+extendedKeyUsage=clientAuth
+subjectKeyIdentifier=hash
+authorityKeyIdentifier=keyid,issuer
+";
+// Ed25519 keys can only sign, so keyEncipherment doesn't apply
+const DEVICE_CERT_EXTENSIONS_ED25519: &str = "basicConstraints=critical,CA:FALSE
+keyUsage=critical,digitalSignature
+extendedKeyUsage=clientAuth
+subjectKeyIdentifier=hash
+authorityKeyIdentifier=keyid,issuer
+";
+
+/// Key type of created device keys.
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum KeyType {
+    /// ECDSA key on curve P-256
+    #[default]
+    ec_p256,
+    /// Ed25519 key, e.g. for small-footprint devices; only usable as EST bootstrap certificate with Azure
+    ed25519,
+}
+
+/// Key of the issuer of device certificates created via openssl.
+#[derive(Clone, Copy)]
+pub enum IssuerKey<'a> {
+    /// path to a pem file
+    File(&'a Path),
+    /// PKCS#11 URI of a key used via the openssl pkcs11 engine
+    Pkcs11(&'a str),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CertInfo {
+    pub partition: String,
+    pub path: String,
+    pub subject: String,
+    pub issuer: String,
+    pub not_before: String,
+    pub not_after: String,
+    pub days_until_expiry: i64,
+    pub expiring: bool,
+}
+
+impl std::fmt::Display for CertInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{}:{}", self.partition, self.path)?;
+        writeln!(f, "  subject:     {}", self.subject)?;
+        writeln!(f, "  issuer:      {}", self.issuer)?;
+        writeln!(f, "  not before:  {}", self.not_before)?;
+        writeln!(f, "  not after:   {}", self.not_after)?;
+        write!(f, "  expires in:  {} days", self.days_until_expiry)?;
+        if self.expiring {
+            write!(f, " (EXPIRING)")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CertVerification {
+    pub partition: String,
+    pub path: String,
+    pub subject: String,
+    pub verified: bool,
+    /// reason reported by openssl if the certificate doesn't verify
+    pub reason: Option<String>,
+}
+
+impl std::fmt::Display for CertVerification {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{} ({}): ", self.partition, self.path, self.subject)?;
+        match &self.reason {
+            None => write!(f, "OK"),
+            Some(reason) => write!(f, "FAILED: {reason}"),
+        }
+    }
+}
+
+/// parses dates as printed by "openssl x509", e.g. "Mar 22 14:23:32 2032 GMT"
+fn parse_openssl_date(date: &str) -> Result<OffsetDateTime> {
+    let err = || format!("parse_openssl_date: unexpected date format: {date}");
+    let v: Vec<&str> = date.split_whitespace().collect();
+
+    anyhow::ensure!(v.len() == 5 && v[4] == "GMT", err());
+
+    let month = match v[0] {
+        "Jan" => Month::January,
+        "Feb" => Month::February,
+        "Mar" => Month::March,
+        "Apr" => Month::April,
+        "May" => Month::May,
+        "Jun" => Month::June,
+        "Jul" => Month::July,
+        "Aug" => Month::August,
+        "Sep" => Month::September,
+        "Oct" => Month::October,
+        "Nov" => Month::November,
+        "Dec" => Month::December,
+        _ => anyhow::bail!(err()),
+    };
+    let hms: Vec<u8> = v[2]
+        .split(':')
+        .map(|x| x.parse())
+        .collect::<Result<_, _>>()
+        .with_context(err)?;
+
+    anyhow::ensure!(hms.len() == 3, err());
+
+    let date = Date::from_calendar_date(
+        v[3].parse().with_context(err)?,
+        month,
+        v[1].parse().with_context(err)?,
+    )
+    .with_context(err)?;
+    let time = Time::from_hms(hms[0], hms[1], hms[2]).with_context(err)?;
+
+    Ok(PrimitiveDateTime::new(date, time).assume_utc())
+}
+
+fn cert_info(
+    partition: &Partition,
+    path: &str,
+    pem: &str,
+    threshold_days: i64,
+) -> Result<CertInfo> {
+    let mut openssl = Command::new("openssl")
+        .args([
+            "x509",
+            "-noout",
+            "-nameopt",
+            "RFC2253",
+            "-subject",
+            "-issuer",
+            "-startdate",
+            "-enddate",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("cert_info: cannot run openssl")?;
+
+    openssl
+        .stdin
+        .take()
+        .context("cert_info: cannot get stdin")?
+        .write_all(pem.as_bytes())
+        .context("cert_info: cannot write certificate")?;
+
+    let out = openssl
+        .wait_with_output()
+        .context("cert_info: openssl failed")?;
+
+    anyhow::ensure!(
+        out.status.success(),
+        "cert_info: invalid certificate {partition}:{path}"
+    );
+
+    let out = String::from_utf8(out.stdout).context("cert_info: get output")?;
+    let field = |name: &str| {
+        out.lines()
+            .find_map(|l| l.strip_prefix(name))
+            .map(|v| v.trim().to_string())
+            .context(format!("cert_info: missing {name} in openssl output"))
+    };
+
+    let not_before = parse_openssl_date(&field("notBefore=")?)?;
+    let not_after = parse_openssl_date(&field("notAfter=")?)?;
+    let days_until_expiry = (not_after - OffsetDateTime::now_utc()).whole_days();
+
+    Ok(CertInfo {
+        partition: partition.to_string(),
+        path: path.to_string(),
+        subject: field("subject=")?,
+        issuer: field("issuer=")?,
+        not_before: not_before.format(&Rfc3339)?,
+        not_after: not_after.format(&Rfc3339)?,
+        days_until_expiry,
+        expiring: days_until_expiry < threshold_days,
+    })
+}
+
+/// Returns the content of each of `paths` present in `partition`. Absent paths
+/// are skipped, any other error, e.g. of reading the partition, is returned.
+fn read_present_files(
+    paths: &[&str],
+    partition: &Partition,
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<Vec<(String, String)>> {
+    if paths.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let present = paths_exist(paths, partition, image_file, options)?;
+    let mut files = vec![];
+
+    for (path, present) in paths.iter().zip(present) {
+        if !present {
+            debug!("read_present_files: skip absent {partition}:{path}");
+            continue;
+        }
+
+        let content = read_file_from_image(path, partition.clone(), image_file, options).context(
+            format!("read_present_files: cannot read {partition}:{path}"),
+        )?;
+        files.push((path.to_string(), content));
+    }
+
+    Ok(files)
+}
+
+/// Lists all certificates (including the ones contained in chains) stored at
+/// well known paths in the cert partition and the CA certificates added to the
+/// trust store of rootA.
+pub fn list_certificates(
+    image_file: &Path,
+    threshold_days: i64,
+    options: &FileOptions,
+) -> Result<Vec<CertInfo>> {
+    crate::file::ensure_partitions(
+        image_file,
+        &[Partition::cert, Partition::rootA],
+        "list certificates",
+        options,
+    )?;
+
+    let root_a_certs: Vec<String> =
+        list_dir(ROOT_A_CERT_DIR, &Partition::rootA, image_file, options)?
+            .into_iter()
+            .filter(|name| name.ends_with(".crt"))
+            .map(|name| format!("{ROOT_A_CERT_DIR}/{name}"))
+            .collect();
+    let root_a_certs: Vec<&str> = root_a_certs.iter().map(String::as_str).collect();
+
+    let mut certs = vec![];
+
+    for (partition, paths) in [
+        (Partition::cert, &KNOWN_CERTS[..]),
+        (Partition::rootA, &root_a_certs[..]),
+    ] {
+        for (path, content) in read_present_files(paths, &partition, image_file, options)? {
+            for pem in content
+                .split_inclusive(PEM_END)
+                .filter(|pem| pem.contains(PEM_END))
+            {
+                let info = cert_info(&partition, &path, pem, threshold_days)?;
+
+                if info.expiring {
+                    warn!(
+                        "{}:{} ({}) expires in {} days",
+                        info.partition, info.path, info.subject, info.days_until_expiry
+                    );
+                }
+
+                certs.push(info);
+            }
+        }
+    }
+
+    Ok(certs)
+}
+
+/// Verifies that all certificates (including the ones contained in chains)
+/// stored at well known paths in the cert partition chain to the root
+/// certificate `ca`. All certificates found in the image serve as
+/// intermediates when building the chains.
+pub fn verify_certificates(
+    image_file: &Path,
+    ca: &Path,
+    options: &FileOptions,
+) -> Result<Vec<CertVerification>> {
+    crate::file::ensure_partitions(
+        image_file,
+        &[Partition::cert],
+        "verify certificates",
+        options,
+    )?;
+
+    let ca_pem = fs::read_to_string(ca).context(format!(
+        "verify_certificates: cannot read {}",
+        ca.to_string_lossy()
+    ))?;
+    anyhow::ensure!(
+        ca_pem.contains(PEM_END),
+        "verify_certificates: {} contains no certificate",
+        ca.to_string_lossy()
+    );
+
+    let mut certs = vec![];
+
+    for (path, content) in read_present_files(&KNOWN_CERTS, &Partition::cert, image_file, options)?
+    {
+        for pem in content
+            .split_inclusive(PEM_END)
+            .filter(|pem| pem.contains(PEM_END))
+        {
+            certs.push((path.clone(), pem.to_string()));
+        }
+    }
+
+    if certs.is_empty() {
+        warn!("verify_certificates: no certificates found in the cert partition");
+        return Ok(vec![]);
+    }
+
+    let dir = tempfile::tempdir().context("verify_certificates: cannot create tmp dir")?;
+    let untrusted = dir.path().join("untrusted.pem");
+    let cert = dir.path().join("cert.pem");
+
+    fs::write(
+        &untrusted,
+        certs
+            .iter()
+            .map(|(_, pem)| pem.as_str())
+            .collect::<String>(),
+    )
+    .context("verify_certificates: cannot write intermediates")?;
+
+    certs
+        .iter()
+        .map(|(path, pem)| {
+            let info = cert_info(&Partition::cert, path, pem, 0)?;
+
+            fs::write(&cert, pem).context("verify_certificates: cannot write certificate")?;
+
+            let out = Command::new("openssl")
+                .arg("verify")
+                .arg("-CAfile")
+                .arg(ca)
+                .arg("-untrusted")
+                .arg(&untrusted)
+                .arg(&cert)
+                .output()
+                .context("verify_certificates: cannot run openssl")?;
+
+            let reason = (!out.status.success())
+                .then(|| verify_error(&String::from_utf8_lossy(&out.stderr)));
+
+            if let Some(reason) = &reason {
+                warn!("cert:{path} ({}) doesn't verify: {reason}", info.subject);
+            }
+
+            Ok(CertVerification {
+                partition: info.partition,
+                path: info.path,
+                subject: info.subject,
+                verified: reason.is_none(),
+                reason,
+            })
+        })
+        .collect()
+}
+
+/// Extracts the reason from the output of a failed "openssl verify", e.g.
+/// "error 20 at 0 depth lookup: unable to get local issuer certificate".
+fn verify_error(stderr: &str) -> String {
+    stderr
+        .lines()
+        .find_map(|l| l.split_once("depth lookup:"))
+        .map(|(_, reason)| reason.trim().to_string())
+        .unwrap_or_else(|| stderr.trim().to_string())
+}
+
+/// extracts the common name of a RFC2253 formatted subject, e.g. "CN=my-device,O=omnect"
+pub(crate) fn common_name(subject: &str) -> Option<String> {
+    subject
+        .split(',')
+        .find_map(|rdn| rdn.trim().strip_prefix("CN="))
+        .filter(|cn| !cn.is_empty())
+        .map(str::to_string)
+}
+
+/// Returns the device id, i.e. the common name of the device certificate
+/// currently injected into the cert partition.
+pub fn device_id(image_file: &Path, options: &FileOptions) -> Result<String> {
+    crate::file::ensure_partitions(
+        image_file,
+        &[Partition::cert],
+        "renew device certificate",
+        options,
+    )?;
+
+    let content = read_file_from_image(DEVICE_CERT, Partition::cert, image_file, options)
+        .context("device_id: no device certificate found to renew")?;
+    let pem = content
+        .split_inclusive(PEM_END)
+        .find(|pem| pem.contains(PEM_END))
+        .context("device_id: no device certificate found to renew")?;
+    let info = cert_info(&Partition::cert, DEVICE_CERT, pem, 0)?;
+
+    common_name(&info.subject).context(format!(
+        "device_id: device certificate subject \"{}\" has no common name",
+        info.subject
+    ))
+}
+
+/// Creates a device certificate and key of `key_type` for `device_id` signed
+/// by `intermediate_key`. A PKCS#11 key, e.g. on a HSM, is used via the
+/// openssl pkcs11 engine and never leaves the token; if its URI contains no
+/// PIN, openssl prompts for it. Returns certificate and key as pem.
+pub fn create_cert_and_key_openssl(
+    intermediate_full_chain_cert: &Path,
+    intermediate_key: IssuerKey,
+    key_type: KeyType,
+    device_id: &str,
+    days: u32,
+    dir: &Path,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    if let IssuerKey::Pkcs11(uri) = intermediate_key {
+        anyhow::ensure!(
+            uri.starts_with("pkcs11:"),
+            "create_cert_and_key_openssl: invalid PKCS#11 URI: expected \"pkcs11:...\""
+        );
+    }
+
+    let tmp_dir = tempfile::Builder::new()
+        .prefix("device-cert-")
+        .tempdir_in(dir)
+        .context("create_cert_and_key_openssl: couldn't create tmp dir")?;
+    let key = tmp_dir.path().join("device.key.pem");
+    let csr = tmp_dir.path().join("device.csr.pem");
+    let cert = tmp_dir.path().join("device.cert.pem");
+    let extensions = tmp_dir.path().join("extensions.cnf");
+
+    let (genpkey_args, extensions_cnf): (&[&str], _) = match key_type {
+        KeyType::ec_p256 => (
+            &["-algorithm", "EC", "-pkeyopt", "ec_paramgen_curve:P-256"],
+            DEVICE_CERT_EXTENSIONS,
+        ),
+        KeyType::ed25519 => (&["-algorithm", "ED25519"], DEVICE_CERT_EXTENSIONS_ED25519),
+    };
+
+    fs::write(&extensions, extensions_cnf)
+        .context("create_cert_and_key_openssl: cannot write extensions")?;
+
+    openssl(
+        &[
+            &["genpkey"],
+            genpkey_args,
+            &["-out", &key.to_string_lossy()],
+        ]
+        .concat(),
+    )
+    .context("create_cert_and_key_openssl: cannot create device key")?;
+
+    openssl(&[
+        "req",
+        "-new",
+        "-key",
+        &key.to_string_lossy(),
+        "-subj",
+        &format!(
+            "/CN={}",
+            device_id.replace('\\', "\\\\").replace('/', "\\/")
+        ),
+        "-out",
+        &csr.to_string_lossy(),
+    ])
+    .context("create_cert_and_key_openssl: cannot create certificate signing request")?;
+
+    let intermediate_key_path;
+    let intermediate_key_args = match intermediate_key {
+        IssuerKey::File(path) => {
+            intermediate_key_path = path.to_string_lossy();
+            vec!["-CAkey", &intermediate_key_path]
+        }
+        IssuerKey::Pkcs11(uri) => vec!["-engine", "pkcs11", "-CAkeyform", "engine", "-CAkey", uri],
+    };
+
+    let signed = openssl(
+        &[
+            &[
+                "x509",
+                "-req",
+                "-in",
+                &csr.to_string_lossy(),
+                "-CA",
+                &intermediate_full_chain_cert.to_string_lossy(),
+            ],
+            intermediate_key_args.as_slice(),
+            &[
+                "-set_serial",
+                &format!("0x{}", uuid::Uuid::new_v4().to_simple()),
+                "-days",
+                &days.to_string(),
+                "-sha256",
+                "-extfile",
+                &extensions.to_string_lossy(),
+                "-out",
+                &cert.to_string_lossy(),
+            ],
+        ]
+        .concat(),
+    );
+
+    match intermediate_key {
+        IssuerKey::File(_) => signed,
+        IssuerKey::Pkcs11(_) => signed.map_err(|e| anyhow::anyhow!(pkcs11_error(&e.to_string()))),
+    }
+    // the URI may contain the PIN, so it's not part of the error
+    .context("create_cert_and_key_openssl: cannot sign device certificate")?;
+
+    Ok((
+        fs::read(&cert).context("create_cert_and_key_openssl: cannot read certificate")?,
+        fs::read(&key).context("create_cert_and_key_openssl: cannot read key")?,
+    ))
+}
+
+/// Runs openssl with `args`; a potential PIN prompt is read from the terminal.
+/// Fails with the output of openssl on stderr.
+fn openssl(args: &[&str]) -> Result<()> {
+    let out = Command::new("openssl")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .context("openssl: cannot run openssl")?;
+
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    // args aren't logged, since they may contain a PIN
+    debug!("openssl {}: {}", args[0], stderr.trim());
+
+    anyhow::ensure!(out.status.success(), "{}", stderr.trim());
+
+    Ok(())
+}
+
+/// Maps the openssl output of a failed signing via the pkcs11 engine to a
+/// reason the user can act on.
+fn pkcs11_error(stderr: &str) -> String {
+    let lower = stderr.to_lowercase();
+
+    let reason = if lower.contains("pin incorrect") || lower.contains("ckr_pin_incorrect") {
+        "wrong PIN for the PKCS#11 token"
+    } else if lower.contains("pin locked") || lower.contains("ckr_pin_locked") {
+        "the PIN of the PKCS#11 token is locked"
+    } else if lower.contains("invalid engine") || lower.contains("engine \"pkcs11\"") {
+        "openssl pkcs11 engine isn't available, install it (e.g. package libengine-pkcs11-openssl)"
+    } else if lower.contains("token")
+        || lower.contains("slot")
+        || lower.contains("private key")
+        || lower.contains("object not found")
+    {
+        "PKCS#11 token or key unavailable: check that the token is present and the URI references the intermediate key"
+    } else {
+        return stderr.trim().to_string();
+    };
+
+    format!("{reason}: {}", stderr.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid_openssl_date() {
+        let date = parse_openssl_date("Mar  2 14:23:32 2032 GMT").unwrap();
+
+        assert_eq!(date.format(&Rfc3339).unwrap(), "2032-03-02T14:23:32Z");
+    }
+
+    #[test]
+    fn decline_invalid_openssl_date() {
+        assert!(parse_openssl_date("2032-03-02 14:23:32Z").is_err());
+        assert!(parse_openssl_date("Foo  2 14:23:32 2032 GMT").is_err());
+        assert!(parse_openssl_date("Mar 32 14:23:32 2032 GMT").is_err());
+    }
+
+    #[test]
+    fn pkcs11_error_reason() {
+        assert!(
+            pkcs11_error("PKCS11_login:PIN incorrect\nunable to load CA Private Key")
+                .starts_with("wrong PIN")
+        );
+        assert!(pkcs11_error("invalid engine \"pkcs11\"").contains("engine isn't available"));
+        assert!(
+            pkcs11_error("No matching slot found\nunable to load CA Private Key")
+                .starts_with("PKCS#11 token or key unavailable")
+        );
+        assert_eq!(pkcs11_error("unexpected error\n"), "unexpected error");
+    }
+
+    #[test]
+    fn openssl_verify_error() {
+        assert_eq!(
+            verify_error(
+                "error 20 at 0 depth lookup: unable to get local issuer certificate\nerror cert.pem: verification failed\n"
+            ),
+            "unable to get local issuer certificate"
+        );
+        assert_eq!(
+            verify_error("Could not open file or uri for loading CA\n"),
+            "Could not open file or uri for loading CA"
+        );
+    }
+
+    #[test]
+    fn subject_common_name() {
+        assert_eq!(
+            common_name("CN=my-device-id").as_deref(),
+            Some("my-device-id")
+        );
+        assert_eq!(
+            common_name("O=omnect, CN=my-device-id,C=DE").as_deref(),
+            Some("my-device-id")
+        );
+        assert_eq!(common_name("O=omnect"), None);
+        assert_eq!(common_name("CN="), None);
+    }
+
+    #[test]
+    fn intermediate_full_chain_cert_info() {
+        let pem = std::fs::read_to_string("testfiles/test-int-ca_fullchain.pem").unwrap();
+        let certs: Vec<CertInfo> = pem
+            .split_inclusive(PEM_END)
+            .filter(|pem| pem.contains(PEM_END))
+            .map(|pem| cert_info(&Partition::cert, "/priv/ca.crt.pem", pem, 30).unwrap())
+            .collect();
+
+        assert_eq!(certs.len(), 2);
+        assert!(certs[0].subject.contains("CN=test-int-ca"));
+        assert!(certs[0].issuer.contains("CN=test-ca"));
+        assert_eq!(certs[0].not_after, "2032-03-22T14:23:32Z");
+        assert!(certs[1].subject.contains("CN=test-ca"));
+        assert!(!certs[0].expiring);
+    }
+
+    #[test]
+    fn create_ed25519_cert_and_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let chain = Path::new("testfiles/test-int-ca_fullchain.pem");
+
+        for key_type in [KeyType::ec_p256, KeyType::ed25519] {
+            let (cert_pem, key_pem) = create_cert_and_key_openssl(
+                chain,
+                IssuerKey::File(Path::new("testfiles/test-int-ca.key")),
+                key_type,
+                "my-device",
+                1,
+                dir.path(),
+            )
+            .unwrap();
+
+            let cert = dir.path().join("cert.pem");
+            let key = dir.path().join("key.pem");
+            fs::write(&cert, cert_pem).unwrap();
+            fs::write(&key, key_pem).unwrap();
+
+            assert_eq!(
+                crate::validators::certificate::is_ed25519_key(&key).unwrap(),
+                key_type == KeyType::ed25519
+            );
+
+            // issued by the intermediate and matching the key
+            openssl(&[
+                "verify",
+                "-partial_chain",
+                "-CAfile",
+                chain.to_str().unwrap(),
+                cert.to_str().unwrap(),
+            ])
+            .unwrap();
+            let pubkey =
+                |args: &[&str]| Command::new("openssl").args(args).output().unwrap().stdout;
+            assert_eq!(
+                pubkey(&["x509", "-noout", "-pubkey", "-in", cert.to_str().unwrap()]),
+                pubkey(&["pkey", "-pubout", "-in", key.to_str().unwrap()])
+            );
+        }
+    }
+}
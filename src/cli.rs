@@ -1,13 +1,166 @@
+use crate::certificate::KeyType;
 use crate::file::{
-    compression::Compression,
-    functions::{FileCopyFromParams, FileCopyToParams, Partition},
+    compression::ImageFormat,
+    functions::{
+        parse_dd_block_size, parse_https_url, parse_mtime, parse_owner, parse_partition_path,
+        parse_sha256, parse_size, FileCopyFromParams, FileCopyToParams, FsType, Partition,
+        RawPartition,
+    },
+    parse_label, EnvVar, EstAuth,
 };
-use clap::Parser;
+use crate::validators::schema::SchemaKind;
+use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
+use std::str::FromStr;
 use url::Url;
 
 const COPYRIGHT: &str = "Copyright © 2021 by conplement AG";
 
+#[derive(Args, Debug, Default)]
+pub struct ContainerOptions {
+    /// optional: run the command inside the omnect-cli docker image, which comes with all required tools (mtools, e2tools, fdisk, ...); the directories of all given paths are bind mounted
+    #[arg(long = "in-container")]
+    pub in_container: bool,
+    /// optional: docker image used by '--in-container', defaults to omnect/omnect-cli with the version of this omnect-cli
+    #[arg(long = "container-image", requires = "in_container")]
+    pub container_image: Option<String>,
+}
+
+#[derive(Args, Debug, Default)]
+pub struct ImageOptions {
+    /// optional: generate bmap file (currently not working in docker image)
+    #[arg(short = 'b', long = "generate-bmap-file")]
+    pub generate_bmap: bool,
+    /// optional: path of the generated bmap file, defaults to the written image with extension ".bmap"; missing parent directories are created
+    #[arg(long = "bmap-output", requires = "generate_bmap")]
+    pub bmap_output: Option<PathBuf>,
+    /// optional: fail if bmaptool isn't installed, otherwise only a warning is printed; the image is written anyway
+    #[arg(long = "strict-bmap", requires = "generate_bmap")]
+    pub strict_bmap: bool,
+    /// optional: pack the written image [xz, bzip2 (bz2), gzip (gz), none] independently of the compression of the source image and append the extension accordingly; 'none' (default) writes it uncompressed (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
+    #[arg(
+        short = 'p',
+        long = "pack-image",
+        visible_alias = "output-compression",
+        value_enum
+    )]
+    pub compress_image: Option<ImageFormat>,
+    /// optional: keep the decompressed image next to the packed image (requires '-p'), e.g. to compare it with the packed one; doubles the disk usage
+    #[arg(long = "keep-decompressed", requires = "compress_image")]
+    pub keep_decompressed: bool,
+    /// optional: make gzip output rsync-friendly by periodically flushing the compression stream (requires '-p gzip')
+    #[arg(long = "gzip-rsyncable")]
+    pub gzip_rsyncable: bool,
+    /// optional: bzip2 block size in units of 100k [1-9], default '9'; ignored if the image isn't packed with bzip2
+    #[arg(long = "bzip2-block-size", value_parser = clap::value_parser!(u32).range(1..=9))]
+    pub bzip2_block_size: Option<u32>,
+    /// optional: xz dictionary size in MiB [1-1536], default as of the compression level (64 MiB for '9'); larger dictionaries may improve the ratio of large images, but need more memory for compression and decompression; ignored if the image isn't packed with xz
+    #[arg(long = "xz-dict-size", value_parser = clap::value_parser!(u32).range(1..=1536))]
+    pub xz_dict_size: Option<u32>,
+    /// optional: on failure keep the temporary (decompressed) image for debugging instead of cleaning up
+    #[arg(long = "no-recompress-on-error")]
+    pub no_recompress_on_error: bool,
+    /// optional: force the format of the source image [xz, bzip2 (bz2), gzip (gz), none] instead of detecting it via libmagic, e.g. if detection is wrong; 'none' uses the image as is
+    #[arg(long = "image-format", value_enum)]
+    pub image_format: Option<ImageFormat>,
+    /// optional: directory used for temporary files, defaults to the system's temp dir
+    #[arg(long = "work-dir")]
+    pub work_dir: Option<PathBuf>,
+    /// optional: path to a .toml file mapping partitions to partition table indexes or labels, for images deviating from the default omnect-os layout
+    #[arg(long = "layout")]
+    pub layout: Option<PathBuf>,
+    /// optional: treat image as a single file system [boot (vfat), ext] instead of a partitioned wic image; the partition of files is ignored
+    #[arg(long = "raw-partition", value_enum, conflicts_with = "layout")]
+    pub raw_partition: Option<RawPartition>,
+    /// optional: label, e.g. a build id, written to /etc/omnect/build-info in the factory partition as provenance marker
+    #[arg(long = "label", value_parser = parse_label)]
+    pub label: Option<String>,
+    /// optional: warn if the file system of the work dir doesn't support sparse files, which lets images take their full size
+    #[arg(long = "sparse-check")]
+    pub sparse_check: bool,
+    /// optional: fail instead of warn if the sparse check fails
+    #[arg(long = "strict", requires = "sparse_check")]
+    pub strict: bool,
+    /// optional: directory to keep copies of the partition images extracted by file operations in, e.g. for mounting them
+    #[arg(long = "keep-partitions")]
+    pub keep_partitions: Option<PathBuf>,
+    /// optional: number of partitions processed in parallel when copying files to multiple partitions (files within a partition are always copied serially) and of files read back in parallel by '--verify'; defaults to '--threads' if given, otherwise serial
+    #[arg(long = "parallel", value_parser = clap::value_parser!(u16).range(1..))]
+    pub parallel: Option<u16>,
+    /// optional: leave image (and bmap file) untouched if the command didn't change the image content
+    #[arg(long = "only-if-changed")]
+    pub only_if_changed: bool,
+    /// optional: check modified ext partitions via 'e2fsck -fn' before writing them back and fail if a file system is inconsistent
+    #[arg(long = "fsck")]
+    pub fsck: bool,
+    /// optional: check that the kernels, initrds and device trees referenced by the boot loader configs of the boot partition (grub.cfg, extlinux.conf, systemd-boot entries) exist in the boot partition or rootA before writing back the image and fail on dangling references
+    #[arg(long = "check-boot", conflicts_with = "raw_partition")]
+    pub check_boot: bool,
+    /// optional: don't sync extracted partitions and the image to disk after writing them, e.g. for throwaway images on tmpfs in CI: faster, but the image may be corrupted if the system crashes before the kernel flushed it
+    #[arg(long = "no-sync")]
+    pub no_sync: bool,
+    /// optional: block size partitions are copied with between image and extracted partition files, e.g. 64K or 4M (a multiple of 512 bytes up to 64M, default 1M); only affects throughput
+    #[arg(long = "dd-block-size", value_parser = parse_dd_block_size)]
+    pub dd_block_size: Option<u64>,
+    /// optional: fail immediately instead of waiting if the image is locked by another omnect-cli process
+    #[arg(long = "no-wait")]
+    pub no_wait: bool,
+    /// optional: keep the decompressed source image in the work dir until the command succeeded and reuse it if the command is restarted with the same image, e.g. after an interruption
+    #[arg(long = "resume")]
+    pub resume: bool,
+    /// optional: write the resulting image to the given path instead of back to the source image, which then doesn't need to be writable (e.g. on a read-only mount); with '-p' the compression extension is appended
+    #[arg(long = "output")]
+    pub output: Option<PathBuf>,
+    /// optional: append a json line per external command and per high-level operation applied to the image to the given file, as audit trail
+    #[arg(long = "audit-log")]
+    pub audit_log: Option<PathBuf>,
+    /// optional: write a json manifest of the written image(s) to the given file: the files copied into each partition with size and sha256, the image's sha256 and whether a bmap file was generated
+    #[arg(long = "change-manifest")]
+    pub change_manifest: Option<PathBuf>,
+    /// optional: compress the image before and after the command with the given codec [xz, bzip2 (bz2), gzip (gz)] and print the size delta and the number of changed compressed blocks, e.g. to estimate the payload of a delta update; the settings of '-p' are used if it's the same codec
+    #[arg(long = "compressed-delta", value_enum)]
+    pub compressed_delta: Option<ImageFormat>,
+    /// optional: compressed image the result is compared with by '--compressed-delta' instead of the compressed image before the command, e.g. the currently deployed release
+    #[arg(long = "delta-baseline", requires = "compressed_delta")]
+    pub delta_baseline: Option<PathBuf>,
+    /// optional: print the partition table of the image with partition names, file system types and labels and exit without performing the command
+    #[arg(long = "list-partitions")]
+    pub list_partitions: bool,
+    /// set by commands which only read from the image: no write access is required and the image isn't written back
+    #[arg(skip)]
+    pub read_only: bool,
+}
+
+/// Subset of `ImageOptions` for commands only reading an image.
+#[derive(Args, Debug, Default)]
+pub struct ReadImageOptions {
+    /// optional: force the format of the source image [xz, bzip2 (bz2), gzip (gz), none] instead of detecting it via libmagic, e.g. if detection is wrong; 'none' uses the image as is
+    #[arg(long = "image-format", value_enum)]
+    pub image_format: Option<ImageFormat>,
+    /// optional: directory used for temporary files, defaults to the system's temp dir
+    #[arg(long = "work-dir")]
+    pub work_dir: Option<PathBuf>,
+    /// optional: path to a .toml file mapping partitions to partition table indexes or labels, for images deviating from the default omnect-os layout
+    #[arg(long = "layout")]
+    pub layout: Option<PathBuf>,
+    /// optional: treat image as a single file system [boot (vfat), ext] instead of a partitioned wic image
+    #[arg(long = "raw-partition", value_enum, conflicts_with = "layout")]
+    pub raw_partition: Option<RawPartition>,
+}
+
+impl From<ReadImageOptions> for ImageOptions {
+    fn from(options: ReadImageOptions) -> Self {
+        ImageOptions {
+            image_format: options.image_format,
+            work_dir: options.work_dir,
+            layout: options.layout,
+            raw_partition: options.raw_partition,
+            read_only: true,
+            ..Default::default()
+        }
+    }
+}
+
 // ToDo: command completion
 #[derive(Parser, Debug)]
 #[command(after_help = COPYRIGHT)]
@@ -27,12 +180,220 @@ pub enum Docker {
         /// destination path of the docker image in the firmware image (must end in ".tar.gz")
         #[clap(short = 'e', long = "dest")]
         dest: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
-        #[arg(short = 'b', long = "generate-bmap-file")]
-        generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
-        compress_image: Option<Compression>,
+        #[command(flatten)]
+        image_options: ImageOptions,
+    },
+}
+
+#[derive(Parser, Debug)]
+#[command(after_help = COPYRIGHT)]
+/// authentication used for ssh tunnels
+pub enum Auth {
+    /// show whether a login is cached for an environment, for whom and until when; no request is sent to the identity provider
+    Status {
+        /// optional: path to a .toml configuration specifying the devices execution
+        /// environment, defaults to the production environment.
+        #[arg(short = 'e', long = "env")]
+        env: Option<PathBuf>,
+        /// optional: url of a custom OpenID Connect issuer used for authentication
+        /// instead of the one of the environment.
+        #[arg(long = "issuer", requires = "client_id")]
+        issuer: Option<Url>,
+        /// optional: client id registered at the custom issuer.
+        #[arg(long = "client-id", requires = "issuer")]
+        client_id: Option<String>,
+        /// optional: print the status as json
+        #[arg(short = 'j', long = "json")]
+        json: bool,
+    },
+}
+
+#[derive(Parser, Debug)]
+#[command(after_help = COPYRIGHT)]
+/// check bmap files used for flashing images
+pub enum Bmap {
+    /// verify that the checksums recorded in a bmap file match the mapped blocks of an image, e.g. to detect a stale bmap after a rebuild; fails on any mismatch
+    Verify {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// path to the bmap file created for the (decompressed) image
+        #[arg(short = 'm', long = "bmap")]
+        bmap: PathBuf,
+        /// optional: print output as json
+        #[arg(short = 'j', long = "json")]
+        json: bool,
+    },
+}
+
+#[derive(Parser, Debug)]
+#[command(after_help = COPYRIGHT)]
+/// inspect certificates of a firmware image
+pub enum Cert {
+    /// list subject, issuer and validity of all certificates injected into the cert partition and of the CA certificates added to /usr/local/share/ca-certificates of rootA
+    List {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// optional: flag certificates expiring within the given number of days
+        #[arg(short = 't', long = "threshold-days", default_value = "30")]
+        threshold_days: i64,
+        /// optional: print output as json
+        #[arg(short = 'j', long = "json")]
+        json: bool,
+    },
+    /// verify that all certificates injected into the cert partition chain to a root CA; fails if any doesn't
+    Verify {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// path to the expected (self-signed) root CA certificate in pem format
+        #[arg(short = 'c', long = "ca")]
+        ca: PathBuf,
+        /// optional: print output as json
+        #[arg(short = 'j', long = "json")]
+        json: bool,
+    },
+}
+
+#[derive(Parser, Debug)]
+#[command(after_help = COPYRIGHT)]
+/// inspect firmware images
+pub enum Image {
+    /// print the compression detected for an image and the decompressed image it results in, without decompressing it
+    Detect {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// optional: print output as json
+        #[arg(short = 'j', long = "json")]
+        json: bool,
+    },
+    /// print the size of an image, its uncompressed size and the used and free space of its partitions; optionally the size it's compressed to by each codec
+    SizeReport {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// optional: compress the image with xz, bzip2 and gzip and report the resulting sizes and ratios; takes a while for big images
+        #[arg(long = "trial-compress")]
+        trial_compress: bool,
+        /// optional: print output as json
+        #[arg(short = 'j', long = "json")]
+        json: bool,
+        #[command(flatten)]
+        image_options: ReadImageOptions,
+    },
+    /// print start and end sector, byte offset and size of partitions of an image, e.g. for extracting them via dd; offsets of compressed images refer to the decompressed image
+    Offsets {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// optional: partition to print, e.g. rootA; may also be an absolute mountpoint configured in /etc/fstab of rootA or UUID=<uuid> of the partition's file system; defaults to boot, rootA, cert and factory
+        #[arg(short = 'a', long = "partition", value_parser = Partition::from_str)]
+        partition: Option<Partition>,
+        /// optional: print output as json
+        #[arg(short = 'j', long = "json")]
+        json: bool,
+        #[command(flatten)]
+        image_options: ReadImageOptions,
+    },
+    /// print distro, version, build id and architecture of an image as recorded in os-release of rootA, along with the label set via --label, e.g. to confirm the image version before injecting identity and certificates
+    BuildInfo {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// optional: print output as json
+        #[arg(short = 'j', long = "json")]
+        json: bool,
+        #[command(flatten)]
+        image_options: ReadImageOptions,
+    },
+    /// dump the partition table (mbr, extended boot records of logical partitions, primary and backup gpt) of an image to a file; the backup gpt is checked to be consistent
+    DumpTable {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// file the partition table is dumped to
+        #[arg(short = 'o', long = "out")]
+        out: PathBuf,
+    },
+    /// restore a partition table dumped via dump-table into an image, e.g. if it got damaged
+    RestoreTable {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// partition table dump created via dump-table
+        #[arg(short = 't', long = "table")]
+        table: PathBuf,
+        #[command(flatten)]
+        image_options: ImageOptions,
+    },
+    /// compute the dm-verity hash tree and root hash of a partition via veritysetup, e.g. after files were injected into rootA
+    Verity {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// partition the hash tree is computed for
+        #[clap(short = 'a', long = "partition", value_enum, default_value = "rootA")]
+        partition: Partition,
+        /// file the hash tree is written to
+        #[arg(short = 't', long = "hash-tree")]
+        hash_tree: PathBuf,
+        /// file the root hash is written to
+        #[arg(short = 'r', long = "root-hash", required_unless_present = "cmdline")]
+        root_hash: Option<PathBuf>,
+        /// absolute path of a kernel command line file in the boot partition the root hash is set in as "roothash=<hash>"
+        #[arg(short = 'c', long = "cmdline")]
+        cmdline: Option<PathBuf>,
+        #[command(flatten)]
+        image_options: ImageOptions,
+    },
+    /// append parameters to or replace the kernel command line in the boot loader config of the boot partition
+    SetCmdline {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// kernel parameters appended to the command line, e.g. "console=ttyS0,115200 quiet"; parameters already contained aren't added again
+        #[arg(
+            long = "append",
+            conflicts_with = "set",
+            required_unless_present = "set"
+        )]
+        append: Option<String>,
+        /// kernel command line replacing the current one
+        #[arg(long = "set")]
+        set: Option<String>,
+        /// optional: absolute path of the config in the boot partition, e.g. "/extlinux/extlinux.conf", "/EFI/BOOT/grub.cfg", a systemd-boot entry, "/uboot.env" or "/cmdline.txt"; detected if omitted
+        #[arg(short = 'c', long = "config")]
+        config: Option<PathBuf>,
+        #[command(flatten)]
+        image_options: ImageOptions,
+    },
+}
+
+#[derive(Parser, Debug)]
+#[command(after_help = COPYRIGHT)]
+/// manage partitions of a firmware image
+pub enum PartitionConfig {
+    /// wipe a partition and recreate an empty file system in it, e.g. to reset the factory partition of a populated image
+    Format {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// partition to format: boot, rootA, cert, factory, an absolute mountpoint configured in /etc/fstab of rootA or UUID=<uuid> of the partition's file system
+        #[arg(short = 'a', long = "partition", value_parser = Partition::from_str)]
+        partition: Partition,
+        /// optional: file system to create, defaults to the current one (vfat for boot, otherwise ext4)
+        #[arg(long = "fs", value_enum)]
+        fs: Option<FsType>,
+        /// optional: label of the created file system, defaults to the current one if the file system type isn't changed
+        #[arg(short = 'L', long = "fs-label")]
+        fs_label: Option<String>,
+        /// optional: don't ask for confirmation before wiping the partition
+        #[arg(short = 'y', long = "yes")]
+        yes: bool,
+        #[command(flatten)]
+        image_options: ImageOptions,
     },
 }
 
@@ -42,27 +403,147 @@ pub enum Docker {
 pub enum File {
     /// file commands, e.g. copy multiple files to/from image
     CopyToImage {
-        /// vector of copy triples in the format [in-file-path,out-partition:out-file-path]
-        #[clap(short = 'f', long = "files", value_parser = clap::value_parser!(FileCopyToParams), required(true))]
+        /// vector of copy triples in the format [in-file-path,out-partition:out-file-path]; out-partition may also be an absolute mountpoint configured in /etc/fstab of rootA or UUID=<uuid> of the partition's file system; paths containing ',' or ':' must be enclosed in double quotes; if in-file-path is a directory its tree is copied into out-file-path
+        #[clap(short = 'f', long = "files", value_parser = clap::value_parser!(FileCopyToParams), required_unless_present = "file")]
         file_copy_params: Vec<FileCopyToParams>,
+        /// optional: in-file-path copied to --destination in each partition given via --partition, in addition to the copy triples of --files; if it is a directory its tree is copied
+        #[arg(long = "file", requires_all = ["partitions", "destination"])]
+        file: Option<PathBuf>,
+        /// optional: out-partitions --file is copied to as comma-separated list or repeated, e.g. rootA,cert; each may be any out-partition accepted by --files
+        #[arg(long = "partition", value_parser = Partition::from_str, value_delimiter = ',', requires = "file")]
+        partitions: Vec<Partition>,
+        /// optional: absolute out-file-path --file is copied to in each of its partitions
+        #[arg(long = "destination", requires = "file")]
+        destination: Option<PathBuf>,
         /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
-        #[arg(short = 'b', long = "generate-bmap-file")]
-        generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
-        compress_image: Option<Compression>,
+        /// optional: follow symlinked in-files and copy the content of their targets (default)
+        #[arg(long = "dereference", overrides_with = "no_dereference")]
+        dereference: bool,
+        /// optional: recreate symlinked in-files as symlinks in the image (not supported for the vfat boot partition)
+        #[arg(long = "no-dereference", overrides_with = "dereference")]
+        no_dereference: bool,
+        /// optional: modification time of copied files in RFC 3339 format or as "@<seconds since unix epoch>", defaults to the modification time of the in-files
+        #[arg(long = "mtime", value_parser = parse_mtime)]
+        mtime: Option<u64>,
+        /// optional: numeric owner "<uid>:<gid>" of copied files, of files in copied directory trees and of directories created for them (not supported for the vfat boot partition)
+        #[arg(long = "chown", value_parser = parse_owner)]
+        owner: Option<(u32, u32)>,
+        /// optional: copy the extended attributes of in-files, e.g. capabilities or SELinux labels (not supported for the vfat boot partition)
+        #[arg(long = "preserve-xattrs")]
+        preserve_xattrs: bool,
+        /// optional: only warn instead of failing if several in-files are copied to the same destination; the last one wins
+        #[arg(long = "allow-overwrite")]
+        allow_overwrite: bool,
+        /// optional: skip in-files whose destination in the image already has the same content (the modification time isn't compared); partitions without changes aren't written back
+        #[arg(long = "incremental")]
+        incremental: bool,
+        /// optional: skip entries of in-file directories matching the glob (can be repeated); globs without '/' match entry names at any depth, others the path relative to the in-file directory; '*' and '?' don't match '/', '**' does; excluded directories are skipped with their whole tree
+        #[arg(long = "exclude")]
+        excludes: Vec<String>,
+        /// optional: refuse to copy in-files larger than the given size, e.g. 512K, 10M or 1G (in-files must always fit into the free space of their partition)
+        #[arg(long = "max-file-size", value_parser = parse_size)]
+        max_file_size: Option<u64>,
+        /// optional: read the copied files back from the image and fail if their sha256 differs from the in-files
+        #[arg(long = "verify")]
+        verify: bool,
+        /// optional: only copy if the destination already exists with the given sha256 (64 hex digits), otherwise fail with its actual hash; requires a single copy triple
+        #[arg(long = "expect-existing-sha256", value_parser = parse_sha256, conflicts_with = "expect_absent")]
+        expect_existing_sha256: Option<String>,
+        /// optional: only copy if none of the destinations exists yet, otherwise fail
+        #[arg(long = "expect-absent")]
+        expect_absent: bool,
+        /// optional: skip copy triples failing before anything is copied, e.g. due to a missing in-file, an unresolvable out-partition or an unmet expectation, and copy the others; the image is written and the command fails afterwards with a summary of the skipped triples
+        #[arg(long = "keep-going")]
+        keep_going: bool,
+        #[command(flatten)]
+        container_options: ContainerOptions,
+        #[command(flatten)]
+        image_options: ImageOptions,
     },
     /// copy files from image
     CopyFromImage {
-        /// vector of copy triples in the format [in-partition:in-file-path,out-file-path]
+        /// vector of copy triples in the format [in-partition:in-file-path,out-file-path]; in-partition may also be an absolute mountpoint configured in /etc/fstab of rootA or UUID=<uuid> of the partition's file system; paths containing ',' or ':' must be enclosed in double quotes; if in-file-path is a directory its tree is extracted into out-file-path; if out-file-path is an existing directory or ends with '/', a file is copied into it keeping its name
         #[clap(short = 'f', long = "files", value_parser = clap::value_parser!(FileCopyFromParams), required(true))]
         file_copy_params: Vec<FileCopyFromParams>,
         /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
+        #[command(flatten)]
+        read_options: ReadImageOptions,
+        /// optional: directory to keep copies of the partition images extracted by file operations in, e.g. for mounting them
+        #[arg(long = "keep-partitions")]
+        keep_partitions: Option<PathBuf>,
+        #[command(flatten)]
+        container_options: ContainerOptions,
+    },
+    /// move a file from one partition of the image to another, e.g. from factory to rootA
+    MovePartition {
+        /// source in the format partition:path, e.g. factory:/etc/config.toml; partition may also be an absolute mountpoint configured in /etc/fstab of rootA or UUID=<uuid> of the partition's file system
+        #[arg(long = "from", value_parser = parse_partition_path)]
+        from: (Partition, PathBuf),
+        /// destination in the format partition:path, e.g. rootA:/etc/config.toml; an existing file is overwritten
+        #[arg(long = "to", value_parser = parse_partition_path)]
+        to: (Partition, PathBuf),
+        /// optional: keep the source, i.e. copy instead of move; required for directories
+        #[arg(long = "keep-source")]
+        keep_source: bool,
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        #[command(flatten)]
+        image_options: ImageOptions,
+    },
+    /// append content to a file in the image (the file is created if it doesn't exist)
+    Append {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// partition of the file
+        #[clap(short = 'a', long = "partition", value_enum)]
+        partition: Partition,
+        /// absolute path of the file in the partition
+        #[clap(short = 'f', long = "path")]
+        path: PathBuf,
+        /// content to append or "@<path>" to append the content of a file
+        #[clap(short = 'c', long = "content")]
+        content: String,
+        #[command(flatten)]
+        image_options: ImageOptions,
+    },
+    /// set environment variables in /etc/environment of the image
+    SetEnv {
+        /// environment variable in the format KEY=VALUE (can be repeated); existing keys are updated
+        #[clap(short = 'v', long = "var", value_parser = clap::value_parser!(EnvVar), required(true))]
+        env_vars: Vec<EnvVar>,
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        #[command(flatten)]
+        image_options: ImageOptions,
+    },
+    /// copy systemd-networkd config files (.network, .netdev or .link), e.g. for a static ip or a vlan, to /etc/systemd/network in rootA of the image; the files are checked for syntax errors before
+    SetNetwork {
+        /// path to a systemd-networkd config file (can be repeated), copied keeping its name
+        #[arg(short = 'c', long = "config", required(true))]
+        config_files: Vec<PathBuf>,
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        #[command(flatten)]
+        image_options: ImageOptions,
+    },
+    /// set the timezone in rootA of the image: writes /etc/timezone and links /etc/localtime to the zoneinfo file of the image's tzdata (if the image has no tzdata, the host's zoneinfo file is copied)
+    SetTimezone {
+        /// timezone as named in the tzdata, e.g. Europe/Berlin or UTC
+        #[arg(short = 't', long = "tz")]
+        tz: String,
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        #[command(flatten)]
+        image_options: ImageOptions,
     },
 }
 
@@ -78,15 +559,34 @@ pub enum IdentityConfig {
         /// optional: path to extra DPS payload file
         #[arg(short = 'e', long = "extra-dps-payload")]
         payload: Option<PathBuf>,
+        /// optional: deep-merge the config file on top of the config.toml of the image instead of replacing it (tables are merged, other values and arrays are replaced)
+        #[arg(short = 'm', long = "merge")]
+        merge: bool,
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        #[command(flatten)]
+        image_options: ImageOptions,
+    },
+    /// set dps provisioning (id scope, registration id and optionally the attestation) in the config.toml of the image, other settings are kept
+    SetProvisioning {
         /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
-        #[arg(short = 'b', long = "generate-bmap-file")]
-        generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
-        compress_image: Option<Compression>,
+        /// dps id scope
+        #[arg(short = 's', long = "id-scope")]
+        id_scope: String,
+        /// dps registration id; for x509 with EST the common name of the requested certificate is set too
+        #[arg(short = 'r', long = "registration-id")]
+        registration_id: String,
+        /// optional: attest via the given base64 encoded symmetric key
+        #[arg(long = "symmetric-key", conflicts_with = "x509")]
+        symmetric_key: Option<String>,
+        /// optional: attest via the device certificate injected by set-device-certificate-no-est; an existing x509 attestation (e.g. with EST) is kept
+        #[arg(long = "x509")]
+        x509: bool,
+        #[command(flatten)]
+        image_options: ImageOptions,
     },
     /// EXPERIMENTAL: set transparent gateway config.toml file and additional certificates and keys
     SetIotedgeGatewayConfig {
@@ -105,12 +605,8 @@ pub enum IdentityConfig {
         /// path to device identity certificate key file
         #[arg(short = 'k', long = "device_identity_key")]
         device_identity_key: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
-        #[arg(short = 'b', long = "generate-bmap-file")]
-        generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
-        compress_image: Option<Compression>,
+        #[command(flatten)]
+        image_options: ImageOptions,
     },
     /// EXPERIMENTAL: set leaf device config.toml file and additional certificate
     SetIotLeafSasConfig {
@@ -123,12 +619,8 @@ pub enum IdentityConfig {
         /// path to root ca certificate file
         #[arg(short = 'r', long = "root_ca")]
         root_ca: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
-        #[arg(short = 'b', long = "generate-bmap-file")]
-        generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
-        compress_image: Option<Compression>,
+        #[command(flatten)]
+        image_options: ImageOptions,
     },
     /// set certificates in order to support X.509 based DPS provisioning and certificate renewal via EST
     SetDeviceCertificate {
@@ -136,23 +628,75 @@ pub enum IdentityConfig {
         #[arg(short = 'c', long = "intermediate-full-chain-cert")]
         intermediate_full_chain_cert: PathBuf,
         /// path to intermediate key pem file
-        #[arg(short = 'k', long = "intermediate-key")]
-        intermediate_key: PathBuf,
+        #[arg(
+            short = 'k',
+            long = "intermediate-key",
+            required_unless_present = "intermediate_key_pkcs11"
+        )]
+        intermediate_key: Option<PathBuf>,
+        /// PKCS#11 URI of the intermediate key, e.g. on a HSM, used via the openssl pkcs11 engine instead of a key file
+        /// (e.g. "pkcs11:token=my-token;object=int-ca-key;pin-source=file:/run/pin"); without PIN openssl prompts for it
+        #[arg(long = "intermediate-key-pkcs11", conflicts_with = "intermediate_key")]
+        intermediate_key_pkcs11: Option<String>,
+        /// optional: type of the created device key; Ed25519 keys are only accepted as EST bootstrap certificate, not for X.509 attestation with DPS
+        #[arg(long = "key-type", value_enum, default_value = "ec-p256")]
+        key_type: KeyType,
         /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
         /// device id
-        #[arg(short = 'd', long = "device-id")]
-        device_id: String,
+        #[arg(
+            short = 'd',
+            long = "device-id",
+            required_unless_present = "device_ids_csv"
+        )]
+        device_id: Option<String>,
+        /// path to csv file with one "device_id[,output_image]" per line: creates a copy of the image per device
+        /// (default output: "<image>_<device_id>.wic" next to the image); the given image is left unchanged
+        #[arg(long = "device-ids-csv", conflicts_with = "device_id")]
+        device_ids_csv: Option<PathBuf>,
+        /// period of validity in days
+        #[arg(short = 'D', long = "days")]
+        days: u32,
+        /// optional: https URL of the EST server set in the identity config of the image, e.g. "https://est.example.com/.well-known/est"
+        #[arg(long = "est-url", value_parser = parse_https_url)]
+        est_url: Option<Url>,
+        /// optional: pem file of the CA trusted for the EST server; injected into the cert partition and added to the trusted certificates of the identity config
+        #[arg(long = "est-trusted-ca")]
+        est_trusted_ca: Option<PathBuf>,
+        /// optional: how the device certificate authenticates to the EST server, set in the identity config; by default the configured auth is kept, a missing EST config is created with 'bootstrap'
+        #[arg(long = "est-auth", value_enum)]
+        est_auth: Option<EstAuth>,
+        #[command(flatten)]
+        image_options: ImageOptions,
+    },
+    /// renew the device certificate of an image: issues a new certificate and key for the device id of the injected certificate
+    RenewCert {
+        /// path to intermediate full-chain-certificate pem file
+        #[arg(short = 'c', long = "intermediate-full-chain-cert")]
+        intermediate_full_chain_cert: PathBuf,
+        /// path to intermediate key pem file
+        #[arg(
+            short = 'k',
+            long = "intermediate-key",
+            required_unless_present = "intermediate_key_pkcs11"
+        )]
+        intermediate_key: Option<PathBuf>,
+        /// PKCS#11 URI of the intermediate key, e.g. on a HSM, used via the openssl pkcs11 engine instead of a key file
+        /// (e.g. "pkcs11:token=my-token;object=int-ca-key;pin-source=file:/run/pin"); without PIN openssl prompts for it
+        #[arg(long = "intermediate-key-pkcs11", conflicts_with = "intermediate_key")]
+        intermediate_key_pkcs11: Option<String>,
+        /// optional: type of the created device key; Ed25519 keys are only accepted as EST bootstrap certificate, not for X.509 attestation with DPS
+        #[arg(long = "key-type", value_enum, default_value = "ec-p256")]
+        key_type: KeyType,
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
         /// period of validity in days
         #[arg(short = 'D', long = "days")]
         days: u32,
-        /// optional: generate bmap file (currently not working in docker image)
-        #[arg(short = 'b', long = "generate-bmap-file")]
-        generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
-        compress_image: Option<Compression>,
+        #[command(flatten)]
+        image_options: ImageOptions,
     },
     /// set certificates in order to support X.509 based DPS provisioning WITHOUT certificate renewal via EST
     SetDeviceCertificateNoEst {
@@ -165,12 +709,35 @@ pub enum IdentityConfig {
         /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
-        #[arg(short = 'b', long = "generate-bmap-file")]
-        generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
-        compress_image: Option<Compression>,
+        #[command(flatten)]
+        image_options: ImageOptions,
+    },
+    /// check an identity config and the certificates and keys injected along with it for consistency without touching an image; all problems found are reported
+    Validate {
+        /// path to config.toml file
+        #[arg(short = 'c', long = "config")]
+        config: PathBuf,
+        /// optional: path to extra DPS payload file
+        #[arg(short = 'e', long = "extra-dps-payload")]
+        payload: Option<PathBuf>,
+        /// optional: path to device certificate pem file
+        #[arg(long = "device-cert")]
+        device_cert: Option<PathBuf>,
+        /// optional: path to device key pem file
+        #[arg(long = "device-key", requires = "device_cert")]
+        device_key: Option<PathBuf>,
+        /// optional: path to intermediate full-chain-certificate pem file the device certificate has to be issued by
+        #[arg(long = "intermediate-full-chain-cert", requires = "device_cert")]
+        intermediate_full_chain_cert: Option<PathBuf>,
+    },
+    /// print the config.toml of the image consistently formatted, e.g. for support requests
+    Show {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// optional: replace secrets (symmetric keys, connection strings, passwords) by "<redacted>"
+        #[arg(long = "redact")]
+        redact: bool,
     },
 }
 
@@ -181,17 +748,58 @@ pub enum IotHubDeviceUpdate {
     /// copy device update configuration to image
     SetDeviceConfig {
         /// path to device-update configuration file
-        #[arg(short = 'c', long = "config")]
-        iot_hub_device_update_config: PathBuf,
+        #[arg(
+            short = 'c',
+            long = "config",
+            required_unless_present = "from_components"
+        )]
+        iot_hub_device_update_config: Option<PathBuf>,
+        /// assemble the configuration from --manufacturer, --model and the following options instead of a file
+        #[arg(
+            long = "from-components",
+            conflicts_with = "iot_hub_device_update_config",
+            requires_all = ["manufacturer", "model"]
+        )]
+        from_components: bool,
+        /// device manufacturer reported by the agent
+        #[arg(long = "manufacturer", requires = "from_components")]
+        manufacturer: Option<String>,
+        /// device model reported by the agent
+        #[arg(long = "model", requires = "from_components")]
+        model: Option<String>,
+        /// optional: compatibility id added to the compatibility properties
+        #[arg(long = "compatibility-id", requires = "from_components")]
+        compatibility_id: Option<String>,
+        /// optional: name of the agent
+        #[arg(
+            long = "agent-name",
+            default_value = "AducIotAgent",
+            requires = "from_components"
+        )]
+        agent_name: String,
+        /// optional: iot hub hostname of the agent's connection string; the agent connects via the identity service if omitted
+        #[arg(long = "iot-hub-hostname", requires_all = ["from_components", "device_id", "shared_access_key"])]
+        iot_hub_hostname: Option<String>,
+        /// optional: device id of the agent's connection string
+        #[arg(long = "device-id", requires = "iot_hub_hostname")]
+        device_id: Option<String>,
+        /// optional: module id of the agent's connection string
+        #[arg(long = "module-id", requires = "iot_hub_hostname")]
+        module_id: Option<String>,
+        /// optional: base64 encoded shared access key of the agent's connection string
+        #[arg(long = "shared-access-key", requires = "iot_hub_hostname")]
+        shared_access_key: Option<String>,
+        /// optional: partition the configuration is stored to
+        #[clap(short = 'a', long = "partition", value_enum, default_value = "factory")]
+        partition: Partition,
+        /// optional: destination path of the configuration in the partition
+        #[clap(short = 'f', long = "path", default_value = "/etc/adu/du-config.json")]
+        path: PathBuf,
         /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
-        #[arg(short = 'b', long = "generate-bmap-file")]
-        generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
-        compress_image: Option<Compression>,
+        #[command(flatten)]
+        image_options: ImageOptions,
     },
     /// import update to azure iot-hub
     ImportUpdate {
@@ -293,6 +901,40 @@ pub enum IotHubDeviceUpdate {
     },
 }
 
+#[derive(Parser, Debug)]
+#[command(after_help = COPYRIGHT)]
+/// enable or disable systemd units in rootA of the image
+pub enum Service {
+    /// enable a unit at boot by linking it into /etc/systemd/system/<target>.wants for each target of its WantedBy= (the unit file must exist in the image)
+    Enable {
+        /// unit name, e.g. my-app or fstrim.timer ('.service' is appended if no unit type is given)
+        #[arg(short = 'n', long = "name")]
+        name: String,
+        /// optional: target to enable the unit for instead of the targets of its WantedBy=
+        #[arg(short = 't', long = "target")]
+        target: Option<String>,
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        #[command(flatten)]
+        image_options: ImageOptions,
+    },
+    /// disable a unit by removing its links from /etc/systemd/system/<target>.wants for each target of its WantedBy= (the unit file must exist in the image)
+    Disable {
+        /// unit name, e.g. my-app or fstrim.timer ('.service' is appended if no unit type is given)
+        #[arg(short = 'n', long = "name")]
+        name: String,
+        /// optional: target to disable the unit for instead of the targets of its WantedBy=
+        #[arg(short = 't', long = "target")]
+        target: Option<String>,
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        #[command(flatten)]
+        image_options: ImageOptions,
+    },
+}
+
 #[derive(Parser, Debug)]
 #[command(after_help = COPYRIGHT)]
 /// ssh tunnel configuration
@@ -305,12 +947,22 @@ pub enum SshConfig {
         /// path to public key of the ssh root ca
         #[arg(short = 'r', long = "root_ca")]
         root_ca: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
-        #[arg(short = 'b', long = "generate-bmap-file")]
-        generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
-        compress_image: Option<Compression>,
+        #[command(flatten)]
+        image_options: ImageOptions,
+    },
+    /// set the authorized ssh keys of a user in rootA (~/.ssh/authorized_keys, replacing existing keys)
+    SetAuthorizedKeys {
+        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// user configured in /etc/passwd of rootA
+        #[arg(short = 'u', long = "user")]
+        user: String,
+        /// ssh public key in authorized_keys format or "@<path>" of a file containing keys (can be repeated)
+        #[arg(short = 'k', long = "key", required = true)]
+        keys: Vec<String>,
+        #[command(flatten)]
+        image_options: ImageOptions,
     },
 
     /// set ssh connection parameters (currently not working in docker image)
@@ -337,6 +989,18 @@ pub enum SshConfig {
         /// environment, defaults to the production environment.
         #[arg(short = 'e', long = "env")]
         env: Option<PathBuf>,
+        /// optional: url of a custom OpenID Connect issuer used for authentication
+        /// instead of the one of the environment. The endpoints are taken from its
+        /// discovery document.
+        #[arg(long = "issuer", requires = "client_id")]
+        issuer: Option<Url>,
+        /// optional: client id registered at the custom issuer.
+        #[arg(long = "client-id", requires = "issuer")]
+        client_id: Option<String>,
+        /// optional: scope requested from the custom issuer (can be repeated),
+        /// defaults to "openid".
+        #[arg(long = "scope", requires = "issuer")]
+        scopes: Vec<String>,
         /// name of the device for which the ssh tunnel should be created.
         device: String,
     },
@@ -346,7 +1010,31 @@ pub enum SshConfig {
 #[command(version, after_help = COPYRIGHT, verbatim_doc_comment)]
 /// This tool helps to manage your omnect devices. For more information visit:
 /// https://github.com/omnect/omnect-cli
+pub struct Cli {
+    /// optional: on failure print the error as json to stdout, including its context chain and originating source, e.g. a failed external command
+    #[arg(long = "json-errors", global = true)]
+    pub json_errors: bool,
+    /// optional: fail if omnect-cli logged any warning, e.g. an ignored failure of an external command; the command itself is completed anyway
+    #[arg(long = "fail-on-warning", global = true)]
+    pub fail_on_warning: bool,
+    /// optional: number of threads used at most, e.g. to limit the cpu usage on shared machines: by xz compression (default: number of cpus) and by default as number of workers of file operations, which '--parallel' overrides
+    #[arg(long = "threads", global = true, value_parser = clap::value_parser!(u16).range(1..))]
+    pub threads: Option<u16>,
+    /// optional: refuse to write to any image, e.g. when inspecting production images: commands modifying an image fail before touching it, so only reading commands like 'file copy-from-image' or 'image detect' can run
+    #[arg(long = "read-only", global = true)]
+    pub read_only: bool,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
 pub enum Command {
+    #[command(subcommand)]
+    Auth(Auth),
+    #[command(subcommand)]
+    Bmap(Bmap),
+    #[command(subcommand)]
+    Cert(Cert),
     #[command(subcommand)]
     Docker(Docker),
     #[command(subcommand)]
@@ -354,11 +1042,23 @@ pub enum Command {
     #[command(subcommand)]
     Identity(IdentityConfig),
     #[command(subcommand)]
+    Image(Image),
+    #[command(subcommand)]
     IotHubDeviceUpdate(IotHubDeviceUpdate),
     #[command(subcommand)]
+    Partition(PartitionConfig),
+    /// print the JSON schema of a config file as validated by omnect-cli, e.g. for editor validation
+    Schema {
+        /// kind of config file
+        #[arg(value_enum)]
+        kind: SchemaKind,
+    },
+    #[command(subcommand)]
+    Service(Service),
+    #[command(subcommand)]
     Ssh(SshConfig),
 }
 
-pub fn from_args() -> Command {
-    Command::parse()
+pub fn from_args() -> Cli {
+    Cli::parse()
 }
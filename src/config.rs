@@ -1,3 +1,4 @@
+use anyhow::{Context, Result};
 use serde::Deserialize;
 
 use crate::auth::AuthInfo;
@@ -25,19 +26,142 @@ impl From<KeycloakInfo> for AuthInfo {
             bind_addrs: val.bind_addrs,
             redirect_addr: val.redirect,
             client_id: val.client_id,
+            scopes: vec![],
         }
     }
 }
 
+fn default_bind_addrs() -> Vec<String> {
+    vec!["127.0.0.1:4000".to_string(), "[::1]:4000".to_string()]
+}
+
+fn default_redirect() -> url::Url {
+    url::Url::parse("http://localhost:4000").unwrap()
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".to_string()]
+}
+
+/// Generic OpenID Connect provider whose endpoints are taken from its
+/// discovery document.
+#[derive(Clone, Deserialize)]
+pub struct OidcInfo {
+    pub issuer: url::Url,
+    pub client_id: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    #[serde(default = "default_bind_addrs")]
+    pub bind_addrs: Vec<String>,
+    #[serde(default = "default_redirect")]
+    pub redirect: url::Url,
+}
+
+#[derive(Deserialize)]
+struct OidcDiscovery {
+    issuer: String,
+    authorization_endpoint: Option<String>,
+    token_endpoint: Option<String>,
+    #[serde(default)]
+    scopes_supported: Vec<String>,
+}
+
+impl OidcInfo {
+    pub fn new(issuer: url::Url, client_id: String, scopes: Vec<String>) -> Self {
+        OidcInfo {
+            issuer,
+            client_id,
+            scopes: if scopes.is_empty() {
+                default_scopes()
+            } else {
+                scopes
+            },
+            bind_addrs: default_bind_addrs(),
+            redirect: default_redirect(),
+        }
+    }
+
+    fn discovery_url(&self) -> String {
+        format!(
+            "{}/.well-known/openid-configuration",
+            self.issuer.as_str().trim_end_matches('/')
+        )
+    }
+
+    async fn discover(self) -> Result<AuthInfo> {
+        let url = self.discovery_url();
+
+        log::debug!("fetch discovery document {url}");
+
+        let discovery: OidcDiscovery = reqwest::get(&url)
+            .await
+            .and_then(|r| r.error_for_status())
+            .context(format!("discover: cannot get {url}"))?
+            .json()
+            .await
+            .context(format!("discover: invalid discovery document {url}"))?;
+
+        anyhow::ensure!(
+            discovery.issuer.trim_end_matches('/') == self.issuer.as_str().trim_end_matches('/'),
+            "discover: issuer mismatch: expected {}, discovery document states {}",
+            self.issuer,
+            discovery.issuer
+        );
+
+        let auth_url = discovery
+            .authorization_endpoint
+            .context("discover: issuer doesn't advertise an authorization_endpoint")?;
+        let token_url = discovery
+            .token_endpoint
+            .context("discover: issuer doesn't advertise a token_endpoint")?;
+
+        for scope in &self.scopes {
+            if !discovery.scopes_supported.is_empty() && !discovery.scopes_supported.contains(scope)
+            {
+                log::warn!("scope {scope} isn't advertised by issuer {}", self.issuer);
+            }
+        }
+
+        Ok(AuthInfo {
+            auth_url,
+            token_url,
+            bind_addrs: self.bind_addrs,
+            redirect_addr: self.redirect,
+            client_id: self.client_id,
+            scopes: self.scopes,
+        })
+    }
+}
+
 #[derive(Clone, Deserialize)]
 pub enum AuthProvider {
     Keycloak(KeycloakInfo),
+    Oidc(OidcInfo),
 }
 
-impl From<AuthProvider> for AuthInfo {
-    fn from(val: AuthProvider) -> Self {
-        match val {
-            AuthProvider::Keycloak(kc) => kc.into(),
+impl AuthProvider {
+    /// Resolves the endpoints of the provider, which requires fetching the
+    /// discovery document in case of a generic OpenID Connect provider.
+    pub async fn auth_info(self) -> Result<AuthInfo> {
+        match self {
+            AuthProvider::Keycloak(kc) => Ok(kc.into()),
+            AuthProvider::Oidc(oidc) => oidc.discover().await,
+        }
+    }
+
+    /// Returns the issuer of tokens without contacting the provider.
+    pub fn issuer(&self) -> String {
+        match self {
+            AuthProvider::Keycloak(kc) => format!("{}/realms/{}", kc.provider, kc.realm),
+            AuthProvider::Oidc(oidc) => oidc.issuer.to_string(),
+        }
+    }
+
+    /// Returns the client id, which tokens are cached in the key ring for.
+    pub fn client_id(&self) -> &str {
+        match self {
+            AuthProvider::Keycloak(kc) => &kc.client_id,
+            AuthProvider::Oidc(oidc) => &oidc.client_id,
         }
     }
 }
@@ -66,3 +190,85 @@ lazy_static::lazy_static! {
         })
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+
+    #[tokio::test]
+    async fn oidc_discovery() {
+        let server = MockServer::start();
+        let issuer = server.url("/realms/test");
+
+        server.mock(|when, then| {
+            when.method(GET)
+                .path("/realms/test/.well-known/openid-configuration");
+            then.status(200).json_body(serde_json::json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{issuer}/auth"),
+                "token_endpoint": format!("{issuer}/token"),
+                "scopes_supported": ["openid", "profile"],
+            }));
+        });
+
+        let info = OidcInfo::new(
+            url::Url::parse(&issuer).unwrap(),
+            "my-client".to_string(),
+            vec![],
+        );
+        let auth_info = AuthProvider::Oidc(info).auth_info().await.unwrap();
+
+        assert_eq!(auth_info.auth_url, format!("{issuer}/auth"));
+        assert_eq!(auth_info.token_url, format!("{issuer}/token"));
+        assert_eq!(auth_info.client_id, "my-client");
+        assert_eq!(auth_info.scopes, vec!["openid".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn oidc_discovery_missing_endpoint() {
+        let server = MockServer::start();
+        let issuer = server.url("/realms/test");
+
+        server.mock(|when, then| {
+            when.method(GET)
+                .path("/realms/test/.well-known/openid-configuration");
+            then.status(200).json_body(serde_json::json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{issuer}/auth"),
+            }));
+        });
+
+        let info = OidcInfo::new(
+            url::Url::parse(&issuer).unwrap(),
+            "my-client".to_string(),
+            vec![],
+        );
+
+        assert!(AuthProvider::Oidc(info).auth_info().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oidc_discovery_issuer_mismatch() {
+        let server = MockServer::start();
+        let issuer = server.url("/realms/test");
+
+        server.mock(|when, then| {
+            when.method(GET)
+                .path("/realms/test/.well-known/openid-configuration");
+            then.status(200).json_body(serde_json::json!({
+                "issuer": "https://other.example.com",
+                "authorization_endpoint": format!("{issuer}/auth"),
+                "token_endpoint": format!("{issuer}/token"),
+            }));
+        });
+
+        let info = OidcInfo::new(
+            url::Url::parse(&issuer).unwrap(),
+            "my-client".to_string(),
+            vec![],
+        );
+
+        assert!(AuthProvider::Oidc(info).auth_info().await.is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::sync::Mutex;
+
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+/// Logger forwarding all records to `inner` and collecting the warnings of
+/// omnect-cli, regardless of the level `inner` is filtered with.
+struct Collector<L: Log> {
+    inner: L,
+}
+
+impl<L: Log> Log for Collector<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        is_collected(metadata) || self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if is_collected(record.metadata()) {
+            WARNINGS.lock().unwrap().push(record.args().to_string());
+        }
+
+        if self.inner.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// warnings of dependencies aren't collected, since they aren't actionable
+fn is_collected(metadata: &Metadata) -> bool {
+    metadata.level() == Level::Warn && metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+}
+
+/// Installs `logger` as global logger and collects the warnings logged via it,
+/// as returned by `warnings`.
+pub fn init(logger: env_logger::Logger) -> Result<(), SetLoggerError> {
+    let max_level = logger.filter().max(LevelFilter::Warn);
+
+    log::set_boxed_logger(Box::new(Collector { inner: logger }))?;
+    log::set_max_level(max_level);
+
+    Ok(())
+}
+
+/// Returns the warnings logged by omnect-cli so far.
+pub fn warnings() -> Vec<String> {
+    WARNINGS.lock().unwrap().clone()
+}
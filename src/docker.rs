@@ -1,5 +1,6 @@
 use anyhow::{Context, Result};
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 
 use crate::file::compression::Compression;
 use crate::image::Architecture;
@@ -54,7 +55,7 @@ pub fn pull_image(name: impl AsRef<str>, arch: Architecture) -> Result<PathBuf>
             fs::canonicalize(&out_path).unwrap().to_string_lossy(),
         ))?;
 
-    Compression::gzip.compress(&mut image_file, &mut out_file)?;
+    Compression::gzip { rsyncable: false }.compress(&mut image_file, &mut out_file)?;
 
     let error_code = child.wait()?;
 
@@ -65,3 +66,145 @@ pub fn pull_image(name: impl AsRef<str>, arch: Architecture) -> Result<PathBuf>
 
     Ok(out_path)
 }
+
+/// Runs the current omnect-cli command line inside the omnect-cli docker image,
+/// which comes with all tools required by file operations. Host paths are bind
+/// mounted to the same path in the container, so that the arguments stay valid:
+/// for each of `paths` its nearest existing directory is mounted. The options
+/// `--in-container` and `--container-image` aren't passed on.
+pub fn run_in_container(container_image: Option<&str>, paths: &[&Path]) -> Result<()> {
+    if let Ok("true") | Ok("1") = std::env::var("CONTAINERIZED").as_deref() {
+        anyhow::bail!("run_in_container: already running in a containerized environment.");
+    }
+
+    let cwd = std::env::current_dir().context("run_in_container: cannot get current dir")?;
+
+    let mut mounts = paths
+        .iter()
+        .map(|p| mount_dir(&cwd, p))
+        .collect::<Result<Vec<_>>>()?;
+    mounts.sort();
+    mounts.dedup();
+
+    let cli_args = container_args(std::env::args_os().skip(1));
+
+    let default_image = format!("omnect/omnect-cli:{}", env!("CARGO_PKG_VERSION"));
+    // SAFETY: getuid and getgid are always successful and don't access memory
+    let user = unsafe { format!("{}:{}", libc::getuid(), libc::getgid()) };
+
+    let mut docker = Command::new("docker");
+    docker
+        .args(["run", "--rm"])
+        .args(["-u", &user])
+        .args(["-e", "RUST_LOG", "-e", "XZ_COMPRESSION_LEVEL"])
+        .arg("-w")
+        .arg(&cwd);
+    for mount in &mounts {
+        let mount = mount.to_str().context(format!(
+            "run_in_container: invalid path {}",
+            mount.to_string_lossy()
+        ))?;
+        docker.arg("-v").arg(format!("{mount}:{mount}"));
+    }
+    docker
+        .arg(container_image.unwrap_or(&default_image))
+        .args(cli_args);
+
+    log::debug!("run_in_container: {docker:?}");
+
+    let status = docker
+        .status()
+        .context("run_in_container: could not run \"docker run\" command")?;
+
+    anyhow::ensure!(
+        status.success(),
+        "run_in_container: omnect-cli failed in container ({status})"
+    );
+
+    Ok(())
+}
+
+/// Returns the command line arguments `args` without the options
+/// `--in-container` and `--container-image`, which would otherwise be passed
+/// on to the omnect-cli in the container.
+fn container_args(mut args: impl Iterator<Item = OsString>) -> Vec<OsString> {
+    let mut cli_args: Vec<OsString> = vec![];
+    while let Some(arg) = args.next() {
+        if arg == "--in-container" || arg.to_string_lossy().starts_with("--container-image=") {
+            continue;
+        }
+        if arg == "--container-image" {
+            args.next();
+            continue;
+        }
+        cli_args.push(arg);
+    }
+
+    cli_args
+}
+
+/// Returns the nearest existing directory of `path`, which may not exist yet,
+/// e.g. if it is the destination of a copy.
+fn mount_dir(cwd: &Path, path: &Path) -> Result<PathBuf> {
+    let path = cwd.join(path);
+    let dir = path
+        .ancestors()
+        .find(|p| p.is_dir())
+        .unwrap_or(Path::new("/"));
+
+    anyhow::ensure!(
+        dir != Path::new("/"),
+        "run_in_container: refusing to mount / for {}",
+        path.to_string_lossy()
+    );
+
+    Ok(dir.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn container_args_filtered() {
+        let args = [
+            "file",
+            "--in-container",
+            "copy-to-image",
+            "--container-image",
+            "my/image:1.0",
+            "-i",
+            "image.wic",
+            "--container-image=my/image:2.0",
+            "--verify",
+        ]
+        .map(OsString::from);
+
+        assert_eq!(
+            container_args(args.into_iter()),
+            ["file", "copy-to-image", "-i", "image.wic", "--verify"].map(OsString::from)
+        );
+    }
+
+    #[test]
+    fn mount_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwd = dir.path();
+        fs::create_dir(cwd.join("sub")).unwrap();
+        fs::write(cwd.join("sub/image.wic"), "").unwrap();
+
+        // existing files are mounted via their directory
+        assert_eq!(
+            mount_dir(cwd, Path::new("sub/image.wic")).unwrap(),
+            cwd.join("sub")
+        );
+        assert_eq!(mount_dir(cwd, &cwd.join("sub")).unwrap(), cwd.join("sub"));
+
+        // missing paths are mounted via their nearest existing directory
+        assert_eq!(
+            mount_dir(cwd, Path::new("sub/missing/out-file")).unwrap(),
+            cwd.join("sub")
+        );
+        assert!(mount_dir(cwd, Path::new("/missing/out-file")).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use serde_json::{json, Value};
+use std::fmt::{self, Display};
+use std::process::{Command, ExitStatus};
+
+/// Failure of an external command. Kept as typed error in the anyhow chain, so
+/// that `--json-errors` is able to report the command as originating source.
+#[derive(Debug)]
+pub struct CommandError {
+    function: String,
+    cmd: String,
+    argv: Vec<String>,
+    exit_status: Option<i32>,
+}
+
+impl CommandError {
+    pub fn new(function: &str, cmd: &Command, status: &ExitStatus) -> Self {
+        CommandError {
+            function: function.to_string(),
+            cmd: format!("{cmd:?}"),
+            argv: argv(cmd),
+            exit_status: status.code(),
+        }
+    }
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: cmd failed: {}", self.function, self.cmd)
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Program and arguments of `cmd`.
+pub fn argv(cmd: &Command) -> Vec<String> {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy().to_string())
+        .collect()
+}
+
+/// Serializes the context chain of `err`, outermost context first, and the
+/// originating source if it is an external command or an io error.
+pub fn to_json(err: &anyhow::Error) -> Value {
+    let chain: Vec<String> = err.chain().map(|e| e.to_string()).collect();
+
+    let source = err.chain().find_map(|e| {
+        if let Some(e) = e.downcast_ref::<CommandError>() {
+            Some(json!({
+                "type": "command",
+                "function": e.function,
+                "argv": e.argv,
+                "exitStatus": e.exit_status,
+            }))
+        } else {
+            e.downcast_ref::<std::io::Error>().map(|e| {
+                json!({
+                    "type": "io",
+                    "kind": format!("{:?}", e.kind()),
+                    "osError": e.raw_os_error(),
+                })
+            })
+        }
+    });
+
+    json!({
+        "error": {
+            "chain": chain,
+            "source": source,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn json_chain_with_command_source() {
+        let status = Command::new("false").status().unwrap();
+        let mut cmd = Command::new("false");
+        cmd.arg("-x");
+
+        let err = Err::<(), _>(anyhow::Error::new(CommandError::new(
+            "my_function",
+            &cmd,
+            &status,
+        )))
+        .context("inner context")
+        .context("outer context")
+        .unwrap_err();
+
+        let json = to_json(&err);
+
+        assert_eq!(
+            json["error"]["chain"],
+            json!([
+                "outer context",
+                "inner context",
+                "my_function: cmd failed: \"false\" \"-x\""
+            ])
+        );
+        assert_eq!(json["error"]["source"]["type"], "command");
+        assert_eq!(json["error"]["source"]["argv"], json!(["false", "-x"]));
+        assert_eq!(json["error"]["source"]["exitStatus"], 1);
+    }
+
+    #[test]
+    fn json_chain_with_io_source() {
+        let err = std::fs::read("/non/existing/file")
+            .context("cannot read /non/existing/file")
+            .unwrap_err();
+
+        let json = to_json(&err);
+
+        assert_eq!(json["error"]["chain"][0], "cannot read /non/existing/file");
+        assert_eq!(json["error"]["source"]["type"], "io");
+        assert_eq!(json["error"]["source"]["kind"], "NotFound");
+    }
+}
@@ -3,7 +3,9 @@ use filemagic::Magic;
 use log::debug;
 use std::env;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
@@ -11,9 +13,59 @@ use strum_macros::EnumIter;
 #[derive(Clone, Debug, EnumIter)]
 #[allow(non_camel_case_types)]
 pub enum Compression {
-    xz { compression_level: u32 },
+    // dict_size in bytes, the preset's dictionary size if not set; threads
+    // used for compression, the number of CPUs if not set
+    xz {
+        compression_level: u32,
+        dict_size: Option<u32>,
+        threads: Option<u32>,
+    },
+    // block_size in units of 100k
+    bzip2 {
+        block_size: u32,
+    },
+    gzip {
+        rsyncable: bool,
+    },
+}
+
+/// Format of a source image forced via `--image-format`, e.g. if libmagic
+/// misidentifies it, or of the written image as chosen via `-p`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum ImageFormat {
+    xz,
+    #[value(alias = "bz2")]
     bzip2,
+    #[value(alias = "gz")]
     gzip,
+    /// uncompressed image
+    none,
+}
+
+impl ImageFormat {
+    fn compression(&self) -> Option<Compression> {
+        match self {
+            // level, dictionary and block size only matter for compression
+            ImageFormat::xz => Some(Compression::xz {
+                compression_level: 9,
+                dict_size: None,
+                threads: None,
+            }),
+            ImageFormat::bzip2 => Some(Compression::bzip2 { block_size: 9 }),
+            ImageFormat::gzip => Some(Compression::gzip { rsyncable: false }),
+            ImageFormat::none => None,
+        }
+    }
+
+    /// Returns the compression a written image is packed with, i.e. for xz the
+    /// level set via `XZ_COMPRESSION_LEVEL`.
+    pub fn pack_compression(&self) -> Option<Compression> {
+        match self {
+            ImageFormat::xz => Compression::from_str("xz").ok(),
+            format => format.compression(),
+        }
+    }
 }
 
 impl FromStr for Compression {
@@ -31,37 +83,72 @@ impl FromStr for Compression {
 
                 Ok(Compression::xz {
                     compression_level: level,
+                    dict_size: None,
+                    threads: None,
                 })
             }
-            "bzip2" => Ok(Compression::bzip2),
-            "gzip" => Ok(Compression::gzip),
+            "bzip2" => Ok(Compression::bzip2 { block_size: 9 }),
+            "gzip" => Ok(Compression::gzip { rsyncable: false }),
             _ => anyhow::bail!("unknown compression: use either xz, bzip2 or gzip"),
         }
     }
 }
 
 impl Compression {
+    /// Limits the threads used for compression to `threads`, if set. Only xz
+    /// compresses multithreaded.
+    pub fn with_threads(self, threads: Option<usize>) -> Compression {
+        match self {
+            Compression::xz {
+                compression_level,
+                dict_size,
+                threads: xz_threads,
+            } => Compression::xz {
+                compression_level,
+                dict_size,
+                threads: threads.map(|t| t as u32).or(xz_threads),
+            },
+            c => c,
+        }
+    }
+
     pub fn compress(
         &self,
         source: &mut std::fs::File,
         destination: &mut std::fs::File,
     ) -> std::io::Result<u64> {
         let mut enc: Box<dyn std::io::Write> = match &self {
-            Compression::bzip2 => Box::new(bzip2::write::BzEncoder::new(
-                destination,
-                bzip2::Compression::best(),
-            )),
-            Compression::gzip => Box::new(flate2::write::GzEncoder::new(
+            Compression::bzip2 { block_size } => Box::new(bzip2::write::BzEncoder::new(
                 destination,
-                flate2::Compression::best(),
+                bzip2::Compression::new(*block_size),
             )),
+            Compression::gzip { rsyncable } => {
+                let enc = flate2::write::GzEncoder::new(destination, flate2::Compression::best());
+                if *rsyncable {
+                    Box::new(RsyncableWriter::new(enc))
+                } else {
+                    Box::new(enc)
+                }
+            }
             Compression::xz {
                 compression_level: level,
+                dict_size,
+                threads,
             } => {
-                let stream = xz2::stream::MtStreamBuilder::new()
-                    .threads(num_cpus::get() as u32)
-                    .preset(*level)
-                    .encoder()?;
+                let mut builder = xz2::stream::MtStreamBuilder::new();
+                builder
+                    .threads(threads.unwrap_or_else(|| num_cpus::get() as u32))
+                    .preset(*level);
+
+                if let Some(dict_size) = dict_size {
+                    let mut options = xz2::stream::LzmaOptions::new_preset(*level)?;
+                    options.dict_size(*dict_size);
+                    let mut filters = xz2::stream::Filters::new();
+                    filters.lzma2(&options);
+                    builder.filters(filters);
+                }
+
+                let stream = builder.encoder()?;
                 Box::new(xz2::write::XzEncoder::new_stream(destination, stream))
             }
         };
@@ -77,8 +164,8 @@ impl Compression {
         destination: &mut std::fs::File,
     ) -> std::io::Result<u64> {
         let mut dec: Box<dyn std::io::Write> = match &self {
-            Compression::bzip2 => Box::new(bzip2::write::BzDecoder::new(destination)),
-            Compression::gzip => Box::new(flate2::write::GzDecoder::new(destination)),
+            Compression::bzip2 { .. } => Box::new(bzip2::write::BzDecoder::new(destination)),
+            Compression::gzip { .. } => Box::new(flate2::write::GzDecoder::new(destination)),
             Compression::xz { .. } => Box::new(xz2::write::XzDecoder::new(destination)),
         };
 
@@ -90,69 +177,492 @@ impl Compression {
 
     fn marker(&self) -> &'static str {
         match &self {
-            Compression::bzip2 => "bzip2 compressed data",
-            Compression::gzip => "gzip compressed data",
+            Compression::bzip2 { .. } => "bzip2 compressed data",
+            Compression::gzip { .. } => "gzip compressed data",
             Compression::xz { .. } => "XZ compressed data",
         }
     }
 
-    fn extension(&self) -> &'static str {
+    pub fn extension(&self) -> &'static str {
+        match &self {
+            Compression::bzip2 { .. } => "bz2",
+            Compression::gzip { .. } => "gz",
+            Compression::xz { .. } => "xz",
+        }
+    }
+
+    // extension appended to packed images by earlier versions
+    fn legacy_extension(&self) -> &'static str {
         match &self {
-            Compression::bzip2 => "bzip2",
-            Compression::gzip => "gzip",
+            Compression::bzip2 { .. } => "bzip2",
+            Compression::gzip { .. } => "gzip",
             Compression::xz { .. } => "xz",
         }
     }
 
     pub fn from_file(image_file_name: &PathBuf) -> Result<Option<Compression>> {
-        let detector = Magic::open(Default::default())
-            .context("image::compression: failed to open libmagic")?;
+        Ok(Compression::from_magic(&magic(image_file_name)?))
+    }
+
+    /// Returns the compression of `image_file_name` as forced by `format` or,
+    /// if not given, as detected via libmagic.
+    pub fn from_file_or_format(
+        image_file_name: &PathBuf,
+        format: Option<ImageFormat>,
+    ) -> Result<Option<Compression>> {
+        match format {
+            Some(format) => {
+                debug!("compression of {image_file_name:?} forced to {format:?}");
+                Ok(format.compression())
+            }
+            None => Compression::from_file(image_file_name),
+        }
+    }
+
+    /// Returns the compression matching a libmagic description as returned by
+    /// `magic`. The description has to consist of the marker, optionally
+    /// followed by ", <details>", so that e.g. files whose description merely
+    /// mentions the marker aren't taken for compressed images.
+    pub fn from_magic(magic: &str) -> Option<Compression> {
+        Compression::iter().find(|c| {
+            magic
+                .strip_prefix(c.marker())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(','))
+        })
+    }
+}
 
-        detector
-            .load::<String>(&[])
-            .context("image::compression: failed to load libmagic")?;
+/// Returns the libmagic description of a file, e.g. "XZ compressed data, ...".
+pub fn magic(image_file_name: &Path) -> Result<String> {
+    let detector =
+        Magic::open(Default::default()).context("image::compression: failed to open libmagic")?;
 
-        let magic = detector
-            .file(image_file_name)
-            .context("image::compression: failed to open image")?;
+    detector
+        .load::<String>(&[])
+        .context("image::compression: failed to load libmagic")?;
 
-        for c in Compression::iter() {
-            if magic.contains(c.marker()) {
-                return Ok(Some(c));
+    detector
+        .file(image_file_name)
+        .context("image::compression: failed to open image")
+}
+
+// rolling hash over the last RSYNC_BITS input bytes (same as pigz --rsyncable).
+// the deflate stream is flushed whenever the hash hits RSYNC_HIT, so that chunk
+// boundaries only depend on the content and local changes of the input only
+// cause local changes of the compressed output.
+const RSYNC_BITS: u32 = 12;
+const RSYNC_MASK: u32 = (1 << RSYNC_BITS) - 1;
+const RSYNC_HIT: u32 = RSYNC_MASK >> 1;
+
+struct RsyncableWriter<W: Write> {
+    inner: W,
+    hash: u32,
+}
+
+impl<W: Write> RsyncableWriter<W> {
+    fn new(inner: W) -> Self {
+        RsyncableWriter { inner, hash: 0 }
+    }
+}
+
+impl<W: Write> Write for RsyncableWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut start = 0;
+
+        for (i, byte) in buf.iter().enumerate() {
+            self.hash = ((self.hash << 1) ^ *byte as u32) & RSYNC_MASK;
+            if self.hash == RSYNC_HIT {
+                self.inner.write_all(&buf[start..=i])?;
+                self.inner.flush()?;
+                start = i + 1;
             }
         }
 
-        Ok(None)
+        self.inner.write_all(&buf[start..])?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
     }
 }
 
-pub fn decompress(image_file_name: &PathBuf, compression: &Compression) -> Result<PathBuf> {
-    let mut new_image_file = PathBuf::from(image_file_name);
+/// Returns the path of the decompressed image, i.e. `image_file_name` without
+/// the extension of `compression`.
+pub fn decompressed_path(image_file_name: &Path, compression: &Compression) -> PathBuf {
+    let mut new_image_file = image_file_name.to_path_buf();
 
     if let Some(extension) = new_image_file.extension() {
-        if extension == compression.extension() {
+        if extension == compression.extension() || extension == compression.legacy_extension() {
             new_image_file.set_extension("");
         }
     }
 
-    let mut destination = File::create(&new_image_file)?;
-    let mut source = File::open(image_file_name)?;
-    debug!("decompress {image_file_name:?} to {new_image_file:?}");
-    let bytes_written = compression.decompress(&mut source, &mut destination)?;
-    debug!("image::decompress: copied {} bytes.", bytes_written);
-    Ok(new_image_file)
+    new_image_file
 }
 
-pub fn compress(image_file_name: &PathBuf, compression: &Compression) -> Result<PathBuf> {
-    let new_image_file = PathBuf::from(format!(
+/// Returns the path of the compressed image, i.e. `image_file_name` with the
+/// extension of `compression` appended.
+pub fn compressed_path(image_file_name: &Path, compression: &Compression) -> PathBuf {
+    PathBuf::from(format!(
         "{}.{}",
         image_file_name.to_str().unwrap(),
         compression.extension()
-    ));
-    let mut destination = File::create(&new_image_file)?;
-    let mut source = File::open(image_file_name)?;
-    debug!("compress {image_file_name:?} to {new_image_file:?}");
-    let bytes_written = compression.compress(&mut source, &mut destination)?;
+    ))
+}
+
+pub fn decompress_to(source: &Path, destination: &Path, compression: &Compression) -> Result<()> {
+    let mut destination_file = File::create(destination).context(format!(
+        "decompress_to: cannot create {}",
+        destination.to_string_lossy()
+    ))?;
+    let mut source_file = File::open(source).context(format!(
+        "decompress_to: cannot open {}",
+        source.to_string_lossy()
+    ))?;
+    debug!("decompress {source:?} to {destination:?}");
+    let bytes_written = compression.decompress(&mut source_file, &mut destination_file)?;
+    debug!("image::decompress: copied {} bytes.", bytes_written);
+    Ok(())
+}
+
+pub fn compress_to(source: &Path, destination: &Path, compression: &Compression) -> Result<()> {
+    let mut destination_file = File::create(destination).context(format!(
+        "compress_to: cannot create {}",
+        destination.to_string_lossy()
+    ))?;
+    let mut source_file = File::open(source).context(format!(
+        "compress_to: cannot open {}",
+        source.to_string_lossy()
+    ))?;
+    debug!("compress {source:?} to {destination:?}");
+    let bytes_written = compression.compress(&mut source_file, &mut destination_file)?;
     debug!("image::compress: copied {} bytes.", bytes_written);
+    Ok(())
+}
+
+pub fn decompress(image_file_name: &PathBuf, compression: &Compression) -> Result<PathBuf> {
+    let new_image_file = decompressed_path(image_file_name, compression);
+    decompress_to(image_file_name, &new_image_file, compression)?;
+    Ok(new_image_file)
+}
+
+pub fn compress(image_file_name: &PathBuf, compression: &Compression) -> Result<PathBuf> {
+    let new_image_file = compressed_path(image_file_name, compression);
+    compress_to(image_file_name, &new_image_file, compression)?;
     Ok(new_image_file)
 }
+
+/// Returns the uncompressed size of `image_file_name` as recorded by its
+/// compression format, if any: xz records the sizes of all blocks in the index
+/// of each stream. gzip records the size of the last member modulo 2^32, which
+/// is thus only a lower bound for images of 4 GiB and more. bzip2 records no
+/// size.
+pub fn uncompressed_size_hint(
+    image_file_name: &Path,
+    compression: &Compression,
+) -> Result<Option<u64>> {
+    let file = File::open(image_file_name).context(format!(
+        "uncompressed_size_hint: cannot open {}",
+        image_file_name.to_string_lossy()
+    ))?;
+    let len = file
+        .metadata()
+        .context("uncompressed_size_hint: cannot get metadata")?
+        .len();
+
+    match compression {
+        Compression::xz { .. } => {
+            xz_uncompressed_size(&file, len).context("uncompressed_size_hint: cannot read xz index")
+        }
+        // 10 bytes header and 8 bytes trailer at least
+        Compression::gzip { .. } if len >= 18 => {
+            let mut isize = [0u8; 4];
+            file.read_exact_at(&mut isize, len - 4)
+                .context("uncompressed_size_hint: cannot read gzip trailer")?;
+            Ok(Some(u32::from_le_bytes(isize).into()))
+        }
+        Compression::gzip { .. } | Compression::bzip2 { .. } => Ok(None),
+    }
+}
+
+/// Sums up the uncompressed sizes recorded in the indexes of the xz streams
+/// in `file` of `len` bytes, walking backwards from the last stream footer.
+/// Returns `None` if the structure isn't as expected, e.g. if `file` is
+/// truncated.
+fn xz_uncompressed_size(file: &File, len: u64) -> std::io::Result<Option<u64>> {
+    const HEADER_SIZE: u64 = 12;
+    const FOOTER_SIZE: u64 = 12;
+
+    let mut pos = len;
+    let mut size = 0u64;
+
+    while pos > 0 {
+        // stream padding consists of multiples of 4 null bytes
+        let mut word = [0u8; 4];
+        if pos < 4 {
+            return Ok(None);
+        }
+        file.read_exact_at(&mut word, pos - 4)?;
+        if word == [0; 4] {
+            pos -= 4;
+            continue;
+        }
+
+        if pos < HEADER_SIZE + FOOTER_SIZE {
+            return Ok(None);
+        }
+
+        let mut footer = [0u8; FOOTER_SIZE as usize];
+        file.read_exact_at(&mut footer, pos - FOOTER_SIZE)?;
+        if footer[10..] != *b"YZ" {
+            return Ok(None);
+        }
+
+        let backward_size =
+            (u64::from(u32::from_le_bytes(footer[4..8].try_into().unwrap())) + 1) * 4;
+        let Some(index_start) = (pos - FOOTER_SIZE).checked_sub(backward_size) else {
+            return Ok(None);
+        };
+
+        let mut index = vec![0u8; backward_size as usize];
+        file.read_exact_at(&mut index, index_start)?;
+
+        let Some((blocks_size, uncompressed_size)) = parse_xz_index(&index) else {
+            return Ok(None);
+        };
+        let Some(stream_start) = index_start.checked_sub(blocks_size + HEADER_SIZE) else {
+            return Ok(None);
+        };
+
+        size += uncompressed_size;
+        pos = stream_start;
+    }
+
+    Ok(Some(size))
+}
+
+/// Parses a xz index and returns the size of the blocks it describes (i.e. of
+/// the stream without header, index and footer) and their uncompressed size.
+fn parse_xz_index(index: &[u8]) -> Option<(u64, u64)> {
+    let mut bytes = index.iter();
+    // multibyte integer: 7 bits per byte, least significant first
+    let mut next = || -> Option<u64> {
+        let mut value = 0u64;
+        for i in 0..9 {
+            let byte = *bytes.next()?;
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    };
+
+    // index indicator
+    if next()? != 0 {
+        return None;
+    }
+
+    let mut blocks_size = 0u64;
+    let mut uncompressed_size = 0u64;
+
+    for _ in 0..next()? {
+        // blocks are padded to a multiple of 4 bytes
+        blocks_size = blocks_size.checked_add(next()?.checked_add(3)? & !3)?;
+        uncompressed_size = uncompressed_size.checked_add(next()?)?;
+    }
+
+    Some((blocks_size, uncompressed_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn magic_marker_at_start() {
+        assert!(matches!(
+            Compression::from_magic("XZ compressed data, checksum CRC64"),
+            Some(Compression::xz { .. })
+        ));
+        assert!(
+            Compression::from_magic("POSIX tar archive (gzip compressed data inside)").is_none()
+        );
+        assert!(matches!(
+            Compression::from_file_or_format(
+                &PathBuf::from("does-not-exist.wic"),
+                Some(ImageFormat::gzip)
+            ),
+            Ok(Some(Compression::gzip { .. }))
+        ));
+        assert!(matches!(
+            Compression::from_file_or_format(
+                &PathBuf::from("does-not-exist.wic"),
+                Some(ImageFormat::none)
+            ),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn magic_false_positives() {
+        for magic in [
+            "data, was XZ compressed data originally",
+            "XZ compressed data-like custom framing",
+            "POSIX tar archive, contains bzip2 compressed data",
+            "ASCII text, with gzip compressed data markers",
+            "",
+        ] {
+            assert!(Compression::from_magic(magic).is_none(), "{magic}");
+        }
+
+        assert!(matches!(
+            Compression::from_magic("XZ compressed data"),
+            Some(Compression::xz { .. })
+        ));
+        assert!(matches!(
+            Compression::from_magic("bzip2 compressed data, block size = 900k"),
+            Some(Compression::bzip2 { .. })
+        ));
+        assert!(matches!(
+            Compression::from_magic(
+                "gzip compressed data, was \"image.wic\", from Unix, original size modulo 2^32 0"
+            ),
+            Some(Compression::gzip { .. })
+        ));
+    }
+
+    #[test]
+    fn rsyncable_gzip_roundtrip() {
+        let input: Vec<u8> = (0..1_000_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+
+        let mut writer = RsyncableWriter::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::best(),
+        ));
+        writer.write_all(&input).unwrap();
+        let compressed = writer.inner.finish().unwrap();
+
+        let mut output = Vec::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut output)
+            .unwrap();
+
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn uncompressed_size_hints() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.wic");
+        let input: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&image, &input).unwrap();
+
+        for (compression, hint) in [
+            (
+                Compression::xz {
+                    compression_level: 1,
+                    dict_size: None,
+                    threads: None,
+                },
+                Some(input.len() as u64),
+            ),
+            (
+                Compression::gzip { rsyncable: false },
+                Some(input.len() as u64),
+            ),
+            (Compression::bzip2 { block_size: 9 }, None),
+        ] {
+            let compressed = dir.path().join("image.compressed");
+            compress_to(&image, &compressed, &compression).unwrap();
+
+            assert_eq!(
+                uncompressed_size_hint(&compressed, &compression).unwrap(),
+                hint
+            );
+        }
+
+        // concatenated xz streams with stream padding in between
+        let xz = |data: &[u8]| {
+            let mut enc = xz2::write::XzEncoder::new(Vec::new(), 1);
+            enc.write_all(data).unwrap();
+            enc.finish().unwrap()
+        };
+        let compressed = dir.path().join("image.wic.xz");
+        std::fs::write(
+            &compressed,
+            [xz(&input), vec![0; 8], xz(&input[..1000]), vec![0; 4]].concat(),
+        )
+        .unwrap();
+        let compression = Compression::from_str("xz").unwrap();
+        assert_eq!(
+            uncompressed_size_hint(&compressed, &compression).unwrap(),
+            Some(input.len() as u64 + 1000)
+        );
+
+        // no xz stream at all
+        std::fs::write(&compressed, [1u8; 64]).unwrap();
+        assert_eq!(
+            uncompressed_size_hint(&compressed, &compression).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn compress_to_decompress_to_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.wic");
+        let input: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&image, &input).unwrap();
+
+        for compression in [
+            Compression::xz {
+                compression_level: 1,
+                dict_size: None,
+                threads: None,
+            },
+            Compression::xz {
+                compression_level: 1,
+                dict_size: Some(1 << 20),
+                threads: Some(2),
+            },
+            Compression::bzip2 { block_size: 9 },
+            Compression::bzip2 { block_size: 1 },
+            Compression::gzip { rsyncable: false },
+            Compression::gzip { rsyncable: true },
+        ] {
+            let compressed = dir.path().join("other-dir-image.compressed");
+            let decompressed = dir.path().join("decompressed.wic");
+
+            compress_to(&image, &compressed, &compression).unwrap();
+            decompress_to(&compressed, &decompressed, &compression).unwrap();
+
+            assert_eq!(std::fs::read(&decompressed).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn compressed_and_decompressed_path() {
+        let c = Compression::bzip2 { block_size: 9 };
+
+        assert_eq!(
+            compressed_path(Path::new("/a/image.wic"), &c),
+            PathBuf::from("/a/image.wic.bz2")
+        );
+        // images packed by earlier versions end with .bzip2
+        for image in ["/a/image.wic.bz2", "/a/image.wic.bzip2"] {
+            assert_eq!(
+                decompressed_path(Path::new(image), &c),
+                PathBuf::from("/a/image.wic")
+            );
+        }
+        assert_eq!(
+            decompressed_path(Path::new("/a/image.wic.xz"), &c),
+            PathBuf::from("/a/image.wic.xz")
+        );
+    }
+}
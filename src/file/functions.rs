@@ -1,12 +1,20 @@
+use crate::audit::AuditLog;
+use crate::error::CommandError;
+use crate::manifest::{self, ChangeManifest};
 use anyhow::{Context, Result};
-use log::{debug, warn};
+use log::{debug, info, warn};
 use regex::Regex;
-use std::collections::HashMap;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 use std::fmt::{self, Display};
 use std::fs;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use stdext::function_name;
 use uuid::Uuid;
 
@@ -18,13 +26,282 @@ pub enum Partition {
     rootA,
     cert,
     factory,
+    /// partition resolved via its mountpoint in /etc/fstab of rootA
+    #[value(skip)]
+    mountpoint(PathBuf),
+    /// partition resolved via the UUID of its file system
+    #[value(skip)]
+    uuid(String),
 }
 
-#[derive(Debug)]
+/// File system type of an image passed via `--raw-partition`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Eq, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum RawPartition {
+    /// vfat file system as used for the boot partition
+    boot,
+    /// ext2/3/4 file system
+    ext,
+}
+
+/// File system created by `format_partition`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Eq, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum FsType {
+    ext4,
+    vfat,
+}
+
+#[derive(Clone, Debug)]
 struct PartitionInfo {
     num: String,
     start: String,
     end: String,
+    vfat: bool,
+    raw: bool,
+}
+
+const FSTAB_PATH: &str = "/etc/fstab";
+const SPARSE_PROBE_SIZE: usize = 1024 * 1024;
+// warn when extracting directory trees exceeding this size
+const EXTRACT_WARN_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Optional mapping of logical partitions to partition table entries, e.g.
+/// ```toml
+/// [factory]
+/// index = 7
+///
+/// [cert]
+/// label = "cert"
+/// ```
+/// Partitions not contained fall back to the default omnect-os layout.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartitionLayout {
+    boot: Option<PartitionLayoutEntry>,
+    #[serde(rename = "rootA")]
+    root_a: Option<PartitionLayoutEntry>,
+    cert: Option<PartitionLayoutEntry>,
+    factory: Option<PartitionLayoutEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PartitionLayoutEntry {
+    index: Option<u32>,
+    label: Option<String>,
+}
+
+impl PartitionLayout {
+    /// Reads a layout file, see `PartitionLayout`.
+    pub fn from_file(layout_file: &Path) -> Result<PartitionLayout> {
+        let layout: PartitionLayout =
+            toml::from_str(&fs::read_to_string(layout_file).context(format!(
+                "PartitionLayout: cannot read {}",
+                layout_file.to_string_lossy()
+            ))?)
+            .context("PartitionLayout: invalid layout file")?;
+
+        for entry in [&layout.boot, &layout.root_a, &layout.cert, &layout.factory]
+            .into_iter()
+            .flatten()
+        {
+            anyhow::ensure!(
+                entry.index.is_some() != entry.label.is_some(),
+                "PartitionLayout: either index or label must be set for a partition"
+            );
+        }
+
+        debug!("PartitionLayout: {layout:?}");
+
+        Ok(layout)
+    }
+}
+
+const DEFAULT_DD_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// Settings of the file operations on an image, as given by the options of the
+/// image command, and the audit log and change manifest they are recorded in.
+/// The default settings process a partitioned omnect-os image serially.
+#[derive(Debug, Default)]
+pub struct FileOptions {
+    /// mapping of partitions deviating from the default omnect-os layout
+    pub layout: Option<PartitionLayout>,
+    /// treats images as a single file system instead of a partitioned image,
+    /// the partition of file operations is ignored in this case
+    pub raw_partition: Option<RawPartition>,
+    /// number of partitions `copy_to_image` processes concurrently, which also
+    /// bounds the number of files verified concurrently by
+    /// `verify_copy_to_image`; defaults to `threads`
+    pub parallel: Option<usize>,
+    /// number of threads used at most, see `parallel`
+    pub threads: Option<usize>,
+    /// only warn instead of failing if several files are copied to the same
+    /// destination, in which case the last one wins
+    pub allow_overwrite: bool,
+    /// skip in-files whose destination already has the same content;
+    /// partitions without any copied file aren't written back
+    pub incremental: bool,
+    /// skip copy triples failing before anything is written, e.g. due to a
+    /// missing in-file, and continue with the others; the skipped triples are
+    /// returned by `copy_failures`
+    pub keep_going: bool,
+    /// refuse in-files larger than the given number of bytes
+    pub max_file_size: Option<u64>,
+    /// skip entries of directory in-files matching one of the globs, see
+    /// `parse_excludes`
+    pub excludes: Vec<Regex>,
+    /// makes writing to images a hard error, as safety belt when inspecting
+    /// images, e.g. production images
+    pub read_only: bool,
+    /// check ext file systems via `e2fsck` before writing modified partitions
+    /// back into the image
+    pub fsck: bool,
+    /// skip syncing extracted partitions and the image to disk after writing
+    /// them: the image may be corrupted if the system crashes before the kernel
+    /// flushed it, which doesn't matter for throwaway images, e.g. on tmpfs
+    pub no_sync: bool,
+    /// block size partitions are copied with between image and partition
+    /// files, i.e. the size of the single I/O operations, see
+    /// `parse_dd_block_size`
+    pub dd_block_size: Option<u64>,
+    /// keeps copies of all partition images extracted by file operations in
+    /// this dir for inspection, e.g. by mounting them
+    pub keep_partitions_dir: Option<PathBuf>,
+    pub audit_log: AuditLog,
+    pub change_manifest: ChangeManifest,
+    copy_failures: Mutex<Vec<(FileCopyToParams, String)>>,
+    // partitions are written back into the same image, which must not happen
+    // concurrently since `fallocate -d` operates on the whole image
+    write_partition_lock: Mutex<()>,
+}
+
+impl FileOptions {
+    // workers set via parallel take precedence, by default work is done serially
+    fn parallel(&self) -> usize {
+        self.parallel.or(self.threads).unwrap_or(1)
+    }
+
+    fn sync_enabled(&self) -> bool {
+        !self.no_sync
+    }
+
+    fn dd_block_size(&self) -> u64 {
+        self.dd_block_size.unwrap_or(DEFAULT_DD_BLOCK_SIZE)
+    }
+
+    /// Fails with a message naming `action` if images are read only.
+    pub fn ensure_writable(&self, action: &str) -> Result<()> {
+        anyhow::ensure!(!self.read_only, "--read-only: refusing to {action}");
+
+        Ok(())
+    }
+
+    /// Returns the copy triples skipped by `copy_to_image` as set via
+    /// `keep_going`, each with its error.
+    pub fn copy_failures(&self) -> Vec<String> {
+        self.copy_failures
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(params, e)| {
+                format!(
+                    "{},{}:{}: {e}",
+                    params.in_file.to_str().unwrap(),
+                    params.partition,
+                    params.out_file.to_str().unwrap()
+                )
+            })
+            .collect()
+    }
+
+    // records the failed copy triple if keep_going is set, otherwise fails
+    fn skip_or_fail(&self, params: &FileCopyToParams, e: anyhow::Error) -> Result<()> {
+        if !self.keep_going {
+            return Err(e);
+        }
+
+        warn!(
+            "copy_to_image: skip {}: {e:#}",
+            params.in_file.to_str().unwrap()
+        );
+        self.copy_failures
+            .lock()
+            .unwrap()
+            .push((params.clone(), format!("{e:#}")));
+
+        Ok(())
+    }
+
+    fn is_failed_copy(&self, params: &FileCopyToParams) -> bool {
+        self.copy_failures
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(failed, _)| failed.is_same_copy(params))
+    }
+
+    fn keep_partition(&self, partition_file: &str, partition_info: &PartitionInfo) -> Result<()> {
+        let Some(dir) = &self.keep_partitions_dir else {
+            return Ok(());
+        };
+
+        if partition_info.raw {
+            return Ok(());
+        }
+
+        let kept_file = dir.join(format!("{}.img", partition_info.num));
+
+        // copy sparse file (std::fs::copy isn't able)
+        libfs::copy_file(partition_file, &kept_file).context(format!(
+            "keep_partition: couldn't copy {partition_file} to {}",
+            kept_file.to_string_lossy()
+        ))?;
+
+        debug!("keep_partition: kept {}", kept_file.to_string_lossy());
+
+        Ok(())
+    }
+}
+
+/// Parses the glob `patterns` of `FileOptions::excludes`, see `glob_to_regex`.
+pub fn parse_excludes(patterns: &[String]) -> Result<Vec<Regex>> {
+    patterns.iter().map(|p| glob_to_regex(p)).collect()
+}
+
+/// Parses a block size of `FileOptions::dd_block_size`: a size as accepted by
+/// `parse_size`, which has to be a multiple of 512 bytes up to 64M.
+pub fn parse_dd_block_size(s: &str) -> Result<u64> {
+    let block_size = parse_size(s)?;
+
+    anyhow::ensure!(
+        block_size > 0 && block_size % 512 == 0 && block_size <= 64 << 20,
+        "parse_dd_block_size: block size has to be a multiple of 512 bytes up to 64M"
+    );
+
+    Ok(block_size)
+}
+
+/// Returns the largest divisor of `block_size` which also divides `offset` and
+/// `len`, so that dd's skip resp. seek and count, given in blocks, exactly
+/// address a partition. Partitions are aligned to 512 byte sectors, so the
+/// result is at least 512 for block sizes being a multiple of 512.
+#[cfg(any(not(target_os = "linux"), test))]
+fn aligned_block_size(block_size: u64, offset: u64, len: u64) -> u64 {
+    fn gcd(a: u64, b: u64) -> u64 {
+        if b == 0 {
+            a
+        } else {
+            gcd(b, a % b)
+        }
+    }
+
+    gcd(gcd(block_size, offset), len)
+}
+
+lazy_static! {
+    // e.g. /dev/mmcblk0p7, /dev/sda7 or /dev/nvme0n1p7
+    static ref RE_DEVICE_NUM: Regex = Regex::new(r"^/dev/\w+?p?(\d+)$").unwrap();
 }
 
 impl Display for Partition {
@@ -34,6 +311,8 @@ impl Display for Partition {
             Partition::rootA => write!(f, "rootA"),
             Partition::cert => write!(f, "cert"),
             Partition::factory => write!(f, "factory"),
+            Partition::mountpoint(m) => write!(f, "{}", m.to_string_lossy()),
+            Partition::uuid(u) => write!(f, "UUID={u}"),
         }
     }
 }
@@ -47,7 +326,13 @@ impl FromStr for Partition {
             "rootA" => Ok(Partition::rootA),
             "cert" => Ok(Partition::cert),
             "factory" => Ok(Partition::factory),
-            _ => anyhow::bail!("unknown partition: use either boot, rootA, cert or factory"),
+            m if m.starts_with('/') => Ok(Partition::mountpoint(PathBuf::from(m))),
+            u if u.starts_with("UUID=") && u.len() > "UUID=".len() => {
+                Ok(Partition::uuid(u["UUID=".len()..].to_string()))
+            }
+            _ => anyhow::bail!(
+                "unknown partition: use either boot, rootA, cert, factory, an absolute mountpoint as configured in /etc/fstab or UUID=<file system uuid>"
+            ),
         }
     }
 }
@@ -58,6 +343,22 @@ pub struct FileCopyToParams {
     in_file: std::path::PathBuf,
     partition: Partition,
     out_file: std::path::PathBuf,
+    mode: Option<u32>,
+    owner: Option<(u32, u32)>,
+    dereference: bool,
+    mtime: Option<u64>,
+    expectation: Option<Expectation>,
+    preserve_xattrs: bool,
+}
+
+/// Condition on the current destination of a copied file, checked before the
+/// file is copied.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Expectation {
+    /// the destination exists with the given sha256 (lowercase hex)
+    Sha256(String),
+    /// the destination doesn't exist
+    Absent,
 }
 
 impl FileCopyToParams {
@@ -70,7 +371,99 @@ impl FileCopyToParams {
             in_file: in_file.to_path_buf(),
             partition,
             out_file: out_file.to_path_buf(),
+            mode: None,
+            owner: None,
+            dereference: true,
+            mtime: None,
+            expectation: None,
+            preserve_xattrs: false,
+        }
+    }
+
+    /// set permissions of the destination file (ignored for vfat partitions)
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// set uid and gid of the destination file and of directories created for
+    /// it (not supported for vfat partitions)
+    pub fn with_owner(mut self, uid: u32, gid: u32) -> Self {
+        self.owner = Some((uid, gid));
+        self
+    }
+
+    /// if `false` a symlinked source file is recreated as symlink instead of
+    /// copying the content of its target (not supported for vfat partitions)
+    pub fn with_dereference(mut self, dereference: bool) -> Self {
+        self.dereference = dereference;
+        self
+    }
+
+    /// set modification time (seconds since unix epoch) of the destination file
+    /// instead of preserving the modification time of the source file
+    pub fn with_mtime(mut self, mtime: u64) -> Self {
+        self.mtime = Some(mtime);
+        self
+    }
+
+    /// copy the extended attributes of the source file, e.g. capabilities or
+    /// SELinux labels (not supported for vfat partitions)
+    pub fn with_preserve_xattrs(mut self, preserve_xattrs: bool) -> Self {
+        self.preserve_xattrs = preserve_xattrs;
+        self
+    }
+
+    /// only copy if the current destination meets `expectation`, otherwise fail
+    pub fn with_expectation(mut self, expectation: Expectation) -> Self {
+        self.expectation = Some(expectation);
+        self
+    }
+
+    pub fn in_file(&self) -> &Path {
+        &self.in_file
+    }
+
+    /// Fails if the in-file doesn't exist.
+    pub fn check_in_file(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.in_file.try_exists().is_ok_and(|exists| exists),
+            "in-file-path {} doesn't exist",
+            self.in_file.to_str().unwrap()
+        );
+
+        Ok(())
+    }
+
+    // copy triples are identified by their in-file and destination
+    fn is_same_copy(&self, other: &Self) -> bool {
+        self.in_file == other.in_file
+            && self.partition == other.partition
+            && self.out_file == other.out_file
+    }
+
+    /// Expands copying `in_file` to `out_file` in each of `partitions` into one
+    /// copy triple per partition, validated like parsed copy triples.
+    pub fn for_partitions(
+        in_file: &Path,
+        partitions: &[Partition],
+        out_file: &Path,
+    ) -> Result<Vec<Self>> {
+        check_copy_paths(out_file)?;
+
+        anyhow::ensure!(!partitions.is_empty(), "no out-partition given");
+
+        for (i, partition) in partitions.iter().enumerate() {
+            anyhow::ensure!(
+                !partitions[..i].contains(partition),
+                "out-partition {partition} is given multiple times"
+            );
         }
+
+        Ok(partitions
+            .iter()
+            .map(|p| Self::new(in_file, p.clone(), out_file))
+            .collect())
     }
 }
 
@@ -78,36 +471,142 @@ impl FromStr for FileCopyToParams {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        let err_msg = "format not matched: in-file-path,out-partition:out-file-path";
+        let v = split_copy_params(s, [',', ':']).context(
+            "format not matched: in-file-path,out-partition:out-file-path (quote paths containing ',' or ':')",
+        )?;
 
-        anyhow::ensure!(
-            s.matches(',').count() == 1 && s.matches(':').count() == 1,
-            err_msg
-        );
+        let in_file = std::path::PathBuf::from(&v[0]);
+        let partition = Partition::from_str(&v[1])?;
+        let out_file = std::path::PathBuf::from(&v[2]);
 
-        let v: Vec<&str> = s.split(&[',', ':']).collect();
+        check_copy_paths(&out_file)?;
 
-        anyhow::ensure!(v.len() == 3, err_msg);
+        Ok(Self::new(&in_file, partition, &out_file))
+    }
+}
 
-        let in_file = std::path::PathBuf::from(v[0]);
-        let partition = Partition::from_str(v[1])?;
-        let out_file = std::path::PathBuf::from(v[2]);
+// the existence of in-files is checked on copying, see `check_in_file`
+fn check_copy_paths(out_file: &Path) -> Result<()> {
+    anyhow::ensure!(
+        out_file.is_absolute(),
+        "out-file-path isn't an absolute path"
+    );
 
-        anyhow::ensure!(
-            in_file.try_exists().is_ok_and(|exists| exists),
-            "in-file-path doesn't exist"
-        );
-        anyhow::ensure!(
-            out_file.is_absolute(),
-            "out-file-path isn't an absolute path"
-        );
+    Ok(())
+}
 
-        Ok(Self {
-            in_file,
-            partition,
-            out_file,
-        })
+/// Splits a copy triple into its three fields, separated by `delimiters` in the
+/// given order. Fields may be enclosed in double quotes, in which case they may
+/// contain ',' and ':', e.g. `"my,file",factory:"/etc/a:b"`. Other double
+/// quotes are part of the field, e.g. `my"file`.
+fn split_copy_params(s: &str, delimiters: [char; 2]) -> Result<[String; 3]> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            // opening quote at the start of a field
+            '"' if !quoted && fields.last().unwrap().is_empty() => quoted = true,
+            // closing quote at the end of a field
+            '"' if quoted && matches!(chars.peek(), None | Some(',' | ':')) => quoted = false,
+            ',' | ':' if !quoted => {
+                anyhow::ensure!(
+                    fields.len() <= delimiters.len() && c == delimiters[fields.len() - 1],
+                    "split_copy_params: unexpected '{c}'"
+                );
+                fields.push(String::new());
+            }
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+
+    anyhow::ensure!(!quoted, "split_copy_params: unterminated quote");
+
+    fields
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("split_copy_params: expected three fields"))
+}
+
+/// Parses a timestamp given either in RFC 3339 format or as "@<seconds since unix epoch>".
+pub fn parse_mtime(s: &str) -> Result<u64> {
+    if let Some(epoch) = s.strip_prefix('@') {
+        return epoch
+            .parse()
+            .context(format!("parse_mtime: invalid epoch {epoch}"));
     }
+
+    let mtime = time::OffsetDateTime::parse(s, &time::format_description::well_known::Rfc3339)
+        .context(format!("parse_mtime: invalid RFC 3339 timestamp {s}"))?;
+
+    u64::try_from(mtime.unix_timestamp())
+        .context(format!("parse_mtime: timestamp {s} is before unix epoch"))
+}
+
+/// Parses a sha256 given as 64 hex digits and returns it in lowercase.
+pub fn parse_sha256(s: &str) -> Result<String> {
+    anyhow::ensure!(
+        s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit()),
+        "parse_sha256: invalid sha256 {s}, expected 64 hex digits"
+    );
+
+    Ok(s.to_ascii_lowercase())
+}
+
+/// Parses a well-formed https URL with a host, e.g. of an EST server.
+pub fn parse_https_url(s: &str) -> Result<url::Url> {
+    let url = url::Url::parse(s).context(format!("parse_https_url: invalid URL {s}"))?;
+
+    anyhow::ensure!(
+        url.scheme() == "https" && url.host_str().is_some_and(|host| !host.is_empty()),
+        "parse_https_url: {s} isn't an https URL"
+    );
+
+    Ok(url)
+}
+
+/// Parses a size in bytes with an optional binary suffix, e.g. "512", "64K", "10M" or "1G".
+pub fn parse_size(s: &str) -> Result<u64> {
+    let (num, factor) = match s.trim().to_uppercase() {
+        v if v.ends_with('K') => (v.trim_end_matches('K').to_string(), 1 << 10),
+        v if v.ends_with('M') => (v.trim_end_matches('M').to_string(), 1 << 20),
+        v if v.ends_with('G') => (v.trim_end_matches('G').to_string(), 1 << 30),
+        v => (v, 1),
+    };
+
+    num.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(factor))
+        .context(format!(
+            "parse_size: invalid size {s}, use e.g. 512, 64K, 10M or 1G"
+        ))
+}
+
+/// Parses an owner given as numeric "<uid>:<gid>"; names can't be resolved
+/// since the image's passwd isn't consulted.
+pub fn parse_owner(s: &str) -> Result<(u32, u32)> {
+    s.split_once(':')
+        .and_then(|(uid, gid)| Some((uid.parse().ok()?, gid.parse().ok()?)))
+        .context(format!(
+            "parse_owner: invalid owner {s}, expected numeric <uid>:<gid>"
+        ))
+}
+
+/// Parses a file in the image given as "<partition>:<path>", e.g.
+/// "factory:/etc/config.toml"; the path may be enclosed in double quotes.
+pub fn parse_partition_path(s: &str) -> Result<(Partition, PathBuf)> {
+    let (partition, path) = s.split_once(':').context(format!(
+        "parse_partition_path: invalid {s}, expected <partition>:<path>"
+    ))?;
+    let path = PathBuf::from(path.trim_matches('"'));
+
+    anyhow::ensure!(
+        path.is_absolute(),
+        "parse_partition_path: {} isn't an absolute path",
+        path.to_string_lossy()
+    );
+
+    Ok((Partition::from_str(partition)?, path))
 }
 
 #[derive(Clone, Debug)]
@@ -129,26 +628,23 @@ impl FileCopyFromParams {
             out_file: out_file.to_path_buf(),
         }
     }
+
+    pub fn out_file(&self) -> &Path {
+        &self.out_file
+    }
 }
 
 impl FromStr for FileCopyFromParams {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        let err_msg = "format not matched: in-partition:in-file-path,out-file-path";
-
-        anyhow::ensure!(
-            s.matches(',').count() == 1 && s.matches(':').count() == 1,
-            err_msg
-        );
-
-        let v: Vec<&str> = s.split(&[',', ':']).collect();
+        let v = split_copy_params(s, [':', ',']).context(
+            "format not matched: in-partition:in-file-path,out-file-path (quote paths containing ',' or ':')",
+        )?;
 
-        anyhow::ensure!(v.len() == 3, err_msg);
-
-        let partition = Partition::from_str(v[0])?;
-        let in_file = std::path::PathBuf::from(v[1]);
-        let out_file = std::path::PathBuf::from(v[2]);
+        let partition = Partition::from_str(&v[0])?;
+        let in_file = std::path::PathBuf::from(&v[1]);
+        let out_file = std::path::PathBuf::from(&v[2]);
 
         Ok(Self {
             in_file,
@@ -158,25 +654,63 @@ impl FromStr for FileCopyFromParams {
     }
 }
 
+/// Creates a unique directory next to the image for temporary partition files,
+/// so that concurrent runs on images in the same directory don't interfere.
+/// The directory is removed when the returned guard is dropped.
+fn create_working_dir(image_file: &Path) -> Result<tempfile::TempDir> {
+    let dir = image_file
+        .parent()
+        .context("create_working_dir: cannot get directory of image")?;
+
+    tempfile::Builder::new()
+        .prefix("partitions-")
+        .tempdir_in(dir)
+        .context(format!(
+            "create_working_dir: cannot create tmp dir in {}",
+            dir.to_string_lossy()
+        ))
+}
+
+/// Returns the file a partition is accessed in: either a partition file in
+/// `working_dir` or the image itself if it is a raw partition.
+fn partition_file(image_file: &str, working_dir: &Path, partition_info: &PartitionInfo) -> String {
+    if partition_info.raw {
+        return image_file.to_string();
+    }
+
+    working_dir
+        .join(format!("{}.img", partition_info.num))
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
 macro_rules! exec_cmd {
-    ($cmd:ident) => {
-        anyhow::ensure!(
+    ($cmd:ident, $options:expr) => {
+        let start = Instant::now();
+        let status =
             $cmd.status()
-                .context(format!("{}: status failed: {:?}", function_name!(), $cmd))?
-                .success(),
-            format!("{}: cmd failed: {:?}", function_name!(), $cmd)
-        );
+                .context(format!("{}: status failed: {:?}", function_name!(), $cmd))?;
+        $options
+            .audit_log
+            .command(function_name!(), &$cmd, &status, start.elapsed())?;
+        if !status.success() {
+            return Err(CommandError::new(function_name!(), &$cmd, &status).into());
+        }
         debug!("{}: {:?}", function_name!(), $cmd);
     };
 }
 
 macro_rules! try_exec_cmd {
-    ($cmd:ident) => {
-        if $cmd
-            .status()
-            .context(format!("{}: status failed: {:?}", function_name!(), $cmd))?
-            .success()
-        {
+    ($cmd:ident, $options:expr) => {
+        let start = Instant::now();
+        let status =
+            $cmd.status()
+                .context(format!("{}: status failed: {:?}", function_name!(), $cmd))?;
+        $options
+            .audit_log
+            .command(function_name!(), &$cmd, &status, start.elapsed())?;
+        if status.success() {
             debug!("{}: {:?}", function_name!(), $cmd);
         } else {
             warn!("{}: {:?}", function_name!(), $cmd)
@@ -185,10 +719,14 @@ macro_rules! try_exec_cmd {
 }
 
 macro_rules! exec_cmd_with_output {
-    ($cmd:expr) => {{
+    ($cmd:expr, $options:expr) => {{
+        let start = Instant::now();
         let res = $cmd
             .output()
             .context(format!("{}: spawn {:?}", function_name!(), $cmd))?;
+        $options
+            .audit_log
+            .command(function_name!(), &$cmd, &res.status, start.elapsed())?;
 
         let output =
             String::from_utf8(res.stdout).context(format!("{}: get output", function_name!()))?;
@@ -201,202 +739,2166 @@ macro_rules! exec_cmd_with_output {
     }};
 }
 
-pub fn copy_to_image(file_copy_params: &[FileCopyToParams], image_file: &Path) -> Result<()> {
-    // we use the folder the image is located in
-    // the caller is responsible to create a /tmp/ directory if needed
-    let working_dir = image_file
-        .parent()
-        .context("copy_to_image: cannot get directory of image")?
-        .to_path_buf();
+pub fn copy_to_image(
+    file_copy_params: &[FileCopyToParams],
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    let tmp_dir = create_working_dir(image_file)?;
+    let working_dir = tmp_dir.path().to_path_buf();
     let image_file = image_file.to_str().unwrap();
-    let mut partition_map: HashMap<&Partition, Vec<(&PathBuf, &PathBuf)>> = HashMap::new();
+    let mut partition_map: HashMap<&Partition, PartitionInfo> = HashMap::new();
+    let file_copy_params = expand_dirs(file_copy_params, &options.excludes)?;
 
-    // create map with partition as key
+    // group by partition number, since a partition may be given by name as well as by
+    // mountpoint; params keep their order, so that the last copy to a destination wins
+    let mut jobs: Vec<(PartitionInfo, Vec<&FileCopyToParams>)> = vec![];
     for params in file_copy_params.iter() {
-        let e = (&params.in_file, &params.out_file);
-        partition_map
-            .entry(&params.partition)
-            .and_modify(|v| v.push(e))
-            .or_insert(vec![e]);
+        let partition_info = match partition_map.entry(&params.partition) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => match get_partition_info(image_file, &params.partition, options) {
+                Ok(partition_info) => e.insert(partition_info),
+                Err(e) => {
+                    options.skip_or_fail(params, e)?;
+                    continue;
+                }
+            },
+        };
+
+        match jobs
+            .iter_mut()
+            .find(|(info, _)| info.num == partition_info.num)
+        {
+            Some((_, v)) => v.push(params),
+            None => jobs.push((partition_info.clone(), vec![params])),
+        }
     }
 
-    // 1. for each involved partition
-    for partition in partition_map.keys() {
-        let mut partition_file = working_dir.clone();
-        let partition_info = get_partition_info(image_file, partition)?;
+    // catch copy-paste mistakes before the image is modified
+    for (_, params) in jobs.iter() {
+        check_duplicate_destinations(params, options.allow_overwrite)?;
+    }
 
-        partition_file.push(Path::new(&format!("{}.img", partition_info.num)));
-        let partition_file = partition_file.to_str().unwrap();
+    // in-files copied to several destinations are only hashed and staged once
+    let sources = SourceCache::default();
 
-        // 2. read partition
-        read_partition(image_file, partition_file, &partition_info)?;
+    // e2tools and mtools must not operate concurrently on the same partition image,
+    // so only distinct partitions are processed in parallel
+    let parallel = match options.raw_partition {
+        None => options.parallel().min(jobs.len()),
+        Some(_) => 1,
+    };
 
-        // 3. copy files
-        for (in_file, out_file) in partition_map.get(partition).unwrap().iter() {
-            let dir_path = out_file.parent().context(format!(
-                "copy_to_image: invalid destination path {}",
-                out_file.to_str().unwrap()
-            ))?;
+    // all partitions are staged before any of them is written back, so that a
+    // failure leaves the image untouched; a raw partition is modified in place
+    let changed = Mutex::new(vec![false; jobs.len()]);
+    let stage = |i: usize| -> Result<()> {
+        let (partition_info, params) = &jobs[i];
+        let copied = copy_to_partition(
+            image_file,
+            &working_dir,
+            &sources,
+            partition_info,
+            params,
+            options,
+        )
+        .map_err(|e| {
+            explain_fs_mismatch(
+                e,
+                &params[0].partition,
+                &partition_file(image_file, &working_dir, partition_info),
+                partition_info,
+                options,
+            )
+        })?;
+        changed.lock().unwrap()[i] = copied;
+        Ok(())
+    };
 
-            let out_file = out_file.to_str().unwrap();
-
-            if **partition == Partition::boot {
-                let mut p = PathBuf::from("/");
-
-                for dir in dir_path.iter().skip(1).map(|d| d.to_str().unwrap()) {
-                    p.push(dir);
-                    let mut mmd = Command::new("mmd");
-                    mmd.arg("-D")
-                        .arg("sS")
-                        .arg("-i")
-                        .arg(partition_file)
-                        .arg(p.to_str().unwrap());
-                    // we ignore `mmd` errors in order to ignore potential name clashes when a dir already exists
-                    // in case mmd fails mcopy will fail respectively with a reasonable error output
-                    try_exec_cmd!(mmd);
-                }
+    if parallel <= 1 {
+        (0..jobs.len()).try_for_each(stage)?;
+    } else {
+        debug!(
+            "copy_to_image: process {} partitions with {parallel} workers",
+            jobs.len()
+        );
 
-                let mut mcopy = Command::new("mcopy");
-                mcopy
-                    .arg("-o")
-                    .arg("-i")
-                    .arg(partition_file)
-                    .arg(in_file)
-                    .arg(format!("::{out_file}"));
-                exec_cmd!(mcopy);
-            } else {
-                let mut e2mkdir = Command::new("e2mkdir");
-                e2mkdir.arg(format!("{partition_file}:{}", dir_path.to_str().unwrap()));
-                exec_cmd!(e2mkdir);
+        let queue = Mutex::new(0..jobs.len());
+
+        std::thread::scope(|s| {
+            let workers: Vec<_> = (0..parallel)
+                .map(|_| {
+                    s.spawn(|| -> Result<()> {
+                        loop {
+                            let Some(i) = queue.lock().unwrap().next() else {
+                                return Ok(());
+                            };
+                            stage(i)?;
+                        }
+                    })
+                })
+                .collect();
+
+            workers.into_iter().try_for_each(|worker| {
+                worker
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("copy_to_image: worker panicked"))?
+            })
+        })?;
+    }
 
-                let mut e2cp = Command::new("e2cp");
-                e2cp.arg(in_file)
-                    .arg(format!("{partition_file}:{out_file}"));
-                exec_cmd!(e2cp);
-            }
-        }
+    let changed = changed.into_inner().unwrap();
 
-        // 4. write back partition
-        write_partition(image_file, partition_file, &partition_info)?;
+    for ((partition_info, _), _) in jobs.iter().zip(changed).filter(|(_, changed)| *changed) {
+        commit_partition(
+            image_file,
+            &partition_file(image_file, &working_dir, partition_info),
+            partition_info,
+            options,
+        )?;
     }
 
     Ok(())
 }
 
-pub fn copy_from_image(file_copy_params: &[FileCopyFromParams], image_file: &Path) -> Result<()> {
-    // we use the folder the image is located in
-    // the caller is responsible to create a /tmp/ directory if needed
-    let working_dir = image_file
-        .parent()
-        .context("copy_to_image: cannot get directory of image")?
-        .to_path_buf();
-    let image_file = image_file.to_str().unwrap();
+/// Copies files into the partition image of `partition_info` without writing
+/// it back into the image. Returns whether anything was copied.
+fn copy_to_partition(
+    image_file: &str,
+    working_dir: &Path,
+    sources: &SourceCache,
+    partition_info: &PartitionInfo,
+    file_copy_params: &[&FileCopyToParams],
+    options: &FileOptions,
+) -> Result<bool> {
+    let partition_file = &partition_file(image_file, working_dir, partition_info);
 
-    for param in file_copy_params.iter() {
-        let mut partition_file = working_dir.clone();
+    // read partition
+    read_partition(image_file, partition_file, partition_info, options)?;
 
-        let partition_info = get_partition_info(image_file, &param.partition)?;
-        let in_file = param.in_file.to_str().unwrap();
+    let incremental = options.incremental;
+    let mut copied = false;
+    let mut vfat_created_dirs = HashSet::new();
+    // timestamps of ext copies are set at once after copying, see `set_timestamps`
+    let mut timestamps = vec![];
 
-        partition_file.push(Path::new(&format!("{}.img", partition_info.num)));
-        let partition_file = partition_file.to_str().unwrap();
+    // copy files
+    for params in file_copy_params.iter() {
+        let in_file = &params.in_file;
+        let checked = check_copy(
+            partition_file,
+            partition_info,
+            working_dir,
+            sources,
+            params,
+            incremental,
+            options,
+        );
+        let CheckedCopy {
+            out_path,
+            dir_path,
+            mtime,
+            symlink,
+            xattrs,
+        } = match checked {
+            Ok(Some(checked)) => checked,
+            Ok(None) => continue,
+            Err(e) => {
+                options.skip_or_fail(params, e)?;
+                continue;
+            }
+        };
+        let out_file = out_path.to_str().unwrap();
+
+        if partition_info.vfat {
+            for dir in vfat_dirs(&dir_path, &mut vfat_created_dirs) {
+                let mut mmd = mtools_cmd("mmd");
+                mmd.arg("-D")
+                    .arg("sS")
+                    .arg("-i")
+                    .arg(partition_file)
+                    .arg(dir.to_str().unwrap());
+                // we ignore `mmd` errors in order to ignore potential name clashes when a dir already exists
+                // in case mmd fails mcopy will fail respectively with a reasonable error output
+                try_exec_cmd!(mmd, options);
+            }
 
-        read_partition(image_file, partition_file, &partition_info)?;
+            // mcopy can only preserve the mtime of the source file, so we set it on a temp copy
+            let in_file = match params.mtime {
+                Some(mtime) => sources.with_mtime(in_file, mtime, working_dir)?,
+                None => in_file.to_path_buf(),
+            };
 
-        anyhow::ensure!(
-            param
-                .out_file
-                .parent()
-                .unwrap()
-                .try_exists()
-                .is_ok_and(|exists| exists),
-            "copy_from_image: output dir does not exist."
-        );
-
-        // copy
-        if param.partition == Partition::boot {
-            let mut tmp_out_file = working_dir.clone();
-            // mcopy deadlocks when target file is not residing in workingdir so we copy to a temp file
-            tmp_out_file.push(format!(
-                "{}-{}",
-                Uuid::new_v4(),
-                param.out_file.file_name().unwrap().to_str().unwrap()
-            ));
-
-            let mut mcopy = Command::new("mcopy");
+            let mut mcopy = mtools_cmd("mcopy");
             mcopy
                 .arg("-o")
+                .arg("-m")
                 .arg("-i")
                 .arg(partition_file)
-                .arg(format!("::{in_file}"))
-                .arg(&tmp_out_file);
-            exec_cmd!(mcopy);
-            // instead of rename we copy and delete to prevent "Invalid cross-device link" errors
-            let bytes_copied = fs::copy(&tmp_out_file, &param.out_file).context(format!(
-                "copy_from_image: couldn't copy temp file {} to destination {}",
-                tmp_out_file.to_str().unwrap(),
-                param.out_file.to_str().unwrap()
-            ))?;
-            anyhow::ensure!(
-                tmp_out_file.metadata().unwrap().len() == bytes_copied,
-                "copy_from_image: copy temp file failed"
-            );
-            fs::remove_file(&tmp_out_file).context(format!(
-                "copy_from_image: couldn't delete temp file {}",
-                tmp_out_file.to_str().unwrap()
-            ))?;
+                .arg(in_file)
+                .arg(format!("::{out_file}"));
+            exec_cmd!(mcopy, options);
         } else {
-            let mut e2cp = Command::new("e2cp");
-            e2cp.arg(format!("{partition_file}:{in_file}"))
-                .arg(param.out_file.to_str().unwrap());
-            exec_cmd!(e2cp);
-            // since e2cp doesn't return errors in any case we check if output file exists
-            anyhow::ensure!(
-                param.out_file.try_exists().is_ok_and(|exists| exists),
-                format!("copy_from_image: cmd failed: {:?}", e2cp)
-            )
+            let mut e2mkdir = Command::new("e2mkdir");
+            if let Some((uid, gid)) = params.owner {
+                e2mkdir.arg("-O").arg(uid.to_string());
+                e2mkdir.arg("-G").arg(gid.to_string());
+            }
+            e2mkdir.arg(format!("{partition_file}:{}", dir_path.to_str().unwrap()));
+            exec_cmd!(e2mkdir, options);
+
+            if symlink {
+                let target = fs::read_link(in_file).context(format!(
+                    "copy_to_image: cannot read symlink {}",
+                    in_file.to_str().unwrap()
+                ))?;
+
+                // debugfs doesn't overwrite existing files, so remove the destination first
+                let mut rm = Command::new("debugfs");
+                rm.arg("-w")
+                    .arg("-R")
+                    .arg(format!("rm {}", debugfs_quote(out_file)))
+                    .arg(partition_file);
+                try_exec_cmd!(rm, options);
+
+                let mut symlink = Command::new("debugfs");
+                symlink
+                    .arg("-w")
+                    .arg("-R")
+                    .arg(format!(
+                        "symlink {} {}",
+                        debugfs_quote(out_file),
+                        debugfs_quote(target.to_str().unwrap())
+                    ))
+                    .arg(partition_file);
+                exec_cmd!(symlink, options);
+
+                if let Some((uid, gid)) = params.owner {
+                    for (field, id) in [("uid", uid), ("gid", gid)] {
+                        let mut sif = Command::new("debugfs");
+                        sif.arg("-w")
+                            .arg("-R")
+                            .arg(format!("sif {} {field} {id}", debugfs_quote(out_file)))
+                            .arg(partition_file);
+                        exec_cmd!(sif, options);
+                    }
+                }
+            } else {
+                let mut e2cp = Command::new("e2cp");
+                if let Some(mode) = params.mode {
+                    e2cp.arg("-P").arg(format!("{mode:o}"));
+                }
+                if let Some((uid, gid)) = params.owner {
+                    e2cp.arg("-O").arg(uid.to_string());
+                    e2cp.arg("-G").arg(gid.to_string());
+                }
+                e2cp.arg(in_file)
+                    .arg(format!("{partition_file}:{out_file}"));
+                exec_cmd!(e2cp, options);
+
+                if !xattrs.is_empty() {
+                    set_xattrs(partition_file, out_file, &xattrs, working_dir, options)?;
+                }
+            }
+
+            timestamps.push((out_file.to_string(), mtime));
         }
-    }
 
-    Ok(())
-}
+        options.audit_log.operation(
+            "copy-to-image",
+            serde_json::json!({
+                "image": image_file,
+                "partition": params.partition.to_string(),
+                "inFile": in_file,
+                "outFile": out_file,
+                "mtime": mtime,
+            }),
+        )?;
+
+        if options.change_manifest.enabled() {
+            let details = if symlink {
+                serde_json::json!({ "symlink": fs::read_link(in_file)? })
+            } else {
+                serde_json::json!({
+                    "size": fs::metadata(in_file)?.len(),
+                    "sha256": manifest::hex(&sources.sha256(in_file)?),
+                })
+            };
+
+            options.change_manifest.file(
+                &params.partition.to_string(),
+                &partition_info.num,
+                out_file,
+                details,
+            )?;
+        }
 
-pub fn read_file_from_image(
-    path: impl AsRef<Path>,
-    partition: Partition,
-    image_file: impl AsRef<Path>,
-) -> Result<String> {
-    let tmp_file = tempfile::NamedTempFile::new()
-        .context("read_file_from_image: could not create temporary file path")?;
+        copied = true;
+    }
 
-    let params = FileCopyFromParams::new(path.as_ref(), partition, tmp_file.path());
+    if !timestamps.is_empty() {
+        set_timestamps(partition_file, &timestamps, working_dir, options)?;
+    }
 
-    copy_from_image(&[params], image_file.as_ref())
-        .context("read_file_from_image: could not copy file content")?;
+    // the partition is written back by copy_to_image once all partitions are staged
+    if copied {
+        check_partition(partition_file, partition_info, options)?;
+    } else {
+        debug!(
+            "copy_to_image: all files in partition #{} unchanged",
+            partition_info.num
+        );
+    }
+    options.keep_partition(partition_file, partition_info)?;
 
-    let content = std::fs::read_to_string(tmp_file.path())
-        .context("read_file_from_image: could not read file content")?;
+    Ok(copied)
+}
 
-    Ok(content)
+/// Copy of a file into a partition image whose checks passed, see `check_copy`.
+struct CheckedCopy {
+    out_path: PathBuf,
+    dir_path: PathBuf,
+    mtime: u64,
+    symlink: bool,
+    xattrs: Vec<(String, Vec<u8>)>,
 }
 
-fn get_partition_info(image_file: &str, partition: &Partition) -> Result<PartitionInfo> {
-    let mut fdisk = Command::new("fdisk");
-    fdisk
-        .arg("-l")
-        .arg("-o")
-        .arg("Device,Start,End")
-        .arg(image_file);
-    let fdisk_out = exec_cmd_with_output!(fdisk);
+/// Checks a copy into the partition image before anything is written, e.g.
+/// that the in-file exists and fits. Returns `None` if the destination is
+/// unchanged and `incremental` is set.
+fn check_copy(
+    partition_file: &str,
+    partition_info: &PartitionInfo,
+    working_dir: &Path,
+    sources: &SourceCache,
+    params: &FileCopyToParams,
+    incremental: bool,
+    options: &FileOptions,
+) -> Result<Option<CheckedCopy>> {
+    params.check_in_file()?;
+
+    let in_file = &params.in_file;
+    let out_path = resolve_destination(
+        partition_file,
+        partition_info,
+        in_file,
+        &params.out_file,
+        options,
+    )?;
+    let dir_path = out_path
+        .parent()
+        .context(format!(
+            "copy_to_image: invalid destination path {}",
+            params.out_file.to_str().unwrap()
+        ))?
+        .to_path_buf();
 
-    let partition_num = match partition {
-        Partition::boot => 1,
+    let out_file = out_path.to_str().unwrap();
+    let symlink = !params.dereference && in_file.is_symlink();
+
+    anyhow::ensure!(
+        !(params.owner.is_some() && partition_info.vfat),
+        "copy_to_image: cannot set owner of {} on vfat partition {}",
+        in_file.to_str().unwrap(),
+        params.partition
+    );
+
+    anyhow::ensure!(
+        !(symlink && partition_info.vfat),
+        "copy_to_image: cannot preserve symlink {} on vfat partition {}",
+        in_file.to_str().unwrap(),
+        params.partition
+    );
+
+    let mtime = match params.mtime {
+        Some(mtime) => mtime,
+        None => get_mtime(in_file, symlink)?,
+    };
+
+    if let Some(expectation) = &params.expectation {
+        check_expectation(
+            partition_file,
+            partition_info,
+            out_file,
+            &params.partition,
+            expectation,
+            working_dir,
+            options,
+        )?;
+    }
+
+    if incremental
+        && !symlink
+        && is_unchanged(
+            partition_file,
+            partition_info,
+            &sources.sha256(in_file)?,
+            out_file,
+            working_dir,
+            options,
+        )?
+    {
+        debug!(
+            "copy_to_image: skip unchanged {} ({})",
+            out_file, params.partition
+        );
+        return Ok(None);
+    }
+
+    if !symlink {
+        check_file_size(partition_file, partition_info, in_file, options)?;
+    }
+
+    let xattrs = if params.preserve_xattrs && !symlink {
+        super::xattr::read(in_file).context(format!(
+            "copy_to_image: cannot read extended attributes of {}",
+            in_file.to_str().unwrap()
+        ))?
+    } else {
+        vec![]
+    };
+
+    anyhow::ensure!(
+        xattrs.is_empty() || !partition_info.vfat,
+        "copy_to_image: cannot preserve extended attributes of {} on vfat partition {}",
+        in_file.to_str().unwrap(),
+        params.partition
+    );
+
+    Ok(Some(CheckedCopy {
+        out_path,
+        dir_path,
+        mtime,
+        symlink,
+        xattrs,
+    }))
+}
+
+/// Reads the files copied by `copy_to_image` back from the image and fails if
+/// the content of any of them differs from its in-file, since e2tools and
+/// mtools may fail silently. Symlinks recreated in the image aren't verified.
+/// Files are verified concurrently as set via `FileOptions::parallel` and all
+/// mismatches are reported at once.
+pub fn verify_copy_to_image(
+    file_copy_params: &[FileCopyToParams],
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    let file_copy_params = expand_dirs(file_copy_params, &options.excludes)?;
+
+    // only the last copy to a destination ends up in the image, skipped copies
+    // don't end up in it at all; destinations are compared like by
+    // `check_duplicate_destinations`
+    let mut copied: Vec<&FileCopyToParams> = vec![];
+    for params in file_copy_params.iter().rev() {
+        if (params.dereference || !params.in_file.is_symlink())
+            && !options.is_failed_copy(params)
+            && !copied.iter().any(|p| {
+                p.partition == params.partition && destination_key(p) == destination_key(params)
+            })
+        {
+            copied.push(params);
+        }
+    }
+    copied.reverse();
+
+    let tmp_dir = create_working_dir(image_file)?;
+    let working_dir = tmp_dir.path();
+    let image_file = image_file.to_str().unwrap();
+
+    // read_partition skips partitions already read, so each partition is read
+    // once and its files are extracted from the partition image
+    let mut jobs = vec![];
+    for (i, params) in copied.iter().enumerate() {
+        let partition_info = get_partition_info(image_file, &params.partition, options)?;
+        let partition_file = partition_file(image_file, working_dir, &partition_info);
+        read_partition(image_file, &partition_file, &partition_info, options)?;
+
+        // the destination is resolved like on copying, e.g. for a directory
+        let out_file = resolve_destination(
+            &partition_file,
+            &partition_info,
+            &params.in_file,
+            &params.out_file,
+            options,
+        )?;
+        let read_back = FileCopyFromParams::new(
+            &out_file,
+            params.partition.clone(),
+            &working_dir.join(format!("verify-{i}")),
+        );
+
+        jobs.push((*params, partition_info, partition_file, read_back));
+    }
+
+    // files are only read from the partition images, so they may be extracted
+    // concurrently even from the same partition
+    let parallel = options.parallel().min(jobs.len());
+    let queue = Mutex::new(jobs.iter().enumerate());
+    let mismatches = Mutex::new(vec![]);
+
+    debug!(
+        "verify_copy_to_image: verify {} files with {parallel} workers",
+        jobs.len()
+    );
+
+    std::thread::scope(|s| {
+        for _ in 0..parallel {
+            s.spawn(|| loop {
+                let Some((i, (params, partition_info, partition_file, read_back))) =
+                    queue.lock().unwrap().next()
+                else {
+                    return;
+                };
+
+                let mismatch = match verify_file(
+                    params,
+                    partition_info,
+                    partition_file,
+                    read_back,
+                    working_dir,
+                    options,
+                ) {
+                    Ok(true) => continue,
+                    Ok(false) => "content differs from in-file".to_string(),
+                    Err(e) => format!("cannot read back: {e:#}"),
+                };
+
+                mismatches.lock().unwrap().push((
+                    i,
+                    format!(
+                        "{}:{} ({mismatch})",
+                        params.partition,
+                        read_back.in_file.to_str().unwrap()
+                    ),
+                ));
+            });
+        }
+    });
+
+    // report all mismatches in the order of the copies
+    let mut mismatches = mismatches.into_inner().unwrap();
+    mismatches.sort();
+
+    anyhow::ensure!(
+        mismatches.is_empty(),
+        "verify_copy_to_image: {} of {} files failed verification: {}",
+        mismatches.len(),
+        copied.len(),
+        mismatches
+            .into_iter()
+            .map(|(_, m)| m)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    info!("verify_copy_to_image: verified {} files", copied.len());
+
+    Ok(())
+}
+
+/// Extracts the copy of `params` from the partition image and returns whether
+/// its content equals the in-file.
+fn verify_file(
+    params: &FileCopyToParams,
+    partition_info: &PartitionInfo,
+    partition_file: &str,
+    read_back: &FileCopyFromParams,
+    working_dir: &Path,
+    options: &FileOptions,
+) -> Result<bool> {
+    copy_from_partition(
+        partition_file,
+        partition_info,
+        read_back,
+        working_dir,
+        options,
+    )?;
+
+    Ok(file_sha256(&params.in_file)? == file_sha256(&read_back.out_file)?)
+}
+
+pub fn copy_from_image(
+    file_copy_params: &[FileCopyFromParams],
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    let tmp_dir = create_working_dir(image_file)?;
+    let working_dir = tmp_dir.path().to_path_buf();
+    let image_file = image_file.to_str().unwrap();
+
+    for param in file_copy_params.iter() {
+        let partition_info = get_partition_info(image_file, &param.partition, options)?;
+        let in_file = param.in_file.to_str().unwrap();
+        let partition_file = &partition_file(image_file, &working_dir, &partition_info);
+
+        read_partition(image_file, partition_file, &partition_info, options)?;
+        options.keep_partition(partition_file, &partition_info)?;
+
+        let out_file = copy_from_partition(
+            partition_file,
+            &partition_info,
+            param,
+            &working_dir,
+            options,
+        )
+        .map_err(|e| {
+            explain_fs_mismatch(
+                e,
+                &param.partition,
+                partition_file,
+                &partition_info,
+                options,
+            )
+        })?;
+
+        options.audit_log.operation(
+            "copy-from-image",
+            serde_json::json!({
+                "image": image_file,
+                "partition": param.partition.to_string(),
+                "inFile": in_file,
+                "outFile": out_file,
+            }),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn copy_from_partition(
+    partition_file: &str,
+    partition_info: &PartitionInfo,
+    param: &FileCopyFromParams,
+    working_dir: &Path,
+    options: &FileOptions,
+) -> Result<PathBuf> {
+    let in_file = param.in_file.to_str().unwrap();
+
+    let out_file = if partition_info.vfat {
+        // mcopy deadlocks when target file is not residing in workingdir so we copy to a temp dir
+        let tmp_out_dir = create_extract_dir(working_dir)?;
+
+        let mut mcopy = mtools_cmd("mcopy");
+        mcopy
+            .arg("-s")
+            .arg("-o")
+            .arg("-i")
+            .arg(partition_file)
+            .arg(format!("::{in_file}"))
+            .arg(&tmp_out_dir);
+        exec_cmd!(mcopy, options);
+
+        move_extracted(&tmp_out_dir, &param.out_file)?
+    } else if is_ext_dir(partition_file, in_file, options)? {
+        let tmp_out_dir = create_extract_dir(working_dir)?;
+
+        let mut debugfs = Command::new("debugfs");
+        debugfs
+            .arg("-R")
+            .arg(format!(
+                "rdump {} {}",
+                debugfs_quote(in_file),
+                debugfs_quote(tmp_out_dir.to_str().unwrap())
+            ))
+            .arg(partition_file);
+        exec_cmd!(debugfs, options);
+
+        move_extracted(&tmp_out_dir, &param.out_file)?
+    } else {
+        let out_file = resolve_out_file(&param.in_file, &param.out_file)?;
+        ensure_out_dir(&out_file)?;
+
+        let mut e2cp = Command::new("e2cp");
+        e2cp.arg(format!("{partition_file}:{in_file}"))
+            .arg(out_file.to_str().unwrap());
+        exec_cmd!(e2cp, options);
+        // since e2cp doesn't return errors in any case we check if output file exists
+        anyhow::ensure!(
+            out_file.try_exists().is_ok_and(|exists| exists),
+            format!("copy_from_image: cmd failed: {:?}", e2cp)
+        );
+
+        out_file
+    };
+
+    Ok(out_file)
+}
+
+/// Returns the first of `paths` existing in the ext partition `partition`,
+/// e.g. to locate a file which may reside in several directories.
+pub fn find_in_image(
+    paths: &[&Path],
+    partition: &Partition,
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<Option<PathBuf>> {
+    let tmp_dir = create_working_dir(image_file)?;
+    let image_file = image_file.to_str().unwrap();
+    let partition_info = get_partition_info(image_file, partition, options)?;
+    let partition_file = &partition_file(image_file, tmp_dir.path(), &partition_info);
+
+    anyhow::ensure!(
+        !partition_info.vfat,
+        "find_in_image: vfat partition {partition} isn't supported"
+    );
+
+    read_partition(image_file, partition_file, &partition_info, options)?;
+
+    for path in paths {
+        if ext_path_exists(partition_file, path.to_str().unwrap(), options)? {
+            return Ok(Some(path.to_path_buf()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Returns for each of `paths` whether it exists in `partition`, reading the
+/// partition only once. Symlinks aren't followed.
+pub fn paths_exist(
+    paths: &[&str],
+    partition: &Partition,
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<Vec<bool>> {
+    let tmp_dir = create_working_dir(image_file)?;
+    let image_file = image_file.to_str().unwrap();
+    let partition_info = get_partition_info(image_file, partition, options)?;
+    let partition_file = &partition_file(image_file, tmp_dir.path(), &partition_info);
+
+    read_partition(image_file, partition_file, &partition_info, options)?;
+
+    paths
+        .iter()
+        .map(|path| {
+            if partition_info.vfat {
+                vfat_path_exists(partition_file, path, options)
+            } else {
+                ext_path_exists(partition_file, path, options)
+            }
+        })
+        .collect()
+}
+
+/// Returns the names of the entries of directory `path` in `partition`, which
+/// is empty if the directory doesn't exist.
+pub fn list_dir(
+    path: &str,
+    partition: &Partition,
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<Vec<String>> {
+    let tmp_dir = create_working_dir(image_file)?;
+    let image_file = image_file.to_str().unwrap();
+    let partition_info = get_partition_info(image_file, partition, options)?;
+    let partition_file = &partition_file(image_file, tmp_dir.path(), &partition_info);
+
+    read_partition(image_file, partition_file, &partition_info, options)?;
+
+    let names: Vec<String> = if partition_info.vfat {
+        // concise listing of one path per line
+        let mut mdir = mtools_cmd("mdir");
+        mdir.arg("-b")
+            .arg("-i")
+            .arg(partition_file)
+            .arg(format!("::{path}"));
+        exec_cmd_with_output!(mdir, options)
+            .lines()
+            .filter_map(|line| line.trim_end_matches('/').rsplit('/').next())
+            .map(str::to_string)
+            .collect()
+    } else {
+        // parseable listing: /inode/mode/uid/gid/name/size/
+        let mut debugfs = Command::new("debugfs");
+        debugfs
+            .arg("-R")
+            .arg(format!("ls -p {}", debugfs_quote(path)))
+            .arg(partition_file);
+        exec_cmd_with_output!(debugfs, options)
+            .lines()
+            .filter_map(|line| line.split('/').nth(5))
+            .map(str::to_string)
+            .collect()
+    };
+
+    Ok(names
+        .into_iter()
+        .filter(|name| !name.is_empty() && name != "." && name != "..")
+        .collect())
+}
+
+/// Removes `paths`, which must not be directories, from `partition`. Missing
+/// paths are skipped and the partition is only written back if anything was
+/// removed. Returns the number of removed paths.
+pub fn remove_from_image(
+    paths: &[&Path],
+    partition: &Partition,
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<usize> {
+    let tmp_dir = create_working_dir(image_file)?;
+    let image_file = image_file.to_str().unwrap();
+    let partition_info = get_partition_info(image_file, partition, options)?;
+    let partition_file = &partition_file(image_file, tmp_dir.path(), &partition_info);
+
+    read_partition(image_file, partition_file, &partition_info, options)?;
+
+    let mut removed = 0;
+    for path in paths.iter().map(|p| p.to_str().unwrap()) {
+        let exists = if partition_info.vfat {
+            vfat_path_exists(partition_file, path, options)?
+        } else {
+            ext_path_exists(partition_file, path, options)?
+        };
+
+        if !exists {
+            debug!("remove_from_image: skip missing {path} ({partition})");
+            continue;
+        }
+
+        let is_dir = if partition_info.vfat {
+            is_vfat_dir(partition_file, path, options)?
+        } else {
+            is_ext_dir(partition_file, path, options)?
+        };
+
+        anyhow::ensure!(
+            !is_dir,
+            "remove_from_image: {path} ({partition}) is a directory"
+        );
+
+        let mut rm = if partition_info.vfat {
+            let mut mdel = mtools_cmd("mdel");
+            mdel.arg("-i").arg(partition_file).arg(format!("::{path}"));
+            mdel
+        } else {
+            let mut debugfs = Command::new("debugfs");
+            debugfs
+                .arg("-w")
+                .arg("-R")
+                .arg(format!("rm {}", debugfs_quote(path)))
+                .arg(partition_file);
+            debugfs
+        };
+        exec_cmd!(rm, options);
+
+        options.audit_log.operation(
+            "remove-from-image",
+            serde_json::json!({
+                "image": image_file,
+                "partition": partition.to_string(),
+                "path": path,
+            }),
+        )?;
+
+        removed += 1;
+    }
+
+    if removed > 0 {
+        write_partition(image_file, partition_file, &partition_info, options)?;
+    }
+    options.keep_partition(partition_file, &partition_info)?;
+
+    Ok(removed)
+}
+
+/// Quotes `arg` of a debugfs request, since debugfs splits requests at
+/// whitespace. Within double quotes a double quote is escaped by doubling it.
+fn debugfs_quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('"', "\"\""))
+}
+
+/// Creates an mtools command, which always stores long file names as VFAT
+/// entries, even if they are disabled by the mtools configuration of the host.
+fn mtools_cmd(program: &str) -> Command {
+    let mut cmd = Command::new(program);
+    cmd.env("MTOOLS_NO_VFAT", "0");
+    cmd
+}
+
+/// Returns whether `out_file` in the partition already exists with the content
+/// hashed as `in_file_sha256`. The modification time isn't compared.
+fn is_unchanged(
+    partition_file: &str,
+    partition_info: &PartitionInfo,
+    in_file_sha256: &[u8],
+    out_file: &str,
+    working_dir: &Path,
+    options: &FileOptions,
+) -> Result<bool> {
+    Ok(existing_sha256(
+        partition_file,
+        partition_info,
+        out_file,
+        working_dir,
+        options,
+    )?
+    .as_deref()
+        == Some(in_file_sha256))
+}
+
+/// Fails if the current `out_file` in the partition doesn't meet `expectation`.
+fn check_expectation(
+    partition_file: &str,
+    partition_info: &PartitionInfo,
+    out_file: &str,
+    partition: &Partition,
+    expectation: &Expectation,
+    working_dir: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    let existing = existing_sha256(
+        partition_file,
+        partition_info,
+        out_file,
+        working_dir,
+        options,
+    )?
+    .map(|sha256| manifest::hex(&sha256));
+
+    match (expectation, existing) {
+        (Expectation::Absent, None) => Ok(()),
+        (Expectation::Absent, Some(_)) => {
+            anyhow::bail!("copy_to_image: {out_file} ({partition}) already exists")
+        }
+        (Expectation::Sha256(expected), Some(actual)) if *expected == actual => Ok(()),
+        (Expectation::Sha256(expected), Some(actual)) => anyhow::bail!(
+            "copy_to_image: {out_file} ({partition}) has sha256 {actual}, expected {expected}"
+        ),
+        (Expectation::Sha256(expected), None) => anyhow::bail!(
+            "copy_to_image: {out_file} ({partition}) doesn't exist, expected sha256 {expected}"
+        ),
+    }
+}
+
+/// Returns the sha256 of `out_file` in the partition or `None` if it doesn't
+/// exist.
+fn existing_sha256(
+    partition_file: &str,
+    partition_info: &PartitionInfo,
+    out_file: &str,
+    working_dir: &Path,
+    options: &FileOptions,
+) -> Result<Option<Vec<u8>>> {
+    // mcopy deadlocks when target file is not residing in workingdir so we copy to a temp dir
+    let tmp_out_dir = create_extract_dir(working_dir)?;
+    let current = tmp_out_dir.join("current");
+
+    let mut cmd = if partition_info.vfat {
+        let mut mcopy = mtools_cmd("mcopy");
+        mcopy
+            .arg("-i")
+            .arg(partition_file)
+            .arg(format!("::{out_file}"))
+            .arg(&current);
+        mcopy
+    } else {
+        let mut e2cp = Command::new("e2cp");
+        e2cp.arg(format!("{partition_file}:{out_file}"))
+            .arg(&current);
+        e2cp
+    };
+    // a missing destination isn't an error, it just doesn't yield a file
+    exec_cmd_with_output!(cmd, options);
+
+    let sha256 = if current.is_file() {
+        Some(file_sha256(&current)?)
+    } else {
+        None
+    };
+
+    fs::remove_dir_all(&tmp_out_dir).context(format!(
+        "existing_sha256: couldn't remove {}",
+        tmp_out_dir.to_str().unwrap()
+    ))?;
+
+    Ok(sha256)
+}
+
+/// Caches what is derived from in-files during one `copy_to_image` run, so that
+/// in-files copied to several destinations, e.g. a ca certificate copied to
+/// multiple partitions, are only read once. Shared by the partition workers.
+#[derive(Default)]
+struct SourceCache {
+    sha256: Mutex<HashMap<PathBuf, Vec<u8>>>,
+    // copies of in-files with a modification time set via `--mtime`
+    mtime_copies: Mutex<HashMap<(PathBuf, u64), PathBuf>>,
+}
+
+impl SourceCache {
+    fn sha256(&self, in_file: &Path) -> Result<Vec<u8>> {
+        if let Some(sha256) = self.sha256.lock().unwrap().get(in_file) {
+            return Ok(sha256.clone());
+        }
+
+        // hash without holding the lock, so that workers don't wait for each other
+        let sha256 = file_sha256(in_file)?;
+        self.sha256
+            .lock()
+            .unwrap()
+            .insert(in_file.to_path_buf(), sha256.clone());
+
+        Ok(sha256)
+    }
+
+    /// Returns a copy of `in_file` in `working_dir` with modification time `mtime`.
+    fn with_mtime(&self, in_file: &Path, mtime: u64, working_dir: &Path) -> Result<PathBuf> {
+        let mut copies = self.mtime_copies.lock().unwrap();
+
+        if let Some(tmp_in_file) = copies.get(&(in_file.to_path_buf(), mtime)) {
+            return Ok(tmp_in_file.clone());
+        }
+
+        let tmp_in_file = working_dir.join(format!(
+            "{}-{}",
+            Uuid::new_v4(),
+            in_file.file_name().unwrap().to_str().unwrap()
+        ));
+        fs::copy(in_file, &tmp_in_file).context(format!(
+            "copy_to_image: couldn't copy {} to temp file",
+            in_file.to_str().unwrap()
+        ))?;
+        fs::File::options()
+            .write(true)
+            .open(&tmp_in_file)
+            .and_then(|f| f.set_modified(UNIX_EPOCH + Duration::from_secs(mtime)))
+            .context("copy_to_image: couldn't set mtime of temp file")?;
+
+        copies.insert((in_file.to_path_buf(), mtime), tmp_in_file.clone());
+
+        Ok(tmp_in_file)
+    }
+}
+
+fn file_sha256(path: &Path) -> Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    let mut file = fs::File::open(path).context(format!(
+        "file_sha256: cannot open {}",
+        path.to_str().unwrap()
+    ))?;
+
+    std::io::copy(&mut file, &mut hasher).context("file_sha256: cannot read file")?;
+
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Replaces params of directory in-files by params of the files of their trees,
+/// which are copied into the out-file path. Entries matching an exclude are
+/// skipped including their subtree. Empty directories aren't created.
+fn expand_dirs(
+    file_copy_params: &[FileCopyToParams],
+    excludes: &[Regex],
+) -> Result<Vec<FileCopyToParams>> {
+    fn walk(
+        params: &FileCopyToParams,
+        excludes: &[Regex],
+        dir: &Path,
+        rel_dir: &Path,
+        visited: &mut Vec<PathBuf>,
+        expanded: &mut Vec<FileCopyToParams>,
+    ) -> Result<()> {
+        // guards against symlinks pointing to one of their parent directories
+        let canonical = fs::canonicalize(dir).context(format!(
+            "expand_dirs: cannot resolve {}",
+            dir.to_str().unwrap()
+        ))?;
+        if visited.contains(&canonical) {
+            warn!(
+                "expand_dirs: skip directory loop at {}",
+                dir.to_str().unwrap()
+            );
+            return Ok(());
+        }
+        visited.push(canonical);
+
+        let mut entries = fs::read_dir(dir)
+            .context(format!(
+                "expand_dirs: cannot read {}",
+                dir.to_str().unwrap()
+            ))?
+            .collect::<std::io::Result<Vec<_>>>()
+            .context("expand_dirs: cannot read dir entry")?;
+        entries.sort_by_key(|e| e.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let rel_path = rel_dir.join(entry.file_name());
+            let rel = rel_path.to_str().unwrap();
+
+            if excludes.iter().any(|e| e.is_match(rel)) {
+                debug!("expand_dirs: exclude {}", path.to_str().unwrap());
+                continue;
+            }
+
+            if path.is_dir() && (params.dereference || !path.is_symlink()) {
+                walk(params, excludes, &path, &rel_path, visited, expanded)?;
+            } else {
+                let mut p = params.clone();
+                p.out_file = params.out_file.join(&rel_path);
+                p.in_file = path;
+                expanded.push(p);
+            }
+        }
+
+        visited.pop();
+        Ok(())
+    }
+
+    let mut expanded = vec![];
+
+    for params in file_copy_params {
+        let in_file = &params.in_file;
+
+        if in_file.is_dir() && (params.dereference || !in_file.is_symlink()) {
+            walk(
+                params,
+                excludes,
+                in_file,
+                Path::new(""),
+                &mut vec![],
+                &mut expanded,
+            )?;
+        } else {
+            expanded.push(params.clone());
+        }
+    }
+
+    Ok(expanded)
+}
+
+/// Converts a glob to a regex matching paths relative to a copied directory.
+/// `*` and `?` don't match '/', `**` matches across directories and `[...]`
+/// matches a character class. Globs without '/' match the name of an entry at
+/// any depth, others the whole relative path, e.g. `.git` or `cache/**/*.tmp`.
+fn glob_to_regex(glob: &str) -> Result<Regex> {
+    let glob = glob.trim_matches('/');
+    anyhow::ensure!(!glob.is_empty(), "glob_to_regex: empty exclude pattern");
+
+    let mut re = String::from(if glob.contains('/') { "^" } else { "(^|/)" });
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.next_if_eq(&'*').is_some() => {
+                if chars.next_if_eq(&'/').is_some() {
+                    re.push_str("(.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                re.push('[');
+                if chars.next_if_eq(&'!').is_some() {
+                    re.push('^');
+                }
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some('-') => re.push('-'),
+                        Some(c) => re.push_str(&regex::escape(&c.to_string())),
+                        None => anyhow::bail!("glob_to_regex: unterminated '[' in {glob}"),
+                    }
+                }
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+
+    Regex::new(&re).context(format!("glob_to_regex: invalid exclude pattern {glob}"))
+}
+
+/// Detects params of the same partition copying to the same destination.
+/// Destinations ending with '/' are completed by the in-file name, existing
+/// directories in the image aren't considered. With `allow_overwrite` only a
+/// warning is logged.
+fn check_duplicate_destinations(params: &[&FileCopyToParams], allow_overwrite: bool) -> Result<()> {
+    let mut destinations: HashMap<PathBuf, &FileCopyToParams> = HashMap::new();
+
+    for p in params {
+        let destination = destination_key(p);
+
+        if let Some(other) = destinations.insert(destination.clone(), p) {
+            let msg = format!(
+                "copy_to_image: {}:{} is target of {} and {}",
+                p.partition,
+                destination.to_string_lossy(),
+                other.in_file.to_string_lossy(),
+                p.in_file.to_string_lossy()
+            );
+
+            anyhow::ensure!(
+                allow_overwrite,
+                "{msg} (use --allow-overwrite to let the last one win)"
+            );
+
+            warn!("{msg}: the last one wins");
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the normalized destination of `params`, e.g. `/etc/file` for
+/// `/etc//./file` or for `/etc/` if the in-file is named `file`.
+fn destination_key(params: &FileCopyToParams) -> PathBuf {
+    let mut destination: PathBuf = params.out_file.components().collect();
+
+    if params.out_file.to_str().unwrap().ends_with('/') {
+        if let Some(file_name) = params.in_file.file_name() {
+            destination.push(file_name);
+        }
+    }
+
+    destination
+}
+
+/// Returns the directories `mmd` has to create for `dir_path` on a vfat
+/// partition, parents before children, e.g. `/a`, `/a/b` and `/a/b/c` for
+/// `/a/b/c`. Directories already in `created` are skipped, the returned ones
+/// are added to it.
+fn vfat_dirs(dir_path: &Path, created: &mut HashSet<PathBuf>) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    let mut dir = PathBuf::from("/");
+
+    for component in dir_path.components() {
+        match component {
+            std::path::Component::Normal(name) => dir.push(name),
+            std::path::Component::ParentDir => {
+                dir.pop();
+                continue;
+            }
+            _ => continue,
+        }
+
+        if created.insert(dir.clone()) {
+            dirs.push(dir.clone());
+        }
+    }
+
+    dirs
+}
+
+/// Fails if `in_file` exceeds the max file size or doesn't fit into the free
+/// space of the partition, instead of letting e2cp or mcopy fail midway.
+fn check_file_size(
+    partition_file: &str,
+    partition_info: &PartitionInfo,
+    in_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    let size = fs::metadata(in_file)
+        .context(format!(
+            "copy_to_image: cannot get size of {}",
+            in_file.to_str().unwrap()
+        ))?
+        .len();
+
+    if let Some(max_file_size) = options.max_file_size {
+        anyhow::ensure!(
+            size <= max_file_size,
+            "copy_to_image: {} exceeds --max-file-size: need {size} bytes, max {max_file_size} bytes",
+            in_file.to_str().unwrap()
+        );
+    }
+
+    let free = partition_free_space(partition_file, partition_info, options)?;
+
+    anyhow::ensure!(
+        size <= free,
+        "copy_to_image: {} doesn't fit into partition {}: need {size} bytes, have {free} bytes free",
+        in_file.to_str().unwrap(),
+        partition_info.num
+    );
+
+    Ok(())
+}
+
+fn partition_free_space(
+    partition_file: &str,
+    partition_info: &PartitionInfo,
+    options: &FileOptions,
+) -> Result<u64> {
+    let err = || {
+        format!(
+            "partition_free_space: cannot get free space of partition {}",
+            partition_info.num
+        )
+    };
+
+    if partition_info.vfat {
+        // the summary of mdir ends with e.g. "  1 234 567 bytes free"
+        let mut mdir = mtools_cmd("mdir");
+        mdir.arg("-i").arg(partition_file).arg("::/");
+        let mdir_out = exec_cmd_with_output!(mdir, options);
+
+        return mdir_out
+            .lines()
+            .find_map(|l| l.trim().strip_suffix("bytes free"))
+            .map(|free| {
+                free.chars()
+                    .filter(char::is_ascii_digit)
+                    .collect::<String>()
+            })
+            .and_then(|free| free.parse().ok())
+            .with_context(err);
+    }
+
+    let mut debugfs = Command::new("debugfs");
+    debugfs.arg("-R").arg("stats").arg(partition_file);
+    let stats = exec_cmd_with_output!(debugfs, options);
+    let field = |name: &str| -> Option<u64> {
+        stats
+            .lines()
+            .find_map(|l| l.strip_prefix(name))
+            .and_then(|v| v.trim().parse().ok())
+    };
+
+    field("Free blocks:")
+        .zip(field("Block size:"))
+        .map(|(blocks, block_size)| blocks * block_size)
+        .with_context(err)
+}
+
+/// Returns partition name, size and free space in bytes of the file systems of
+/// the boot, rootA, cert and factory partitions of `image_file`, measured the
+/// same way as when checking if copied files fit. Partitions that can't be
+/// resolved, e.g. cert of a foreign image, are omitted.
+pub fn partition_usage(
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<Vec<(String, u64, u64)>> {
+    let tmp_dir = create_working_dir(image_file)?;
+    let partitions = if options.raw_partition.is_some() {
+        vec![Partition::boot]
+    } else {
+        [
+            Partition::boot,
+            Partition::rootA,
+            Partition::cert,
+            Partition::factory,
+        ]
+        .into_iter()
+        .filter(|p| has_partition(image_file, p, options).unwrap_or(false))
+        .collect()
+    };
+    let image_file = image_file.to_str().unwrap();
+    let mut usage = vec![];
+
+    for partition in partitions {
+        let partition_info = get_partition_info(image_file, &partition, options)?;
+        let partition_file = &partition_file(image_file, tmp_dir.path(), &partition_info);
+
+        let size = if partition_info.raw {
+            fs::metadata(image_file)
+                .context("partition_usage: cannot get image size")?
+                .len()
+        } else {
+            partition_range(&partition_info)?.1
+        };
+
+        read_partition(image_file, partition_file, &partition_info, options)?;
+        let free = partition_free_space(partition_file, &partition_info, options)?;
+
+        let name = if partition_info.raw {
+            String::from("raw")
+        } else {
+            partition.to_string()
+        };
+
+        usage.push((name, size, free));
+
+        if !partition_info.raw {
+            fs::remove_file(partition_file)
+                .context(format!("partition_usage: cannot remove {partition_file}"))?;
+        }
+    }
+
+    Ok(usage)
+}
+
+/// Applies `cp` semantics to the destination of a file copied into the image:
+/// if the destination ends with a slash or is an existing directory, the file
+/// is copied into it keeping its name.
+fn resolve_destination(
+    partition_file: &str,
+    partition_info: &PartitionInfo,
+    in_file: &Path,
+    out_file: &Path,
+    options: &FileOptions,
+) -> Result<PathBuf> {
+    let out = out_file.to_str().unwrap();
+
+    let is_dir = out.ends_with('/')
+        || if partition_info.vfat {
+            is_vfat_dir(partition_file, out, options)?
+        } else {
+            is_ext_dir(partition_file, out, options)?
+        };
+
+    if !is_dir {
+        return Ok(out_file.to_path_buf());
+    }
+
+    let file_name = in_file.file_name().context(format!(
+        "resolve_destination: cannot get file name of {}",
+        in_file.to_str().unwrap()
+    ))?;
+
+    Ok(out_file.join(file_name))
+}
+
+/// Applies `cp` semantics to the destination of a file copied from the image:
+/// if the destination ends with a slash or is an existing directory, the file
+/// is copied into it keeping its name.
+fn resolve_out_file(in_file: &Path, out_file: &Path) -> Result<PathBuf> {
+    if !out_file.to_str().unwrap().ends_with('/') && !out_file.is_dir() {
+        return Ok(out_file.to_path_buf());
+    }
+
+    let file_name = in_file.file_name().context(format!(
+        "resolve_out_file: cannot get file name of {}",
+        in_file.to_str().unwrap()
+    ))?;
+
+    Ok(out_file.join(file_name))
+}
+
+fn is_vfat_dir(partition_file: &str, path: &str, options: &FileOptions) -> Result<bool> {
+    let mut mdir = mtools_cmd("mdir");
+    mdir.arg("-i").arg(partition_file).arg(format!("::{path}"));
+    let mdir_out = exec_cmd_with_output!(mdir, options);
+
+    // listing a directory prints its own path in the header, listing a file
+    // prints the path of the parent directory
+    let header = format!("directory for ::{}", path.trim_end_matches('/')).to_lowercase();
+
+    Ok(mdir_out
+        .lines()
+        .any(|line| line.trim().to_lowercase() == header))
+}
+
+fn ensure_out_dir(out_file: &Path) -> Result<()> {
+    anyhow::ensure!(
+        out_file
+            .parent()
+            .unwrap()
+            .try_exists()
+            .is_ok_and(|exists| exists),
+        "copy_from_image: output dir does not exist."
+    );
+
+    Ok(())
+}
+
+fn is_ext_dir(partition_file: &str, path: &str, options: &FileOptions) -> Result<bool> {
+    let mut debugfs = Command::new("debugfs");
+    debugfs
+        .arg("-R")
+        .arg(format!("stat {}", debugfs_quote(path)))
+        .arg(partition_file);
+    let stat = exec_cmd_with_output!(debugfs, options);
+
+    Ok(stat.contains("Type: directory"))
+}
+
+/// Returns whether `path` exists in the ext partition, without following a
+/// symlink.
+fn ext_path_exists(partition_file: &str, path: &str, options: &FileOptions) -> Result<bool> {
+    let mut debugfs = Command::new("debugfs");
+    debugfs
+        .arg("-R")
+        .arg(format!("stat {}", debugfs_quote(path)))
+        .arg(partition_file);
+    let stat = exec_cmd_with_output!(debugfs, options);
+
+    // errors like "File not found by ext2_lookup" are printed to stderr
+    Ok(stat.contains("Type: "))
+}
+
+/// Returns whether `path` exists in the vfat partition. Like vfat itself the
+/// lookup is case insensitive.
+fn vfat_path_exists(partition_file: &str, path: &str, options: &FileOptions) -> Result<bool> {
+    let mut mdir = mtools_cmd("mdir");
+    mdir.arg("-b")
+        .arg("-i")
+        .arg(partition_file)
+        .arg(format!("::{path}"));
+
+    // a file is listed by its path, but an empty directory isn't listed at all
+    Ok(!exec_cmd_with_output!(mdir, options).is_empty()
+        || is_vfat_dir(partition_file, path, options)?)
+}
+
+fn create_extract_dir(working_dir: &Path) -> Result<PathBuf> {
+    let dir = working_dir.join(Uuid::new_v4().to_string());
+
+    fs::create_dir(&dir).context(format!(
+        "create_extract_dir: couldn't create {}",
+        dir.to_str().unwrap()
+    ))?;
+
+    Ok(dir)
+}
+
+/// Moves the single file or directory extracted to `tmp_out_dir` to `out_file`
+/// and returns the final path. Directories are merged into `out_file`, which is
+/// created if needed. Files are moved into `out_file` if it is a directory.
+fn move_extracted(tmp_out_dir: &Path, out_file: &Path) -> Result<PathBuf> {
+    let extracted = fs::read_dir(tmp_out_dir)
+        .context("move_extracted: couldn't read extract dir")?
+        .next()
+        .context("move_extracted: nothing extracted")?
+        .context("move_extracted: couldn't read extracted file")?
+        .path();
+
+    let out_file = if extracted.is_dir() {
+        let size = dir_size(&extracted)?;
+        if size > EXTRACT_WARN_SIZE {
+            warn!(
+                "move_extracted: extracted directory is large ({} MiB)",
+                size / (1024 * 1024)
+            );
+        }
+
+        // instead of rename we copy to prevent "Invalid cross-device link" errors
+        copy_tree(&extracted, out_file)?;
+        out_file.to_path_buf()
+    } else {
+        let out_file = resolve_out_file(&extracted, out_file)?;
+        ensure_out_dir(&out_file)?;
+
+        // instead of rename we copy and delete to prevent "Invalid cross-device link" errors
+        let bytes_copied = fs::copy(&extracted, &out_file).context(format!(
+            "move_extracted: couldn't copy temp file {} to destination {}",
+            extracted.to_str().unwrap(),
+            out_file.to_str().unwrap()
+        ))?;
+        anyhow::ensure!(
+            extracted.metadata().unwrap().len() == bytes_copied,
+            "move_extracted: copy temp file failed"
+        );
+        out_file
+    };
+
+    fs::remove_dir_all(tmp_out_dir).context(format!(
+        "move_extracted: couldn't delete temp dir {}",
+        tmp_out_dir.to_str().unwrap()
+    ))?;
+
+    Ok(out_file)
+}
+
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+
+    for entry in fs::read_dir(dir).context("dir_size: couldn't read dir")? {
+        let entry = entry.context("dir_size: couldn't read dir entry")?;
+        let metadata = entry
+            .metadata()
+            .context("dir_size: couldn't get metadata")?;
+
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+
+    Ok(size)
+}
+
+fn copy_tree(source: &Path, destination: &Path) -> Result<()> {
+    fs::create_dir_all(destination).context(format!(
+        "copy_tree: couldn't create {}",
+        destination.to_str().unwrap()
+    ))?;
+
+    for entry in fs::read_dir(source).context("copy_tree: couldn't read dir")? {
+        let entry = entry.context("copy_tree: couldn't read dir entry")?;
+        let file_type = entry
+            .file_type()
+            .context("copy_tree: couldn't get file type")?;
+        let target = destination.join(entry.file_name());
+
+        // a symlink left by a previous extraction can't be recreated and
+        // would be followed by fs::copy, so it's replaced
+        if !file_type.is_dir() && target.is_symlink() {
+            fs::remove_file(&target).context(format!(
+                "copy_tree: couldn't remove symlink {}",
+                target.to_str().unwrap()
+            ))?;
+        }
+
+        if file_type.is_dir() {
+            copy_tree(&entry.path(), &target)?;
+        } else if file_type.is_symlink() {
+            let link = fs::read_link(entry.path()).context("copy_tree: couldn't read symlink")?;
+            std::os::unix::fs::symlink(link, &target).context(format!(
+                "copy_tree: couldn't create symlink {}",
+                target.to_str().unwrap()
+            ))?;
+        } else {
+            fs::copy(entry.path(), &target).context(format!(
+                "copy_tree: couldn't copy to {}",
+                target.to_str().unwrap()
+            ))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn get_mtime(path: &Path, symlink: bool) -> Result<u64> {
+    let metadata = if symlink {
+        fs::symlink_metadata(path)
+    } else {
+        fs::metadata(path)
+    }
+    .context(format!(
+        "get_mtime: cannot get metadata of {}",
+        path.to_str().unwrap()
+    ))?;
+
+    Ok(metadata
+        .modified()
+        .context("get_mtime: cannot get modification time")?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs()))
+}
+
+/// Sets all timestamps of each file of `timestamps` in an ext partition to its
+/// modification time, since e2cp uses the current time. All files are handled
+/// by a single debugfs run.
+fn set_timestamps(
+    partition_file: &str,
+    timestamps: &[(String, u64)],
+    working_dir: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    let cmd_file = working_dir.join(format!("{}-debugfs.cmd", Uuid::new_v4()));
+    let cmds: String = timestamps
+        .iter()
+        .flat_map(|(path, mtime)| {
+            ["atime", "ctime", "mtime", "crtime"]
+                .map(|field| format!("set_inode_field {} {field} @{mtime}\n", debugfs_quote(path)))
+        })
+        .collect();
+
+    fs::write(&cmd_file, cmds).context("set_timestamps: cannot write debugfs command file")?;
+
+    let mut debugfs = Command::new("debugfs");
+    debugfs
+        .arg("-w")
+        .arg("-f")
+        .arg(&cmd_file)
+        .arg(partition_file);
+    exec_cmd!(debugfs, options);
+
+    fs::remove_file(&cmd_file).context("set_timestamps: cannot remove debugfs command file")
+}
+
+/// Sets the extended attributes `xattrs` of a file in an ext partition, since
+/// e2cp doesn't copy them.
+fn set_xattrs(
+    partition_file: &str,
+    path: &str,
+    xattrs: &[(String, Vec<u8>)],
+    working_dir: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    let id = Uuid::new_v4();
+    let cmd_file = working_dir.join(format!("{id}-debugfs.cmd"));
+    let mut cmds = String::new();
+    let mut value_files = vec![];
+
+    // values are passed as files, since they are binary, e.g. security.capability
+    for (i, (name, value)) in xattrs.iter().enumerate() {
+        let value_file = working_dir.join(format!("{id}-xattr-{i}"));
+        fs::write(&value_file, value).context("set_xattrs: cannot write value file")?;
+        cmds.push_str(&format!(
+            "ea_set -f {} {} {}\n",
+            debugfs_quote(value_file.to_str().unwrap()),
+            debugfs_quote(path),
+            debugfs_quote(name)
+        ));
+        value_files.push(value_file);
+    }
+
+    fs::write(&cmd_file, cmds).context("set_xattrs: cannot write debugfs command file")?;
+
+    let mut debugfs = Command::new("debugfs");
+    debugfs
+        .arg("-w")
+        .arg("-f")
+        .arg(&cmd_file)
+        .arg(partition_file);
+    exec_cmd!(debugfs, options);
+
+    for file in value_files.iter().chain([&cmd_file]) {
+        fs::remove_file(file).context("set_xattrs: cannot remove temporary file")?;
+    }
+
+    Ok(())
+}
+
+/// Returns the permission bits of a file in the image or `None` if the file
+/// doesn't exist or the partition doesn't support permissions (vfat).
+pub fn get_file_mode(
+    path: impl AsRef<Path>,
+    partition: &Partition,
+    image_file: impl AsRef<Path>,
+    options: &FileOptions,
+) -> Result<Option<u32>> {
+    let tmp_dir = create_working_dir(image_file.as_ref())?;
+    let image_file = image_file.as_ref().to_str().unwrap();
+    let partition_info = get_partition_info(image_file, partition, options)?;
+
+    if partition_info.vfat {
+        return Ok(None);
+    }
+
+    let partition_file = &partition_file(image_file, tmp_dir.path(), &partition_info);
+
+    read_partition(image_file, partition_file, &partition_info, options)?;
+
+    let mut e2ls = Command::new("e2ls");
+    e2ls.arg("-l").arg(format!(
+        "{partition_file}:{}",
+        path.as_ref().to_str().unwrap()
+    ));
+    let e2ls_out = exec_cmd_with_output!(e2ls, options);
+
+    // e.g. "   12  100644     0     0       42 15-Oct-2024 12:00 file"
+    let Some(mode) = e2ls_out.split_whitespace().nth(1) else {
+        return Ok(None);
+    };
+
+    let mode = u32::from_str_radix(mode, 8)
+        .context(format!("get_file_mode: unexpected e2ls output: {e2ls_out}"))?;
+
+    Ok(Some(mode & 0o7777))
+}
+
+/// Computes the dm-verity hash tree of `partition` via `veritysetup format`,
+/// writes it to `hash_tree_file` and returns the root hash.
+pub fn verity_format(
+    partition: &Partition,
+    image_file: impl AsRef<Path>,
+    hash_tree_file: &Path,
+    options: &FileOptions,
+) -> Result<String> {
+    let tmp_dir = create_working_dir(image_file.as_ref())?;
+    let image_file = image_file.as_ref().to_str().unwrap();
+    let partition_info = get_partition_info(image_file, partition, options)?;
+    let partition_file = &partition_file(image_file, tmp_dir.path(), &partition_info);
+    let root_hash_file = tmp_dir.path().join("root_hash");
+
+    read_partition(image_file, partition_file, &partition_info, options)?;
+
+    let mut veritysetup = Command::new("veritysetup");
+    veritysetup
+        .arg("format")
+        .arg("--root-hash-file")
+        .arg(&root_hash_file)
+        .arg(partition_file)
+        .arg(hash_tree_file);
+    exec_cmd!(veritysetup, options);
+
+    let root_hash = fs::read_to_string(&root_hash_file)
+        .context("verity_format: cannot read root hash")?
+        .trim()
+        .to_string();
+
+    anyhow::ensure!(
+        !root_hash.is_empty() && root_hash.chars().all(|c| c.is_ascii_hexdigit()),
+        "verity_format: unexpected root hash: {root_hash}"
+    );
+
+    Ok(root_hash)
+}
+
+/// Wipes `partition` and recreates an empty file system of type `fs_type` in
+/// its extent. The type defaults to the current one, i.e. vfat for the boot
+/// partition and ext4 otherwise. If the type doesn't change, UUID and (unless
+/// `label` is given) label of the current file system are kept, so that
+/// references e.g. in /etc/fstab stay valid.
+pub fn format_partition(
+    partition: &Partition,
+    image_file: impl AsRef<Path>,
+    fs_type: Option<FsType>,
+    label: Option<&str>,
+    options: &FileOptions,
+) -> Result<()> {
+    let tmp_dir = create_working_dir(image_file.as_ref())?;
+    let image_file = image_file.as_ref().to_str().unwrap();
+    let partition_info = get_partition_info(image_file, partition, options)?;
+    let partition_file = &partition_file(image_file, tmp_dir.path(), &partition_info);
+
+    let (offset, len) = if partition_info.raw {
+        let len = fs::metadata(image_file)
+            .context("format_partition: cannot get image size")?
+            .len();
+        (0, len)
+    } else {
+        partition_range(&partition_info)?
+    };
+
+    let current_fs_type = if partition_info.vfat {
+        FsType::vfat
+    } else {
+        FsType::ext4
+    };
+    let fs_type = fs_type.unwrap_or(current_fs_type);
+
+    let mut tags = if fs_type == current_fs_type {
+        fs_tags(image_file, offset, options)?
+    } else {
+        warn!("format_partition: change file system of {partition} to {fs_type:?}");
+        HashMap::new()
+    };
+    let uuid = tags.remove("UUID");
+    let label = label.map(str::to_string).or_else(|| tags.remove("LABEL"));
+
+    if let Some(label) = &label {
+        let max_len = match fs_type {
+            FsType::ext4 => 16,
+            FsType::vfat => 11,
+        };
+        anyhow::ensure!(
+            label.len() <= max_len,
+            "format_partition: label \"{label}\" exceeds {max_len} bytes allowed for {fs_type:?}"
+        );
+    }
+
+    // start from a zeroed (sparse) file, so that no previous content survives
+    let file = fs::File::options()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(partition_file)
+        .context(format!("format_partition: cannot create {partition_file}"))?;
+    file.set_len(len)
+        .context("format_partition: cannot set partition size")?;
+    drop(file);
+
+    match fs_type {
+        FsType::ext4 => {
+            let mut mkfs = Command::new("mkfs.ext4");
+            mkfs.arg("-F").arg("-q");
+            if let Some(uuid) = &uuid {
+                mkfs.arg("-U").arg(uuid);
+            }
+            if let Some(label) = &label {
+                mkfs.arg("-L").arg(label);
+            }
+            mkfs.arg(partition_file);
+            exec_cmd!(mkfs, options);
+        }
+        FsType::vfat => {
+            let mut mkfs = Command::new("mkfs.vfat");
+            // blkid reports vfat volume ids as "XXXX-XXXX"
+            if let Some(uuid) = &uuid {
+                mkfs.arg("-i").arg(uuid.replace('-', ""));
+            }
+            if let Some(label) = &label {
+                mkfs.arg("-n").arg(label);
+            }
+            mkfs.arg(partition_file);
+            exec_cmd!(mkfs, options);
+        }
+    }
+
+    let partition_info = PartitionInfo {
+        vfat: fs_type == FsType::vfat,
+        ..partition_info
+    };
+
+    write_partition(image_file, partition_file, &partition_info, options)?;
+    options.keep_partition(partition_file, &partition_info)?;
+
+    options.audit_log.operation(
+        "format-partition",
+        serde_json::json!({
+            "image": image_file,
+            "partition": partition.to_string(),
+            "fsType": format!("{fs_type:?}"),
+            "label": label,
+        }),
+    )
+}
+
+pub fn read_file_from_image(
+    path: impl AsRef<Path>,
+    partition: Partition,
+    image_file: impl AsRef<Path>,
+    options: &FileOptions,
+) -> Result<String> {
+    let tmp_file = tempfile::NamedTempFile::new()
+        .context("read_file_from_image: could not create temporary file path")?;
+
+    let params = FileCopyFromParams::new(path.as_ref(), partition, tmp_file.path());
+
+    copy_from_image(&[params], image_file.as_ref(), options)
+        .context("read_file_from_image: could not copy file content")?;
+
+    let content = std::fs::read_to_string(tmp_file.path())
+        .context("read_file_from_image: could not read file content")?;
+
+    Ok(content)
+}
+
+/// Like `read_file_from_image`, but returns `None` if `path` doesn't exist in
+/// `partition`. Any other error, e.g. of reading the partition, is returned.
+pub fn read_file_from_image_if_exists(
+    path: &str,
+    partition: Partition,
+    image_file: impl AsRef<Path>,
+    options: &FileOptions,
+) -> Result<Option<String>> {
+    if !paths_exist(&[path], &partition, image_file.as_ref(), options)?[0] {
+        return Ok(None);
+    }
+
+    read_file_from_image(path, partition, image_file, options).map(Some)
+}
+
+/// Returns whether the partition table of the image contains `partition`.
+/// Raw partition images are considered to contain every partition.
+pub fn has_partition(
+    image_file: impl AsRef<Path>,
+    partition: &Partition,
+    options: &FileOptions,
+) -> Result<bool> {
+    if options.raw_partition.is_some() {
+        return Ok(true);
+    }
+
+    let image_file = image_file.as_ref().to_str().unwrap();
+    let fdisk_out = list_partitions(image_file, options)?;
+
+    let partition_num = match partition {
+        Partition::mountpoint(m) => resolve_mountpoint(image_file, &fdisk_out, m, options)?.0,
+        Partition::uuid(u) => {
+            match find_partition_by_fs_uuid(image_file, &fdisk_out, u, options)? {
+                Some((num, _)) => num,
+                None => return Ok(false),
+            }
+        }
+        p => get_partition_num(image_file, &fdisk_out, p, options)?,
+    };
+
+    let re = Regex::new(format!(r"(?m)^{}{partition_num}\s", regex::escape(image_file)).as_str())
+        .context("has_partition: failed to create regex")?;
+
+    Ok(re.is_match(&fdisk_out))
+}
+
+/// Describes the partition table of `image_file` with one line per partition:
+/// number, start and end sector, size, gpt partition name, file system type
+/// and label and the partition name to be used with commands, if any. The
+/// partitions are resolved the same way as by commands operating on them.
+pub fn describe_partitions(image_file: &Path, options: &FileOptions) -> Result<String> {
+    let image_file = image_file.to_str().unwrap();
+    let mut out = format!(
+        "{:<3} {:>10} {:>10} {:>10} {:<12} {:<6} {:<12} {}\n",
+        "#", "start", "end", "size", "name", "fs", "fs-label", "partition"
+    );
+
+    if options.raw_partition.is_some() {
+        let tags = fs_tags(image_file, 0, options)?;
+        let size = fs::metadata(image_file)
+            .context("describe_partitions: cannot get image size")?
+            .len();
+        out.push_str(&format!(
+            "{:<3} {:>10} {:>10} {size:>10} {:<12} {:<6} {:<12} {}\n",
+            "-",
+            "-",
+            "-",
+            "-",
+            tags.get("TYPE").map_or("-", String::as_str),
+            tags.get("LABEL").map_or("-", String::as_str),
+            "raw"
+        ));
+        return Ok(out);
+    }
+
+    let fdisk_out = list_partitions(image_file, options)?;
+
+    let mut fdisk = Command::new("fdisk");
+    fdisk.arg("-l").arg("-o").arg("Device,Name").arg(image_file);
+    let names = if fdisk_out.contains("Disklabel type: gpt") {
+        exec_cmd_with_output!(fdisk, options)
+    } else {
+        String::new()
+    };
+
+    // partitions that can't be resolved, e.g. cert of a foreign image, are omitted
+    let logical: Vec<(u32, Partition)> = [
+        Partition::boot,
+        Partition::rootA,
+        Partition::cert,
+        Partition::factory,
+    ]
+    .into_iter()
+    .filter_map(|p| {
+        get_partition_num(image_file, &fdisk_out, &p, options)
+            .ok()
+            .map(|num| (num, p))
+    })
+    .collect();
+
+    let re =
+        Regex::new(format!(r"(?m)^{}(\d+)\s+(\d+)\s+(\d+)", regex::escape(image_file)).as_str())
+            .context("describe_partitions: failed to create regex")?;
+
+    for caps in re.captures_iter(&fdisk_out) {
+        let num: u32 = caps[1]
+            .parse()
+            .context("describe_partitions: invalid partition number")?;
+        let start: u64 = caps[2]
+            .parse()
+            .context("describe_partitions: invalid start sector")?;
+        let end: u64 = caps[3]
+            .parse()
+            .context("describe_partitions: invalid end sector")?;
+
+        let name =
+            Regex::new(format!(r"(?m)^{}{num}\s+(\S.*?)\s*$", regex::escape(image_file)).as_str())
+                .context("describe_partitions: failed to create regex")?
+                .captures(&names)
+                .map_or("-".to_string(), |caps| caps[1].to_string());
+
+        let tags = fs_tags(image_file, start * 512, options)?;
+        let partition = logical
+            .iter()
+            .filter(|(n, _)| *n == num)
+            .map(|(_, p)| p.to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+
+        out.push_str(&format!(
+            "{num:<3} {start:>10} {end:>10} {:>10} {name:<12} {:<6} {:<12} {}\n",
+            (end + 1 - start) * 512,
+            tags.get("TYPE").map_or("-", String::as_str),
+            tags.get("LABEL").map_or("-", String::as_str),
+            if partition.is_empty() {
+                "-"
+            } else {
+                &partition
+            },
+        ));
+    }
+
+    Ok(out)
+}
+
+fn list_partitions(image_file: &str, options: &FileOptions) -> Result<String> {
+    let mut fdisk = Command::new("fdisk");
+    fdisk
+        .arg("-l")
+        .arg("-o")
+        .arg("Device,Start,End")
+        .arg(image_file);
+
+    Ok(exec_cmd_with_output!(fdisk, options))
+}
+
+fn get_partition_info(
+    image_file: &str,
+    partition: &Partition,
+    options: &FileOptions,
+) -> Result<PartitionInfo> {
+    if let Some(raw_partition) = options.raw_partition {
+        debug!("get_partition_info: ignore {partition} for raw partition image");
+
+        return Ok(PartitionInfo {
+            num: String::from("raw"),
+            start: String::new(),
+            end: String::new(),
+            vfat: raw_partition == RawPartition::boot,
+            raw: true,
+        });
+    }
+
+    let fdisk_out = list_partitions(image_file, options)?;
+
+    let (partition_num, vfat) = match partition {
+        Partition::mountpoint(m) => resolve_mountpoint(image_file, &fdisk_out, m, options)?,
+        Partition::uuid(u) => find_partition_by_fs_uuid(image_file, &fdisk_out, u, options)?
+            .context(format!(
+                "get_partition_info: no partition with file system UUID {u}"
+            ))?,
+        p => (
+            get_partition_num(image_file, &fdisk_out, p, options)?,
+            *p == Partition::boot,
+        ),
+    };
+
+    let re = Regex::new(format!(r"{image_file}{partition_num}\s+(\d+)\s+(\d+)").as_str())
+        .context("get_partition_info: failed to create regex")?;
+
+    let matches = re.captures(&fdisk_out).context(format!(
+        "get_partition_info: image has no '{partition}' partition (#{partition_num})"
+    ))?;
+    anyhow::ensure!(
+        matches.len() == 3,
+        "'get_partition_info: regex contains unexpected number of matches"
+    );
+
+    let partition_offset = (matches[1].to_string(), matches[2].to_string());
+
+    let info = PartitionInfo {
+        num: partition_num.to_string(),
+        start: partition_offset.0,
+        end: partition_offset.1,
+        vfat,
+        raw: false,
+    };
+
+    debug!("get_partition_info: {:?}", info);
+
+    Ok(info)
+}
+
+fn get_partition_num(
+    image_file: &str,
+    fdisk_out: &str,
+    partition: &Partition,
+    options: &FileOptions,
+) -> Result<u32> {
+    if let Some(layout) = &options.layout {
+        let entry = match partition {
+            Partition::boot => &layout.boot,
+            Partition::rootA => &layout.root_a,
+            Partition::cert => &layout.cert,
+            Partition::factory => &layout.factory,
+            Partition::mountpoint(_) | Partition::uuid(_) => &None,
+        };
+
+        match entry {
+            Some(PartitionLayoutEntry {
+                index: Some(index), ..
+            }) => return Ok(*index),
+            Some(PartitionLayoutEntry {
+                label: Some(label), ..
+            }) => return get_partition_num_by_label(image_file, label, options),
+            _ => {}
+        }
+    }
+
+    let partition_num = match partition {
+        Partition::boot => 1,
         Partition::rootA => 2,
         p @ (Partition::factory | Partition::cert) => {
             let re = Regex::new(r"Disklabel type: (\D{3})").unwrap();
 
             let matches = re
-                .captures(&fdisk_out)
+                .captures(fdisk_out)
                 .context("get_partition_info: regex no matches found")?;
             anyhow::ensure!(
                 matches.len() == 2,
@@ -407,98 +2909,1134 @@ fn get_partition_info(image_file: &str, partition: &Partition) -> Result<Partiti
 
             debug!("partition type: {partition_type}");
 
-            match (p, partition_type) {
-                (Partition::factory, "gpt") => 4,
-                (Partition::factory, "dos") => 5,
-                (Partition::cert, "gpt") => 5,
-                (Partition::cert, "dos") => 6,
+            // prefer the label, so that additional partitions don't shift the lookup
+            let by_label = match partition_type {
+                "gpt" => find_partition_num_by_label(image_file, &p.to_string(), options)?,
+                _ => {
+                    find_partition_num_by_fs_label(image_file, fdisk_out, &p.to_string(), options)?
+                }
+            };
+
+            match (by_label, p, partition_type) {
+                (Some(num), _, _) => num,
+                (None, Partition::factory, "gpt") => 4,
+                (None, Partition::factory, "dos") => 5,
+                (None, Partition::cert, "gpt") => 5,
+                (None, Partition::cert, "dos") => 6,
                 _ => anyhow::bail!("get_partition_info: unhandled partition type"),
             }
         }
+        Partition::mountpoint(_) => {
+            anyhow::bail!("get_partition_num: mountpoints must be resolved via fstab")
+        }
+        Partition::uuid(_) => {
+            anyhow::bail!("get_partition_num: uuids must be resolved via the file systems")
+        }
     };
 
-    let re = Regex::new(format!(r"{image_file}{partition_num}\s+(\d+)\s+(\d+)").as_str())
-        .context("get_partition_info: failed to create regex")?;
+    Ok(partition_num)
+}
 
-    let matches = re
-        .captures(&fdisk_out)
-        .context("get_partition_info: regex no matches found")?;
-    anyhow::ensure!(
-        matches.len() == 3,
-        "'get_partition_info: regex contains unexpected number of matches"
-    );
+/// Returns device and file system type of the /etc/fstab entry of `mountpoint`.
+fn fstab_entry(fstab: &str, mountpoint: &Path) -> Result<(String, String)> {
+    fstab
+        .lines()
+        .map(|l| l.split_whitespace().collect::<Vec<&str>>())
+        .find(|f| f.len() >= 3 && !f[0].starts_with('#') && Path::new(f[1]) == mountpoint)
+        .map(|f| (f[0].to_string(), f[2].to_string()))
+        .context(format!(
+            "resolve_mountpoint: no entry for {} in /etc/fstab",
+            mountpoint.to_string_lossy()
+        ))
+}
 
-    let partition_offset = (matches[1].to_string(), matches[2].to_string());
+fn resolve_mountpoint(
+    image_file: &str,
+    fdisk_out: &str,
+    mountpoint: &Path,
+    options: &FileOptions,
+) -> Result<(u32, bool)> {
+    let fstab = read_file_from_image(FSTAB_PATH, Partition::rootA, image_file, options)
+        .context("resolve_mountpoint: couldn't read /etc/fstab from rootA")?;
+
+    let (device, fs_type) = fstab_entry(&fstab, mountpoint)?;
+
+    debug!("resolve_mountpoint: {mountpoint:?} -> {device} ({fs_type})");
+
+    let vfat = matches!(fs_type.as_str(), "vfat" | "fat" | "msdos");
+
+    let partition_num = if let Some(label) = device
+        .strip_prefix("PARTLABEL=")
+        .or_else(|| device.strip_prefix("/dev/disk/by-partlabel/"))
+    {
+        get_partition_num_by_label(image_file, label, options)?
+    } else if let Some(uuid) = device.strip_prefix("UUID=") {
+        find_partition_by_fs_uuid(image_file, fdisk_out, uuid, options)?
+            .context(format!(
+                "resolve_mountpoint: no partition with file system UUID {uuid}"
+            ))?
+            .0
+    } else if let Some(name) = device.strip_prefix("/dev/omnect/") {
+        get_partition_num(image_file, fdisk_out, &Partition::from_str(name)?, options)?
+    } else if let Some(caps) = RE_DEVICE_NUM.captures(&device) {
+        caps[1]
+            .parse()
+            .context("resolve_mountpoint: invalid partition number")?
+    } else {
+        anyhow::bail!(
+            "resolve_mountpoint: unsupported device specification in /etc/fstab: {device}"
+        )
+    };
 
-    let info = PartitionInfo {
-        num: partition_num.to_string(),
-        start: partition_offset.0,
-        end: partition_offset.1,
+    Ok((partition_num, vfat))
+}
+
+fn get_partition_num_by_label(image_file: &str, label: &str, options: &FileOptions) -> Result<u32> {
+    find_partition_num_by_label(image_file, label, options)?.context(format!(
+        "get_partition_num_by_label: no partition labeled {label}"
+    ))
+}
+
+/// Looks up the number of the gpt partition named `label`.
+fn find_partition_num_by_label(
+    image_file: &str,
+    label: &str,
+    options: &FileOptions,
+) -> Result<Option<u32>> {
+    let mut fdisk = Command::new("fdisk");
+    fdisk.arg("-l").arg("-o").arg("Device,Name").arg(image_file);
+    let fdisk_out = exec_cmd_with_output!(fdisk, options);
+
+    let re = Regex::new(
+        format!(
+            r"(?m)^{}(\d+)\s+{}\s*$",
+            regex::escape(image_file),
+            regex::escape(label)
+        )
+        .as_str(),
+    )
+    .context("find_partition_num_by_label: failed to create regex")?;
+
+    re.captures(&fdisk_out)
+        .map(|caps| {
+            caps[1]
+                .parse()
+                .context("find_partition_num_by_label: invalid partition number")
+        })
+        .transpose()
+}
+
+/// Looks up the number of the partition containing a file system labeled
+/// `label`. Used for dos partition tables, which don't support partition names.
+fn find_partition_num_by_fs_label(
+    image_file: &str,
+    fdisk_out: &str,
+    label: &str,
+    options: &FileOptions,
+) -> Result<Option<u32>> {
+    Ok(
+        find_partition_by_fs_tag(image_file, fdisk_out, "LABEL", label, options)?
+            .map(|(num, _)| num),
+    )
+}
+
+/// Looks up the number of the partition containing a file system with UUID
+/// `uuid` and whether it is a vfat file system.
+fn find_partition_by_fs_uuid(
+    image_file: &str,
+    fdisk_out: &str,
+    uuid: &str,
+    options: &FileOptions,
+) -> Result<Option<(u32, bool)>> {
+    find_partition_by_fs_tag(image_file, fdisk_out, "UUID", uuid, options)
+}
+
+/// Probes the file systems of all partitions for `tag` (as reported by blkid,
+/// compared case-insensitively) and returns number and vfat flag of the first
+/// partition matching `value`.
+fn find_partition_by_fs_tag(
+    image_file: &str,
+    fdisk_out: &str,
+    tag: &str,
+    value: &str,
+    options: &FileOptions,
+) -> Result<Option<(u32, bool)>> {
+    let re = Regex::new(format!(r"(?m)^{}(\d+)\s+(\d+)\s", regex::escape(image_file)).as_str())
+        .context("find_partition_by_fs_tag: failed to create regex")?;
+
+    for caps in re.captures_iter(fdisk_out) {
+        let offset = caps[2]
+            .parse::<u64>()
+            .context("find_partition_by_fs_tag: invalid start sector")?
+            * 512;
+
+        let tags = fs_tags(image_file, offset, options)?;
+
+        if tags.get(tag).is_some_and(|v| v.eq_ignore_ascii_case(value)) {
+            let num = caps[1]
+                .parse()
+                .context("find_partition_by_fs_tag: invalid partition number")?;
+            let vfat = tags.get("TYPE").is_some_and(|t| t == "vfat");
+
+            return Ok(Some((num, vfat)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Adds the actual file system of `partition_file` to `err` if it doesn't
+/// match the tools used for `partition`. Explains otherwise cryptic failures
+/// of mtools or e2tools on images whose partition roles deviate from the
+/// expected layout.
+fn explain_fs_mismatch(
+    err: anyhow::Error,
+    partition: &Partition,
+    partition_file: &str,
+    partition_info: &PartitionInfo,
+    options: &FileOptions,
+) -> anyhow::Error {
+    // the partition may not have been extracted yet
+    let Ok(tags) = fs_tags(partition_file, 0, options) else {
+        return err;
     };
 
-    debug!("get_partition_info: {:?}", info);
+    match fs_mismatch(
+        partition,
+        partition_info.vfat,
+        tags.get("TYPE").map(String::as_str),
+    ) {
+        Some(mismatch) => err.context(mismatch),
+        None => err,
+    }
+}
 
-    Ok(info)
+fn fs_mismatch(partition: &Partition, vfat: bool, fs_type: Option<&str>) -> Option<String> {
+    let (expected, tools) = if vfat {
+        ("FAT", "mtools")
+    } else {
+        ("ext", "e2tools")
+    };
+
+    match fs_type {
+        Some("vfat" | "msdos") if vfat => None,
+        Some("ext2" | "ext3" | "ext4") if !vfat => None,
+        Some(fs_type) => Some(format!(
+            "partition '{partition}' is {fs_type}, not {expected}; expected {expected} for {tools}"
+        )),
+        None => Some(format!(
+            "partition '{partition}' has no known file system; expected {expected} for {tools}"
+        )),
+    }
+}
+
+/// Returns the tags, e.g. TYPE, UUID or LABEL, of the file system at `offset`
+/// of `image_file` as reported by blkid.
+fn fs_tags(
+    image_file: &str,
+    offset: u64,
+    options: &FileOptions,
+) -> Result<HashMap<String, String>> {
+    let mut blkid = Command::new("blkid");
+    blkid
+        .arg("-p")
+        .arg("-o")
+        .arg("export")
+        .arg("-O")
+        .arg(offset.to_string())
+        .arg(image_file);
+
+    // partitions without file system, e.g. the extended one, yield no tags
+    let tags = exec_cmd_with_output!(blkid, options);
+
+    Ok(tags
+        .lines()
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect())
 }
 
 fn read_partition(
     image_file: &str,
     partition_file: &str,
     partition_info: &PartitionInfo,
+    options: &FileOptions,
 ) -> Result<()> {
+    if partition_info.raw {
+        return Ok(());
+    }
+
     if let Ok(true) = PathBuf::from(partition_file).try_exists() {
         return Ok(());
     }
 
-    let mut dd = Command::new("dd");
-    dd.arg(format!("if={image_file}"))
-        .arg(format!("of={partition_file}"))
-        .arg("bs=512")
-        .arg(format!("skip={}", partition_info.start))
-        .arg(format!("count={}", partition_info.end))
-        .arg("conv=sparse")
-        .arg("status=none");
-    exec_cmd!(dd);
+    let (offset, len) = partition_range(partition_info)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        let start = Instant::now();
+        let image = fs::File::open(image_file)
+            .context(format!("read_partition: cannot open {image_file}"))?;
+        let partition = fs::File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(partition_file)
+            .context(format!("read_partition: cannot create {partition_file}"))?;
+
+        partition
+            .set_len(len)
+            .context("read_partition: cannot set partition size")?;
+        super::sparse::copy_sparse(&image, offset, &partition, 0, len, options.dd_block_size())?;
+        if options.sync_enabled() {
+            partition
+                .sync_all()
+                .context("read_partition: cannot sync partition")?;
+        }
+
+        options.audit_log.operation(
+            "read-partition",
+            serde_json::json!({
+                "image": image_file,
+                "partition": partition_info.num,
+                "durationMs": start.elapsed().as_millis() as u64,
+            }),
+        )?;
+    }
 
-    let mut sync = Command::new("sync");
-    exec_cmd!(sync);
+    #[cfg(not(target_os = "linux"))]
+    {
+        let bs = aligned_block_size(options.dd_block_size(), offset, len);
+        let mut dd = Command::new("dd");
+        dd.arg(format!("if={image_file}"))
+            .arg(format!("of={partition_file}"))
+            .arg(format!("bs={bs}"))
+            .arg(format!("skip={}", offset / bs))
+            .arg(format!("count={}", len / bs))
+            .arg("conv=sparse")
+            .arg("status=none");
+        exec_cmd!(dd, options);
+
+        if options.sync_enabled() {
+            let mut sync = Command::new("sync");
+            exec_cmd!(sync, options);
+        }
+    }
 
     Ok(())
 }
 
+/// Returns number, start and end sector (inclusive, 512 bytes each) of
+/// `partition`, as used to extract it from the image.
+pub fn partition_sectors(
+    image_file: &Path,
+    partition: &Partition,
+    options: &FileOptions,
+) -> Result<(String, u64, u64)> {
+    let partition_info = get_partition_info(image_file.to_str().unwrap(), partition, options)?;
+
+    anyhow::ensure!(
+        !partition_info.raw,
+        "partition_sectors: a raw partition image has no partition table"
+    );
+
+    let (offset, size) = partition_range(&partition_info)?;
+
+    Ok((partition_info.num, offset / 512, (offset + size) / 512 - 1))
+}
+
+/// Returns offset and length of a partition within the image in bytes.
+fn partition_range(partition_info: &PartitionInfo) -> Result<(u64, u64)> {
+    let sector = |s: &str| -> Result<u64> {
+        s.parse::<u64>().context(format!(
+            "partition_range: invalid sector {s} of partition {}",
+            partition_info.num
+        ))
+    };
+    let start = sector(&partition_info.start)?;
+    let end = sector(&partition_info.end)?;
+
+    anyhow::ensure!(
+        start <= end,
+        "partition_range: invalid range of partition {}",
+        partition_info.num
+    );
+
+    Ok((start * 512, (end - start + 1) * 512))
+}
+
 fn write_partition(
     image_file: &str,
     partition_file: &str,
     partition_info: &PartitionInfo,
+    options: &FileOptions,
+) -> Result<()> {
+    options.ensure_writable(&format!(
+        "write partition {} into {image_file}",
+        partition_info.num
+    ))?;
+    check_partition(partition_file, partition_info, options)?;
+    commit_partition(image_file, partition_file, partition_info, options)
+}
+
+/// Checks the file system of a partition image before it is written back, if
+/// enabled via `FileOptions::fsck`.
+fn check_partition(
+    partition_file: &str,
+    partition_info: &PartitionInfo,
+    options: &FileOptions,
+) -> Result<()> {
+    if options.fsck && !partition_info.vfat {
+        fsck_partition(partition_file, options).context(format!(
+            "write_partition: file system of partition #{} is inconsistent",
+            partition_info.num
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Writes a partition image checked via `check_partition` back into the image.
+fn commit_partition(
+    image_file: &str,
+    partition_file: &str,
+    partition_info: &PartitionInfo,
+    options: &FileOptions,
 ) -> Result<()> {
-    let mut dd = Command::new("dd");
-    dd.arg(format!("if={partition_file}"))
-        .arg(format!("of={image_file}"))
-        .arg("bs=512")
-        .arg(format!("seek={}", partition_info.start))
-        .arg(format!("count={}", partition_info.end))
-        .arg("conv=notrunc,sparse")
-        .arg("status=none");
-    exec_cmd!(dd);
+    if partition_info.raw {
+        return Ok(());
+    }
+
+    let _lock = options
+        .write_partition_lock
+        .lock()
+        .map_err(|_| anyhow::anyhow!("write_partition: lock poisoned"))?;
+
+    let (offset, len) = partition_range(partition_info)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        let start = Instant::now();
+        let partition = fs::File::open(partition_file)
+            .context(format!("write_partition: cannot open {partition_file}"))?;
+        let image = fs::File::options()
+            .read(true)
+            .write(true)
+            .open(image_file)
+            .context(format!("write_partition: cannot open {image_file}"))?;
+
+        let changed = super::sparse::changed_sectors(&partition, 0, &image, offset, len, 512)?;
+        info!(
+            "write_partition: {changed} sectors ({} bytes) of partition #{} changed",
+            changed * 512,
+            partition_info.num
+        );
+        options
+            .change_manifest
+            .partition(&partition_info.num, changed, 512)?;
+
+        super::sparse::copy_sparse(&partition, 0, &image, offset, len, options.dd_block_size())?;
+        super::sparse::dig_holes(&image)?;
+        if options.sync_enabled() {
+            image
+                .sync_all()
+                .context("write_partition: cannot sync image")?;
+        }
+
+        options.audit_log.operation(
+            "write-partition",
+            serde_json::json!({
+                "image": image_file,
+                "partition": partition_info.num,
+                "changedSectors": changed,
+                "durationMs": start.elapsed().as_millis() as u64,
+            }),
+        )?;
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let bs = aligned_block_size(options.dd_block_size(), offset, len);
+        let mut dd = Command::new("dd");
+        dd.arg(format!("if={partition_file}"))
+            .arg(format!("of={image_file}"))
+            .arg(format!("bs={bs}"))
+            .arg(format!("seek={}", offset / bs))
+            .arg(format!("count={}", len / bs))
+            .arg("conv=notrunc,sparse")
+            .arg("status=none");
+        exec_cmd!(dd, options);
+
+        let mut fallocate = Command::new("fallocate");
+        fallocate.arg("-d").arg(image_file);
+        exec_cmd!(fallocate, options);
+
+        if options.sync_enabled() {
+            let mut sync = Command::new("sync");
+            exec_cmd!(sync, options);
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks an ext file system without modifying it; fails if e2fsck finds errors.
+fn fsck_partition(partition_file: &str, options: &FileOptions) -> Result<()> {
+    let mut e2fsck = Command::new("e2fsck");
+    e2fsck.arg("-f").arg("-n").arg(partition_file);
+    exec_cmd!(e2fsck, options);
+
+    Ok(())
+}
+
+/// Checks whether the file system of `dir` supports sparse files by punching
+/// holes into a zero filled file the same way `write_partition` does.
+/// Returns the result and the detected file system type.
+pub fn check_sparse_support(dir: &Path, options: &FileOptions) -> Result<(bool, String)> {
+    let mut stat = Command::new("stat");
+    stat.arg("-f").arg("-c").arg("%T").arg(dir);
+    let fs_type = exec_cmd_with_output!(stat, options);
+
+    let probe = tempfile::NamedTempFile::new_in(dir)
+        .context("check_sparse_support: cannot create probe file")?;
+    fs::write(probe.path(), vec![0u8; SPARSE_PROBE_SIZE])
+        .context("check_sparse_support: cannot write probe file")?;
 
     let mut fallocate = Command::new("fallocate");
-    fallocate.arg("-d").arg(image_file);
-    exec_cmd!(fallocate);
+    fallocate.arg("-d").arg(probe.path());
+
+    let start = Instant::now();
+    let status = fallocate.status().context(format!(
+        "check_sparse_support: status failed: {fallocate:?}"
+    ))?;
+    options
+        .audit_log
+        .command("check_sparse_support", &fallocate, &status, start.elapsed())?;
+    let punched = status.success();
+
+    let allocated = fs::metadata(probe.path())
+        .context("check_sparse_support: cannot get metadata of probe file")?
+        .blocks()
+        * 512;
+
+    debug!("check_sparse_support: {fs_type}: punched: {punched}, allocated: {allocated}");
+
+    Ok((punched && allocated < SPARSE_PROBE_SIZE as u64, fs_type))
+}
+
+/// Returns the space in bytes available to unprivileged users in the file
+/// system of `dir`.
+pub fn available_space(dir: &Path, options: &FileOptions) -> Result<u64> {
+    let mut stat = Command::new("stat");
+    stat.arg("-f").arg("-c").arg("%a %S").arg(dir);
+    let out = exec_cmd_with_output!(stat, options);
+
+    out.split_whitespace()
+        .map(str::parse::<u64>)
+        .collect::<Result<Vec<_>, _>>()
+        .ok()
+        .and_then(|v| match v[..] {
+            [blocks, block_size] => Some(blocks * block_size),
+            _ => None,
+        })
+        .context(format!("available_space: unexpected output of stat: {out}"))
+}
+
+/// Creates `{image_file}.bmap`. Returns `false` if bmaptool isn't installed.
+pub fn generate_bmap_file(image_file: &str, options: &FileOptions) -> Result<bool> {
+    match bmaptool_create(image_file, options) {
+        Ok(()) => Ok(true),
+        Err(e)
+            if e.chain().any(|e| {
+                e.downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+            }) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns whether `file` is a tar archive according to libmagic.
+pub fn is_tar_archive(file: &Path) -> Result<bool> {
+    let magic = super::compression::magic(file)?;
+
+    Ok(magic.starts_with("POSIX tar archive") || magic.starts_with("tar archive"))
+}
+
+/// Returns whether `file` starts with an MBR or a protective MBR of a GPT, i.e.
+/// is a partitioned image rather than e.g. compressed data.
+pub fn has_partition_table(file: &Path) -> Result<bool> {
+    use std::io::Read;
+
+    let mut sector = vec![];
+    fs::File::open(file)
+        .context(format!(
+            "has_partition_table: cannot open {}",
+            file.to_string_lossy()
+        ))?
+        .take(512)
+        .read_to_end(&mut sector)
+        .context("has_partition_table: cannot read first sector")?;
+
+    Ok(is_partition_table(&sector))
+}
 
-    let mut sync = Command::new("sync");
-    exec_cmd!(sync);
+// the boot signature alone is also found in boot sectors of file systems, e.g.
+// vfat, so the four partition entries have to be plausible and not all empty
+fn is_partition_table(sector: &[u8]) -> bool {
+    if sector.len() < 512 || sector[510..512] != [0x55, 0xaa] {
+        return false;
+    }
+
+    let entries: Vec<&[u8]> = sector[446..510].chunks(16).collect();
+
+    entries.iter().all(|e| e[0] == 0x00 || e[0] == 0x80) && entries.iter().any(|e| e[4] != 0)
+}
+
+/// Extracts all members of the tar `archive` into `dir`. Returns the path of
+/// its single `.wic` member and the names of all members in archive order, as
+/// needed by `repack_tar`.
+pub fn extract_tar(
+    archive: &Path,
+    dir: &Path,
+    options: &FileOptions,
+) -> Result<(PathBuf, Vec<String>)> {
+    let mut tar = Command::new("tar");
+    tar.arg("--list").arg("--file").arg(archive);
+    let members: Vec<String> = exec_cmd_with_output!(tar, options)
+        .lines()
+        .map(str::to_string)
+        .collect();
+
+    let wic = wic_member(&members)?.to_string();
+
+    let mut tar = Command::new("tar");
+    tar.arg("--extract")
+        .arg("--file")
+        .arg(archive)
+        .arg("--directory")
+        .arg(dir);
+    exec_cmd!(tar, options);
+
+    Ok((dir.join(wic), members))
+}
+
+/// Recreates the tar `archive` from `members` extracted into `dir` by
+/// `extract_tar`, keeping their order.
+pub fn repack_tar(
+    dir: &Path,
+    members: &[String],
+    archive: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    let mut tar = Command::new("tar");
+    tar.arg("--create")
+        .arg("--sparse")
+        .arg("--no-recursion")
+        .arg("--file")
+        .arg(archive)
+        .arg("--directory")
+        .arg(dir)
+        .args(members);
+    exec_cmd!(tar, options);
 
     Ok(())
 }
 
-pub fn generate_bmap_file(image_file: &str) -> Result<()> {
+fn wic_member(members: &[String]) -> Result<&str> {
+    let wics: Vec<&str> = members
+        .iter()
+        .map(String::as_str)
+        .filter(|m| m.ends_with(".wic"))
+        .collect();
+
+    match wics[..] {
+        [wic] => Ok(wic),
+        [] => anyhow::bail!("wic_member: tar archive doesn't contain a .wic image"),
+        _ => anyhow::bail!(
+            "wic_member: tar archive contains multiple .wic images: {}",
+            wics.join(", ")
+        ),
+    }
+}
+
+fn bmaptool_create(image_file: &str, options: &FileOptions) -> Result<()> {
     let mut bmaptool = Command::new("bmaptool");
     bmaptool
         .arg("create")
         .arg("-o")
         .arg(format!("{image_file}.bmap"))
         .arg(image_file);
-    exec_cmd!(bmaptool);
+    exec_cmd!(bmaptool, options);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fstab_mountpoints() {
+        let fstab = "# <file system> <mount point> <type> <options>\n/dev/omnect/boot /boot vfat defaults 0 2\nPARTLABEL=data\t/mnt/data  ext4 defaults 0 2\n#/dev/sda9 /var ext4 defaults 0 2\n";
+
+        assert_eq!(
+            fstab_entry(fstab, Path::new("/boot")).unwrap(),
+            ("/dev/omnect/boot".to_string(), "vfat".to_string())
+        );
+        assert_eq!(
+            fstab_entry(fstab, Path::new("/mnt/data/")).unwrap(),
+            ("PARTLABEL=data".to_string(), "ext4".to_string())
+        );
+        assert!(fstab_entry(fstab, Path::new("/var")).is_err());
+
+        for (device, num) in [
+            ("/dev/mmcblk0p7", "7"),
+            ("/dev/sda7", "7"),
+            ("/dev/nvme0n1p12", "12"),
+        ] {
+            assert_eq!(&RE_DEVICE_NUM.captures(device).unwrap()[1], num);
+        }
+    }
+
+    #[test]
+    fn partition_mountpoint_from_str() {
+        assert_eq!(
+            Partition::from_str("/mnt/data").unwrap(),
+            Partition::mountpoint(PathBuf::from("/mnt/data"))
+        );
+        assert_eq!(Partition::from_str("rootA").unwrap(), Partition::rootA);
+        assert!(Partition::from_str("data").is_err());
+    }
+
+    #[test]
+    fn source_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let in_file = dir.path().join("ca.crt");
+        fs::write(&in_file, "cert").unwrap();
+
+        let sources = SourceCache::default();
+        let copy = sources.with_mtime(&in_file, 42, dir.path()).unwrap();
+
+        assert_eq!(sources.with_mtime(&in_file, 42, dir.path()).unwrap(), copy);
+        assert_ne!(sources.with_mtime(&in_file, 43, dir.path()).unwrap(), copy);
+        assert_eq!(get_mtime(&copy, false).unwrap(), 42);
+        assert_eq!(fs::read_to_string(&copy).unwrap(), "cert");
+
+        let sha256 = sources.sha256(&in_file).unwrap();
+        assert_eq!(sha256, file_sha256(&in_file).unwrap());
+
+        // cached for the run, even if the in-file changes meanwhile
+        fs::write(&in_file, "changed").unwrap();
+        assert_eq!(sources.sha256(&in_file).unwrap(), sha256);
+    }
+
+    #[test]
+    fn copy_tree_twice() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let destination = dir.path().join("destination");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/file"), "content").unwrap();
+        std::os::unix::fs::symlink("sub/file", source.join("link")).unwrap();
+
+        copy_tree(&source, &destination).unwrap();
+
+        // extracting into the same destination again replaces the symlink
+        fs::write(source.join("sub/file"), "changed").unwrap();
+        fs::remove_file(source.join("link")).unwrap();
+        std::os::unix::fs::symlink("sub", source.join("link")).unwrap();
+        copy_tree(&source, &destination).unwrap();
+
+        assert_eq!(
+            fs::read_link(destination.join("link")).unwrap(),
+            Path::new("sub")
+        );
+        assert_eq!(
+            fs::read_to_string(destination.join("sub/file")).unwrap(),
+            "changed"
+        );
+    }
+
+    #[test]
+    fn debugfs_quoting() {
+        assert_eq!(debugfs_quote("/etc/my file"), "\"/etc/my file\"");
+        assert_eq!(debugfs_quote("/etc/\"a\""), "\"/etc/\"\"a\"\"\"");
+    }
+
+    #[test]
+    fn exclude_globs() {
+        let matches = |glob: &str, path: &str| glob_to_regex(glob).unwrap().is_match(path);
+
+        assert!(matches(".git", ".git"));
+        assert!(matches(".git", "sub/.git"));
+        assert!(!matches(".git", "sub/.gitignore"));
+        assert!(matches("*.tmp", "a/b/c.tmp"));
+        assert!(!matches("*.tmp", "c.tmpl"));
+        assert!(matches("cache/*", "cache/file"));
+        assert!(!matches("cache/*", "sub/cache/file"));
+        assert!(matches("cache/**/*.o", "cache/a/b/c.o"));
+        assert!(matches("cache/**/*.o", "cache/c.o"));
+        assert!(matches("file?.[!a-c]", "file1.d"));
+        assert!(!matches("file?.[!a-c]", "file1.b"));
+        assert!(matches("a+b(c)", "a+b(c)"));
+        assert!(glob_to_regex("[abc").is_err());
+        assert!(glob_to_regex("/").is_err());
+    }
+
+    #[test]
+    fn expand_dirs_excludes() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["a", "sub/b", "sub/b.tmp", ".git/config"] {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, file).unwrap();
+        }
+        let file = dir.path().join("a");
+
+        let params = [
+            FileCopyToParams::new(dir.path(), Partition::rootA, Path::new("/opt/tree")),
+            FileCopyToParams::new(&file, Partition::rootA, Path::new("/opt/a.tmp")),
+        ];
+        let excludes = [
+            glob_to_regex(".git").unwrap(),
+            glob_to_regex("*.tmp").unwrap(),
+        ];
+
+        let expanded: Vec<(PathBuf, PathBuf)> = expand_dirs(&params, &excludes)
+            .unwrap()
+            .into_iter()
+            .map(|p| (p.in_file, p.out_file))
+            .collect();
+
+        // explicitly given in-files aren't excluded
+        assert_eq!(
+            expanded,
+            vec![
+                (dir.path().join("a"), PathBuf::from("/opt/tree/a")),
+                (dir.path().join("sub/b"), PathBuf::from("/opt/tree/sub/b")),
+                (file, PathBuf::from("/opt/a.tmp")),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_destinations() {
+        let a = FileCopyToParams::new(
+            Path::new("/a/file"),
+            Partition::factory,
+            Path::new("/etc/file"),
+        );
+        let b = FileCopyToParams::new(
+            Path::new("/b/file"),
+            Partition::factory,
+            Path::new("/etc//./file"),
+        );
+        let c = FileCopyToParams::new(Path::new("/c/file"), Partition::factory, Path::new("/etc/"));
+        let d = FileCopyToParams::new(
+            Path::new("/d/file"),
+            Partition::factory,
+            Path::new("/etc/other"),
+        );
+
+        assert!(check_duplicate_destinations(&[&a, &d], false).is_ok());
+        assert!(check_duplicate_destinations(&[&a, &b], false).is_err());
+        assert!(check_duplicate_destinations(&[&a, &c], false).is_err());
+        assert!(check_duplicate_destinations(&[&a, &b], true).is_ok());
+    }
+
+    #[test]
+    fn partition_uuid_from_str() {
+        assert_eq!(
+            Partition::from_str("UUID=1234-ABCD").unwrap(),
+            Partition::uuid("1234-ABCD".to_string())
+        );
+        assert!(Partition::from_str("UUID=").is_err());
+    }
+
+    #[test]
+    fn copy_params_quoted() {
+        let dir = tempfile::tempdir().unwrap();
+        let in_file = dir.path().join("my,file:1");
+        fs::write(&in_file, "").unwrap();
+
+        let params = FileCopyToParams::from_str(&format!(
+            "\"{}\",factory:\"/etc/a,b:c\"",
+            in_file.to_str().unwrap()
+        ))
+        .unwrap();
+        assert_eq!(params.in_file, in_file);
+        assert_eq!(params.partition, Partition::factory);
+        assert_eq!(params.out_file, Path::new("/etc/a,b:c"));
+        assert!(params.check_in_file().is_ok());
+
+        // missing in-files are only rejected on copying, e.g. to be skipped
+        let params = FileCopyToParams::from_str("/missing/file,rootA:/etc/file").unwrap();
+        assert!(params.check_in_file().is_err());
+        assert!(FileCopyToParams::from_str("/missing/file,rootA:etc/file").is_err());
+
+        let params = FileCopyFromParams::from_str("rootA:\"/etc/a:b\",\"out,file\"").unwrap();
+        assert_eq!(params.partition, Partition::rootA);
+        assert_eq!(params.in_file, Path::new("/etc/a:b"));
+        assert_eq!(params.out_file, Path::new("out,file"));
+
+        // quotes not enclosing a whole field are part of the path
+        let params = FileCopyFromParams::from_str("rootA:/etc/my\"file,\"out \"1\" 2\"").unwrap();
+        assert_eq!(params.in_file, Path::new("/etc/my\"file"));
+        assert_eq!(params.out_file, Path::new("out \"1\" 2"));
+
+        // unquoted delimiters within paths are rejected
+        assert!(FileCopyFromParams::from_str("rootA:/etc/a:b,out").is_err());
+        assert!(FileCopyFromParams::from_str("rootA:/etc/a,out,file").is_err());
+        assert!(FileCopyFromParams::from_str("rootA,/etc/a:out").is_err());
+        assert!(FileCopyFromParams::from_str("rootA:\"/etc/a,out").is_err());
+        assert!(FileCopyFromParams::from_str("rootA:/etc/a").is_err());
+    }
+
+    #[test]
+    fn vfat_dirs_in_order() {
+        let mut created = HashSet::new();
+
+        assert_eq!(
+            vfat_dirs(Path::new("/a/b/c"), &mut created),
+            [
+                PathBuf::from("/a"),
+                PathBuf::from("/a/b"),
+                PathBuf::from("/a/b/c")
+            ]
+        );
+        // existing parents aren't created again
+        assert_eq!(
+            vfat_dirs(Path::new("/a/b/d/"), &mut created),
+            [PathBuf::from("/a/b/d")]
+        );
+        assert!(vfat_dirs(Path::new("/a/b/c"), &mut created).is_empty());
+        // nothing to create for files at the root
+        assert!(vfat_dirs(Path::new("/"), &mut HashSet::new()).is_empty());
+        assert_eq!(
+            vfat_dirs(Path::new("//x/./y"), &mut HashSet::new()),
+            [PathBuf::from("/x"), PathBuf::from("/x/y")]
+        );
+    }
+
+    #[test]
+    fn copy_params_for_partitions() {
+        let dir = tempfile::tempdir().unwrap();
+        let in_file = dir.path().join("ca.crt");
+        fs::write(&in_file, "").unwrap();
+        let out_file = Path::new("/etc/ssl/ca.crt");
+
+        let params = FileCopyToParams::for_partitions(
+            &in_file,
+            &[Partition::rootA, Partition::cert],
+            out_file,
+        )
+        .unwrap();
+        assert_eq!(
+            params
+                .iter()
+                .map(|p| (&p.in_file, &p.partition, &p.out_file))
+                .collect::<Vec<_>>(),
+            [
+                (&in_file, &Partition::rootA, &out_file.to_path_buf()),
+                (&in_file, &Partition::cert, &out_file.to_path_buf()),
+            ]
+        );
+
+        assert!(FileCopyToParams::for_partitions(&in_file, &[], out_file).is_err());
+        assert!(FileCopyToParams::for_partitions(
+            &in_file,
+            &[Partition::cert, Partition::rootA, Partition::cert],
+            out_file
+        )
+        .is_err());
+        assert!(FileCopyToParams::for_partitions(
+            &in_file,
+            &[Partition::rootA],
+            Path::new("etc/ca.crt")
+        )
+        .is_err());
+
+        // missing in-files are only rejected on copying, e.g. to be skipped
+        let params = FileCopyToParams::for_partitions(
+            &dir.path().join("missing"),
+            &[Partition::rootA],
+            out_file,
+        )
+        .unwrap();
+        assert!(params[0].check_in_file().is_err());
+    }
+
+    #[test]
+    fn parse_size_ok() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64K").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("10m").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_size("1G").unwrap(), 1024 * 1024 * 1024);
+    }
+
+    #[test]
+    fn parse_size_invalid() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("-1K").is_err());
+        assert!(parse_size("99999999999999G").is_err());
+    }
+
+    #[test]
+    fn parse_dd_block_sizes() {
+        assert_eq!(parse_dd_block_size("4M").unwrap(), 4 * 1024 * 1024);
+        assert_eq!(parse_dd_block_size("512").unwrap(), 512);
+        assert!(parse_dd_block_size("0").is_err());
+        assert!(parse_dd_block_size("1000").is_err());
+        assert!(parse_dd_block_size("128M").is_err());
+    }
+
+    #[test]
+    fn parse_owner_numeric() {
+        assert_eq!(parse_owner("1000:1001").unwrap(), (1000, 1001));
+        assert_eq!(parse_owner("0:0").unwrap(), (0, 0));
+        assert!(parse_owner("1000").is_err());
+        assert!(parse_owner("omnect:omnect").is_err());
+        assert!(parse_owner("1000:").is_err());
+        assert!(parse_owner("-1:0").is_err());
+        assert!(parse_owner("1:2:3").is_err());
+    }
+
+    #[test]
+    fn partition_path() {
+        assert_eq!(
+            parse_partition_path("factory:/etc/a").unwrap(),
+            (Partition::factory, PathBuf::from("/etc/a"))
+        );
+        assert_eq!(
+            parse_partition_path("/data:\"/a:b\"").unwrap(),
+            (
+                Partition::mountpoint(PathBuf::from("/data")),
+                PathBuf::from("/a:b")
+            )
+        );
+        assert!(parse_partition_path("factory").is_err());
+        assert!(parse_partition_path("factory:etc/a").is_err());
+        assert!(parse_partition_path("home:/a").is_err());
+    }
+
+    #[test]
+    fn parse_mtime_ok() {
+        assert_eq!(parse_mtime("@1700000000").unwrap(), 1700000000);
+        assert_eq!(parse_mtime("2023-11-14T22:13:20Z").unwrap(), 1700000000);
+        assert_eq!(
+            parse_mtime("2023-11-14T23:13:20+01:00").unwrap(),
+            1700000000
+        );
+    }
+
+    #[test]
+    fn parse_mtime_invalid() {
+        assert!(parse_mtime("@-1").is_err());
+        assert!(parse_mtime("2023-11-14").is_err());
+        assert!(parse_mtime("1969-12-31T23:59:59Z").is_err());
+    }
+
+    #[test]
+    fn dd_aligned_block_size() {
+        let mib = 1024 * 1024;
+
+        assert_eq!(aligned_block_size(mib, 8 * mib, 64 * mib), mib);
+        assert_eq!(aligned_block_size(4 * mib, 8 * mib, 64 * mib), 4 * mib);
+        // offset of 2048 sectors + 1 sector
+        assert_eq!(aligned_block_size(mib, mib + 512, 64 * mib), 512);
+        assert_eq!(aligned_block_size(mib, 8 * mib, 64 * mib + 4096), 4096);
+
+        // skip/seek and count address the same bytes for all block sizes
+        for (offset, len) in [(8 * mib, 64 * mib), (mib + 512, 3 * mib), (4096, 2 * mib)] {
+            for block_size in [512, 4096, mib, 4 * mib] {
+                let bs = aligned_block_size(block_size, offset, len);
+                assert_eq!((offset / bs * bs, len / bs * bs), (offset, len));
+            }
+        }
+    }
+
+    #[test]
+    fn parse_sha256_hex() {
+        let sha256 = "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855";
+
+        assert_eq!(parse_sha256(sha256).unwrap(), sha256.to_lowercase());
+        assert!(parse_sha256(&sha256[1..]).is_err());
+        assert!(parse_sha256(&sha256.replace('E', "g")).is_err());
+    }
+
+    #[test]
+    fn parse_https_urls() {
+        let url = parse_https_url("https://est.example.com:8443/.well-known/est").unwrap();
+
+        assert_eq!(url.host_str(), Some("est.example.com"));
+        assert!(parse_https_url("http://est.example.com/.well-known/est").is_err());
+        assert!(parse_https_url("https://:8443/.well-known/est").is_err());
+        assert!(parse_https_url("est.example.com").is_err());
+    }
+
+    #[test]
+    fn partition_table_detection() {
+        let mut sector = vec![0u8; 512];
+        sector[510] = 0x55;
+        sector[511] = 0xaa;
+
+        // no partition entries
+        assert!(!is_partition_table(&sector));
+
+        // protective MBR of a GPT
+        sector[446 + 4] = 0xee;
+        assert!(is_partition_table(&sector));
+        assert!(!is_partition_table(&sector[..511]));
+
+        // implausible boot flag, e.g. boot code of a file system
+        sector[446 + 16] = 0x12;
+        assert!(!is_partition_table(&sector));
+        sector[446 + 16] = 0x80;
+        assert!(is_partition_table(&sector));
+
+        sector[511] = 0;
+        assert!(!is_partition_table(&sector));
+    }
+
+    #[test]
+    fn tar_wic_member() {
+        let members = |m: &[&str]| m.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            wic_member(&members(&[
+                "./",
+                "./README",
+                "./image.wic",
+                "./image.wic.bmap"
+            ]))
+            .unwrap(),
+            "./image.wic"
+        );
+        assert!(wic_member(&members(&["README", "image.wic.xz"])).is_err());
+        assert!(wic_member(&members(&["a.wic", "b/b.wic"]))
+            .unwrap_err()
+            .to_string()
+            .contains("a.wic, b/b.wic"));
+    }
+
+    #[test]
+    fn partition_fs_mismatch() {
+        assert_eq!(fs_mismatch(&Partition::boot, true, Some("vfat")), None);
+        assert_eq!(fs_mismatch(&Partition::factory, false, Some("ext4")), None);
+        assert_eq!(
+            fs_mismatch(&Partition::boot, true, Some("ext4")).unwrap(),
+            "partition 'boot' is ext4, not FAT; expected FAT for mtools"
+        );
+        assert_eq!(
+            fs_mismatch(&Partition::factory, false, Some("vfat")).unwrap(),
+            "partition 'factory' is vfat, not ext; expected ext for e2tools"
+        );
+        assert!(fs_mismatch(&Partition::cert, false, None)
+            .unwrap()
+            .contains("no known file system"));
+    }
+}
@@ -1,16 +1,68 @@
 pub mod compression;
 pub mod functions;
+pub mod partition_table;
+#[cfg(target_os = "linux")]
+mod sparse;
+#[cfg(target_os = "linux")]
+mod xattr;
 use super::validators::{
-    device_update,
-    identity::{validate_identity, IdentityConfig, IdentityType},
-    ssh::validate_ssh_pub_key,
+    self, device_update,
+    identity::{self, validate_identity, DeviceCertUsage, IdentityConfig, IdentityType},
+    ssh::{validate_authorized_key, validate_ssh_pub_key},
 };
-use crate::file::functions::{FileCopyFromParams, FileCopyToParams, Partition};
+use crate::file::functions::{FileCopyFromParams, FileCopyToParams, FileOptions, Partition};
 use anyhow::{Context, Result};
-use log::warn;
+use log::{debug, info, warn};
 use regex::Regex;
 use std::fs;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const DU_CONFIG_PATH: &str = "/etc/adu/du-config.json";
+const IDENTITY_CONFIG_PATH: &str = "/etc/aziot/config.toml";
+// keys of the identity config holding secrets, replaced when redacting
+const IDENTITY_SECRET_KEYS: [&str; 4] = [
+    "connection_string",
+    "device_id_pk",
+    "password",
+    "symmetric_key",
+];
+const REDACTED: &str = "<redacted>";
+const DPS_GLOBAL_ENDPOINT: &str = "https://global.azure-devices-provisioning.net";
+const DEVICE_CERT_URI: &str = "file:///mnt/cert/priv/device_id_cert.pem";
+const DEVICE_KEY_URI: &str = "file:///mnt/cert/priv/device_id_cert_key.pem";
+// CA of the intermediate full chain, trusted by the EST configs of omnect-os
+const EST_DEFAULT_TRUSTED_CERT_URI: &str = "file:///mnt/cert/ca/ca.crt";
+const EST_TRUSTED_CA_PATH: &str = "/ca/est_ca.crt";
+const EST_TRUSTED_CA_URI: &str = "file:///mnt/cert/ca/est_ca.crt";
+// keys only valid for provisioning source "manual"
+const MANUAL_PROVISIONING_KEYS: [&str; 4] = [
+    "authentication",
+    "connection_string",
+    "device_id",
+    "iothub_hostname",
+];
+const BUILD_INFO_PATH: &str = "/etc/omnect/build-info";
+const PASSWD_PATH: &str = "/etc/passwd";
+const LABEL_MAX_LEN: usize = 128;
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+const SYSTEMD_SYSTEM_DIR: &str = "/etc/systemd/system";
+const SYSTEMD_NETWORK_DIR: &str = "/etc/systemd/network";
+// searched in this order for unit files, as by systemd
+const SYSTEMD_UNIT_DIRS: [&str; 3] = [
+    "/etc/systemd/system",
+    "/usr/lib/systemd/system",
+    "/lib/systemd/system",
+];
+const SYSTEMD_UNIT_TYPES: [&str; 5] = ["service", "socket", "timer", "path", "target"];
+
+lazy_static! {
+    // POSIX shell identifier
+    static ref RE_ENV_KEY: Regex = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+    // printable ASCII except characters that need escaping when quoted
+    static ref RE_LABEL: Regex = Regex::new(r#"^[ -~&&[^"\\$`]]+$"#).unwrap();
+}
 
 pub fn set_iotedge_gateway_config(
     config_file: &Path,
@@ -18,12 +70,20 @@ pub fn set_iotedge_gateway_config(
     root_ca_file: &Path,
     edge_device_identity_full_chain_file: &Path,
     edge_device_identity_key_file: &Path,
+    options: &FileOptions,
 ) -> Result<()> {
+    ensure_partitions(
+        image_file,
+        &[Partition::factory, Partition::cert],
+        "set iotedge gateway config",
+        options,
+    )?;
+
     validate_identity(IdentityType::Gateway, config_file, &None)?
         .iter()
         .for_each(|x| warn!("{}", x));
 
-    let mut file_copies = configure_hostname(config_file, image_file)?;
+    let mut file_copies = configure_hostname(config_file, image_file, options)?;
     file_copies.append(&mut vec![
         FileCopyToParams::new(
             config_file,
@@ -47,14 +107,22 @@ pub fn set_iotedge_gateway_config(
         ),
     ]);
 
-    copy_to_image(&file_copies, image_file)
+    copy_to_image(&file_copies, image_file, options)
 }
 
 pub fn set_iot_leaf_sas_config(
     config_file: &Path,
     image_file: &Path,
     root_ca_file: &Path,
+    options: &FileOptions,
 ) -> Result<()> {
+    ensure_partitions(
+        image_file,
+        &[Partition::factory, Partition::cert],
+        "set iot leaf sas config",
+        options,
+    )?;
+
     validate_identity(IdentityType::Leaf, config_file, &None)?
         .iter()
         .for_each(|x| warn!("{}", x));
@@ -63,7 +131,7 @@ pub fn set_iot_leaf_sas_config(
     root_ca_out_file.push(root_ca_file.file_name().unwrap());
     root_ca_out_file.set_extension("crt");
 
-    let mut file_copies = configure_hostname(config_file, image_file)?;
+    let mut file_copies = configure_hostname(config_file, image_file, options)?;
     file_copies.append(&mut vec![
         FileCopyToParams::new(
             config_file,
@@ -73,10 +141,21 @@ pub fn set_iot_leaf_sas_config(
         FileCopyToParams::new(root_ca_file, Partition::cert, &root_ca_out_file),
     ]);
 
-    copy_to_image(&file_copies, image_file)
+    copy_to_image(&file_copies, image_file, options)
 }
 
-pub fn set_ssh_tunnel_certificate(image_file: &Path, root_ca_file: &Path) -> Result<()> {
+pub fn set_ssh_tunnel_certificate(
+    image_file: &Path,
+    root_ca_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    ensure_partitions(
+        image_file,
+        &[Partition::cert],
+        "set ssh tunnel certificate",
+        options,
+    )?;
+
     validate_ssh_pub_key(root_ca_file)?;
 
     copy_to_image(
@@ -86,19 +165,143 @@ pub fn set_ssh_tunnel_certificate(image_file: &Path, root_ca_file: &Path) -> Res
             Path::new("/ssh/root_ca"),
         )],
         image_file,
+        options,
+    )
+}
+
+/// Writes `keys` to `~/.ssh/authorized_keys` of `user` in rootA, replacing
+/// existing keys. A key given as "@<path>" is read from the file, which may
+/// contain several keys. File (mode 0600) and created directories are owned
+/// by `user` as configured in /etc/passwd of rootA.
+pub fn set_authorized_keys(
+    image_file: &Path,
+    user: &str,
+    keys: &[String],
+    options: &FileOptions,
+) -> Result<()> {
+    ensure_partitions(
+        image_file,
+        &[Partition::rootA],
+        "set authorized keys",
+        options,
+    )?;
+
+    let mut lines = vec![];
+    for key in keys {
+        let content = match key.strip_prefix('@') {
+            Some(path) => fs::read_to_string(path)
+                .context(format!("set_authorized_keys: cannot read key file {path}"))?,
+            None => key.to_string(),
+        };
+
+        lines.extend(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(str::to_string),
+        );
+    }
+
+    anyhow::ensure!(!lines.is_empty(), "set_authorized_keys: no keys given");
+
+    for line in &lines {
+        validate_authorized_key(line)?;
+    }
+
+    let passwd =
+        functions::read_file_from_image(PASSWD_PATH, Partition::rootA, image_file, options)
+            .context("set_authorized_keys: cannot read /etc/passwd from rootA")?;
+    let (uid, gid, home) = passwd_entry(&passwd, user)?;
+
+    let authorized_keys = get_file_path(image_file, "authorized_keys")?;
+    fs::write(&authorized_keys, format!("{}\n", lines.join("\n")))
+        .context("set_authorized_keys: cannot write authorized_keys")?;
+
+    copy_to_image(
+        &[FileCopyToParams::new(
+            &authorized_keys,
+            Partition::rootA,
+            &home.join(".ssh/authorized_keys"),
+        )
+        .with_mode(0o600)
+        .with_owner(uid, gid)],
+        image_file,
+        options,
     )
 }
 
+/// Returns uid, gid and home directory of `user` in the passwd file `content`.
+fn passwd_entry(content: &str, user: &str) -> Result<(u32, u32, PathBuf)> {
+    let fields: Vec<&str> = content
+        .lines()
+        .map(|l| l.split(':').collect::<Vec<_>>())
+        .find(|f| f[0] == user)
+        .context(format!("passwd_entry: unknown user {user}"))?;
+
+    anyhow::ensure!(
+        fields.len() == 7,
+        "passwd_entry: invalid passwd entry of {user}"
+    );
+
+    let home = PathBuf::from(fields[5]);
+    anyhow::ensure!(
+        home.is_absolute(),
+        "passwd_entry: home of {user} isn't an absolute path"
+    );
+
+    Ok((
+        fields[2]
+            .parse()
+            .context(format!("passwd_entry: invalid uid of {user}"))?,
+        fields[3]
+            .parse()
+            .context(format!("passwd_entry: invalid gid of {user}"))?,
+        home,
+    ))
+}
+
 pub fn set_identity_config(
     config_file: &Path,
     image_file: &Path,
     payload: Option<&Path>,
+    merge: bool,
+    options: &FileOptions,
 ) -> Result<()> {
+    ensure_partitions(
+        image_file,
+        &[Partition::factory],
+        "set identity config",
+        options,
+    )?;
+
+    let merged_file;
+    let config_file = if merge {
+        let base = functions::read_file_from_image(
+            IDENTITY_CONFIG_PATH,
+            Partition::factory,
+            image_file,
+            options,
+        )
+        .context("set_identity_config: cannot read config.toml from image to merge with")?;
+        let overlay = fs::read_to_string(config_file).context(format!(
+            "set_identity_config: cannot read {}",
+            config_file.to_string_lossy()
+        ))?;
+
+        merged_file = get_file_path(image_file, "config.toml")?;
+        fs::write(&merged_file, merge_toml(&base, &overlay)?)
+            .context("set_identity_config: cannot write merged config file")?;
+        merged_file.as_path()
+    } else {
+        config_file
+    };
+
     validate_identity(IdentityType::Standalone, config_file, &payload)?
         .iter()
         .for_each(|x| warn!("{}", x));
 
-    let mut file_copies = configure_hostname(config_file, image_file)?;
+    let mut file_copies = configure_hostname(config_file, image_file, options)?;
     file_copies.append(&mut vec![FileCopyToParams::new(
         config_file,
         Partition::factory,
@@ -112,7 +315,362 @@ pub fn set_identity_config(
             Path::new("/etc/omnect/dps-payload.json"),
         ));
     }
-    copy_to_image(&file_copies, image_file)
+    copy_to_image(&file_copies, image_file, options)
+}
+
+/// Reads the config.toml of the image and returns it consistently formatted.
+/// If `redact` is set, the values of secrets (symmetric keys, connection
+/// strings, ...) are replaced by "<redacted>".
+pub fn show_identity_config(
+    image_file: &Path,
+    redact: bool,
+    options: &FileOptions,
+) -> Result<String> {
+    ensure_partitions(
+        image_file,
+        &[Partition::factory],
+        "show identity config",
+        options,
+    )?;
+
+    let content = functions::read_file_from_image(
+        IDENTITY_CONFIG_PATH,
+        Partition::factory,
+        image_file,
+        options,
+    )
+    .context("show_identity_config: cannot read config.toml from image")?;
+
+    format_identity_config(&content, redact)
+}
+
+/// Pretty-prints the identity config `content`, optionally redacting secrets.
+/// Comments of `content` are lost.
+fn format_identity_config(content: &str, redact: bool) -> Result<String> {
+    let mut config: toml::Table = toml::from_str(content)
+        .context("show_identity_config: cannot parse config.toml of image")?;
+
+    if redact {
+        redact_secrets(&mut config);
+    }
+
+    toml::to_string_pretty(&config).context("show_identity_config: cannot format config.toml")
+}
+
+fn redact_secrets(table: &mut toml::Table) {
+    for (key, value) in table.iter_mut() {
+        if IDENTITY_SECRET_KEYS.contains(&key.as_str()) {
+            *value = REDACTED.into();
+        } else if let Some(table) = value.as_table_mut() {
+            redact_secrets(table);
+        } else if let Some(array) = value.as_array_mut() {
+            array
+                .iter_mut()
+                .filter_map(toml::Value::as_table_mut)
+                .for_each(redact_secrets);
+        }
+    }
+}
+
+/// Attestation set via `set_provisioning`.
+pub enum DpsAttestation {
+    /// base64 encoded symmetric key
+    SymmetricKey(String),
+    /// device certificate and key as injected via `set-device-certificate-no-est`
+    X509,
+}
+
+/// How the device authenticates to the EST server.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Eq, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum EstAuth {
+    /// device certificate as bootstrap certificate, replaced by an identity certificate issued by the EST server
+    bootstrap,
+    /// device certificate as EST identity certificate
+    x509,
+}
+
+/// EST endpoint settings set via `set_est_config`.
+#[derive(Debug, Default)]
+pub struct EstConfig {
+    pub url: Option<url::Url>,
+    /// pem file of the CA trusted for the EST server
+    pub trusted_ca: Option<PathBuf>,
+    pub auth: Option<EstAuth>,
+}
+
+impl EstConfig {
+    pub fn is_empty(&self) -> bool {
+        self.url.is_none() && self.trusted_ca.is_none() && self.auth.is_none()
+    }
+}
+
+/// Sets the EST endpoint of `est` in the config.toml of the image, so that the
+/// device certificate of `device_id` can be renewed via EST. The trusted CA,
+/// expected to be validated, is injected into the cert partition. All other
+/// settings are left untouched.
+pub fn set_est_config(
+    est: &EstConfig,
+    device_id: &str,
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    ensure_partitions(
+        image_file,
+        &[Partition::factory, Partition::cert],
+        "set EST config",
+        options,
+    )?;
+
+    let content = functions::read_file_from_image(
+        IDENTITY_CONFIG_PATH,
+        Partition::factory,
+        image_file,
+        options,
+    )
+    .context("set_est_config: cannot read config.toml from image")?;
+
+    let config_file = get_file_path(image_file, "config.toml")?;
+    fs::write(&config_file, set_est_fields(&content, est, device_id)?)
+        .context("set_est_config: cannot write config file")?;
+
+    validate_identity(IdentityType::Standalone, &config_file, &None)?
+        .iter()
+        .for_each(|x| warn!("{}", x));
+
+    let mut copy_params = vec![FileCopyToParams::new(
+        &config_file,
+        Partition::factory,
+        Path::new(IDENTITY_CONFIG_PATH),
+    )];
+
+    if let Some(trusted_ca) = &est.trusted_ca {
+        copy_params.push(FileCopyToParams::new(
+            trusted_ca,
+            Partition::cert,
+            Path::new(EST_TRUSTED_CA_PATH),
+        ));
+    }
+
+    copy_to_image(&copy_params, image_file, options)
+}
+
+/// Sets the EST url, trusted CA and auth of `est` in the `[cert_issuance.est]`
+/// section of the identity config `content`, which is created with bootstrap
+/// auth if missing. An EST identity certificate requested for DPS attestation
+/// gets `device_id` as common name. Comments of `content` are lost.
+fn set_est_fields(content: &str, est: &EstConfig, device_id: &str) -> Result<String> {
+    let mut config: toml::Table =
+        toml::from_str(content).context("set_est_config: cannot parse config.toml of image")?;
+
+    let est_table = config
+        .entry("cert_issuance")
+        .or_insert_with(|| toml::Table::new().into())
+        .as_table_mut()
+        .context("set_est_config: cert_issuance isn't a table")?
+        .entry("est")
+        .or_insert_with(|| toml::Table::new().into())
+        .as_table_mut()
+        .context("set_est_config: cert_issuance.est isn't a table")?;
+
+    let urls = est_table
+        .entry("urls")
+        .or_insert_with(|| toml::Table::new().into())
+        .as_table_mut()
+        .context("set_est_config: cert_issuance.est.urls isn't a table")?;
+
+    if let Some(url) = &est.url {
+        urls.insert("default".into(), url.as_str().into());
+    }
+
+    anyhow::ensure!(
+        urls.contains_key("default"),
+        "set_est_config: no EST url configured in config.toml of image, set one via --est-url"
+    );
+
+    let trusted_certs = est_table
+        .entry("trusted_certs")
+        .or_insert_with(|| vec![toml::Value::from(EST_DEFAULT_TRUSTED_CERT_URI)].into())
+        .as_array_mut()
+        .context("set_est_config: cert_issuance.est.trusted_certs isn't an array")?;
+
+    if est.trusted_ca.is_some()
+        && !trusted_certs
+            .iter()
+            .any(|c| c.as_str() == Some(EST_TRUSTED_CA_URI))
+    {
+        trusted_certs.push(EST_TRUSTED_CA_URI.into());
+    }
+
+    let auth = match (est.auth, est_table.contains_key("auth")) {
+        (Some(auth), _) => Some(auth),
+        (None, false) => Some(EstAuth::bootstrap),
+        (None, true) => None,
+    };
+
+    let auth = auth.map(|auth| match auth {
+        EstAuth::bootstrap => toml::Table::from_iter([
+            ("bootstrap_identity_cert".into(), DEVICE_CERT_URI.into()),
+            ("bootstrap_identity_pk".into(), DEVICE_KEY_URI.into()),
+        ]),
+        EstAuth::x509 => toml::Table::from_iter([
+            ("identity_cert".into(), DEVICE_CERT_URI.into()),
+            ("identity_pk".into(), DEVICE_KEY_URI.into()),
+        ]),
+    });
+
+    if let Some(auth) = auth {
+        est_table.insert("auth".into(), auth.into());
+    }
+
+    // EST requires the common name of the requested certificate to match
+    if let Some(identity_cert) = config
+        .get_mut("provisioning")
+        .and_then(|p| p.get_mut("attestation"))
+        .and_then(|a| a.get_mut("identity_cert"))
+        .and_then(toml::Value::as_table_mut)
+    {
+        identity_cert.insert("common_name".into(), device_id.into());
+    }
+
+    toml::to_string(&config).context("set_est_config: cannot serialize config")
+}
+
+/// Sets dps provisioning in the config.toml of the image, leaving all other
+/// settings untouched.
+pub fn set_provisioning(
+    image_file: &Path,
+    id_scope: &str,
+    registration_id: &str,
+    attestation: Option<DpsAttestation>,
+    options: &FileOptions,
+) -> Result<()> {
+    ensure_partitions(
+        image_file,
+        &[Partition::factory],
+        "set provisioning",
+        options,
+    )?;
+
+    let content = functions::read_file_from_image(
+        IDENTITY_CONFIG_PATH,
+        Partition::factory,
+        image_file,
+        options,
+    )
+    .context("set_provisioning: cannot read config.toml from image")?;
+
+    let config_file = get_file_path(image_file, "config.toml")?;
+    fs::write(
+        &config_file,
+        set_provisioning_fields(&content, id_scope, registration_id, attestation.as_ref())?,
+    )
+    .context("set_provisioning: cannot write config file")?;
+
+    validate_identity(IdentityType::Standalone, &config_file, &None)?
+        .iter()
+        .for_each(|x| warn!("{}", x));
+
+    copy_to_image(
+        &[FileCopyToParams::new(
+            &config_file,
+            Partition::factory,
+            Path::new(IDENTITY_CONFIG_PATH),
+        )],
+        image_file,
+        options,
+    )
+}
+
+/// Sets source "dps", `id_scope` and `registration_id` in the provisioning
+/// section of the identity config `content`. The attestation is replaced if
+/// `attestation` is given, unless an x509 attestation is already configured,
+/// e.g. with EST. Comments of `content` are lost.
+fn set_provisioning_fields(
+    content: &str,
+    id_scope: &str,
+    registration_id: &str,
+    attestation: Option<&DpsAttestation>,
+) -> Result<String> {
+    anyhow::ensure!(
+        !id_scope.trim().is_empty(),
+        "set_provisioning: id scope must not be empty"
+    );
+    anyhow::ensure!(
+        !registration_id.trim().is_empty(),
+        "set_provisioning: registration id must not be empty"
+    );
+
+    if let Some(DpsAttestation::SymmetricKey(key)) = attestation {
+        anyhow::ensure!(
+            base64::decode(key).is_ok_and(|key| !key.is_empty()),
+            "set_provisioning: symmetric key isn't base64 encoded"
+        );
+    }
+
+    let mut config: toml::Table =
+        toml::from_str(content).context("set_provisioning: cannot parse config.toml of image")?;
+
+    let provisioning = config
+        .entry("provisioning")
+        .or_insert_with(|| toml::Table::new().into())
+        .as_table_mut()
+        .context("set_provisioning: provisioning isn't a table")?;
+
+    for key in MANUAL_PROVISIONING_KEYS {
+        provisioning.remove(key);
+    }
+    provisioning.insert("source".into(), "dps".into());
+    provisioning
+        .entry("global_endpoint")
+        .or_insert_with(|| DPS_GLOBAL_ENDPOINT.into());
+    provisioning.insert("id_scope".into(), id_scope.into());
+
+    let method = provisioning
+        .get("attestation")
+        .and_then(|a| a.get("method"))
+        .and_then(toml::Value::as_str)
+        .map(str::to_string);
+
+    let new_attestation = match (attestation, method.as_deref()) {
+        (Some(DpsAttestation::SymmetricKey(key)), _) => Some(toml::Table::from_iter([
+            ("method".into(), "symmetric_key".into()),
+            (
+                "symmetric_key".into(),
+                toml::Table::from_iter([("value".into(), key.as_str().into())]).into(),
+            ),
+        ])),
+        (Some(DpsAttestation::X509), Some("x509")) | (None, Some(_)) => None,
+        (Some(DpsAttestation::X509), _) => Some(toml::Table::from_iter([
+            ("method".into(), "x509".into()),
+            ("identity_cert".into(), DEVICE_CERT_URI.into()),
+            ("identity_pk".into(), DEVICE_KEY_URI.into()),
+        ])),
+        (None, None) => anyhow::bail!(
+            "set_provisioning: no attestation configured in config.toml of image, choose one"
+        ),
+    };
+
+    if let Some(new_attestation) = new_attestation {
+        provisioning.insert("attestation".into(), new_attestation.into());
+    }
+
+    let attestation = provisioning
+        .get_mut("attestation")
+        .and_then(toml::Value::as_table_mut)
+        .context("set_provisioning: attestation isn't a table")?;
+
+    attestation.insert("registration_id".into(), registration_id.into());
+
+    // EST requires the common name of the requested certificate to match
+    if let Some(identity_cert) = attestation
+        .get_mut("identity_cert")
+        .and_then(toml::Value::as_table_mut)
+    {
+        identity_cert.insert("common_name".into(), registration_id.into());
+    }
+
+    toml::to_string(&config).context("set_provisioning: cannot serialize config")
 }
 
 pub fn set_device_cert(
@@ -120,7 +678,20 @@ pub fn set_device_cert(
     device_cert_path: &Path,
     device_key_path: &Path,
     image_file: &Path,
+    options: &FileOptions,
 ) -> Result<()> {
+    ensure_partitions(
+        image_file,
+        &[Partition::cert],
+        "set device certificate",
+        options,
+    )?;
+
+    // keys openssl can't read are injected as before
+    if validators::certificate::is_ed25519_key(device_key_path).is_ok_and(|ed25519| ed25519) {
+        ensure_ed25519_supported(image_file, options)?;
+    }
+
     let mut copy_params = vec![
         FileCopyToParams::new(
             device_cert_path,
@@ -141,33 +712,731 @@ pub fn set_device_cert(
         ])
     }
 
-    copy_to_image(&copy_params, image_file)
+    copy_to_image(&copy_params, image_file, options)
 }
 
-pub fn set_iot_hub_device_update_config(du_config_file: &Path, image_file: &Path) -> Result<()> {
+/// Ensures that the identity config of the image can use an Ed25519 device
+/// key: Azure IoT Hub and DPS only accept RSA and ECDSA certificates for X.509
+/// attestation, whereas an EST server may accept an Ed25519 bootstrap
+/// certificate.
+fn ensure_ed25519_supported(image_file: &Path, options: &FileOptions) -> Result<()> {
+    // only a missing identity config leaves the check undone, read errors fail
+    let config = if functions::has_partition(image_file, &Partition::factory, options)? {
+        functions::read_file_from_image_if_exists(
+            IDENTITY_CONFIG_PATH,
+            Partition::factory,
+            image_file,
+            options,
+        )
+        .context("ensure_ed25519_supported: cannot read identity config")?
+    } else {
+        None
+    };
+
+    let Some(config) = config else {
+        warn!("image has no identity config, cannot check that its provisioning flow accepts an Ed25519 device key");
+        return Ok(());
+    };
+
+    match identity::device_cert_usage(&config, DEVICE_CERT_URI)? {
+        DeviceCertUsage::Identity => anyhow::bail!(
+            "Ed25519 device keys aren't supported by the identity config of the image: the device certificate is used for X.509 attestation with DPS, which like IoT Hub only accepts RSA and ECDSA certificates. Use '--key-type ec-p256' or configure certificate issuance via EST, where the device certificate only authenticates to the EST server."
+        ),
+        DeviceCertUsage::EstBootstrap => info!(
+            "Ed25519 device certificate is used as EST bootstrap certificate: the EST server must accept Ed25519 client certificates"
+        ),
+        DeviceCertUsage::Unused => {}
+    }
+
+    Ok(())
+}
+
+pub fn set_iot_hub_device_update_config(
+    du_config_file: &Path,
+    partition: Partition,
+    path: &Path,
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
     device_update::validate_config(du_config_file)?;
 
+    ensure_partitions(
+        image_file,
+        &[partition.clone()],
+        "set device update config",
+        options,
+    )?;
+
+    anyhow::ensure!(
+        path.is_absolute() && path.file_name().is_some(),
+        "set_iot_hub_device_update_config: invalid destination path {}",
+        path.to_string_lossy()
+    );
+
+    if partition != Partition::factory || path != Path::new(DU_CONFIG_PATH) {
+        warn!(
+            "device update config is stored to {partition}:{} instead of factory:{DU_CONFIG_PATH}",
+            path.to_string_lossy()
+        );
+    }
+
+    // copy_to_image creates the destination directory if it doesn't exist
+    copy_to_image(
+        &[FileCopyToParams::new(du_config_file, partition, path)],
+        image_file,
+        options,
+    )
+}
+
+pub fn set_iot_hub_device_update_config_from_components(
+    components: &device_update::DuConfigComponents,
+    partition: Partition,
+    path: &Path,
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    let config = device_update::config_from_components(components)?;
+
+    let du_config_file = get_file_path(image_file, "du-config.json")?;
+    fs::write(
+        &du_config_file,
+        serde_json::to_string_pretty(&config)
+            .context("set_iot_hub_device_update_config: cannot serialize config")?,
+    )
+    .context("set_iot_hub_device_update_config: cannot write du-config.json")?;
+
+    set_iot_hub_device_update_config(&du_config_file, partition, path, image_file, options)
+}
+
+pub fn set_env(env_vars: &[EnvVar], image_file: &Path, options: &FileOptions) -> Result<()> {
+    ensure_partitions(
+        image_file,
+        &[Partition::factory],
+        "set environment",
+        options,
+    )?;
+
+    let env_file = get_file_path(image_file, "environment")?;
+
+    // prefer the factory overlay, fall back to rootA and finally to an empty file
+    let mut content = None;
+    for partition in [Partition::factory, Partition::rootA] {
+        content = functions::read_file_from_image_if_exists(
+            "/etc/environment",
+            partition,
+            image_file,
+            options,
+        )
+        .context("set_env: cannot read /etc/environment")?;
+
+        if content.is_some() {
+            break;
+        }
+    }
+    let content = content.unwrap_or_else(|| {
+        debug!("set_env: no /etc/environment found in image");
+        String::new()
+    });
+
+    fs::write(&env_file, merge_env(&content, env_vars))
+        .context("set_env: cannot write to environment file")?;
+
+    copy_to_image(
+        &[FileCopyToParams::new(
+            &env_file,
+            Partition::factory,
+            Path::new("/etc/environment"),
+        )],
+        image_file,
+        options,
+    )
+}
+
+/// Copies the systemd-networkd config files `config_files`, e.g. for a static
+/// ip or a vlan, to /etc/systemd/network of rootA, keeping their names. Files
+/// of the image with the same name are replaced.
+pub fn set_network_config(
+    config_files: &[PathBuf],
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    let mut names = std::collections::HashSet::new();
+
+    for config_file in config_files {
+        validators::network::validate_network_config(config_file)?;
+
+        let name = config_file
+            .file_name()
+            .context("set_network_config: invalid config file path")?;
+
+        anyhow::ensure!(
+            names.insert(name),
+            "set_network_config: {} is given multiple times",
+            name.to_string_lossy()
+        );
+    }
+
+    copy_to_image(
+        &config_files
+            .iter()
+            .map(|config_file| {
+                FileCopyToParams::new(
+                    config_file,
+                    Partition::rootA,
+                    &Path::new(SYSTEMD_NETWORK_DIR).join(config_file.file_name().unwrap()),
+                )
+                .with_mode(0o644)
+            })
+            .collect::<Vec<_>>(),
+        image_file,
+        options,
+    )
+}
+
+/// Sets the timezone `tz`, e.g. "Europe/Berlin", in rootA: writes
+/// /etc/timezone and links /etc/localtime to the zoneinfo file of the tzdata
+/// of the image. If the image doesn't provide the zone, the zoneinfo file of
+/// the host is copied to /etc/localtime instead.
+pub fn set_timezone(tz: &str, image_file: &Path, options: &FileOptions) -> Result<()> {
+    validate_timezone(tz)?;
+
+    let zoneinfo = Path::new(ZONEINFO_DIR).join(tz);
+    let image_zoneinfo = get_file_path(image_file, "zoneinfo")?;
+
+    let in_image = match copy_from_image(
+        &[FileCopyFromParams::new(
+            &zoneinfo,
+            Partition::rootA,
+            &image_zoneinfo,
+        )],
+        image_file,
+        options,
+    ) {
+        Ok(()) => is_zoneinfo(&image_zoneinfo),
+        Err(e) => {
+            debug!("set_timezone: image doesn't provide {tz}: {e:#}");
+            false
+        }
+    };
+
+    let localtime = if in_image {
+        let link = get_file_path(image_file, "localtime")?;
+        std::os::unix::fs::symlink(&zoneinfo, &link)
+            .context("set_timezone: cannot create localtime symlink")?;
+
+        FileCopyToParams::new(&link, Partition::rootA, Path::new("/etc/localtime"))
+            .with_dereference(false)
+    } else if is_zoneinfo(&zoneinfo) {
+        warn!("set_timezone: image doesn't provide {tz}, copy zoneinfo file of host");
+
+        FileCopyToParams::new(&zoneinfo, Partition::rootA, Path::new("/etc/localtime"))
+    } else {
+        anyhow::bail!(
+            "set_timezone: unknown timezone {tz}: neither image nor host provide {}",
+            zoneinfo.to_string_lossy()
+        )
+    };
+
+    let timezone_file = get_file_path(image_file, "timezone")?;
+    fs::write(&timezone_file, format!("{tz}\n"))
+        .context("set_timezone: cannot write to timezone file")?;
+
+    copy_to_image(
+        &[
+            FileCopyToParams::new(&timezone_file, Partition::rootA, Path::new("/etc/timezone")),
+            localtime,
+        ],
+        image_file,
+        options,
+    )
+}
+
+/// Checks that `tz` is a plausible zone name relative to the zoneinfo dir.
+fn validate_timezone(tz: &str) -> Result<()> {
+    anyhow::ensure!(
+        !tz.is_empty()
+            && tz
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_-+/".contains(c))
+            && tz
+                .split('/')
+                .all(|c| !c.is_empty() && c != "." && c != ".."),
+        "validate_timezone: invalid timezone {tz}, expected e.g. Europe/Berlin"
+    );
+
+    Ok(())
+}
+
+/// Returns whether `path` is a compiled zoneinfo file.
+fn is_zoneinfo(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+
+    path.is_file()
+        && fs::File::open(path)
+            .and_then(|mut f| f.read_exact(&mut magic))
+            .is_ok()
+        && &magic == b"TZif"
+}
+
+/// Enables `unit` in rootA by linking it into the `.wants` directory of
+/// `target` or, if not given, of each target of its `WantedBy=`.
+pub fn enable_service(
+    unit: &str,
+    target: Option<&str>,
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    let (unit, unit_path, targets) = resolve_service(unit, target, image_file, options)?;
+
+    let link = get_file_path(image_file, &unit)?;
+    std::os::unix::fs::symlink(&unit_path, &link)
+        .context("enable_service: cannot create unit symlink")?;
+
+    let copy_params: Vec<FileCopyToParams> = targets
+        .iter()
+        .map(|target| {
+            FileCopyToParams::new(&link, Partition::rootA, &wants_link(target, &unit))
+                .with_dereference(false)
+        })
+        .collect();
+
+    copy_to_image(&copy_params, image_file, options)?;
+
+    info!("enabled {unit} for {}", targets.join(", "));
+
+    Ok(())
+}
+
+/// Disables `unit` in rootA by removing its links from the `.wants`
+/// directory of `target` or, if not given, of each target of its
+/// `WantedBy=`.
+pub fn disable_service(
+    unit: &str,
+    target: Option<&str>,
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    let (unit, _, targets) = resolve_service(unit, target, image_file, options)?;
+
+    let links: Vec<PathBuf> = targets
+        .iter()
+        .map(|target| wants_link(target, &unit))
+        .collect();
+    let links: Vec<&Path> = links.iter().map(PathBuf::as_path).collect();
+
+    if functions::remove_from_image(&links, &Partition::rootA, image_file, options)? == 0 {
+        warn!(
+            "disable_service: {unit} isn't enabled for {}",
+            targets.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+fn wants_link(target: &str, unit: &str) -> PathBuf {
+    Path::new(SYSTEMD_SYSTEM_DIR)
+        .join(format!("{target}.wants"))
+        .join(unit)
+}
+
+/// Returns the full unit name, the path of its unit file in rootA and the
+/// targets it is enabled for.
+fn resolve_service(
+    unit: &str,
+    target: Option<&str>,
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<(String, PathBuf, Vec<String>)> {
+    ensure_partitions(
+        image_file,
+        &[Partition::rootA],
+        "configure services",
+        options,
+    )?;
+
+    let (unit, unit_file) = unit_file_name(unit)?;
+    if let Some(target) = target {
+        unit_file_name(target)?;
+    }
+
+    let candidates: Vec<PathBuf> = SYSTEMD_UNIT_DIRS
+        .iter()
+        .map(|dir| Path::new(dir).join(&unit_file))
+        .collect();
+    let candidates: Vec<&Path> = candidates.iter().map(PathBuf::as_path).collect();
+
+    let unit_path = functions::find_in_image(&candidates, &Partition::rootA, image_file, options)?
+        .context(format!(
+            "resolve_service: unit file {unit_file} not found in {}",
+            SYSTEMD_UNIT_DIRS.join(", ")
+        ))?;
+
+    let targets = match target {
+        Some(target) => vec![target.to_string()],
+        None => {
+            let content =
+                functions::read_file_from_image(&unit_path, Partition::rootA, image_file, options)?;
+            let targets = wanted_by(&content);
+            anyhow::ensure!(
+                !targets.is_empty(),
+                "resolve_service: {} has no WantedBy= in its [Install] section, use --target",
+                unit_path.to_string_lossy()
+            );
+            targets
+        }
+    };
+
+    Ok((unit, unit_path, targets))
+}
+
+/// Returns the full name of `unit`, ".service" is appended if it has no unit
+/// type, and the name of its unit file, which is the template for instances
+/// like "getty@tty1.service".
+fn unit_file_name(unit: &str) -> Result<(String, String)> {
+    let unit = match unit.rsplit_once('.') {
+        Some((_, ty)) if SYSTEMD_UNIT_TYPES.contains(&ty) => unit.to_string(),
+        _ => format!("{unit}.service"),
+    };
+    let (name, ty) = unit.rsplit_once('.').unwrap();
+
+    anyhow::ensure!(
+        !name.is_empty()
+            && !name.starts_with('@')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || ":-_.\\@".contains(c)),
+        "unit_file_name: invalid unit name {unit}"
+    );
+
+    let unit_file = match name.split_once('@') {
+        Some((prefix, _)) => format!("{prefix}@.{ty}"),
+        None => unit.clone(),
+    };
+
+    Ok((unit, unit_file))
+}
+
+/// Returns the targets of `WantedBy=` in the [Install] section of a unit file.
+fn wanted_by(unit_file: &str) -> Vec<String> {
+    let mut install = false;
+    let mut targets = vec![];
+
+    for line in unit_file.lines().map(str::trim) {
+        if line.starts_with('[') {
+            install = line == "[Install]";
+        } else if let Some(value) = line.strip_prefix("WantedBy=").filter(|_| install) {
+            // an empty assignment resets the list
+            if value.trim().is_empty() {
+                targets.clear();
+            }
+            targets.extend(value.split_whitespace().map(String::from));
+        }
+    }
+
+    targets
+}
+
+pub fn append_to_file(
+    partition: Partition,
+    path: &Path,
+    content: &[u8],
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    let tmp_file = get_file_path(image_file, "append.tmp")?;
+
+    let path_str = path.to_str().context("append_to_file: invalid path")?;
+
+    // only a missing file is created, other errors must not truncate it
+    let (mut data, mode) =
+        if functions::paths_exist(&[path_str], &partition, image_file, options)?[0] {
+            copy_from_image(
+                &[FileCopyFromParams::new(path, partition.clone(), &tmp_file)],
+                image_file,
+                options,
+            )
+            .context(format!(
+                "append_to_file: cannot read {partition}:{path_str}"
+            ))?;
+
+            (
+                fs::read(&tmp_file).context("append_to_file: cannot read file")?,
+                functions::get_file_mode(path, &partition, image_file, options)?,
+            )
+        } else {
+            debug!("append_to_file: create {partition}:{path_str}");
+            (vec![], None)
+        };
+
+    if data.last().is_some_and(|c| *c != b'\n') {
+        data.push(b'\n');
+    }
+    data.extend_from_slice(content);
+
+    fs::write(&tmp_file, data).context("append_to_file: cannot write file")?;
+
+    let mut params = FileCopyToParams::new(&tmp_file, partition, path);
+
+    if let Some(mode) = mode {
+        params = params.with_mode(mode);
+    }
+
+    copy_to_image(&[params], image_file, options)
+}
+
+/// Moves the file `from` to `to` within the image, e.g. from factory to rootA,
+/// by extracting it into a temporary file and copying that to the destination
+/// partition. The mode is kept if both partitions are ext. The source is
+/// removed afterwards unless `keep_source` is set, which also allows to copy
+/// directories.
+pub fn move_partition(
+    from: &(Partition, PathBuf),
+    to: &(Partition, PathBuf),
+    keep_source: bool,
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    let (from_partition, from_path) = from;
+    let (to_partition, to_path) = to;
+
+    ensure_partitions(
+        image_file,
+        &[from_partition.clone(), to_partition.clone()],
+        "move file",
+        options,
+    )?;
+    anyhow::ensure!(
+        from != to,
+        "move_partition: source and destination are the same"
+    );
+
+    // removed with the extracted file on return
+    let tmp_dir = tempfile::Builder::new()
+        .prefix("move-")
+        .tempdir_in(image_file.parent().context("cannot get image directory")?)
+        .context("move_partition: cannot create tmp dir")?;
+    let tmp_file = tmp_dir.path().join("file");
+
+    copy_from_image(
+        &[FileCopyFromParams::new(
+            from_path,
+            from_partition.clone(),
+            &tmp_file,
+        )],
+        image_file,
+        options,
+    )
+    .context(format!(
+        "move_partition: cannot read {from_partition}:{}",
+        from_path.to_string_lossy()
+    ))?;
+
+    anyhow::ensure!(
+        keep_source || !tmp_file.is_dir(),
+        "move_partition: {from_partition}:{} is a directory, which can only be copied via --keep-source",
+        from_path.to_string_lossy()
+    );
+
+    let mut params = FileCopyToParams::new(&tmp_file, to_partition.clone(), to_path);
+
+    // files of directories keep the modes they were extracted with
+    if tmp_file.is_file() {
+        if let Some(mode) =
+            functions::get_file_mode(from_path, from_partition, image_file, options)?
+        {
+            params = params.with_mode(mode);
+        }
+    }
+
+    copy_to_image(&[params], image_file, options)?;
+
+    if !keep_source {
+        functions::remove_from_image(&[from_path.as_path()], from_partition, image_file, options)?;
+    }
+
+    Ok(())
+}
+
+/// Computes the dm-verity hash tree of `partition`, e.g. after files were
+/// injected into rootA, and writes it to `hash_tree_file`. The root hash is
+/// written to `root_hash_file` and/or set as `roothash=` in the kernel command
+/// line file `cmdline` of the boot partition.
+pub fn set_verity(
+    image_file: &Path,
+    partition: Partition,
+    hash_tree_file: &Path,
+    root_hash_file: Option<&Path>,
+    cmdline: Option<&Path>,
+    options: &FileOptions,
+) -> Result<()> {
+    ensure_partitions(
+        image_file,
+        &[partition.clone()],
+        "compute verity hash",
+        options,
+    )?;
+
+    if cmdline.is_some() {
+        ensure_partitions(image_file, &[Partition::boot], "set root hash", options)?;
+
+        anyhow::ensure!(
+            partition != Partition::boot,
+            "set_verity: root hash of the boot partition cannot be stored in the boot partition"
+        );
+    }
+
+    let root_hash = functions::verity_format(&partition, image_file, hash_tree_file, options)?;
+    info!("root hash of {partition}: {root_hash}");
+
+    if let Some(root_hash_file) = root_hash_file {
+        fs::write(root_hash_file, format!("{root_hash}\n")).context(format!(
+            "set_verity: cannot write {}",
+            root_hash_file.to_string_lossy()
+        ))?;
+    }
+
+    if let Some(cmdline) = cmdline {
+        let content =
+            functions::read_file_from_image(cmdline, Partition::boot, image_file, options)
+                .context(format!(
+                    "set_verity: cannot read {} from boot partition",
+                    cmdline.to_string_lossy()
+                ))?;
+
+        let cmdline_file = get_file_path(image_file, "cmdline")?;
+        fs::write(
+            &cmdline_file,
+            set_cmdline_param(&content, "roothash", &root_hash),
+        )
+        .context("set_verity: cannot write cmdline file")?;
+
+        copy_to_image(
+            &[FileCopyToParams::new(
+                &cmdline_file,
+                Partition::boot,
+                cmdline,
+            )],
+            image_file,
+            options,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Sets `key=value` in the kernel command line `content`, replacing existing
+/// occurrences of `key`. Only the first line is changed.
+fn set_cmdline_param(content: &str, key: &str, value: &str) -> String {
+    let (line, rest) = content.split_once('\n').unwrap_or((content, ""));
+
+    let mut params: Vec<String> = line
+        .split_whitespace()
+        .filter(|p| p.split_once('=').map_or(*p, |(k, _)| k) != key)
+        .map(str::to_string)
+        .collect();
+    params.push(format!("{key}={value}"));
+
+    format!("{}\n{rest}", params.join(" "))
+}
+
+/// Validates a build label passed via `--label`.
+pub fn parse_label(label: &str) -> Result<String> {
+    anyhow::ensure!(
+        label.len() <= LABEL_MAX_LEN,
+        "label exceeds {LABEL_MAX_LEN} characters"
+    );
+    anyhow::ensure!(
+        RE_LABEL.is_match(label),
+        r#"label must consist of printable ASCII characters except '"', '\', '$' and '`'"#
+    );
+
+    Ok(label.to_string())
+}
+
+/// Writes `label` as provenance marker to the build-info file in the factory partition.
+pub fn set_build_info(label: &str, image_file: &Path, options: &FileOptions) -> Result<()> {
+    let build_info_file = get_file_path(image_file, "build-info")?;
+
+    fs::write(&build_info_file, format!("LABEL=\"{label}\"\n"))
+        .context("set_build_info: cannot write build-info file")?;
+
     copy_to_image(
         &[FileCopyToParams::new(
-            du_config_file,
+            &build_info_file,
             Partition::factory,
-            Path::new("/etc/adu/du-config.json"),
+            Path::new(BUILD_INFO_PATH),
         )],
         image_file,
+        options,
     )
 }
 
-pub fn copy_to_image(file_copy_params: &[FileCopyToParams], image_file: &Path) -> Result<()> {
-    functions::copy_to_image(file_copy_params, image_file)
+/// Returns the label written to the build-info file in the factory partition
+/// via `--label`, if any.
+pub fn build_info_label(image_file: &Path, options: &FileOptions) -> Result<Option<String>> {
+    if !functions::has_partition(image_file, &Partition::factory, options)?
+        || !functions::paths_exist(&[BUILD_INFO_PATH], &Partition::factory, image_file, options)?[0]
+    {
+        return Ok(None);
+    }
+
+    let build_info =
+        functions::read_file_from_image(BUILD_INFO_PATH, Partition::factory, image_file, options)
+            .context("build_info_label: cannot read build-info file")?;
+
+    Ok(build_info
+        .lines()
+        .find_map(|line| line.strip_prefix("LABEL=\"")?.strip_suffix('"'))
+        .map(str::to_string))
 }
 
-pub fn copy_from_image(file_copy_params: &[FileCopyFromParams], image_file: &Path) -> Result<()> {
-    functions::copy_from_image(file_copy_params, image_file)
+/// Fails with a descriptive error if the image lacks one of `partitions`,
+/// since e.g. some image variants come without `cert` or `factory` partition.
+pub fn ensure_partitions(
+    image_file: &Path,
+    partitions: &[Partition],
+    action: &str,
+    options: &FileOptions,
+) -> Result<()> {
+    for partition in partitions {
+        anyhow::ensure!(
+            functions::has_partition(image_file, partition, options)?,
+            "this image has no '{partition}' partition; cannot {action}."
+        );
+    }
+
+    Ok(())
+}
+
+pub fn copy_to_image(
+    file_copy_params: &[FileCopyToParams],
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    functions::copy_to_image(file_copy_params, image_file, options)
+}
+
+pub fn verify_copy_to_image(
+    file_copy_params: &[FileCopyToParams],
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    functions::verify_copy_to_image(file_copy_params, image_file, options)
+}
+
+pub fn copy_from_image(
+    file_copy_params: &[FileCopyFromParams],
+    image_file: &Path,
+    options: &FileOptions,
+) -> Result<()> {
+    functions::copy_from_image(file_copy_params, image_file, options)
 }
 
 fn configure_hostname(
     identity_config_file: &Path,
     image_file: &Path,
+    options: &FileOptions,
 ) -> Result<Vec<FileCopyToParams>> {
     let hostname_file = get_file_path(image_file, "hostname")?;
     let hosts_file = get_file_path(image_file, "hosts")?;
@@ -191,6 +1460,7 @@ fn configure_hostname(
             &hosts_file.to_path_buf(),
         )],
         image_file,
+        options,
     )
     .context("configure_hostname: couldn't read /etc/hosts from rootA")?;
 
@@ -220,6 +1490,107 @@ fn configure_hostname(
     ])
 }
 
+#[derive(Clone, Debug)]
+pub struct EnvVar {
+    key: String,
+    value: String,
+}
+
+impl FromStr for EnvVar {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, value) = s.split_once('=').context("format not matched: KEY=VALUE")?;
+
+        anyhow::ensure!(
+            RE_ENV_KEY.is_match(key),
+            "invalid environment variable name: {key}"
+        );
+        anyhow::ensure!(
+            !value.contains(['\n', '\r', '\0']),
+            "environment variable value must not contain line breaks or nul characters"
+        );
+
+        Ok(Self {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+impl EnvVar {
+    fn to_line(&self) -> String {
+        if !self.value.is_empty()
+            && self
+                .value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_-.,:/@%+".contains(c))
+        {
+            return format!("{}={}", self.key, self.value);
+        }
+
+        let mut escaped = String::with_capacity(self.value.len());
+        for c in self.value.chars() {
+            if matches!(c, '"' | '\\' | '$' | '`') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+
+        format!("{}=\"{escaped}\"", self.key)
+    }
+}
+
+/// Deep-merges `overlay` on top of `base`: tables are merged recursively, all
+/// other values including arrays are replaced as a whole. Thus merging the same
+/// overlay repeatedly yields the same result. Comments of `base` are lost.
+fn merge_toml(base: &str, overlay: &str) -> Result<String> {
+    fn merge(base: &mut toml::Table, overlay: toml::Table) {
+        for (key, value) in overlay {
+            match (base.get_mut(&key), value) {
+                (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                    merge(base, overlay)
+                }
+                (_, value) => {
+                    base.insert(key, value);
+                }
+            }
+        }
+    }
+
+    let mut merged: toml::Table =
+        toml::from_str(base).context("merge_toml: cannot parse config.toml of image")?;
+    let overlay: toml::Table =
+        toml::from_str(overlay).context("merge_toml: cannot parse config file")?;
+
+    merge(&mut merged, overlay);
+
+    toml::to_string(&merged).context("merge_toml: cannot serialize merged config")
+}
+
+fn merge_env(content: &str, env_vars: &[EnvVar]) -> String {
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+
+    for var in env_vars {
+        let line = var.to_line();
+        let existing = lines.iter_mut().find(|l| {
+            let l = l.trim_start();
+            let l = l.strip_prefix("export ").unwrap_or(l).trim_start();
+            l.strip_prefix(var.key.as_str())
+                .is_some_and(|rest| rest.starts_with('='))
+        });
+
+        match existing {
+            Some(l) => *l = line,
+            None => lines.push(line),
+        }
+    }
+
+    let mut content = lines.join("\n");
+    content.push('\n');
+    content
+}
+
 pub(crate) fn get_file_path(image_path: &Path, file_name: &str) -> Result<PathBuf> {
     let mut file_path = image_path
         .parent()
@@ -228,3 +1599,396 @@ pub(crate) fn get_file_path(image_path: &Path, file_name: &str) -> Result<PathBu
     file_path.push(file_name);
     Ok(file_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwd_user() {
+        let passwd =
+            "root:x:0:0:root:/home/root:/bin/sh\n\nomnect:x:1000:1001::/home/omnect:/bin/sh\n";
+
+        assert_eq!(
+            passwd_entry(passwd, "omnect").unwrap(),
+            (1000, 1001, PathBuf::from("/home/omnect"))
+        );
+        assert_eq!(passwd_entry(passwd, "root").unwrap().0, 0);
+        assert!(passwd_entry(passwd, "omn").is_err());
+        assert!(passwd_entry("omnect:x:1000\n", "omnect").is_err());
+        assert!(passwd_entry("omnect:x:1000:1000::home:/bin/sh\n", "omnect").is_err());
+    }
+
+    #[test]
+    fn label_valid() {
+        assert_eq!(
+            parse_label("build 2024.10-rc1+42").unwrap(),
+            "build 2024.10-rc1+42"
+        );
+    }
+
+    #[test]
+    fn label_invalid() {
+        assert!(parse_label("").is_err());
+        assert!(parse_label("quote\"").is_err());
+        assert!(parse_label("$(id)").is_err());
+        assert!(parse_label("umlaut-ä").is_err());
+        assert!(parse_label("new\nline").is_err());
+        assert!(parse_label(&"x".repeat(LABEL_MAX_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn env_var_invalid_key() {
+        assert!(EnvVar::from_str("1FOO=bar").is_err());
+        assert!(EnvVar::from_str("FOO-BAR=bar").is_err());
+        assert!(EnvVar::from_str("FOO").is_err());
+        assert!(EnvVar::from_str("FOO=bar\nBAZ=1").is_err());
+    }
+
+    #[test]
+    fn env_var_escaping() {
+        assert_eq!(EnvVar::from_str("FOO=bar").unwrap().to_line(), "FOO=bar");
+        assert_eq!(EnvVar::from_str("FOO=").unwrap().to_line(), "FOO=\"\"");
+        assert_eq!(
+            EnvVar::from_str(r#"FOO=a "b" $c"#).unwrap().to_line(),
+            r#"FOO="a \"b\" \$c""#
+        );
+    }
+
+    #[test]
+    fn env_merge() {
+        let content = "# comment\nFOO=old\nexport BAR=1\nFOOBAR=2";
+        let vars = [
+            EnvVar::from_str("FOO=new").unwrap(),
+            EnvVar::from_str("BAR=2").unwrap(),
+            EnvVar::from_str("BAZ=3").unwrap(),
+        ];
+
+        assert_eq!(
+            merge_env(content, &vars),
+            "# comment\nFOO=new\nBAR=2\nFOOBAR=2\nBAZ=3\n"
+        );
+        assert_eq!(merge_env("", &vars[..1]), "FOO=new\n");
+    }
+
+    #[test]
+    fn cmdline_param() {
+        assert_eq!(
+            set_cmdline_param("root=/dev/dm-0 roothash=old quiet\n", "roothash", "abc"),
+            "root=/dev/dm-0 quiet roothash=abc\n"
+        );
+        assert_eq!(
+            set_cmdline_param("console=ttyS0", "roothash", "abc"),
+            "console=ttyS0 roothash=abc\n"
+        );
+        assert_eq!(set_cmdline_param("", "roothash", "abc"), "roothash=abc\n");
+        assert_eq!(
+            set_cmdline_param("quiet\n# comment\n", "roothash", "abc"),
+            "quiet roothash=abc\n# comment\n"
+        );
+    }
+
+    #[test]
+    fn provisioning_fields() {
+        let manual = "hostname = \"test\"\n\n[provisioning]\nsource = \"manual\"\nconnection_string = \"HostName=hub;DeviceId=dev;SharedAccessKey=key\"\n";
+
+        assert!(set_provisioning_fields(manual, "scope", "reg-id", None).is_err());
+        assert!(set_provisioning_fields(
+            manual,
+            "scope",
+            "reg-id",
+            Some(&DpsAttestation::SymmetricKey("no base64!".to_string()))
+        )
+        .is_err());
+        assert!(
+            set_provisioning_fields(manual, " ", "reg-id", Some(&DpsAttestation::X509)).is_err()
+        );
+
+        let config = set_provisioning_fields(
+            manual,
+            "scope",
+            "reg-id",
+            Some(&DpsAttestation::SymmetricKey("a2V5".to_string())),
+        )
+        .unwrap();
+        let table: toml::Table = toml::from_str(&config).unwrap();
+        let provisioning = &table["provisioning"];
+
+        assert_eq!(table["hostname"].as_str(), Some("test"));
+        assert_eq!(provisioning["source"].as_str(), Some("dps"));
+        assert_eq!(provisioning["id_scope"].as_str(), Some("scope"));
+        assert_eq!(
+            provisioning["global_endpoint"].as_str(),
+            Some(DPS_GLOBAL_ENDPOINT)
+        );
+        assert!(provisioning.get("connection_string").is_none());
+        assert_eq!(
+            provisioning["attestation"]["method"].as_str(),
+            Some("symmetric_key")
+        );
+        assert_eq!(
+            provisioning["attestation"]["registration_id"].as_str(),
+            Some("reg-id")
+        );
+        assert_eq!(
+            provisioning["attestation"]["symmetric_key"]["value"].as_str(),
+            Some("a2V5")
+        );
+
+        // switching to x509 replaces the attestation
+        let config =
+            set_provisioning_fields(&config, "scope", "reg-id", Some(&DpsAttestation::X509))
+                .unwrap();
+        let table: toml::Table = toml::from_str(&config).unwrap();
+        let attestation = &table["provisioning"]["attestation"];
+
+        assert_eq!(attestation["method"].as_str(), Some("x509"));
+        assert_eq!(attestation["identity_pk"].as_str(), Some(DEVICE_KEY_URI));
+        assert!(attestation.get("symmetric_key").is_none());
+
+        // an existing x509 attestation with EST is kept
+        let est = std::fs::read_to_string("conf/config.toml.est.template").unwrap();
+        let config =
+            set_provisioning_fields(&est, "scope", "new-id", Some(&DpsAttestation::X509)).unwrap();
+        let table: toml::Table = toml::from_str(&config).unwrap();
+        let attestation = &table["provisioning"]["attestation"];
+
+        assert_eq!(attestation["registration_id"].as_str(), Some("new-id"));
+        assert_eq!(
+            attestation["identity_cert"]["common_name"].as_str(),
+            Some("new-id")
+        );
+        assert_eq!(attestation["identity_cert"]["method"].as_str(), Some("est"));
+    }
+
+    #[test]
+    fn est_fields() {
+        let est = std::fs::read_to_string("conf/config.toml.est.template").unwrap();
+        let config = set_est_fields(
+            &est,
+            &EstConfig {
+                url: Some(url::Url::parse("https://est.example.com/.well-known/est").unwrap()),
+                trusted_ca: Some(PathBuf::from("est-ca.pem")),
+                auth: Some(EstAuth::x509),
+            },
+            "device-1",
+        )
+        .unwrap();
+        let table: toml::Table = toml::from_str(&config).unwrap();
+        let est_table = &table["cert_issuance"]["est"];
+
+        assert_eq!(
+            est_table["urls"]["default"].as_str(),
+            Some("https://est.example.com/.well-known/est")
+        );
+        assert_eq!(
+            est_table["trusted_certs"],
+            toml::Value::from(vec![EST_DEFAULT_TRUSTED_CERT_URI, EST_TRUSTED_CA_URI])
+        );
+        assert_eq!(
+            est_table["auth"]["identity_cert"].as_str(),
+            Some(DEVICE_CERT_URI)
+        );
+        assert!(est_table["auth"].get("bootstrap_identity_cert").is_none());
+        assert_eq!(
+            table["provisioning"]["attestation"]["identity_cert"]["common_name"].as_str(),
+            Some("device-1")
+        );
+        assert_eq!(
+            identity::device_cert_usage(&config, DEVICE_CERT_URI).unwrap(),
+            DeviceCertUsage::EstBootstrap
+        );
+
+        // the trusted CA is added once, the auth is kept if not given
+        let config = set_est_fields(
+            &config,
+            &EstConfig {
+                trusted_ca: Some(PathBuf::from("est-ca.pem")),
+                ..Default::default()
+            },
+            "device-1",
+        )
+        .unwrap();
+        let table: toml::Table = toml::from_str(&config).unwrap();
+
+        assert_eq!(
+            table["cert_issuance"]["est"]["trusted_certs"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(table["cert_issuance"]["est"]["auth"]
+            .get("identity_cert")
+            .is_some());
+
+        // a missing EST section is created with bootstrap auth, but needs a url
+        let no_est = std::fs::read_to_string("conf/config.toml.no-est.template").unwrap();
+        assert!(set_est_fields(&no_est, &EstConfig::default(), "device-1").is_err());
+
+        let config = set_est_fields(
+            &no_est,
+            &EstConfig {
+                url: Some(url::Url::parse("https://est.example.com").unwrap()),
+                ..Default::default()
+            },
+            "device-1",
+        )
+        .unwrap();
+        let table: toml::Table = toml::from_str(&config).unwrap();
+        let est_table = &table["cert_issuance"]["est"];
+
+        assert_eq!(
+            est_table["auth"]["bootstrap_identity_pk"].as_str(),
+            Some(DEVICE_KEY_URI)
+        );
+        assert_eq!(
+            est_table["trusted_certs"],
+            toml::Value::from(vec![EST_DEFAULT_TRUSTED_CERT_URI])
+        );
+    }
+
+    #[test]
+    fn identity_config_redaction() {
+        let config = "hostname = \"test\"\n[provisioning]\nsource = \"dps\"\nid_scope = \"scope\"\n[provisioning.attestation]\nmethod = \"symmetric_key\"\nregistration_id = \"reg-id\"\nsymmetric_key = { value = \"a2V5\" }\n[[principal]]\nname = \"est\"\npassword = \"secret\"\n";
+
+        let shown = format_identity_config(config, false).unwrap();
+        assert!(shown.contains("a2V5"));
+        assert!(shown.contains("secret"));
+
+        let shown = format_identity_config(config, true).unwrap();
+        let table: toml::Table = toml::from_str(&shown).unwrap();
+        let attestation = &table["provisioning"]["attestation"];
+
+        assert!(!shown.contains("a2V5"));
+        assert!(!shown.contains("secret"));
+        assert_eq!(attestation["symmetric_key"].as_str(), Some(REDACTED));
+        assert_eq!(attestation["method"].as_str(), Some("symmetric_key"));
+        assert_eq!(attestation["registration_id"].as_str(), Some("reg-id"));
+        assert_eq!(table["principal"][0]["password"].as_str(), Some(REDACTED));
+        assert_eq!(table["hostname"].as_str(), Some("test"));
+
+        let manual = "[provisioning]\nsource = \"manual\"\nconnection_string = \"HostName=hub;DeviceId=dev;SharedAccessKey=key\"\n";
+        let shown = format_identity_config(manual, true).unwrap();
+        assert!(!shown.contains("SharedAccessKey"));
+
+        assert!(format_identity_config("no toml", true).is_err());
+    }
+
+    #[test]
+    fn toml_merge() {
+        let base = r#"
+hostname = "base"
+local_gateway_hostname = "gw"
+
+[provisioning]
+source = "dps"
+id_scope = "scope"
+
+[provisioning.attestation]
+method = "tpm"
+registration_id = "base-id"
+
+[edge_ca]
+auto_generated_edge_ca_expiry_days = [1, 2]
+"#;
+        let overlay = r#"
+hostname = "site"
+
+[provisioning]
+global_endpoint = "https://global.azure-devices-provisioning.net"
+
+[provisioning.attestation]
+registration_id = "site-id"
+
+[edge_ca]
+auto_generated_edge_ca_expiry_days = [3]
+"#;
+
+        let merged = merge_toml(base, overlay).unwrap();
+        let table: toml::Table = toml::from_str(&merged).unwrap();
+
+        assert_eq!(table["hostname"].as_str(), Some("site"));
+        assert_eq!(table["local_gateway_hostname"].as_str(), Some("gw"));
+        assert_eq!(table["provisioning"]["source"].as_str(), Some("dps"));
+        assert_eq!(table["provisioning"]["id_scope"].as_str(), Some("scope"));
+        assert_eq!(
+            table["provisioning"]["global_endpoint"].as_str(),
+            Some("https://global.azure-devices-provisioning.net")
+        );
+        assert_eq!(
+            table["provisioning"]["attestation"]["method"].as_str(),
+            Some("tpm")
+        );
+        assert_eq!(
+            table["provisioning"]["attestation"]["registration_id"].as_str(),
+            Some("site-id")
+        );
+        assert_eq!(
+            table["edge_ca"]["auto_generated_edge_ca_expiry_days"],
+            toml::Value::Array(vec![toml::Value::Integer(3)])
+        );
+
+        // merging again doesn't change the result
+        assert_eq!(merge_toml(&merged, overlay).unwrap(), merged);
+        assert!(merge_toml(base, "hostname = ").is_err());
+    }
+
+    #[test]
+    fn timezone_names() {
+        for tz in [
+            "UTC",
+            "Europe/Berlin",
+            "America/Argentina/Buenos_Aires",
+            "Etc/GMT+1",
+        ] {
+            assert!(validate_timezone(tz).is_ok(), "{tz}");
+        }
+        for tz in [
+            "",
+            "/Europe/Berlin",
+            "Europe/",
+            "../etc/passwd",
+            "Europe/Ber lin",
+        ] {
+            assert!(validate_timezone(tz).is_err(), "{tz}");
+        }
+    }
+
+    #[test]
+    fn service_unit_file_name() {
+        assert_eq!(
+            unit_file_name("sshd").unwrap(),
+            ("sshd.service".to_string(), "sshd.service".to_string())
+        );
+        assert_eq!(
+            unit_file_name("fstrim.timer").unwrap(),
+            ("fstrim.timer".to_string(), "fstrim.timer".to_string())
+        );
+        assert_eq!(
+            unit_file_name("getty@tty1.service").unwrap(),
+            (
+                "getty@tty1.service".to_string(),
+                "getty@.service".to_string()
+            )
+        );
+        assert_eq!(
+            unit_file_name("my.app").unwrap().0,
+            "my.app.service".to_string()
+        );
+        assert!(unit_file_name("../sshd").is_err());
+        assert!(unit_file_name(".service").is_err());
+        assert!(unit_file_name("@.service").is_err());
+    }
+
+    #[test]
+    fn service_wanted_by() {
+        let unit = "[Unit]\nWantedBy=ignored.target\n\n[Install]\nWantedBy=multi-user.target\nWantedBy=a.target b.target\nAlias=x.service\n";
+        assert_eq!(
+            wanted_by(unit),
+            vec!["multi-user.target", "a.target", "b.target"]
+        );
+        assert!(wanted_by("[Install]\nWantedBy=a.target\nWantedBy=\n").is_empty());
+        assert!(wanted_by("[Service]\nExecStart=/bin/true\n").is_empty());
+    }
+}
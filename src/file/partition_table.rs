@@ -0,0 +1,326 @@
+use anyhow::{Context, Result};
+use log::{debug, info};
+use std::fs::{self, File};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+const SECTOR_SIZE: u64 = 512;
+// identifies dumps written by `dump`
+const DUMP_MAGIC: &[u8; 8] = b"OMNECTPT";
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+// guards against cyclic chains of extended boot records
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+/// A range of the image belonging to the partition table.
+struct Region {
+    offset: u64,
+    data: Vec<u8>,
+}
+
+/// Fields of a gpt header relevant for locating and validating the table.
+struct GptHeader {
+    my_lba: u64,
+    alternate_lba: u64,
+    disk_guid: [u8; 16],
+    entries_lba: u64,
+    num_entries: u32,
+    entry_size: u32,
+    entries_crc: u32,
+}
+
+impl GptHeader {
+    fn parse(sector: &[u8]) -> Result<Self> {
+        anyhow::ensure!(
+            &sector[0..8] == GPT_SIGNATURE,
+            "GptHeader::parse: invalid signature"
+        );
+
+        let header_size = u32_at(sector, 12) as usize;
+        anyhow::ensure!(
+            (92..=sector.len()).contains(&header_size),
+            "GptHeader::parse: invalid header size {header_size}"
+        );
+
+        let mut header = sector[..header_size].to_vec();
+        header[16..20].fill(0);
+        anyhow::ensure!(
+            crc32(&header) == u32_at(sector, 16),
+            "GptHeader::parse: header checksum mismatch"
+        );
+
+        Ok(GptHeader {
+            my_lba: u64_at(sector, 24),
+            alternate_lba: u64_at(sector, 32),
+            disk_guid: sector[56..72].try_into().unwrap(),
+            entries_lba: u64_at(sector, 72),
+            num_entries: u32_at(sector, 80),
+            entry_size: u32_at(sector, 84),
+            entries_crc: u32_at(sector, 88),
+        })
+    }
+
+    fn entries_len(&self) -> u64 {
+        u64::from(self.num_entries) * u64::from(self.entry_size)
+    }
+
+    fn entries_sectors(&self) -> u64 {
+        self.entries_len().div_ceil(SECTOR_SIZE)
+    }
+}
+
+/// Writes the partition table of `image_file` to `out_file`: the mbr, the
+/// extended boot records of logical dos partitions and, for gpt, the primary
+/// and backup header with their partition entries. The backup gpt is checked
+/// to be consistent with the primary one.
+pub fn dump(image_file: &Path, out_file: &Path) -> Result<()> {
+    let image = File::open(image_file).context(format!(
+        "dump: cannot open {}",
+        image_file.to_string_lossy()
+    ))?;
+    let image_len = image
+        .metadata()
+        .context("dump: cannot get image size")?
+        .len();
+
+    let mbr = read_region(&image, 0, SECTOR_SIZE)?;
+    anyhow::ensure!(
+        mbr.data[510..512] == MBR_SIGNATURE,
+        "dump: image has no partition table"
+    );
+
+    let mbr_types: Vec<u8> = (0..4)
+        .map(|i| mbr.data[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE + 4])
+        .collect();
+
+    let regions = if mbr_types.contains(&MBR_TYPE_GPT_PROTECTIVE) {
+        gpt_regions(&image, image_len, mbr)?
+    } else {
+        dos_regions(&image, mbr)?
+    };
+
+    let mut dump = DUMP_MAGIC.to_vec();
+    for region in &regions {
+        info!(
+            "dump: partition table region at offset {} ({} bytes)",
+            region.offset,
+            region.data.len()
+        );
+        dump.extend_from_slice(&region.offset.to_le_bytes());
+        dump.extend_from_slice(&(region.data.len() as u64).to_le_bytes());
+        dump.extend_from_slice(&region.data);
+    }
+
+    fs::write(out_file, dump).context(format!("dump: cannot write {}", out_file.to_string_lossy()))
+}
+
+/// Writes a partition table dumped via `dump` back into `image_file`.
+pub fn restore(image_file: &Path, dump_file: &Path) -> Result<()> {
+    let dump = fs::read(dump_file).context(format!(
+        "restore: cannot read {}",
+        dump_file.to_string_lossy()
+    ))?;
+
+    anyhow::ensure!(
+        dump.starts_with(DUMP_MAGIC),
+        "restore: {} isn't a partition table dump",
+        dump_file.to_string_lossy()
+    );
+
+    let mut regions = vec![];
+    let mut pos = DUMP_MAGIC.len();
+    while pos < dump.len() {
+        anyhow::ensure!(pos + 16 <= dump.len(), "restore: truncated dump");
+        let offset = u64_at(&dump, pos);
+        let len = u64_at(&dump, pos + 8) as usize;
+        pos += 16;
+
+        anyhow::ensure!(pos + len <= dump.len(), "restore: truncated dump");
+        regions.push(Region {
+            offset,
+            data: dump[pos..pos + len].to_vec(),
+        });
+        pos += len;
+    }
+
+    let image = File::options()
+        .read(true)
+        .write(true)
+        .open(image_file)
+        .context(format!(
+            "restore: cannot open {}",
+            image_file.to_string_lossy()
+        ))?;
+    let image_len = image
+        .metadata()
+        .context("restore: cannot get image size")?
+        .len();
+
+    // check all regions before writing any
+    for region in &regions {
+        anyhow::ensure!(
+            region.offset + region.data.len() as u64 <= image_len,
+            "restore: image is too small for the partition table (region at offset {})",
+            region.offset
+        );
+    }
+
+    for region in &regions {
+        debug!(
+            "restore: write {} bytes at offset {}",
+            region.data.len(),
+            region.offset
+        );
+        image
+            .write_all_at(&region.data, region.offset)
+            .context("restore: cannot write partition table")?;
+    }
+
+    image.sync_all().context("restore: cannot sync image")
+}
+
+fn gpt_regions(image: &File, image_len: u64, mbr: Region) -> Result<Vec<Region>> {
+    let primary = GptHeader::parse(&read_region(image, SECTOR_SIZE, SECTOR_SIZE)?.data)
+        .context("gpt_regions: invalid primary gpt header")?;
+    anyhow::ensure!(
+        primary.my_lba == 1,
+        "gpt_regions: primary gpt header isn't located at lba 1"
+    );
+
+    let primary_entries = read_region(
+        image,
+        primary.entries_lba * SECTOR_SIZE,
+        primary.entries_len(),
+    )?;
+    anyhow::ensure!(
+        crc32(&primary_entries.data) == primary.entries_crc,
+        "gpt_regions: primary partition entries checksum mismatch"
+    );
+
+    anyhow::ensure!(
+        (primary.alternate_lba + 1) * SECTOR_SIZE <= image_len,
+        "gpt_regions: backup gpt header lies beyond the end of the image"
+    );
+    let backup = GptHeader::parse(
+        &read_region(image, primary.alternate_lba * SECTOR_SIZE, SECTOR_SIZE)?.data,
+    )
+    .context("gpt_regions: invalid backup gpt header")?;
+
+    anyhow::ensure!(
+        backup.my_lba == primary.alternate_lba
+            && backup.alternate_lba == primary.my_lba
+            && backup.disk_guid == primary.disk_guid
+            && backup.num_entries == primary.num_entries
+            && backup.entry_size == primary.entry_size
+            && backup.entries_crc == primary.entries_crc,
+        "gpt_regions: backup gpt header is inconsistent with the primary one"
+    );
+
+    let backup_entries = read_region(
+        image,
+        backup.entries_lba * SECTOR_SIZE,
+        backup.entries_len(),
+    )?;
+    anyhow::ensure!(
+        crc32(&backup_entries.data) == backup.entries_crc,
+        "gpt_regions: backup partition entries checksum mismatch"
+    );
+
+    Ok(vec![
+        mbr,
+        // primary header and entries, which usually follow it directly
+        read_region(
+            image,
+            SECTOR_SIZE,
+            (primary.entries_lba + primary.entries_sectors() - 1) * SECTOR_SIZE,
+        )?,
+        // backup entries and header at the end of the image
+        read_region(
+            image,
+            backup.entries_lba * SECTOR_SIZE,
+            (backup.my_lba - backup.entries_lba + 1) * SECTOR_SIZE,
+        )?,
+    ])
+}
+
+fn dos_regions(image: &File, mbr: Region) -> Result<Vec<Region>> {
+    let extended_lba = (0..4)
+        .map(|i| &mbr.data[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE])
+        .find(|entry| MBR_TYPES_EXTENDED.contains(&entry[4]))
+        .map(|entry| u64::from(u32_at(entry, 8)));
+
+    let mut regions = vec![mbr];
+
+    // logical partitions are described by a chain of extended boot records
+    if let Some(extended_lba) = extended_lba {
+        let mut ebr_lba = extended_lba;
+
+        loop {
+            anyhow::ensure!(
+                regions.len() <= MAX_LOGICAL_PARTITIONS,
+                "dos_regions: too many logical partitions"
+            );
+
+            let ebr = read_region(image, ebr_lba * SECTOR_SIZE, SECTOR_SIZE)?;
+            anyhow::ensure!(
+                ebr.data[510..512] == MBR_SIGNATURE,
+                "dos_regions: invalid extended boot record at lba {ebr_lba}"
+            );
+
+            let next = &ebr.data[MBR_ENTRIES_OFFSET + MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+            let next_lba = u64::from(u32_at(next, 8));
+            let has_next = next[4] != 0 && next_lba != 0;
+
+            regions.push(ebr);
+
+            if !has_next {
+                break;
+            }
+
+            ebr_lba = extended_lba + next_lba;
+        }
+    }
+
+    Ok(regions)
+}
+
+fn read_region(image: &File, offset: u64, len: u64) -> Result<Region> {
+    let mut data = vec![0u8; len as usize];
+
+    image.read_exact_at(&mut data, offset).context(format!(
+        "read_region: cannot read {len} bytes at offset {offset}"
+    ))?;
+
+    Ok(Region { offset, data })
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// CRC-32 (IEEE) as used by gpt.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, b| {
+        (0..8).fold(crc ^ u32::from(*b), |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
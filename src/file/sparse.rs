@@ -0,0 +1,450 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+// granularity of zero detection when digging holes
+const BLOCK_SIZE: usize = 4096;
+
+/// Seeks to the next data (resp. hole) at or after `offset`. Returns `None` if
+/// there is none.
+fn seek(file: &File, offset: u64, whence: i32) -> io::Result<Option<u64>> {
+    // SAFETY: lseek64 has no memory safety requirements
+    let res = unsafe { libc::lseek64(file.as_raw_fd(), offset as libc::off64_t, whence) };
+
+    if res < 0 {
+        let err = io::Error::last_os_error();
+
+        return match err.raw_os_error() {
+            Some(libc::ENXIO) => Ok(None),
+            _ => Err(err),
+        };
+    }
+
+    Ok(Some(res as u64))
+}
+
+/// Returns the data extents of `file` within `[offset, end)` as `(start, end)` pairs.
+fn data_extents(file: &File, offset: u64, end: u64) -> io::Result<Vec<(u64, u64)>> {
+    let mut extents = vec![];
+    let mut pos = offset;
+
+    while pos < end {
+        let start = match seek(file, pos, libc::SEEK_DATA) {
+            Ok(Some(start)) if start < end => start,
+            Ok(_) => break,
+            // file system doesn't support SEEK_DATA: everything is data
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                extents.push((pos, end));
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let stop = seek(file, start, libc::SEEK_HOLE)?.unwrap_or(end).min(end);
+
+        extents.push((start, stop));
+        pos = stop;
+    }
+
+    Ok(extents)
+}
+
+/// Like `punch_hole`, but ignores file systems not supporting holes.
+fn try_punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    match punch_hole(file, offset, len) {
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+        res => res,
+    }
+}
+
+fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    // SAFETY: fallocate64 has no memory safety requirements
+    let res = unsafe {
+        libc::fallocate64(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off64_t,
+            len as libc::off64_t,
+        )
+    };
+
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Zeroes `[offset, offset + len)` of `file`, preferably by punching a hole.
+fn clear(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    match punch_hole(file, offset, len) {
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+            let zeros = vec![0u8; CHUNK_SIZE];
+            let mut pos = 0;
+
+            while pos < len {
+                let n = ((len - pos) as usize).min(CHUNK_SIZE);
+                file.write_all_at(&zeros[..n], offset + pos)?;
+                pos += n as u64;
+            }
+
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+fn copy_data(
+    src: &File,
+    src_offset: u64,
+    dst: &File,
+    dst_offset: u64,
+    len: u64,
+    block_size: u64,
+) -> io::Result<()> {
+    let mut off_in = src_offset as libc::loff_t;
+    let mut off_out = dst_offset as libc::loff_t;
+    let mut remaining = len;
+
+    while remaining > 0 {
+        // SAFETY: the offsets point to valid, exclusively borrowed locals
+        let res = unsafe {
+            libc::copy_file_range(
+                src.as_raw_fd(),
+                &mut off_in,
+                dst.as_raw_fd(),
+                &mut off_out,
+                remaining.min(block_size) as usize,
+                0,
+            )
+        };
+
+        if res < 0 {
+            let err = io::Error::last_os_error();
+
+            return match err.raw_os_error() {
+                // not supported by kernel or file system: copy via userspace
+                Some(libc::ENOSYS | libc::EXDEV | libc::EINVAL | libc::EOPNOTSUPP) => {
+                    copy_buffered(
+                        src,
+                        off_in as u64,
+                        dst,
+                        off_out as u64,
+                        remaining,
+                        block_size,
+                    )
+                }
+                _ => Err(err),
+            };
+        }
+
+        if res == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        remaining -= res as u64;
+    }
+
+    Ok(())
+}
+
+fn copy_buffered(
+    src: &File,
+    src_offset: u64,
+    dst: &File,
+    dst_offset: u64,
+    len: u64,
+    block_size: u64,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; block_size as usize];
+    let mut pos = 0;
+
+    while pos < len {
+        let n = ((len - pos) as usize).min(block_size as usize);
+        src.read_exact_at(&mut buf[..n], src_offset + pos)?;
+        dst.write_all_at(&buf[..n], dst_offset + pos)?;
+        pos += n as u64;
+    }
+
+    Ok(())
+}
+
+/// Copies `len` bytes of `src` at `src_offset` to `dst` at `dst_offset` in
+/// blocks of up to `block_size` bytes. Only data extents of `src` are copied,
+/// holes of `src` become holes in `dst`.
+pub fn copy_sparse(
+    src: &File,
+    src_offset: u64,
+    dst: &File,
+    dst_offset: u64,
+    len: u64,
+    block_size: u64,
+) -> Result<()> {
+    let end = src_offset + len;
+    let extents = data_extents(src, src_offset, end).context("copy_sparse: cannot seek data")?;
+    let mut pos = src_offset;
+
+    for (start, stop) in extents.into_iter().chain([(end, end)]) {
+        if start > pos {
+            clear(dst, dst_offset + pos - src_offset, start - pos)
+                .context("copy_sparse: cannot clear hole")?;
+        }
+
+        if stop > start {
+            copy_data(
+                src,
+                start,
+                dst,
+                dst_offset + start - src_offset,
+                stop - start,
+                block_size,
+            )
+            .context("copy_sparse: cannot copy data")?;
+        }
+
+        pos = stop;
+    }
+
+    Ok(())
+}
+
+/// Counts the sectors of `sector_size` bytes differing between `len` bytes of
+/// `a` at `a_offset` and of `b` at `b_offset`. Ranges which are holes in both
+/// files are equal and skipped.
+pub fn changed_sectors(
+    a: &File,
+    a_offset: u64,
+    b: &File,
+    b_offset: u64,
+    len: u64,
+    sector_size: u64,
+) -> Result<u64> {
+    let relative = |file: &File, offset: u64| -> io::Result<Vec<(u64, u64)>> {
+        Ok(data_extents(file, offset, offset + len)?
+            .into_iter()
+            .map(|(start, stop)| (start - offset, stop - offset))
+            .collect())
+    };
+
+    // union of the data extents of both files, aligned to sectors
+    let mut extents = relative(a, a_offset).context("changed_sectors: cannot seek data")?;
+    extents.extend(relative(b, b_offset).context("changed_sectors: cannot seek data")?);
+    extents.sort_unstable();
+
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    for (start, stop) in extents {
+        let start = start / sector_size * sector_size;
+        let stop = stop
+            .div_ceil(sector_size)
+            .saturating_mul(sector_size)
+            .min(len);
+
+        match ranges.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(stop),
+            _ => ranges.push((start, stop)),
+        }
+    }
+
+    let chunk_size = CHUNK_SIZE as u64 / sector_size * sector_size;
+    let mut buf_a = vec![0u8; chunk_size as usize];
+    let mut buf_b = vec![0u8; chunk_size as usize];
+    let mut changed = 0;
+
+    for (start, stop) in ranges {
+        let mut pos = start;
+
+        while pos < stop {
+            let n = ((stop - pos).min(chunk_size)) as usize;
+            a.read_exact_at(&mut buf_a[..n], a_offset + pos)
+                .context("changed_sectors: cannot read")?;
+            b.read_exact_at(&mut buf_b[..n], b_offset + pos)
+                .context("changed_sectors: cannot read")?;
+
+            changed += buf_a[..n]
+                .chunks(sector_size as usize)
+                .zip(buf_b[..n].chunks(sector_size as usize))
+                .filter(|(a, b)| a != b)
+                .count() as u64;
+
+            pos += n as u64;
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Punches holes into zero filled blocks of `file`, like `fallocate -d`.
+pub fn dig_holes(file: &File) -> Result<()> {
+    let size = file
+        .metadata()
+        .context("dig_holes: cannot get metadata")?
+        .len();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+
+    for (start, stop) in data_extents(file, 0, size).context("dig_holes: cannot seek data")? {
+        let mut zero_start: Option<u64> = None;
+        let mut pos = start;
+
+        while pos < stop {
+            let n = ((stop - pos) as usize).min(CHUNK_SIZE);
+            file.read_exact_at(&mut buf[..n], pos)
+                .context("dig_holes: cannot read")?;
+
+            for (i, block) in buf[..n].chunks(BLOCK_SIZE).enumerate() {
+                let offset = pos + (i * BLOCK_SIZE) as u64;
+
+                if block.iter().all(|b| *b == 0) {
+                    zero_start.get_or_insert(offset);
+                } else if let Some(zero_start) = zero_start.take() {
+                    try_punch_hole(file, zero_start, offset - zero_start)
+                        .context("dig_holes: cannot punch hole")?;
+                }
+            }
+
+            pos += n as u64;
+        }
+
+        if let Some(zero_start) = zero_start {
+            try_punch_hole(file, zero_start, stop - zero_start)
+                .context("dig_holes: cannot punch hole")?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn copy_sparse_keeps_holes() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.path().join("src"))
+            .unwrap();
+        let dst = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.path().join("dst"))
+            .unwrap();
+        let len = 4 * CHUNK_SIZE as u64;
+
+        src.set_len(len).unwrap();
+        src.write_all_at(b"begin", 0).unwrap();
+        src.write_all_at(b"end", len - 3).unwrap();
+
+        // dst content outside the copied range is kept, holes of src are cleared
+        dst.write_all_at(&vec![0xffu8; 2 * len as usize], 0)
+            .unwrap();
+        copy_sparse(&src, 0, &dst, len, len, CHUNK_SIZE as u64).unwrap();
+
+        let mut out = vec![0u8; 2 * len as usize];
+        dst.read_exact_at(&mut out, 0).unwrap();
+
+        assert!(out[..len as usize].iter().all(|b| *b == 0xff));
+        assert_eq!(&out[len as usize..len as usize + 5], b"begin");
+        assert!(out[len as usize + 5..2 * len as usize - 3]
+            .iter()
+            .all(|b| *b == 0));
+        assert_eq!(&out[2 * len as usize - 3..], b"end");
+    }
+
+    #[test]
+    fn copy_sparse_block_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.path().join("src"))
+            .unwrap();
+        let len = 3 * CHUNK_SIZE as u64 + 512;
+        let content: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        src.write_all_at(&content, 0).unwrap();
+
+        // the output doesn't depend on the block size, also for unaligned offsets
+        for block_size in [512, 4096, 1 << 20, 4 << 20] {
+            let dst = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(dir.path().join(format!("dst-{block_size}")))
+                .unwrap();
+            dst.set_len(len + 512).unwrap();
+
+            copy_sparse(&src, 512, &dst, 1024, len - 512, block_size).unwrap();
+
+            let mut out = vec![0u8; (len - 512) as usize];
+            dst.read_exact_at(&mut out, 1024).unwrap();
+            assert_eq!(out, content[512..], "block size {block_size}");
+        }
+    }
+
+    #[test]
+    fn changed_sectors_between_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.path().join("a"))
+            .unwrap();
+        let b = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.path().join("b"))
+            .unwrap();
+        let len = 4 * CHUNK_SIZE as u64;
+
+        // b contains the range of a at offset 512
+        a.set_len(len).unwrap();
+        b.set_len(len + 512).unwrap();
+        a.write_all_at(b"same", 0).unwrap();
+        b.write_all_at(b"same", 512).unwrap();
+        assert_eq!(changed_sectors(&a, 0, &b, 512, len, 512).unwrap(), 0);
+
+        // a change spanning a sector boundary and one in a hole of a
+        a.write_all_at(b"changed", 1020).unwrap();
+        b.write_all_at(b"x", 512 + 3 * CHUNK_SIZE as u64).unwrap();
+        assert_eq!(changed_sectors(&a, 0, &b, 512, len, 512).unwrap(), 3);
+    }
+
+    #[test]
+    fn dig_holes_keeps_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.path().join("file"))
+            .unwrap();
+        let mut content = vec![0u8; 4 * CHUNK_SIZE];
+        content[CHUNK_SIZE + 1] = 1;
+
+        file.write_all_at(&content, 0).unwrap();
+        file.sync_all().unwrap();
+        dig_holes(&file).unwrap();
+
+        let mut out = vec![0u8; content.len()];
+        file.read_exact_at(&mut out, 0).unwrap();
+
+        assert_eq!(out, content);
+        assert!(file.metadata().unwrap().blocks() * 512 <= content.len() as u64);
+    }
+}
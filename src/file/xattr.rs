@@ -0,0 +1,100 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Returns the extended attributes of `path` as `(name, value)` pairs sorted by
+/// name, e.g. `("security.capability", ...)`. Symlinks are followed. File
+/// systems not supporting extended attributes have none.
+pub fn read(path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+
+    // SAFETY: path is nul terminated and buf is valid for len bytes or null with len 0
+    let names = query(|buf, len| unsafe { libc::listxattr(path.as_ptr(), buf.cast(), len) })?;
+    let mut attrs = vec![];
+
+    for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+        let c_name = CString::new(name)?;
+        // SAFETY: path and name are nul terminated and buf is valid for len bytes or null with len 0
+        let value = query(|buf, len| unsafe {
+            libc::getxattr(path.as_ptr(), c_name.as_ptr(), buf.cast(), len)
+        })?;
+
+        attrs.push((String::from_utf8_lossy(name).into_owned(), value));
+    }
+
+    attrs.sort();
+    Ok(attrs)
+}
+
+/// Calls `f` with a null buffer to get the size of the result and again with a
+/// buffer of that size, which is repeated if the result grew in between.
+fn query(f: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let size = f(std::ptr::null_mut(), 0);
+
+        if size < 0 {
+            let err = io::Error::last_os_error();
+
+            return match err.raw_os_error() {
+                Some(libc::ENOTSUP) => Ok(vec![]),
+                _ => Err(err),
+            };
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let res = f(buf.as_mut_ptr(), buf.len());
+
+        if res >= 0 {
+            buf.truncate(res as usize);
+            return Ok(buf);
+        }
+
+        let err = io::Error::last_os_error();
+
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_user_xattrs() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "content").unwrap();
+
+        let path = CString::new(file.as_os_str().as_bytes()).unwrap();
+        for (name, value) in [("user.b", &b"2"[..]), ("user.a", &b"\x00\x01"[..])] {
+            let name = CString::new(name).unwrap();
+            // SAFETY: path and name are nul terminated, value is valid for its length
+            let res = unsafe {
+                libc::setxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                    0,
+                )
+            };
+
+            // the temp dir may not support user xattrs, e.g. on older tmpfs
+            if res < 0 {
+                return;
+            }
+        }
+
+        assert_eq!(
+            read(&file).unwrap(),
+            vec![
+                ("user.a".to_string(), vec![0, 1]),
+                ("user.b".to_string(), b"2".to_vec())
+            ]
+        );
+        assert!(read(&dir.path().join("missing")).is_err());
+    }
+}
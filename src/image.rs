@@ -1,9 +1,21 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use crate::file::compression::{self, Compression, ImageFormat};
+pub use crate::file::compression::{compress_to, decompress_to};
 use crate::file::functions::read_file_from_image;
-use crate::file::functions::Partition;
+use crate::file::functions::{
+    copy_from_image, copy_to_image, has_partition, list_dir, partition_sectors, partition_usage,
+    paths_exist, FileCopyFromParams, FileCopyToParams, FileOptions, Partition,
+};
+use crate::file::get_file_path;
 use anyhow::{Context, Result};
+use log::{info, warn};
 use regex::Regex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::str::FromStr;
 
 // NOTE (2024-05-29 Tobias Langer): /etc/os-release is a symlink in our yocto
 // builds. The e2tools-suite cannot handle symlinks so we use its target
@@ -11,6 +23,22 @@ use regex::Regex;
 const OS_RELEASE_PATH: &str = "/usr/lib/os-release";
 const OS_RELEASE_PARTITION: Partition = Partition::rootA;
 
+// boot loader configs looked up in the boot partition
+const GRUB_CONFIGS: [&str; 3] = [
+    "/EFI/BOOT/grub.cfg",
+    "/grub/grub.cfg",
+    "/boot/grub/grub.cfg",
+];
+const EXTLINUX_CONFIGS: [&str; 2] = ["/extlinux/extlinux.conf", "/boot/extlinux/extlinux.conf"];
+const LOADER_ENTRIES_DIR: &str = "/loader/entries";
+// files only holding a kernel command line, looked up in the boot partition
+const UBOOT_ENV: &str = "/uboot.env";
+const CMDLINE_FILE: &str = "/cmdline.txt";
+// COMMAND_LINE_SIZE of the kernel on arm64 and x86
+const CMDLINE_MAX_LEN: usize = 2048;
+// compressed images are compared in blocks of this size
+const DELTA_BLOCK_SIZE: usize = 4096;
+
 lazy_static::lazy_static! {
     pub static ref ARCH_REGEX: Regex = {
         Regex::new(r#"OMNECT_TARGET_ARCH="(?<arch>.*)""#).unwrap()
@@ -42,9 +70,10 @@ impl TryInto<Architecture> for &str {
     }
 }
 
-pub fn image_arch(image: impl AsRef<Path>) -> Result<Architecture> {
-    let os_release_info = read_file_from_image(OS_RELEASE_PATH, OS_RELEASE_PARTITION, image)
-        .context("image_arch: could not read os-release info")?;
+pub fn image_arch(image: impl AsRef<Path>, options: &FileOptions) -> Result<Architecture> {
+    let os_release_info =
+        read_file_from_image(OS_RELEASE_PATH, OS_RELEASE_PARTITION, image, options)
+            .context("image_arch: could not read os-release info")?;
 
     let arch = ARCH_REGEX
         .captures(&os_release_info)
@@ -56,3 +85,1375 @@ pub fn image_arch(image: impl AsRef<Path>) -> Result<Architecture> {
         .try_into()
         .context(format!("Unsupported architecture type: {}", &arch["arch"]))
 }
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DetectInfo {
+    pub image: PathBuf,
+    pub magic: String,
+    pub compression: Option<String>,
+    /// path modifying commands write the decompressed image back to
+    pub decompressed_image: Option<PathBuf>,
+}
+
+impl std::fmt::Display for DetectInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "image:              {}", self.image.to_string_lossy())?;
+        writeln!(f, "magic:              {}", self.magic)?;
+        match (&self.compression, &self.decompressed_image) {
+            (Some(compression), Some(decompressed_image)) => {
+                writeln!(f, "compression:        {compression}")?;
+                write!(
+                    f,
+                    "decompressed image: {}",
+                    decompressed_image.to_string_lossy()
+                )?;
+                if self
+                    .image
+                    .extension()
+                    .is_some_and(|e| e != compression.as_str())
+                {
+                    write!(f, " (image doesn't end with .{compression})")?;
+                }
+                Ok(())
+            }
+            _ => write!(f, "compression:        none (image is used as is)"),
+        }
+    }
+}
+
+/// Runs the compression detection applied to images by all image commands
+/// without decompressing the image.
+pub fn detect(image: &Path) -> Result<DetectInfo> {
+    let magic = compression::magic(image)?;
+    let detected = Compression::from_magic(&magic);
+
+    Ok(DetectInfo {
+        image: image.to_path_buf(),
+        compression: detected.as_ref().map(|c| c.extension().to_string()),
+        // same as done by image commands when writing back a decompressed image
+        decompressed_image: detected.as_ref().map(|_| image.with_extension("")),
+        magic,
+    })
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    pub image: PathBuf,
+    /// NAME of os-release, falling back to ID
+    pub distro: Option<String>,
+    /// VERSION_ID of os-release, falling back to VERSION
+    pub version: Option<String>,
+    pub build_id: Option<String>,
+    pub arch: Option<String>,
+    /// label set via `--label` in the factory partition
+    pub label: Option<String>,
+    /// all fields of os-release
+    pub os_release: BTreeMap<String, String>,
+}
+
+impl std::fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let field = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
+
+        writeln!(f, "image:    {}", self.image.to_string_lossy())?;
+        writeln!(f, "distro:   {}", field(&self.distro))?;
+        writeln!(f, "version:  {}", field(&self.version))?;
+        writeln!(f, "build id: {}", field(&self.build_id))?;
+        writeln!(f, "arch:     {}", field(&self.arch))?;
+        write!(f, "label:    {}", field(&self.label))
+    }
+}
+
+/// Parses the KEY=VALUE lines of an os-release file, see os-release(5).
+/// Values may be quoted with single or double quotes; in double quotes
+/// backslash escapes of '"', '\\', '$' and '`' are resolved.
+fn parse_os_release(content: &str) -> BTreeMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(quoted) => {
+                    let mut unescaped = String::new();
+                    let mut chars = quoted.chars();
+                    while let Some(c) = chars.next() {
+                        match (c, chars.clone().next()) {
+                            ('\\', Some(next @ ('"' | '\\' | '$' | '`'))) => {
+                                unescaped.push(next);
+                                chars.next();
+                            }
+                            _ => unescaped.push(c),
+                        }
+                    }
+                    unescaped
+                }
+                None => value
+                    .strip_prefix('\'')
+                    .and_then(|v| v.strip_suffix('\''))
+                    .unwrap_or(value)
+                    .to_string(),
+            };
+
+            (key.trim().to_string(), value)
+        })
+        .collect()
+}
+
+/// Reads os-release from rootA and the label from the factory partition of
+/// the (decompressed) image `decompressed_image`, so that users can confirm
+/// which image build they are about to configure.
+pub fn build_info(
+    image: &Path,
+    decompressed_image: &Path,
+    options: &FileOptions,
+) -> Result<BuildInfo> {
+    let os_release = read_file_from_image(
+        OS_RELEASE_PATH,
+        OS_RELEASE_PARTITION,
+        decompressed_image,
+        options,
+    )
+    .context("build_info: could not read os-release info")?;
+    let os_release = parse_os_release(&os_release);
+    let field = |keys: &[&str]| keys.iter().find_map(|key| os_release.get(*key).cloned());
+
+    Ok(BuildInfo {
+        image: image.to_path_buf(),
+        distro: field(&["NAME", "ID"]),
+        version: field(&["VERSION_ID", "VERSION"]),
+        build_id: field(&["BUILD_ID"]),
+        arch: field(&["OMNECT_TARGET_ARCH"]),
+        label: crate::file::build_info_label(decompressed_image, options)?,
+        os_release,
+    })
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PartitionSize {
+    pub partition: String,
+    pub size: u64,
+    pub used: u64,
+    pub free: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompressedSize {
+    pub compression: String,
+    pub size: u64,
+    /// compressed size relative to the uncompressed image
+    pub ratio: f64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SizeReport {
+    pub image: PathBuf,
+    pub image_size: u64,
+    pub compression: Option<String>,
+    pub uncompressed_size: u64,
+    pub partitions: Vec<PartitionSize>,
+    /// only filled if trial compression was requested
+    pub compressed: Vec<CompressedSize>,
+}
+
+impl std::fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "image:             {}", self.image.to_string_lossy())?;
+        writeln!(
+            f,
+            "image size:        {} ({})",
+            self.image_size,
+            self.compression.as_deref().unwrap_or("uncompressed")
+        )?;
+        write!(f, "uncompressed size: {}", self.uncompressed_size)?;
+
+        write!(
+            f,
+            "\n\n{:<10} {:>12} {:>12} {:>12}",
+            "partition", "size", "used", "free"
+        )?;
+        for p in &self.partitions {
+            write!(
+                f,
+                "\n{:<10} {:>12} {:>12} {:>12}",
+                p.partition, p.size, p.used, p.free
+            )?;
+        }
+
+        if !self.compressed.is_empty() {
+            write!(f, "\n\n{:<10} {:>12} {:>7}", "codec", "size", "ratio")?;
+            for c in &self.compressed {
+                write!(
+                    f,
+                    "\n{:<10} {:>12} {:>6.1}%",
+                    c.compression,
+                    c.size,
+                    c.ratio * 100.0
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reports the size of `image`, the size of its decompressed copy
+/// `decompressed_image`, the usage of its partitions and, if `trial_compress`
+/// is set, the size `decompressed_image` is compressed to by each codec.
+pub fn size_report(
+    image: &Path,
+    image_format: Option<ImageFormat>,
+    decompressed_image: &Path,
+    trial_compress: bool,
+    options: &FileOptions,
+) -> Result<SizeReport> {
+    let size = |file: &Path| -> Result<u64> {
+        Ok(std::fs::metadata(file)
+            .context(format!(
+                "size_report: cannot get size of {}",
+                file.to_string_lossy()
+            ))?
+            .len())
+    };
+    let uncompressed_size = size(decompressed_image)?;
+
+    let partitions = partition_usage(decompressed_image, options)?
+        .into_iter()
+        .map(|(partition, size, free)| PartitionSize {
+            partition,
+            size,
+            used: size.saturating_sub(free),
+            free,
+        })
+        .collect();
+
+    let mut compressed = vec![];
+
+    if trial_compress {
+        let dir = decompressed_image
+            .parent()
+            .context("size_report: cannot get directory of image")?;
+
+        for codec in ["xz", "bzip2", "gzip"] {
+            let compression = Compression::from_str(codec)?;
+            let trial = tempfile::NamedTempFile::new_in(dir)
+                .context("size_report: cannot create trial compression file")?;
+
+            compress_to(decompressed_image, trial.path(), &compression)?;
+
+            let size = size(trial.path())?;
+            compressed.push(CompressedSize {
+                compression: compression.extension().to_string(),
+                size,
+                ratio: size as f64 / uncompressed_size.max(1) as f64,
+            });
+        }
+    }
+
+    Ok(SizeReport {
+        image: image.to_path_buf(),
+        image_size: size(image)?,
+        compression: Compression::from_file_or_format(&image.to_path_buf(), image_format)?
+            .map(|c| c.extension().to_string()),
+        uncompressed_size,
+        partitions,
+        compressed,
+    })
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PartitionOffset {
+    pub partition: String,
+    pub num: String,
+    /// first sector of 512 bytes
+    pub start_sector: u64,
+    /// last sector of 512 bytes (inclusive)
+    pub end_sector: u64,
+    /// offset in bytes
+    pub offset: u64,
+    /// size in bytes
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OffsetReport {
+    pub image: PathBuf,
+    pub partitions: Vec<PartitionOffset>,
+}
+
+impl std::fmt::Display for OffsetReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<10} {:<3} {:>12} {:>12} {:>14} {:>14}",
+            "partition", "#", "start", "end", "offset", "size"
+        )?;
+        for p in &self.partitions {
+            write!(
+                f,
+                "\n{:<10} {:<3} {:>12} {:>12} {:>14} {:>14}",
+                p.partition, p.num, p.start_sector, p.end_sector, p.offset, p.size
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Reports the sectors and byte offsets of `partition` or, if not given, of
+/// the boot, rootA, cert and factory partitions in the (decompressed) image
+/// `decompressed_image`, as resolved by file operations.
+pub fn partition_offsets(
+    image: &Path,
+    decompressed_image: &Path,
+    partition: Option<Partition>,
+    options: &FileOptions,
+) -> Result<OffsetReport> {
+    let partitions = match partition {
+        Some(partition) => vec![partition],
+        None => [
+            Partition::boot,
+            Partition::rootA,
+            Partition::cert,
+            Partition::factory,
+        ]
+        .into_iter()
+        .filter(|p| has_partition(decompressed_image, p, options).unwrap_or(false))
+        .collect(),
+    };
+
+    let partitions = partitions
+        .into_iter()
+        .map(|partition| {
+            let (num, start_sector, end_sector) =
+                partition_sectors(decompressed_image, &partition, options)?;
+
+            Ok(PartitionOffset {
+                partition: partition.to_string(),
+                num,
+                start_sector,
+                end_sector,
+                offset: start_sector * 512,
+                size: (end_sector + 1 - start_sector) * 512,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(OffsetReport {
+        image: image.to_path_buf(),
+        partitions,
+    })
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompressedDelta {
+    pub compression: String,
+    pub baseline_size: u64,
+    pub size: u64,
+    /// size minus baseline size
+    pub delta: i64,
+    pub block_size: usize,
+    pub blocks: u64,
+    /// blocks whose content isn't contained in the baseline at any block offset
+    pub changed_blocks: u64,
+}
+
+impl std::fmt::Display for CompressedDelta {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "compression:    {}", self.compression)?;
+        writeln!(f, "baseline size:  {}", self.baseline_size)?;
+        writeln!(f, "size:           {}", self.size)?;
+        writeln!(f, "delta:          {:+}", self.delta)?;
+        write!(
+            f,
+            "changed blocks: {} of {} ({} bytes each)",
+            self.changed_blocks, self.blocks, self.block_size
+        )
+    }
+}
+
+/// Compares the compressed image `compressed` with the compressed `baseline`,
+/// e.g. the image before a modification, in order to estimate the payload of a
+/// block based delta update. Blocks moved within the image aren't counted as
+/// changed.
+pub fn compressed_delta(
+    baseline: &Path,
+    compressed: &Path,
+    compression: &Compression,
+) -> Result<CompressedDelta> {
+    let baseline_blocks = block_hashes(baseline)?;
+    let blocks = block_hashes(compressed)?;
+    let known: std::collections::HashSet<&[u8; 32]> = baseline_blocks.iter().collect();
+    let size = |file: &Path| -> Result<u64> {
+        Ok(std::fs::metadata(file)
+            .context(format!(
+                "compressed_delta: cannot get size of {}",
+                file.to_string_lossy()
+            ))?
+            .len())
+    };
+    let baseline_size = size(baseline)?;
+    let compressed_size = size(compressed)?;
+
+    Ok(CompressedDelta {
+        compression: compression.extension().to_string(),
+        baseline_size,
+        size: compressed_size,
+        delta: compressed_size as i64 - baseline_size as i64,
+        block_size: DELTA_BLOCK_SIZE,
+        blocks: blocks.len() as u64,
+        changed_blocks: blocks.iter().filter(|b| !known.contains(b)).count() as u64,
+    })
+}
+
+fn block_hashes(file: &Path) -> Result<Vec<[u8; 32]>> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(file).context(format!(
+        "block_hashes: cannot open {}",
+        file.to_string_lossy()
+    ))?);
+    let mut hashes = vec![];
+
+    loop {
+        let mut block = Vec::with_capacity(DELTA_BLOCK_SIZE);
+        (&mut reader)
+            .take(DELTA_BLOCK_SIZE as u64)
+            .read_to_end(&mut block)
+            .context(format!(
+                "block_hashes: cannot read {}",
+                file.to_string_lossy()
+            ))?;
+
+        if block.is_empty() {
+            return Ok(hashes);
+        }
+
+        hashes.push(Sha256::digest(&block).into());
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Bmap {
+    image_size: u64,
+    block_size: u64,
+    /// sha256 of the bmap file with this checksum replaced by zeros
+    file_checksum: Option<String>,
+    /// first and last block (inclusive) and sha256 of mapped block ranges
+    ranges: Vec<(u64, u64, String)>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BmapVerification {
+    pub image: PathBuf,
+    pub bmap: PathBuf,
+    pub image_size: u64,
+    /// image size recorded in the bmap
+    pub bmap_image_size: u64,
+    /// `None` if the bmap records no checksum of itself
+    pub bmap_checksum_valid: Option<bool>,
+    pub block_size: u64,
+    pub mapped_blocks: u64,
+    /// block ranges (first-last) whose checksum doesn't match the image
+    pub mismatched_ranges: Vec<String>,
+}
+
+impl BmapVerification {
+    pub fn matches(&self) -> bool {
+        self.image_size == self.bmap_image_size
+            && self.bmap_checksum_valid != Some(false)
+            && self.mismatched_ranges.is_empty()
+    }
+}
+
+impl std::fmt::Display for BmapVerification {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "image size:        {}", self.image_size)?;
+        writeln!(f, "bmap image size:   {}", self.bmap_image_size)?;
+        writeln!(
+            f,
+            "bmap checksum:     {}",
+            match self.bmap_checksum_valid {
+                Some(true) => "valid",
+                Some(false) => "invalid",
+                None => "not recorded",
+            }
+        )?;
+        writeln!(
+            f,
+            "mapped blocks:     {} ({} bytes each)",
+            self.mapped_blocks, self.block_size
+        )?;
+        write!(
+            f,
+            "mismatched ranges: {}",
+            match self.mismatched_ranges.is_empty() {
+                true => "none".to_string(),
+                false => self.mismatched_ranges.join(", "),
+            }
+        )
+    }
+}
+
+/// Recomputes the checksums of the mapped block ranges of the (decompressed)
+/// image `decompressed_image` and compares them with the ones recorded in
+/// `bmap`, in order to detect a stale bmap which would make bmaptool skip
+/// changed regions when flashing. The checksum of the bmap file itself is
+/// verified as well if recorded.
+pub fn verify_bmap(
+    image: &Path,
+    decompressed_image: &Path,
+    bmap: &Path,
+) -> Result<BmapVerification> {
+    use std::io::{Seek, SeekFrom};
+
+    let content = std::fs::read_to_string(bmap).context(format!(
+        "verify_bmap: cannot read {}",
+        bmap.to_string_lossy()
+    ))?;
+    let parsed = parse_bmap(&content).context(format!(
+        "verify_bmap: invalid bmap {}",
+        bmap.to_string_lossy()
+    ))?;
+
+    let bmap_checksum_valid = parsed.file_checksum.as_ref().map(|checksum| {
+        let zeroed = content.replace(checksum.as_str(), &"0".repeat(checksum.len()));
+        crate::manifest::hex(&Sha256::digest(zeroed.as_bytes())).eq_ignore_ascii_case(checksum)
+    });
+
+    let mut file = std::fs::File::open(decompressed_image).context(format!(
+        "verify_bmap: cannot open {}",
+        decompressed_image.to_string_lossy()
+    ))?;
+    let image_size = file
+        .metadata()
+        .context("verify_bmap: cannot get image size")?
+        .len();
+    let mut mapped_blocks = 0;
+    let mut mismatched_ranges = vec![];
+
+    for (first, last, checksum) in &parsed.ranges {
+        let blocks = last + 1 - first;
+        let mut hasher = Sha256::new();
+
+        // the last block of the image may be partial, blocks beyond the image
+        // result in a mismatch
+        file.seek(SeekFrom::Start(first * parsed.block_size))
+            .context("verify_bmap: cannot seek in image")?;
+        std::io::copy(
+            &mut (&mut file).take(blocks * parsed.block_size),
+            &mut hasher,
+        )
+        .context("verify_bmap: cannot read image")?;
+
+        if !crate::manifest::hex(&hasher.finalize()).eq_ignore_ascii_case(checksum) {
+            mismatched_ranges.push(format!("{first}-{last}"));
+        }
+
+        mapped_blocks += blocks;
+    }
+
+    Ok(BmapVerification {
+        image: image.to_path_buf(),
+        bmap: bmap.to_path_buf(),
+        image_size,
+        bmap_image_size: parsed.image_size,
+        bmap_checksum_valid,
+        block_size: parsed.block_size,
+        mapped_blocks,
+        mismatched_ranges,
+    })
+}
+
+/// Parses the xml of a bmap file as created by bmaptool. Only sha256
+/// checksums, i.e. bmap format 1.4 and later, are supported.
+fn parse_bmap(content: &str) -> Result<Bmap> {
+    let tag = |name: &str| -> Option<String> {
+        Regex::new(&format!(r"<{name}>\s*([^<]*?)\s*</{name}>"))
+            .unwrap()
+            .captures(content)
+            .map(|c| c[1].to_string())
+    };
+    let number = |name: &str| -> Result<u64> {
+        let value = tag(name).context(format!("missing <{name}>"))?;
+
+        value
+            .parse::<u64>()
+            .context(format!("invalid <{name}> {value}"))
+    };
+
+    let checksum_type = tag("ChecksumType").unwrap_or("sha1".to_string());
+    anyhow::ensure!(
+        checksum_type == "sha256",
+        "unsupported checksum type {checksum_type}"
+    );
+
+    let block_size = number("BlockSize")?;
+    anyhow::ensure!(block_size > 0, "invalid <BlockSize> 0");
+
+    let range_regex =
+        Regex::new(r#"<Range\s+chksum="([0-9a-fA-F]+)"\s*>\s*(\d+)\s*(?:-\s*(\d+)\s*)?</Range>"#)
+            .unwrap();
+    let ranges = range_regex
+        .captures_iter(content)
+        .map(|c| {
+            let first = c[2].parse::<u64>().context("invalid range")?;
+            let last = match c.get(3) {
+                Some(last) => last.as_str().parse::<u64>().context("invalid range")?,
+                None => first,
+            };
+
+            anyhow::ensure!(first <= last, "invalid range {first}-{last}");
+
+            Ok((first, last, c[1].to_string()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    anyhow::ensure!(
+        ranges.len() == content.matches("<Range").count(),
+        "cannot parse all block ranges"
+    );
+
+    Ok(Bmap {
+        image_size: number("ImageSize")?,
+        block_size,
+        file_checksum: tag("BmapFileChecksum"),
+        ranges,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BootLoader {
+    Grub,
+    Extlinux,
+    SystemdBoot,
+    /// binary u-boot environment with the command line in `bootargs`
+    UbootEnv,
+    /// plain text file with the command line in its first line
+    CmdlineFile,
+}
+
+impl BootLoader {
+    fn from_config(config: &str) -> BootLoader {
+        let path = Path::new(config);
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default();
+
+        match (path.file_name().and_then(|f| f.to_str()), extension) {
+            (Some("extlinux.conf"), _) => BootLoader::Extlinux,
+            (_, "cfg") => BootLoader::Grub,
+            (_, "env") => BootLoader::UbootEnv,
+            (_, "conf") if config.starts_with(LOADER_ENTRIES_DIR) => BootLoader::SystemdBoot,
+            _ => BootLoader::CmdlineFile,
+        }
+    }
+}
+
+/// Change of the kernel command line applied by `set_cmdline`.
+#[derive(Debug)]
+pub enum CmdlineEdit {
+    /// append the parameters not contained yet
+    Append(String),
+    /// replace the command line
+    Set(String),
+}
+
+impl CmdlineEdit {
+    fn apply(&self, cmdline: &str) -> Result<String> {
+        let (CmdlineEdit::Append(fragment) | CmdlineEdit::Set(fragment)) = self;
+
+        anyhow::ensure!(
+            !fragment.chars().any(char::is_control),
+            "kernel command line must not contain control characters, e.g. line breaks"
+        );
+        anyhow::ensure!(
+            fragment.matches('"').count() % 2 == 0,
+            "kernel command line has unbalanced quotes: {fragment}"
+        );
+
+        let params = match self {
+            CmdlineEdit::Append(fragment) => {
+                let mut params = split_cmdline(cmdline);
+                for param in split_cmdline(fragment) {
+                    if !params.contains(&param) {
+                        params.push(param);
+                    }
+                }
+                params
+            }
+            CmdlineEdit::Set(fragment) => split_cmdline(fragment),
+        };
+        let cmdline = params.join(" ");
+
+        anyhow::ensure!(
+            cmdline.len() <= CMDLINE_MAX_LEN,
+            "kernel command line exceeds {CMDLINE_MAX_LEN} bytes ({}): {cmdline}",
+            cmdline.len()
+        );
+
+        Ok(cmdline)
+    }
+}
+
+/// Checks that the kernels, initrds and device trees referenced by the boot
+/// loader configs in the boot partition (grub.cfg, extlinux.conf and
+/// systemd-boot entries) exist in the boot partition or in rootA, e.g. after
+/// files were copied to the image. Fails listing the dangling references.
+/// References depending on grub variables can't be resolved and are skipped.
+pub fn check_boot(image: &Path, options: &FileOptions) -> Result<()> {
+    let mut candidates: Vec<(String, BootLoader)> = GRUB_CONFIGS
+        .iter()
+        .map(|c| (c.to_string(), BootLoader::Grub))
+        .chain(
+            EXTLINUX_CONFIGS
+                .iter()
+                .map(|c| (c.to_string(), BootLoader::Extlinux)),
+        )
+        .collect();
+
+    for entry in list_dir(LOADER_ENTRIES_DIR, &Partition::boot, image, options)? {
+        if entry.to_lowercase().ends_with(".conf") {
+            candidates.push((
+                format!("{LOADER_ENTRIES_DIR}/{entry}"),
+                BootLoader::SystemdBoot,
+            ));
+        }
+    }
+
+    let exists = paths_exist(
+        &candidates
+            .iter()
+            .map(|(c, _)| c.as_str())
+            .collect::<Vec<_>>(),
+        &Partition::boot,
+        image,
+        options,
+    )?;
+    let configs: Vec<_> = candidates
+        .into_iter()
+        .zip(exists)
+        .filter_map(|(candidate, exists)| exists.then_some(candidate))
+        .collect();
+
+    if configs.is_empty() {
+        warn!("check_boot: no boot loader config found in boot partition");
+        return Ok(());
+    }
+
+    let mut references: Vec<(String, String)> = vec![];
+
+    for (config, loader) in &configs {
+        let content = read_file_from_image(config, Partition::boot, image, options)?;
+
+        for path in boot_references(*loader, &content) {
+            let resolved = resolve_boot_path(config, *loader, &path);
+
+            if resolved.contains('$') {
+                info!("check_boot: skip {path} in {config}: variables aren't resolved");
+                continue;
+            }
+
+            references.push((config.clone(), resolved));
+        }
+    }
+
+    let paths: Vec<&str> = references.iter().map(|(_, p)| p.as_str()).collect();
+    let on_boot = paths_exist(&paths, &Partition::boot, image, options)?;
+    let missing: Vec<&str> = paths
+        .iter()
+        .zip(&on_boot)
+        .filter_map(|(p, exists)| (!exists).then_some(*p))
+        .collect();
+    let on_root = paths_exist(&missing, &Partition::rootA, image, options)?;
+    let dangling: Vec<String> = references
+        .iter()
+        .zip(on_boot)
+        .filter(|(_, exists)| !exists)
+        .zip(on_root)
+        .filter(|(_, exists)| !exists)
+        .map(|(((config, path), _), _)| format!("  boot:{config}: {path}"))
+        .collect();
+
+    anyhow::ensure!(
+        dangling.is_empty(),
+        "check_boot: dangling boot loader references:\n{}",
+        dangling.join("\n")
+    );
+
+    info!(
+        "check_boot: {} references of {} boot loader config(s) resolved",
+        references.len(),
+        configs.len()
+    );
+
+    Ok(())
+}
+
+/// Appends to or replaces the kernel command line in `config` of the boot
+/// partition, e.g. to add `console=`. `config` may be an extlinux.conf, where
+/// all APPEND lines are changed, a grub.cfg, where the arguments of all linux
+/// commands are changed, a systemd-boot entry, a u-boot environment file
+/// (`bootargs`) or a plain command line file. If omitted, the single one of
+/// these existing at its default location is used.
+pub fn set_cmdline(
+    image: &Path,
+    config: Option<&Path>,
+    edit: &CmdlineEdit,
+    options: &FileOptions,
+) -> Result<()> {
+    let config = match config {
+        Some(config) => config.to_string_lossy().to_string(),
+        None => {
+            let candidates: Vec<&str> = EXTLINUX_CONFIGS
+                .into_iter()
+                .chain(GRUB_CONFIGS)
+                .chain([UBOOT_ENV, CMDLINE_FILE])
+                .collect();
+            let found: Vec<&str> = candidates
+                .iter()
+                .zip(paths_exist(&candidates, &Partition::boot, image, options)?)
+                .filter_map(|(c, exists)| exists.then_some(*c))
+                .collect();
+
+            match found[..] {
+                [config] => config.to_string(),
+                [] => anyhow::bail!(
+                    "set_cmdline: no kernel command line found in boot partition, choose one via --config"
+                ),
+                _ => anyhow::bail!(
+                    "set_cmdline: several kernel command lines found in boot partition ({}), choose one via --config",
+                    found.join(", ")
+                ),
+            }
+        }
+    };
+
+    let cmdline_file = get_file_path(image, "cmdline")?;
+
+    copy_from_image(
+        &[FileCopyFromParams::new(
+            Path::new(&config),
+            Partition::boot,
+            &cmdline_file,
+        )],
+        image,
+        options,
+    )
+    .context(format!(
+        "set_cmdline: cannot read {config} from boot partition"
+    ))?;
+
+    let content = std::fs::read(&cmdline_file).context("set_cmdline: cannot read cmdline file")?;
+    let content = match BootLoader::from_config(&config) {
+        BootLoader::UbootEnv => edit_uboot_env(&content, edit),
+        loader => String::from_utf8(content)
+            .context("set_cmdline: config isn't valid utf-8")
+            .and_then(|content| edit_cmdline_lines(loader, &content, edit))
+            .map(String::into_bytes),
+    }
+    .context(format!(
+        "set_cmdline: cannot change kernel command line in {config}"
+    ))?;
+
+    std::fs::write(&cmdline_file, content).context("set_cmdline: cannot write cmdline file")?;
+
+    copy_to_image(
+        &[FileCopyToParams::new(
+            &cmdline_file,
+            Partition::boot,
+            Path::new(&config),
+        )],
+        image,
+        options,
+    )?;
+
+    info!("set_cmdline: changed kernel command line in boot:{config}");
+
+    Ok(())
+}
+
+/// Applies `edit` to the command lines of a text config and returns the result.
+fn edit_cmdline_lines(loader: BootLoader, content: &str, edit: &CmdlineEdit) -> Result<String> {
+    let mut lines = vec![];
+    let mut edited = 0;
+
+    for (i, line) in content.lines().enumerate() {
+        let keyword = line.split_whitespace().next().unwrap_or_default();
+
+        // number of tokens preceding the command line
+        let prefix_tokens = match (loader, keyword.to_lowercase().as_str()) {
+            (BootLoader::Extlinux, "append") => Some(1),
+            (BootLoader::Grub, "linux" | "linuxefi" | "linux16") => Some(2),
+            (BootLoader::SystemdBoot, "options") => Some(1),
+            (BootLoader::CmdlineFile, _) if i == 0 => Some(0),
+            _ => None,
+        };
+
+        let Some(prefix_tokens) = prefix_tokens else {
+            lines.push(line.to_string());
+            continue;
+        };
+
+        let offset = token_offset(line, prefix_tokens);
+        let prefix = line[..offset].trim_end();
+        let cmdline = edit.apply(&line[offset..])?;
+
+        lines.push(
+            [prefix, &cmdline]
+                .into_iter()
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+        edited += 1;
+    }
+
+    if loader == BootLoader::CmdlineFile && edited == 0 {
+        lines.push(edit.apply("")?);
+        edited += 1;
+    }
+
+    anyhow::ensure!(edited > 0, "no kernel command line found");
+
+    Ok(format!("{}\n", lines.join("\n")))
+}
+
+/// Returns the byte offset of the `n`-th whitespace separated token of `line`,
+/// or the length of `line` if it has fewer tokens.
+fn token_offset(line: &str, n: usize) -> usize {
+    let mut tokens = 0;
+    let mut in_token = false;
+
+    for (i, c) in line.char_indices() {
+        if c.is_whitespace() {
+            in_token = false;
+        } else if !in_token {
+            if tokens == n {
+                return i;
+            }
+            tokens += 1;
+            in_token = true;
+        }
+    }
+
+    line.len()
+}
+
+/// Splits a kernel command line into parameters. Like the kernel, whitespace
+/// enclosed in double quotes doesn't separate parameters.
+fn split_cmdline(cmdline: &str) -> Vec<String> {
+    let mut params = vec![];
+    let mut param = String::new();
+    let mut quoted = false;
+
+    for c in cmdline.chars() {
+        if c == '"' {
+            quoted = !quoted;
+        }
+
+        if c.is_whitespace() && !quoted {
+            if !param.is_empty() {
+                params.push(std::mem::take(&mut param));
+            }
+        } else {
+            param.push(c);
+        }
+    }
+
+    if !param.is_empty() {
+        params.push(param);
+    }
+
+    params
+}
+
+/// Applies `edit` to `bootargs` of a u-boot environment and returns the
+/// result with updated crc. The environment consists of a crc32, a flags byte
+/// if it is redundant and `key=value` pairs terminated by a nul byte each,
+/// padded to the size of the environment.
+fn edit_uboot_env(env: &[u8], edit: &CmdlineEdit) -> Result<Vec<u8>> {
+    anyhow::ensure!(env.len() > 5, "u-boot environment is too small");
+
+    let crc = u32::from_le_bytes(env[..4].try_into().unwrap());
+    let data_offset = if crc32(&env[4..]) == crc {
+        4
+    } else if crc32(&env[5..]) == crc {
+        5
+    } else {
+        anyhow::bail!("u-boot environment has an invalid crc");
+    };
+    let data = &env[data_offset..];
+
+    let mut vars: Vec<String> = vec![];
+    for var in data.split(|b| *b == 0).take_while(|var| !var.is_empty()) {
+        vars.push(String::from_utf8(var.to_vec()).context("u-boot environment isn't valid utf-8")?);
+    }
+
+    match vars.iter_mut().find(|var| var.starts_with("bootargs=")) {
+        Some(bootargs) => *bootargs = format!("bootargs={}", edit.apply(&bootargs[9..])?),
+        None => vars.push(format!("bootargs={}", edit.apply("")?)),
+    }
+
+    let mut new_data: Vec<u8> = vars.join("\0").into_bytes();
+    new_data.extend([0, 0]);
+
+    anyhow::ensure!(
+        new_data.len() <= data.len(),
+        "u-boot environment exceeds its size of {} bytes",
+        data.len()
+    );
+    new_data.resize(data.len(), 0);
+
+    let mut new_env = crc32(&new_data).to_le_bytes().to_vec();
+    new_env.extend(&env[4..data_offset]);
+    new_env.extend(new_data);
+
+    Ok(new_env)
+}
+
+/// Computes the crc32 (IEEE 802.3) as used by u-boot.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+/// Returns the paths of kernels, initrds and device trees referenced by a boot
+/// loader config, without kernel command lines.
+fn boot_references(loader: BootLoader, content: &str) -> Vec<String> {
+    let mut paths = vec![];
+
+    for line in content.lines() {
+        let mut tokens = line.split_whitespace();
+        let Some(key) = tokens.next().filter(|k| !k.starts_with('#')) else {
+            continue;
+        };
+        let args: Vec<&str> = tokens
+            .map(|t| t.trim_matches(|c| c == '"' || c == '\''))
+            .collect();
+
+        let values: Vec<&str> = match (loader, key.to_lowercase().as_str()) {
+            // the arguments following the kernel are its command line
+            (BootLoader::Grub, "linux" | "linuxefi" | "linux16" | "devicetree") => {
+                args.into_iter().take(1).collect()
+            }
+            (BootLoader::Grub, "initrd" | "initrdefi" | "initrd16") => args,
+            (BootLoader::Extlinux, "kernel" | "linux" | "fdt" | "devicetree" | "fdtdir") => {
+                args.into_iter().take(1).collect()
+            }
+            // several initrds are separated by commas
+            (BootLoader::Extlinux, "initrd") => args.iter().flat_map(|a| a.split(',')).collect(),
+            (BootLoader::Extlinux, "fdtoverlays") => args,
+            (BootLoader::SystemdBoot, "linux" | "efi" | "devicetree") => {
+                args.into_iter().take(1).collect()
+            }
+            (BootLoader::SystemdBoot, "initrd" | "devicetree-overlay") => args,
+            _ => vec![],
+        };
+
+        paths.extend(
+            values
+                .into_iter()
+                .filter(|v| !v.is_empty())
+                .map(str::to_string),
+        );
+    }
+
+    paths
+}
+
+/// Resolves `path` referenced by `config` to an absolute path: grub paths may
+/// be prefixed by a device, e.g. "(hd0,gpt1)/Image", and relative extlinux
+/// paths are relative to the directory of extlinux.conf, as done by u-boot.
+fn resolve_boot_path(config: &str, loader: BootLoader, path: &str) -> String {
+    let path = match (loader, path.strip_prefix('(')) {
+        (BootLoader::Grub, Some(rest)) => rest.split_once(')').map_or(path, |(_, p)| p),
+        _ => path,
+    };
+
+    let path = if path.starts_with('/') {
+        PathBuf::from(path)
+    } else {
+        Path::new(config)
+            .parent()
+            .unwrap_or(Path::new("/"))
+            .join(path)
+    };
+
+    let mut components: Vec<String> = vec![];
+
+    for component in path.components() {
+        match component {
+            std::path::Component::Normal(c) => components.push(c.to_string_lossy().into()),
+            std::path::Component::ParentDir => {
+                components.pop();
+            }
+            _ => {}
+        }
+    }
+
+    format!("/{}", components.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn os_release_fields() {
+        let os_release = parse_os_release(
+            "# omnect-os\nNAME=\"OMNECT-gateway-devel\"\nID=omnect-os\nVERSION_ID='4.0.17'\nPRETTY_NAME=\"say \\\"hi\\\" \\$HOME\"\n\nno assignment\n",
+        );
+
+        assert_eq!(
+            os_release,
+            BTreeMap::from([
+                ("ID".to_string(), "omnect-os".to_string()),
+                ("NAME".to_string(), "OMNECT-gateway-devel".to_string()),
+                ("PRETTY_NAME".to_string(), "say \"hi\" $HOME".to_string()),
+                ("VERSION_ID".to_string(), "4.0.17".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn boot_loader_references() {
+        let grub = "set timeout=3\nmenuentry 'omnect-os' {\n    linux (hd0,gpt2)/boot/bzImage root=PARTLABEL=rootA rootwait\n    initrd /boot/initrd /boot/microcode.cpio\n}\n# linux /old\nlinux ($root)/boot/bzImage-$version\n";
+        assert_eq!(
+            boot_references(BootLoader::Grub, grub),
+            vec![
+                "(hd0,gpt2)/boot/bzImage",
+                "/boot/initrd",
+                "/boot/microcode.cpio",
+                "($root)/boot/bzImage-$version"
+            ]
+        );
+
+        let extlinux = "DEFAULT omnect\nLABEL omnect\n  KERNEL ../Image\n  FDT /bcm2711-rpi-4-b.dtb\n  INITRD /initrd1,/initrd2\n  APPEND root=/dev/mmcblk0p2\n";
+        assert_eq!(
+            boot_references(BootLoader::Extlinux, extlinux),
+            vec!["../Image", "/bcm2711-rpi-4-b.dtb", "/initrd1", "/initrd2"]
+        );
+
+        let entry =
+            "title omnect-os\nlinux /vmlinuz\ninitrd /initrd.img\noptions root=PARTLABEL=rootA\n";
+        assert_eq!(
+            boot_references(BootLoader::SystemdBoot, entry),
+            vec!["/vmlinuz", "/initrd.img"]
+        );
+    }
+
+    #[test]
+    fn cmdline_edit() {
+        let append = CmdlineEdit::Append("console=ttyS0 quiet".to_string());
+        assert_eq!(
+            append.apply("root=/dev/mmcblk0p2 quiet").unwrap(),
+            "root=/dev/mmcblk0p2 quiet console=ttyS0"
+        );
+        assert_eq!(append.apply("").unwrap(), "console=ttyS0 quiet");
+        assert_eq!(
+            CmdlineEdit::Set(" rootwait  dyndbg=\"file x.c +p\" ".to_string())
+                .apply("quiet")
+                .unwrap(),
+            "rootwait dyndbg=\"file x.c +p\""
+        );
+        assert!(CmdlineEdit::Append("a\nb".to_string()).apply("").is_err());
+        assert!(CmdlineEdit::Append("a=\"b".to_string()).apply("").is_err());
+        assert!(CmdlineEdit::Append("x".repeat(CMDLINE_MAX_LEN))
+            .apply("quiet")
+            .is_err());
+
+        let extlinux = "LABEL a\n  KERNEL /Image\n  APPEND root=/dev/sda2\nLABEL b\n  append\n";
+        assert_eq!(
+            edit_cmdline_lines(BootLoader::Extlinux, extlinux, &append).unwrap(),
+            "LABEL a\n  KERNEL /Image\n  APPEND root=/dev/sda2 console=ttyS0 quiet\nLABEL b\n  append console=ttyS0 quiet\n"
+        );
+        assert!(edit_cmdline_lines(BootLoader::Extlinux, "LABEL a\n", &append).is_err());
+
+        let grub =
+            "menuentry 'a' {\n\tlinux\t($root)/bzImage root=/dev/sda2\n\tinitrd /initrd\n}\n";
+        assert_eq!(
+            edit_cmdline_lines(BootLoader::Grub, grub, &append).unwrap(),
+            "menuentry 'a' {\n\tlinux\t($root)/bzImage root=/dev/sda2 console=ttyS0 quiet\n\tinitrd /initrd\n}\n"
+        );
+
+        assert_eq!(
+            edit_cmdline_lines(
+                BootLoader::CmdlineFile,
+                "quiet\n",
+                &CmdlineEdit::Set("root=/dev/sda2".to_string())
+            )
+            .unwrap(),
+            "root=/dev/sda2\n"
+        );
+        assert_eq!(
+            edit_cmdline_lines(BootLoader::CmdlineFile, "", &append).unwrap(),
+            "console=ttyS0 quiet\n"
+        );
+    }
+
+    #[test]
+    fn uboot_env_bootargs() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let env = |data: &[u8], redundant: bool| {
+            let mut data = data.to_vec();
+            data.resize(64, 0);
+            let mut env = crc32(&data).to_le_bytes().to_vec();
+            if redundant {
+                env.push(1);
+            }
+            env.extend(data);
+            env
+        };
+        let append = CmdlineEdit::Append("quiet".to_string());
+
+        for redundant in [false, true] {
+            assert_eq!(
+                edit_uboot_env(
+                    &env(b"bootdelay=0\0bootargs=console=ttyS0\0\0", redundant),
+                    &append
+                )
+                .unwrap(),
+                env(b"bootdelay=0\0bootargs=console=ttyS0 quiet\0\0", redundant)
+            );
+        }
+
+        assert_eq!(
+            edit_uboot_env(&env(b"bootdelay=0\0\0", false), &append).unwrap(),
+            env(b"bootdelay=0\0bootargs=quiet\0\0", false)
+        );
+
+        let mut corrupted = env(b"bootdelay=0\0\0", false);
+        corrupted[0] ^= 1;
+        assert!(edit_uboot_env(&corrupted, &append).is_err());
+        assert!(edit_uboot_env(
+            &env(b"bootdelay=0\0\0", false),
+            &CmdlineEdit::Set("x".repeat(60))
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn compressed_block_delta() {
+        let dir = tempfile::tempdir().unwrap();
+        let baseline = dir.path().join("baseline.gz");
+        let compressed = dir.path().join("compressed.gz");
+        let block = |b: u8| vec![b; DELTA_BLOCK_SIZE];
+
+        std::fs::write(&baseline, [block(1), block(2), block(3)].concat()).unwrap();
+        // a moved, a changed and a partial block
+        std::fs::write(&compressed, [block(3), block(4), vec![5; 10]].concat()).unwrap();
+
+        let delta = compressed_delta(
+            &baseline,
+            &compressed,
+            &Compression::gzip { rsyncable: false },
+        )
+        .unwrap();
+
+        assert_eq!(delta.compression, "gz");
+        assert_eq!(delta.delta, 10 - DELTA_BLOCK_SIZE as i64);
+        assert_eq!(delta.blocks, 3);
+        assert_eq!(delta.changed_blocks, 2);
+    }
+
+    #[test]
+    fn bmap_verification() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.wic");
+        let bmap = dir.path().join("image.wic.bmap");
+        let sha256 = |data: &[u8]| crate::manifest::hex(&Sha256::digest(data));
+
+        // blocks 0-1 and 3 mapped, block 2 a hole, block 4 partial
+        let content = [vec![1; 8], vec![0; 4], vec![3; 4], vec![4; 2]].concat();
+        std::fs::write(&image, &content).unwrap();
+
+        let xml = format!(
+            "<?xml version=\"1.0\" ?>\n<bmap version=\"2.0\">\n    <ImageSize> {} </ImageSize>\n    <BlockSize> 4 </BlockSize>\n    <BlocksCnt> 5 </BlocksCnt>\n    <MappedBlocksCnt> 4 </MappedBlocksCnt>\n    <ChecksumType> sha256 </ChecksumType>\n    <BmapFileChecksum> {} </BmapFileChecksum>\n    <BlockMap>\n        <Range chksum=\"{}\"> 0-1 </Range>\n        <Range chksum=\"{}\"> 3-4 </Range>\n    </BlockMap>\n</bmap>\n",
+            content.len(),
+            "0".repeat(64),
+            sha256(&content[..8]),
+            sha256(&content[12..]),
+        );
+        let xml = xml.replace(&"0".repeat(64), &sha256(xml.as_bytes()));
+        std::fs::write(&bmap, &xml).unwrap();
+
+        let verification = verify_bmap(&image, &image, &bmap).unwrap();
+        assert!(verification.matches());
+        assert_eq!(verification.bmap_checksum_valid, Some(true));
+        assert_eq!(verification.mapped_blocks, 4);
+
+        // a changed hole isn't covered by the bmap
+        let mut changed = content.clone();
+        changed[9] = 9;
+        changed[13] = 9;
+        std::fs::write(&image, &changed).unwrap();
+
+        let verification = verify_bmap(&image, &image, &bmap).unwrap();
+        assert!(!verification.matches());
+        assert_eq!(verification.mismatched_ranges, vec!["3-4"]);
+
+        std::fs::write(&image, [content.clone(), vec![0; 4]].concat()).unwrap();
+        assert!(!verify_bmap(&image, &image, &bmap).unwrap().matches());
+
+        std::fs::write(&image, &content).unwrap();
+        std::fs::write(&bmap, xml.replace("0-1", "0-2")).unwrap();
+        let verification = verify_bmap(&image, &image, &bmap).unwrap();
+        assert_eq!(verification.bmap_checksum_valid, Some(false));
+        assert_eq!(verification.mismatched_ranges, vec!["0-2"]);
+
+        // bmap format 1.3 and earlier only has sha1 checksums
+        assert!(parse_bmap("<bmap version=\"1.3\"><ImageSize>4</ImageSize><BlockSize>4</BlockSize><BlockMap><Range sha1=\"00\">0</Range></BlockMap></bmap>").is_err());
+        assert!(parse_bmap(&xml.replace(" 3-4 ", " 4-3 ")).is_err());
+    }
+
+    #[test]
+    fn boot_path_resolution() {
+        let extlinux = "/extlinux/extlinux.conf";
+        assert_eq!(
+            resolve_boot_path(extlinux, BootLoader::Extlinux, "../Image"),
+            "/Image"
+        );
+        assert_eq!(
+            resolve_boot_path(extlinux, BootLoader::Extlinux, "dtbs/board.dtb"),
+            "/extlinux/dtbs/board.dtb"
+        );
+        assert_eq!(
+            resolve_boot_path(
+                "/EFI/BOOT/grub.cfg",
+                BootLoader::Grub,
+                "(hd0,gpt2)/boot/bzImage"
+            ),
+            "/boot/bzImage"
+        );
+        assert_eq!(
+            resolve_boot_path(
+                "/loader/entries/a.conf",
+                BootLoader::SystemdBoot,
+                "/vmlinuz"
+            ),
+            "/vmlinuz"
+        );
+    }
+}
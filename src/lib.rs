@@ -1,63 +1,254 @@
 #[macro_use]
 extern crate lazy_static;
+pub mod audit;
 pub mod auth;
+pub mod certificate;
 pub mod cli;
 pub mod config;
 pub mod device_update;
+pub mod diagnostics;
 pub mod docker;
+pub mod error;
 pub mod file;
 pub mod image;
+pub mod manifest;
+mod prompt;
 pub mod ssh;
 mod validators;
 use anyhow::{Context, Result};
+use audit::AuditLog;
+use certificate::{IssuerKey, KeyType};
 use cli::{
-    Command,
+    Auth::Status as AuthStatus,
+    Bmap::Verify as BmapVerify,
+    Cert::List as CertList,
+    Cert::Verify as CertVerify,
+    Cli, Command,
     Docker::Inject,
-    File::{CopyFromImage, CopyToImage},
+    File::{Append, CopyFromImage, CopyToImage, MovePartition, SetEnv, SetNetwork, SetTimezone},
     IdentityConfig::{
-        SetConfig, SetDeviceCertificate, SetDeviceCertificateNoEst, SetIotLeafSasConfig,
-        SetIotedgeGatewayConfig,
+        RenewCert, SetConfig, SetDeviceCertificate, SetDeviceCertificateNoEst, SetIotLeafSasConfig,
+        SetIotedgeGatewayConfig, SetProvisioning, Show as IdentityShow, Validate,
     },
+    Image::{BuildInfo, Detect, DumpTable, Offsets, RestoreTable, SetCmdline, SizeReport, Verity},
+    ImageOptions,
     IotHubDeviceUpdate::{self, SetDeviceConfig as IotHubDeviceUpdateSet},
-    SshConfig::{SetCertificate, SetConnection},
+    PartitionConfig::Format,
+    Service,
+    SshConfig::{SetAuthorizedKeys, SetCertificate, SetConnection},
+};
+use file::{
+    compression::{Compression, ImageFormat},
+    functions::{FileCopyToParams, FileOptions, PartitionLayout},
+};
+use log::{debug, info, warn};
+use manifest::ChangeManifest;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Path, PathBuf},
 };
-use file::{compression::Compression, functions::FileCopyToParams};
-use log::error;
-use std::{fs, path::PathBuf};
-use tokio::fs::remove_dir_all;
-use uuid::Uuid;
 
 use crate::file::compression;
 
-struct TempDirGuard(PathBuf);
+/// Checks up front that the image is an accessible regular file, so that
+/// invalid paths don't surface as obscure fdisk or dd errors later on.
+fn validate_image_path(image_file: &Path, writable: bool) -> Result<()> {
+    let path = image_file.to_string_lossy();
 
-impl Drop for TempDirGuard {
-    fn drop(&mut self) {
-        let Ok(rt) = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-        else {
-            error!("cannot create tokio runtime");
-            return;
-        };
+    let metadata = fs::metadata(image_file)
+        .context(format!("image {path} doesn't exist or isn't accessible"))?;
 
-        rt.block_on(async {
-            if let Err(e) = remove_dir_all(self.0.clone()).await {
-                error!("cannot remove tmp dir: {e}")
-            }
-        })
+    anyhow::ensure!(metadata.is_file(), "image {path} isn't a regular file");
+
+    fs::File::open(image_file).context(format!("image {path} isn't readable"))?;
+
+    if writable {
+        fs::OpenOptions::new()
+            .write(true)
+            .open(image_file)
+            .context(format!("image {path} isn't writable"))?;
+    }
+
+    Ok(())
+}
+
+/// Takes an advisory lock (flock) on the image, so that concurrent omnect-cli
+/// processes don't interleave their partition writes: exclusive if the image
+/// is written back, otherwise shared. Waits for a conflicting lock unless
+/// `no_wait` is set. The lock is released when the returned file is dropped
+/// or the process terminates, e.g. by a signal.
+fn lock_image(image_file: &Path, exclusive: bool, no_wait: bool) -> Result<fs::File> {
+    use std::os::unix::io::AsRawFd;
+
+    let path = image_file.to_string_lossy();
+    let file = fs::File::open(image_file).context(format!("image {path} isn't readable"))?;
+    let operation = if exclusive {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_SH
+    };
+
+    let flock = |operation| {
+        // SAFETY: flock has no memory safety requirements
+        match unsafe { libc::flock(file.as_raw_fd(), operation) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    };
+
+    match flock(operation | libc::LOCK_NB) {
+        Ok(()) => {}
+        Err(e) if e.raw_os_error() == Some(libc::EWOULDBLOCK) => {
+            anyhow::ensure!(
+                !no_wait,
+                "image {path} is locked by another omnect-cli process"
+            );
+
+            info!("waiting for image {path} locked by another omnect-cli process");
+            flock(operation).context(format!("cannot lock image {path}"))?;
+        }
+        Err(e) => return Err(e).context(format!("cannot lock image {path}")),
     }
+
+    Ok(file)
 }
 
+/// Runs `command` on a decompressed copy of `image_file` and writes the result
+/// back as given by `options`. `file_options` are completed by the file
+/// related `options` and passed to `command`.
 fn run_image_command<F>(
     image_file: PathBuf,
-    generate_bmap: bool,
-    target_compression: Option<Compression>,
+    options: ImageOptions,
+    mut file_options: FileOptions,
     command: F,
 ) -> Result<()>
 where
-    F: FnOnce(&PathBuf) -> Result<()>,
+    F: FnOnce(&PathBuf, &FileOptions) -> Result<()>,
 {
+    let ImageOptions {
+        generate_bmap,
+        bmap_output,
+        strict_bmap,
+        compress_image: target_compression,
+        keep_decompressed,
+        gzip_rsyncable,
+        bzip2_block_size,
+        xz_dict_size,
+        no_recompress_on_error: keep_image_on_error,
+        image_format,
+        work_dir,
+        layout,
+        raw_partition,
+        label,
+        sparse_check,
+        strict,
+        keep_partitions,
+        parallel,
+        only_if_changed,
+        fsck,
+        check_boot,
+        no_sync,
+        dd_block_size,
+        no_wait,
+        resume,
+        output,
+        audit_log,
+        change_manifest,
+        compressed_delta,
+        delta_baseline,
+        list_partitions,
+        read_only,
+    } = options;
+
+    // listing partitions doesn't perform the command, so the image is only read
+    let read_only = read_only || list_partitions;
+
+    if !read_only {
+        file_options.ensure_writable(&format!(
+            "run a command modifying {}",
+            image_file.to_string_lossy()
+        ))?;
+    }
+
+    if let Some(audit_log) = audit_log {
+        file_options.audit_log = AuditLog::open(&audit_log)?;
+    }
+
+    if let Some(change_manifest) = change_manifest {
+        file_options.change_manifest = ChangeManifest::new(&change_manifest);
+    }
+
+    validate_image_path(&image_file, !read_only && output.is_none())?;
+
+    // held until the command finished, also if it fails
+    let _lock = lock_image(&image_file, !read_only && output.is_none(), no_wait)?;
+
+    if let Some(output) = &output {
+        let dir = match output.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        anyhow::ensure!(
+            dir.is_dir(),
+            "run_image_command: output dir {} doesn't exist",
+            dir.to_string_lossy()
+        );
+    }
+
+    let target_compression = resolve_target_compression(
+        target_compression,
+        gzip_rsyncable,
+        bzip2_block_size,
+        xz_dict_size,
+    )?
+    .map(|c| c.with_threads(file_options.threads));
+
+    // the delta reflects the written image, if it's packed with the same codec
+    let delta_compression = compressed_delta
+        .map(|format| {
+            let compression = format.pack_compression().context(
+                "run_image_command: --compressed-delta requires a compression [xz, bzip2, gzip]",
+            )?;
+
+            anyhow::Ok(match &target_compression {
+                Some(c) if c.extension() == compression.extension() => c.clone(),
+                _ => compression.with_threads(file_options.threads),
+            })
+        })
+        .transpose()?;
+
+    if let (Some(compression), Some(baseline)) = (&delta_compression, &delta_baseline) {
+        let baseline_compression = Compression::from_file_or_format(baseline, None)?;
+
+        if baseline_compression.map(|c| c.extension()) != Some(compression.extension()) {
+            warn!(
+                "delta baseline {} isn't compressed with {}: the delta isn't meaningful",
+                baseline.to_string_lossy(),
+                compression.extension()
+            );
+        }
+    }
+
+    if let Some(layout) = layout {
+        file_options.layout = Some(PartitionLayout::from_file(&layout)?);
+    }
+
+    if let Some(keep_partitions) = &keep_partitions {
+        fs::create_dir_all(keep_partitions).context(format!(
+            "run_image_command: cannot create {}",
+            keep_partitions.to_string_lossy()
+        ))?;
+    }
+
+    file_options.raw_partition = raw_partition;
+    file_options.keep_partitions_dir = keep_partitions;
+    file_options.parallel = parallel.map(usize::from);
+    file_options.fsck = fsck;
+    file_options.no_sync = no_sync;
+    file_options.dd_block_size = dd_block_size;
+
     if let Ok("true") | Ok("1") = std::env::var("CONTAINERIZED").as_deref() {
         anyhow::ensure!(
             !generate_bmap,
@@ -65,35 +256,98 @@ where
         );
     }
 
-    anyhow::ensure!(
-        image_file.try_exists().is_ok_and(|exists| exists),
-        "run_image_command: image doesn't exist {}",
-        image_file.to_str().context("cannot get image file path")?
-    );
-
     let mut dest_image_file = image_file.clone();
 
-    // create /tmp/{uuid}/ and copy image into
-    let tmp_dir = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
-    fs::create_dir_all(tmp_dir.clone()).context(format!(
-        "run_image_command: couldn't create destination path {}",
-        tmp_dir.to_str().context("cannot get tmp dir name")?
-    ))?;
+    // create unique tmp dir in work dir and copy image into
+    let work_dir = work_dir.unwrap_or_else(std::env::temp_dir);
+    let tmp_dir = tempfile::Builder::new()
+        .prefix("omnect-cli-")
+        .tempdir_in(&work_dir)
+        .context(format!(
+            "run_image_command: couldn't create tmp dir in {}",
+            work_dir.to_string_lossy()
+        ))?;
+
+    if sparse_check {
+        let (sparse, fs_type) =
+            file::functions::check_sparse_support(tmp_dir.path(), &file_options)?;
+        let msg = format!(
+            "work dir {} ({fs_type}) doesn't support sparse files: images will take their full size, consider --work-dir",
+            work_dir.to_string_lossy()
+        );
 
-    let _guard = TempDirGuard(tmp_dir.clone());
+        anyhow::ensure!(sparse || !strict, msg);
 
-    let mut tmp_image_file = tmp_dir.join(
+        if !sparse {
+            warn!("{msg}");
+        }
+    }
+
+    let mut tmp_image_file = tmp_dir.path().join(
         image_file
             .file_name()
             .context("cannot get image file name")?,
     );
 
+    let mut resume_cache = None;
+
+    let mut source_compression = Compression::from_file_or_format(&image_file, image_format)?;
+
     // if applicable decompress image to *.wic
-    if let Some(source_compression) = Compression::from_file(&image_file)? {
-        std::fs::copy(&image_file, &tmp_image_file)?;
-        tmp_image_file = compression::decompress(&tmp_image_file, &source_compression)?;
-        dest_image_file.set_extension("");
-    } else {
+    if let Some(compression) = &source_compression {
+        let decompressed_image_file = compression::decompressed_path(&tmp_image_file, compression);
+
+        let decompressed = if resume {
+            decompress_resumable(&image_file, &work_dir, compression, &file_options).and_then(
+                |cache| {
+                    // copy sparse file (std::fs::copy isn't able)
+                    libfs::copy_file(&cache, &decompressed_image_file).context(format!(
+                        "error: libfs::copy_file({:?}, {:?})",
+                        cache, decompressed_image_file
+                    ))?;
+                    resume_cache = Some(cache);
+                    Ok(())
+                },
+            )
+        } else {
+            check_decompression_space(&image_file, tmp_dir.path(), compression, &file_options)
+                .and_then(|_| {
+                    image::decompress_to(&image_file, &decompressed_image_file, compression)
+                })
+        };
+
+        // a forced format is trusted, but libmagic may take a plain image for a compressed one
+        if image_format.is_none()
+            && is_misdetected_compression(&image_file, &decompressed_image_file, &decompressed)?
+        {
+            warn!(
+                "{} was detected as {compression:?} compressed, but doesn't decompress to an image: process it as uncompressed image",
+                image_file.to_string_lossy()
+            );
+            if decompressed_image_file.exists() {
+                fs::remove_file(&decompressed_image_file).context(format!(
+                    "run_image_command: cannot remove {}",
+                    decompressed_image_file.to_string_lossy()
+                ))?;
+            }
+            remove_resume_cache(resume_cache.take())?;
+            source_compression = None;
+        } else {
+            decompressed?;
+            tmp_image_file = decompressed_image_file;
+
+            file_options.audit_log.operation(
+                "decompress",
+                serde_json::json!({
+                    "image": image_file,
+                    "compression": format!("{compression:?}"),
+                }),
+            )?;
+            dest_image_file.set_extension("");
+        }
+    }
+
+    if source_compression.is_none() {
         // copy sparse file (std::fs::copy isn't able)
         libfs::copy_file(&image_file, &tmp_image_file).context(format!(
             "error: libfs::copy_file({:?}, {:?})",
@@ -101,36 +355,401 @@ where
         ))?;
     }
 
-    // run command
-    command(&tmp_image_file)?;
+    // operate on the single wic image of a tar archive, e.g. of a release bundle
+    let tar_archive = if file::functions::is_tar_archive(&tmp_image_file)? {
+        anyhow::ensure!(
+            !generate_bmap,
+            "run_image_command: generating bmap file is not supported for images in tar archives"
+        );
+
+        let extract_dir = tmp_dir.path().join("tar");
+        fs::create_dir(&extract_dir).context("run_image_command: cannot create tar dir")?;
+        let (wic, members) =
+            file::functions::extract_tar(&tmp_image_file, &extract_dir, &file_options)?;
+        // the archive is recreated from the extracted members on completion
+        fs::remove_file(&tmp_image_file).context("run_image_command: cannot remove tar")?;
+
+        Some((
+            std::mem::replace(&mut tmp_image_file, wic),
+            extract_dir,
+            members,
+        ))
+    } else {
+        None
+    };
+
+    if list_partitions {
+        print!(
+            "{}",
+            file::functions::describe_partitions(&tmp_image_file, &file_options)?
+        );
+        return Ok(());
+    }
+
+    // without baseline the image before the command is compared
+    let delta_baseline = match (&delta_compression, delta_baseline) {
+        (Some(compression), None) => {
+            let baseline = tmp_dir
+                .path()
+                .join(format!("delta-baseline.{}", compression.extension()));
+            image::compress_to(&tmp_image_file, &baseline, compression)?;
+            Some(baseline)
+        }
+        (_, baseline) => baseline,
+    };
+
+    let image_hash = if only_if_changed && output.is_none() {
+        Some(file_hash(&tmp_image_file)?)
+    } else {
+        None
+    };
+
+    // run command, the boot check is done on the result like a part of it
+    let result = command(&tmp_image_file, &file_options).and_then(|_| {
+        if check_boot {
+            image::check_boot(&tmp_image_file, &file_options)
+        } else {
+            Ok(())
+        }
+    });
+
+    if let Err(e) = result {
+        if keep_image_on_error {
+            // skip cleanup of tmp dir in order to allow inspection of the processed image
+            let _ = tmp_dir.into_path();
+            println!(
+                "kept image for debugging: {}",
+                tmp_image_file.to_string_lossy()
+            );
+        }
+        return Err(e);
+    }
+
+    if read_only {
+        remove_resume_cache(resume_cache)?;
+        return file_options.change_manifest.write();
+    }
+
+    // an unchanged label isn't rewritten, so that --only-if-changed still applies
+    if let Some(label) = label {
+        if file::build_info_label(&tmp_image_file, &file_options)?.as_deref() != Some(&label) {
+            file::set_build_info(&label, &tmp_image_file, &file_options)?;
+        }
+    }
+
+    if let (Some(compression), Some(baseline)) = (&delta_compression, &delta_baseline) {
+        let compressed = tmp_dir
+            .path()
+            .join(format!("delta.{}", compression.extension()));
+        image::compress_to(&tmp_image_file, &compressed, compression)?;
+
+        let delta = image::compressed_delta(baseline, &compressed, compression)?;
+        fs::remove_file(&compressed).context(format!(
+            "run_image_command: cannot remove {}",
+            compressed.to_string_lossy()
+        ))?;
+
+        println!("{delta}");
+        file_options.change_manifest.compressed_delta(
+            serde_json::to_value(&delta).context("run_image_command: cannot serialize delta")?,
+        )?;
+    }
+
+    if let Some(image_hash) = image_hash {
+        if image_hash == file_hash(&tmp_image_file)? {
+            info!("image content unchanged: skip writing back image");
+            remove_resume_cache(resume_cache)?;
+            return file_options.change_manifest.write();
+        }
+    }
+
+    if let Some((archive, extract_dir, members)) = tar_archive {
+        file::functions::repack_tar(&extract_dir, &members, &archive, &file_options)?;
+        tmp_image_file = archive;
+    }
+
+    // name the processed image after the output, so that compressed image and bmap file are too
+    if let Some(output) = output {
+        let renamed = tmp_dir
+            .path()
+            .join(output.file_name().context("cannot get output file name")?);
+        fs::rename(&tmp_image_file, &renamed).context(format!(
+            "error: fs::rename({:?}, {:?})",
+            tmp_image_file, renamed
+        ))?;
+        tmp_image_file = renamed;
+        dest_image_file = output;
+    }
+
+    write_image(
+        tmp_image_file,
+        dest_image_file,
+        generate_bmap.then_some(bmap_output),
+        target_compression,
+        keep_decompressed,
+        strict_bmap,
+        &file_options,
+    )?;
+
+    remove_resume_cache(resume_cache)?;
+    file_options.change_manifest.write()
+}
+
+/// Returns whether the compression detected for `image_file` is a false
+/// positive, i.e. decompressing failed or didn't result in a partitioned image
+/// or tar archive, while `image_file` is a partitioned image itself.
+fn is_misdetected_compression(
+    image_file: &Path,
+    decompressed_image_file: &Path,
+    decompressed: &Result<()>,
+) -> Result<bool> {
+    if decompressed.is_ok()
+        && (file::functions::has_partition_table(decompressed_image_file)?
+            || file::functions::is_tar_archive(decompressed_image_file)?)
+    {
+        return Ok(false);
+    }
+
+    let misdetected = file::functions::has_partition_table(image_file)?;
+
+    debug!(
+        "is_misdetected_compression: {} doesn't decompress to an image, has partition table: {misdetected}",
+        image_file.to_string_lossy()
+    );
+
+    Ok(misdetected)
+}
+
+/// Decompresses `image_file` into a file in `work_dir` named after path, size,
+/// modification time and compression of the image and returns its path. If the
+/// file already exists, e.g. since a previous run with the same image was
+/// interrupted or failed, it is reused instead. The image is decompressed to a
+/// `.part` file first, so that only complete files are reused. Since the
+/// supported codecs can't continue a stream, a `.part` file is started over.
+fn decompress_resumable(
+    image_file: &Path,
+    work_dir: &Path,
+    compression: &Compression,
+    file_options: &FileOptions,
+) -> Result<PathBuf> {
+    let metadata = fs::metadata(image_file).context(format!(
+        "decompress_resumable: cannot get metadata of {}",
+        image_file.to_string_lossy()
+    ))?;
+    let mtime = metadata
+        .modified()
+        .context("decompress_resumable: cannot get modification time")?
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut hasher = Sha256::new();
+    hasher.update(
+        fs::canonicalize(image_file)
+            .context("decompress_resumable: cannot resolve image path")?
+            .to_string_lossy()
+            .as_bytes(),
+    );
+    hasher.update(metadata.len().to_le_bytes());
+    hasher.update(mtime.as_nanos().to_le_bytes());
+    hasher.update(compression.extension());
+    let key = manifest::hex(&hasher.finalize()[..8]);
+
+    let cache_dir = work_dir.join("omnect-cli-resume");
+    fs::create_dir_all(&cache_dir).context(format!(
+        "decompress_resumable: cannot create {}",
+        cache_dir.to_string_lossy()
+    ))?;
+    let cache = cache_dir.join(format!("{key}.wic"));
+
+    if cache.is_file() {
+        info!(
+            "resume: reuse decompressed image {}",
+            cache.to_string_lossy()
+        );
+        return Ok(cache);
+    }
+
+    let part = cache_dir.join(format!("{key}.wic.part"));
+    check_decompression_space(image_file, &cache_dir, compression, file_options)?;
+    image::decompress_to(image_file, &part, compression)?;
+    fs::rename(&part, &cache).context(format!(
+        "decompress_resumable: cannot rename {}",
+        part.to_string_lossy()
+    ))?;
+
+    Ok(cache)
+}
+
+/// Fails early if the uncompressed size recorded by the compression format of
+/// `image_file` exceeds the available space in `dir`, instead of running out of
+/// space midway through decompressing it.
+fn check_decompression_space(
+    image_file: &Path,
+    dir: &Path,
+    compression: &Compression,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let Some(size) = compression::uncompressed_size_hint(image_file, compression)? else {
+        debug!(
+            "check_decompression_space: {} records no uncompressed size",
+            image_file.to_string_lossy()
+        );
+        return Ok(());
+    };
+    let available = file::functions::available_space(dir, file_options)?;
+
+    info!(
+        "decompressing {} needs ~{} in {}, {} available",
+        image_file.to_string_lossy(),
+        human_size(size),
+        dir.to_string_lossy(),
+        human_size(available)
+    );
+
+    anyhow::ensure!(
+        size <= available,
+        "not enough space in {} to decompress {}: need ~{} free, have {} (consider --work-dir)",
+        dir.to_string_lossy(),
+        image_file.to_string_lossy(),
+        human_size(size),
+        human_size(available)
+    );
+
+    Ok(())
+}
+
+fn human_size(bytes: u64) -> String {
+    if bytes < 1 << 30 {
+        format!("{:.1} MiB", bytes as f64 / f64::from(1 << 20))
+    } else {
+        format!("{:.1} GiB", bytes as f64 / f64::from(1 << 30))
+    }
+}
+
+/// Removes the decompressed image kept for resuming once the command succeeded.
+fn remove_resume_cache(cache: Option<PathBuf>) -> Result<()> {
+    if let Some(cache) = cache {
+        fs::remove_file(&cache).context(format!(
+            "remove_resume_cache: cannot remove {}",
+            cache.to_string_lossy()
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Applies the compression tuning options to `compression`. Tuning options of
+/// other formats than the chosen one are ignored.
+fn resolve_target_compression(
+    format: Option<ImageFormat>,
+    gzip_rsyncable: bool,
+    bzip2_block_size: Option<u32>,
+    xz_dict_size: Option<u32>,
+) -> Result<Option<Compression>> {
+    let compression = match (format.and_then(|f| f.pack_compression()), gzip_rsyncable) {
+        (Some(Compression::gzip { .. }), true) => Some(Compression::gzip { rsyncable: true }),
+        (_, true) => anyhow::bail!("run_image_command: --gzip-rsyncable requires '-p gzip'"),
+        (c, false) => c,
+    };
+
+    if bzip2_block_size.is_some() && !matches!(compression, Some(Compression::bzip2 { .. })) {
+        warn!("--bzip2-block-size is ignored, since the image isn't packed with bzip2");
+    }
+    if xz_dict_size.is_some() && !matches!(compression, Some(Compression::xz { .. })) {
+        warn!("--xz-dict-size is ignored, since the image isn't packed with xz");
+    }
+
+    Ok(match compression {
+        Some(Compression::bzip2 { block_size }) => Some(Compression::bzip2 {
+            block_size: bzip2_block_size.unwrap_or(block_size),
+        }),
+        Some(Compression::xz {
+            compression_level,
+            dict_size,
+            threads,
+        }) => Some(Compression::xz {
+            compression_level,
+            dict_size: xz_dict_size.map(|mib| mib << 20).or(dict_size),
+            threads,
+        }),
+        c => c,
+    })
+}
+
+/// Writes the processed `tmp_image_file` to `dest_image_file`, optionally
+/// compressed and accompanied by a bmap file. `bmap` is `None` if no bmap file
+/// is generated, otherwise it optionally contains the bmap file path. If
+/// `keep_decompressed` is set, the image is additionally written uncompressed.
+/// A missing bmaptool only fails after the image is written and only if
+/// `strict_bmap` is set.
+fn write_image(
+    mut tmp_image_file: PathBuf,
+    mut dest_image_file: PathBuf,
+    bmap: Option<Option<PathBuf>>,
+    target_compression: Option<Compression>,
+    keep_decompressed: bool,
+    strict_bmap: bool,
+    file_options: &FileOptions,
+) -> Result<()> {
+    file_options.ensure_writable(&format!("write {}", dest_image_file.to_string_lossy()))?;
+
+    let generate_bmap = bmap.is_some();
+    let mut bmap_missing = false;
 
     // create and copy back bmap file if one was created
-    if generate_bmap {
-        let mut target_bmap = image_file
-            .parent()
-            .context("cannot get parent dir of image path")?
-            .to_path_buf();
+    if let Some(bmap_output) = bmap {
         let tmp_bmap = PathBuf::from(format!(
             "{}.bmap",
             tmp_image_file
                 .to_str()
                 .context("cannot get image file path")?
         ));
-        file::functions::generate_bmap_file(
+        if file::functions::generate_bmap_file(
             tmp_image_file
                 .to_str()
                 .context("cannot get image file path")?,
-        )?;
-        target_bmap.push(tmp_bmap.file_name().context("cannot get bmap file name")?);
-        std::fs::copy(&tmp_bmap, &target_bmap).context(format!(
-            "error: std::fs::copy({:?}, {:?})",
-            tmp_bmap, target_bmap
-        ))?;
+            file_options,
+        )? {
+            let target_bmap = match bmap_output {
+                Some(bmap_output) => {
+                    if let Some(dir) = bmap_output.parent().filter(|d| !d.as_os_str().is_empty()) {
+                        fs::create_dir_all(dir).context(format!(
+                            "write_image: cannot create bmap output dir {}",
+                            dir.to_string_lossy()
+                        ))?;
+                    }
+                    bmap_output
+                }
+                None => dest_image_file
+                    .parent()
+                    .context("cannot get parent dir of image path")?
+                    .join(tmp_bmap.file_name().context("cannot get bmap file name")?),
+            };
+            std::fs::copy(&tmp_bmap, &target_bmap).context(format!(
+                "error: std::fs::copy({:?}, {:?})",
+                tmp_bmap, target_bmap
+            ))?;
+        } else {
+            warn!("bmaptool isn't installed, install it (e.g. package bmap-tools) to generate bmap files: the image is written without bmap file");
+            bmap_missing = true;
+        }
     }
 
     // if applicable compress image
-    if let Some(c) = target_compression {
-        tmp_image_file = compression::compress(&tmp_image_file, &c)?;
+    if let Some(c) = &target_compression {
+        if keep_decompressed {
+            // copy sparse file (std::fs::copy isn't able)
+            libfs::copy_file(&tmp_image_file, &dest_image_file).context(format!(
+                "error: libfs::copy_file({:?}, {:?})",
+                tmp_image_file, dest_image_file
+            ))?;
+            info!(
+                "kept decompressed image: {}",
+                dest_image_file.to_string_lossy()
+            );
+        }
+
+        tmp_image_file = compression::compress(&tmp_image_file, c)?;
         dest_image_file.set_file_name(
             tmp_image_file
                 .file_name()
@@ -148,19 +767,685 @@ where
         ))?;
     }
 
+    if file_options.audit_log.enabled() || file_options.change_manifest.enabled() {
+        let sha256 = manifest::hex(&file_hash(&dest_image_file)?);
+        let compression = target_compression.map(|c| format!("{c:?}"));
+        let bmap = generate_bmap && !bmap_missing;
+
+        file_options.audit_log.operation(
+            "write-image",
+            serde_json::json!({
+                "image": dest_image_file,
+                "compression": compression,
+                "bmap": bmap,
+                "sha256": sha256,
+            }),
+        )?;
+
+        file_options
+            .change_manifest
+            .image(&dest_image_file, compression, bmap, &sha256)?;
+    }
+
+    anyhow::ensure!(
+        !(bmap_missing && strict_bmap),
+        "write_image: bmaptool isn't installed, the image was written without bmap file"
+    );
+
     Ok(())
 }
 
+/// Signer of device certificates: the intermediate key is either read from a
+/// pem file or kept on a PKCS#11 token. Device keys of other types than
+/// omnect-crypto's are created via openssl.
+enum Issuer {
+    Crypto(omnect_crypto::Crypto),
+    KeyFile {
+        intermediate_full_chain_cert: PathBuf,
+        intermediate_key: PathBuf,
+        key_type: KeyType,
+    },
+    Pkcs11 {
+        intermediate_full_chain_cert: PathBuf,
+        key_uri: String,
+        key_type: KeyType,
+    },
+}
+
+fn create_issuer(
+    intermediate_full_chain_cert: &Path,
+    intermediate_key: Option<PathBuf>,
+    intermediate_key_pkcs11: Option<String>,
+    key_type: KeyType,
+    days: u32,
+) -> Result<Issuer> {
+    validators::certificate::validate_validity_period(intermediate_full_chain_cert, days)?;
+
+    if let Some(key_uri) = intermediate_key_pkcs11 {
+        return Ok(Issuer::Pkcs11 {
+            intermediate_full_chain_cert: intermediate_full_chain_cert.to_path_buf(),
+            key_uri,
+            key_type,
+        });
+    }
+
+    let intermediate_key = intermediate_key.context("intermediate key missing")?;
+
+    if key_type != KeyType::ec_p256 {
+        return Ok(Issuer::KeyFile {
+            intermediate_full_chain_cert: intermediate_full_chain_cert.to_path_buf(),
+            intermediate_key,
+            key_type,
+        });
+    }
+
+    let intermediate_full_chain_cert_str = std::fs::read_to_string(intermediate_full_chain_cert)
+        .context("couldn't read intermediate fullchain cert")?;
+    let intermediate_key_str =
+        std::fs::read_to_string(intermediate_key).context("couldn't read intermediate key")?;
+
+    omnect_crypto::Crypto::new(
+        intermediate_key_str.as_bytes(),
+        intermediate_full_chain_cert_str.as_bytes(),
+    )
+    .map(Issuer::Crypto)
+    .context("couldn't load intermediate fullchain cert and key")
+}
+
+/// Creates device certificate and key for `device_id` and writes them to `dir`.
+fn create_device_cert(
+    issuer: &Issuer,
+    device_id: &str,
+    days: u32,
+    dir: &Path,
+) -> Result<(PathBuf, PathBuf)> {
+    let (device_cert_pem, device_key_pem) = match issuer {
+        Issuer::Crypto(crypto) => crypto.create_cert_and_key(device_id, &None, days),
+        Issuer::KeyFile {
+            intermediate_full_chain_cert,
+            intermediate_key,
+            key_type,
+        } => certificate::create_cert_and_key_openssl(
+            intermediate_full_chain_cert,
+            IssuerKey::File(intermediate_key),
+            *key_type,
+            device_id,
+            days,
+            dir,
+        ),
+        Issuer::Pkcs11 {
+            intermediate_full_chain_cert,
+            key_uri,
+            key_type,
+        } => certificate::create_cert_and_key_openssl(
+            intermediate_full_chain_cert,
+            IssuerKey::Pkcs11(key_uri),
+            *key_type,
+            device_id,
+            days,
+            dir,
+        ),
+    }
+    .context(format!(
+        "couldn't create device cert and key for {device_id}"
+    ))?;
+
+    let device_cert_path = dir.join("device_cert_path.pem");
+    let device_key_path = dir.join("device_key_path.key.pem");
+
+    fs::write(&device_cert_path, device_cert_pem)
+        .context("set_device_cert: write device_cert_path")?;
+    fs::write(&device_key_path, device_key_pem)
+        .context("set_device_cert: write device_key_path")?;
+
+    Ok((device_cert_path, device_key_path))
+}
+
+/// Parses a csv file with one "device_id[,output_image]" per line. Empty lines
+/// and lines starting with '#' are ignored. Devices without output image get
+/// "<image stem>_<device_id>.<image extension>" next to `image_file`.
+fn read_device_ids_csv(csv_file: &Path, image_file: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let content = fs::read_to_string(csv_file).context(format!(
+        "read_device_ids_csv: cannot read {}",
+        csv_file.to_string_lossy()
+    ))?;
+    let stem = image_file
+        .file_stem()
+        .context("read_device_ids_csv: cannot get image file stem")?
+        .to_string_lossy();
+    let extension = image_file
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let mut devices: Vec<(String, PathBuf)> = vec![];
+
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.splitn(2, ',').map(str::trim);
+        let device_id = fields.next().unwrap_or_default();
+
+        anyhow::ensure!(
+            !device_id.is_empty(),
+            "read_device_ids_csv: line {}: device id missing",
+            i + 1
+        );
+
+        let output = match fields.next() {
+            Some(output) if !output.is_empty() => PathBuf::from(output),
+            _ => image_file.with_file_name(format!("{stem}_{device_id}{extension}")),
+        };
+
+        anyhow::ensure!(
+            !devices.iter().any(|(_, o)| *o == output),
+            "read_device_ids_csv: line {}: output image {} is used more than once",
+            i + 1,
+            output.to_string_lossy()
+        );
+
+        devices.push((device_id.to_string(), output));
+    }
+
+    anyhow::ensure!(
+        !devices.is_empty(),
+        "read_device_ids_csv: {} doesn't contain any device id",
+        csv_file.to_string_lossy()
+    );
+
+    Ok(devices)
+}
+
+/// Decompresses the base image once and writes a copy with its own device
+/// certificate for every device listed in `csv_file`.
+#[allow(clippy::too_many_arguments)]
+fn set_device_certs_batch(
+    issuer: &Issuer,
+    intermediate_full_chain_cert: &Path,
+    est: &file::EstConfig,
+    csv_file: &Path,
+    days: u32,
+    image_file: PathBuf,
+    mut options: ImageOptions,
+    file_options: FileOptions,
+) -> Result<()> {
+    anyhow::ensure!(
+        options.output.is_none(),
+        "set_device_certs_batch: --output isn't supported with --device-ids-csv, set output images in the csv file instead"
+    );
+    anyhow::ensure!(
+        options.bmap_output.is_none(),
+        "set_device_certs_batch: --bmap-output isn't supported with --device-ids-csv"
+    );
+
+    let generate_bmap = options.generate_bmap;
+    let keep_decompressed = options.keep_decompressed;
+    let strict_bmap = options.strict_bmap;
+    let target_compression = resolve_target_compression(
+        options.compress_image.take(),
+        options.gzip_rsyncable,
+        options.bzip2_block_size.take(),
+        options.xz_dict_size.take(),
+    )?
+    .map(|c| c.with_threads(file_options.threads));
+    let label = options.label.take();
+
+    validate_image_path(&image_file, false)?;
+
+    // outputs are named after the decompressed base image by default
+    let base_name = match Compression::from_file_or_format(&image_file, options.image_format)? {
+        Some(c) => compression::decompressed_path(&image_file, &c),
+        None => image_file.clone(),
+    };
+    let devices = read_device_ids_csv(csv_file, &base_name)?;
+
+    // the base image is only read, outputs are written per device
+    let base_options = ImageOptions {
+        gzip_rsyncable: false,
+        read_only: true,
+        ..options
+    };
+
+    run_image_command(
+        image_file,
+        base_options,
+        file_options,
+        |base_image, options| {
+            for (device_id, output) in devices {
+                let device_dir = tempfile::Builder::new()
+                    .prefix("device-")
+                    .tempdir_in(base_image.parent().context("cannot get tmp dir")?)
+                    .context("set_device_certs_batch: couldn't create tmp dir")?;
+                let device_image = device_dir
+                    .path()
+                    .join(output.file_name().context("cannot get output file name")?);
+
+                libfs::copy_file(base_image, &device_image).context(format!(
+                    "error: libfs::copy_file({:?}, {:?})",
+                    base_image, device_image
+                ))?;
+
+                let (device_cert_path, device_key_path) =
+                    create_device_cert(issuer, &device_id, days, device_dir.path())?;
+
+                file::set_device_cert(
+                    Some(intermediate_full_chain_cert),
+                    &device_cert_path,
+                    &device_key_path,
+                    &device_image,
+                    options,
+                )?;
+
+                if !est.is_empty() {
+                    file::set_est_config(est, &device_id, &device_image, options)?;
+                }
+
+                if let Some(label) = &label {
+                    file::set_build_info(label, &device_image, options)?;
+                }
+
+                write_image(
+                    device_image,
+                    output.clone(),
+                    generate_bmap.then_some(None),
+                    target_compression.clone(),
+                    keep_decompressed,
+                    strict_bmap,
+                    options,
+                )?;
+
+                info!("wrote image for {device_id}: {}", output.to_string_lossy());
+            }
+
+            Ok(())
+        },
+    )
+}
+
+fn file_hash(file: &PathBuf) -> Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    let mut file = fs::File::open(file).context(format!("file_hash: cannot open {file:?}"))?;
+
+    std::io::copy(&mut file, &mut hasher).context("file_hash: cannot read file")?;
+
+    Ok(hasher.finalize().to_vec())
+}
+
 pub fn run() -> Result<()> {
-    match cli::from_args() {
+    let Cli {
+        json_errors,
+        fail_on_warning,
+        threads,
+        read_only,
+        command,
+    } = cli::from_args();
+
+    let mut file_options = FileOptions::default();
+    file_options.threads = threads.map(usize::from);
+    file_options.read_only = read_only;
+
+    let res = run_command(command, file_options).and_then(|_| {
+        let warnings = diagnostics::warnings();
+
+        anyhow::ensure!(
+            !fail_on_warning || warnings.is_empty(),
+            "--fail-on-warning: {} warning(s) logged:\n{}",
+            warnings.len(),
+            warnings.join("\n")
+        );
+
+        Ok(())
+    });
+
+    if json_errors {
+        if let Err(e) = &res {
+            println!("{}", error::to_json(e));
+        }
+    }
+
+    res
+}
+
+/// Returns the environment given via `env` or the production environment, with
+/// its identity provider replaced by a custom OpenID Connect `issuer`.
+fn backend_config(
+    env: Option<PathBuf>,
+    issuer: Option<url::Url>,
+    client_id: Option<String>,
+    scopes: Vec<String>,
+) -> Result<config::BackendConfig> {
+    let mut env_conf: config::BackendConfig = if let Some(env_path) = env {
+        let config_file = std::fs::read_to_string(env_path)?;
+
+        toml::from_str(&config_file)?
+    } else {
+        config::BackendConfig {
+            backend: url::Url::parse("https://cp.omnect.conplement.cloud")?,
+            auth: config::AUTH_INFO_PROD.clone(),
+        }
+    };
+
+    if let (Some(issuer), Some(client_id)) = (issuer, client_id) {
+        env_conf.auth =
+            config::AuthProvider::Oidc(config::OidcInfo::new(issuer, client_id, scopes));
+    }
+
+    Ok(env_conf)
+}
+
+/// Host paths of `options`, which have to be accessible by a command run via
+/// `--in-container`.
+fn image_options_paths(options: &ImageOptions) -> Vec<&Path> {
+    [
+        &options.bmap_output,
+        &options.work_dir,
+        &options.layout,
+        &options.keep_partitions,
+        &options.output,
+        &options.audit_log,
+        &options.change_manifest,
+    ]
+    .into_iter()
+    .flatten()
+    .map(PathBuf::as_path)
+    .collect()
+}
+
+fn run_command(command: Command, mut file_options: FileOptions) -> Result<()> {
+    match command {
+        Command::Auth(AuthStatus {
+            env,
+            issuer,
+            client_id,
+            json,
+        }) => {
+            let env_conf = backend_config(env, issuer, client_id, vec![])?;
+            let status = auth::status(&env_conf.backend, &env_conf.auth);
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&status)?);
+            } else {
+                println!("{status}");
+            }
+        }
+        Command::Bmap(BmapVerify { image, bmap, json }) => run_image_command(
+            image.clone(),
+            ImageOptions {
+                read_only: true,
+                ..Default::default()
+            },
+            file_options,
+            |img: &PathBuf, _| {
+                let verification = image::verify_bmap(&image, img, &bmap)?;
+
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&verification)?);
+                } else {
+                    println!("{verification}");
+                }
+
+                anyhow::ensure!(
+                    verification.matches(),
+                    "{} doesn't match {}",
+                    bmap.to_string_lossy(),
+                    image.to_string_lossy()
+                );
+
+                Ok(())
+            },
+        )?,
+        Command::Cert(CertList {
+            image,
+            threshold_days,
+            json,
+        }) => run_image_command(
+            image,
+            ImageOptions {
+                read_only: true,
+                ..Default::default()
+            },
+            file_options,
+            |img: &PathBuf, options| {
+                let certs = certificate::list_certificates(img, threshold_days, options)?;
+
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&certs)?);
+                } else {
+                    for cert in certs {
+                        println!("{cert}");
+                    }
+                }
+
+                Ok(())
+            },
+        )?,
+        Command::Cert(CertVerify { image, ca, json }) => run_image_command(
+            image,
+            ImageOptions {
+                read_only: true,
+                ..Default::default()
+            },
+            file_options,
+            |img: &PathBuf, options| {
+                let certs = certificate::verify_certificates(img, &ca, options)?;
+
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&certs)?);
+                } else {
+                    for cert in &certs {
+                        println!("{cert}");
+                    }
+                }
+
+                let failed = certs.iter().filter(|c| !c.verified).count();
+
+                anyhow::ensure!(
+                    failed == 0,
+                    "{failed} of {} certificates don't chain to {}",
+                    certs.len(),
+                    ca.to_string_lossy()
+                );
+
+                Ok(())
+            },
+        )?,
+        Command::Image(Detect { image, json }) => {
+            validate_image_path(&image, false)?;
+
+            let info = image::detect(&image)?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            } else {
+                println!("{info}");
+            }
+        }
+        Command::Image(SizeReport {
+            image,
+            trial_compress,
+            json,
+            image_options,
+        }) => {
+            let image_format = image_options.image_format;
+
+            run_image_command(
+                image.clone(),
+                image_options.into(),
+                file_options,
+                |img: &PathBuf, options| {
+                    let report =
+                        image::size_report(&image, image_format, img, trial_compress, options)?;
+
+                    if json {
+                        println!("{}", serde_json::to_string_pretty(&report)?);
+                    } else {
+                        println!("{report}");
+                    }
+
+                    Ok(())
+                },
+            )?
+        }
+        Command::Image(BuildInfo {
+            image,
+            json,
+            image_options,
+        }) => run_image_command(
+            image.clone(),
+            image_options.into(),
+            file_options,
+            |img: &PathBuf, options| {
+                let build_info = image::build_info(&image, img, options)?;
+
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&build_info)?);
+                } else {
+                    println!("{build_info}");
+                }
+
+                Ok(())
+            },
+        )?,
+        Command::Image(Offsets {
+            image,
+            partition,
+            json,
+            image_options,
+        }) => run_image_command(
+            image.clone(),
+            image_options.into(),
+            file_options,
+            |img: &PathBuf, options| {
+                let report = image::partition_offsets(&image, img, partition, options)?;
+
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    println!("{report}");
+                }
+
+                Ok(())
+            },
+        )?,
+        Command::Image(DumpTable { image, out }) => run_image_command(
+            image,
+            ImageOptions {
+                read_only: true,
+                ..Default::default()
+            },
+            file_options,
+            |img: &PathBuf, _| file::partition_table::dump(img, &out),
+        )?,
+        Command::Image(RestoreTable {
+            image,
+            table,
+            image_options,
+        }) => run_image_command(image, image_options, file_options, |img: &PathBuf, _| {
+            file::partition_table::restore(img, &table)
+        })?,
+        Command::Image(Verity {
+            image,
+            partition,
+            hash_tree,
+            root_hash,
+            cmdline,
+            image_options,
+        }) => {
+            if let Some(cmdline) = &cmdline {
+                anyhow::ensure!(cmdline.is_absolute(), "cmdline isn't an absolute path");
+            }
+
+            run_image_command(
+                image,
+                image_options,
+                file_options,
+                |img: &PathBuf, options| {
+                    file::set_verity(
+                        img,
+                        partition,
+                        &hash_tree,
+                        root_hash.as_deref(),
+                        cmdline.as_deref(),
+                        options,
+                    )
+                },
+            )?
+        }
+        Command::Image(SetCmdline {
+            image,
+            append,
+            set,
+            config,
+            image_options,
+        }) => {
+            if let Some(config) = &config {
+                anyhow::ensure!(config.is_absolute(), "config isn't an absolute path");
+            }
+
+            let edit = match (append, set) {
+                (Some(append), _) => image::CmdlineEdit::Append(append),
+                (None, Some(set)) => image::CmdlineEdit::Set(set),
+                (None, None) => anyhow::bail!("either --append or --set is required"),
+            };
+
+            run_image_command(
+                image,
+                image_options,
+                file_options,
+                |img: &PathBuf, options| image::set_cmdline(img, config.as_deref(), &edit, options),
+            )?
+        }
+        Command::Partition(Format {
+            image,
+            partition,
+            fs,
+            fs_label,
+            yes,
+            image_options,
+        }) => {
+            anyhow::ensure!(
+                yes || prompt::query_yes_no(
+                    format!(
+                        "All content of partition {partition} of {} will be lost. Continue? [y/N]",
+                        image.to_string_lossy()
+                    ),
+                    std::io::BufReader::new(std::io::stdin()),
+                    std::io::stderr(),
+                )?,
+                "partition {partition} not formatted"
+            );
+
+            run_image_command(
+                image,
+                image_options,
+                file_options,
+                |img: &PathBuf, options| {
+                    file::ensure_partitions(
+                        img,
+                        &[partition.clone()],
+                        "format partition",
+                        options,
+                    )?;
+                    file::functions::format_partition(
+                        &partition,
+                        img,
+                        fs,
+                        fs_label.as_deref(),
+                        options,
+                    )
+                },
+            )?
+        }
         Command::Docker(Inject {
             docker_image,
             image,
             partition,
             dest,
-            generate_bmap,
-            compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img| {
+            image_options,
+        }) => run_image_command(image, image_options, file_options, |img, options| {
             anyhow::ensure!(
                 dest.to_string_lossy().ends_with(".tar.gz"),
                 format!(
@@ -169,7 +1454,9 @@ pub fn run() -> Result<()> {
                 ),
             );
 
-            let arch = image::image_arch(img)?;
+            file::ensure_partitions(img, &[partition.clone()], "inject docker image", options)?;
+
+            let arch = image::image_arch(img, options)?;
 
             let docker_path = docker::pull_image(&docker_image, arch)?;
 
@@ -180,6 +1467,7 @@ pub fn run() -> Result<()> {
                     &dest,
                 )],
                 img,
+                options,
             );
             std::fs::remove_file(docker_path)?;
 
@@ -198,47 +1486,171 @@ pub fn run() -> Result<()> {
             config,
             image,
             payload,
-            generate_bmap,
-            compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img| {
-            file::set_identity_config(&config, img, payload.as_deref())
+            merge,
+            image_options,
+        }) => run_image_command(image, image_options, file_options, |img, options| {
+            file::set_identity_config(&config, img, payload.as_deref(), merge, options)
         })?,
+        Command::Identity(Validate {
+            config,
+            payload,
+            device_cert,
+            device_key,
+            intermediate_full_chain_cert,
+        }) => {
+            let problems = validators::bundle::validate_bundle(&validators::bundle::Bundle {
+                config: &config,
+                payload: payload.as_deref(),
+                device_cert: device_cert.as_deref(),
+                device_key: device_key.as_deref(),
+                intermediate_full_chain_cert: intermediate_full_chain_cert.as_deref(),
+            })?;
+
+            for problem in &problems {
+                println!("{problem}");
+            }
+
+            anyhow::ensure!(
+                problems.is_empty(),
+                "identity validate: {} problem(s) found",
+                problems.len()
+            );
+
+            println!("{} is consistent", config.to_string_lossy());
+        }
+        Command::Identity(IdentityShow { image, redact }) => run_image_command(
+            image,
+            ImageOptions {
+                read_only: true,
+                ..Default::default()
+            },
+            file_options,
+            |img: &PathBuf, options| {
+                print!("{}", file::show_identity_config(img, redact, options)?);
+                Ok(())
+            },
+        )?,
+        Command::Identity(SetProvisioning {
+            image,
+            id_scope,
+            registration_id,
+            symmetric_key,
+            x509,
+            image_options,
+        }) => {
+            let attestation = match (symmetric_key, x509) {
+                (Some(key), _) => Some(file::DpsAttestation::SymmetricKey(key)),
+                (None, true) => Some(file::DpsAttestation::X509),
+                (None, false) => None,
+            };
+
+            run_image_command(image, image_options, file_options, |img, options| {
+                file::set_provisioning(img, &id_scope, &registration_id, attestation, options)
+            })?
+        }
         Command::Identity(SetDeviceCertificate {
             intermediate_full_chain_cert,
             intermediate_key,
+            intermediate_key_pkcs11,
+            key_type,
             image,
             device_id,
+            device_ids_csv,
+            days,
+            est_url,
+            est_trusted_ca,
+            est_auth,
+            image_options,
+        }) => {
+            let est = file::EstConfig {
+                url: est_url,
+                trusted_ca: est_trusted_ca,
+                auth: est_auth,
+            };
+
+            if let Some(trusted_ca) = &est.trusted_ca {
+                validators::certificate::validate_certificate(trusted_ca)?;
+            }
+
+            let issuer = create_issuer(
+                &intermediate_full_chain_cert,
+                intermediate_key,
+                intermediate_key_pkcs11,
+                key_type,
+                days,
+            )?;
+
+            if let Some(device_ids_csv) = device_ids_csv {
+                return set_device_certs_batch(
+                    &issuer,
+                    &intermediate_full_chain_cert,
+                    &est,
+                    &device_ids_csv,
+                    days,
+                    image,
+                    image_options,
+                    file_options,
+                );
+            }
+
+            let device_id = device_id.context("device id missing")?;
+            let (device_cert_path, device_key_path) = create_device_cert(
+                &issuer,
+                &device_id,
+                days,
+                image.parent().context("cannot get image directory")?,
+            )?;
+
+            run_image_command(image, image_options, file_options, |img, options| {
+                file::set_device_cert(
+                    Some(&intermediate_full_chain_cert),
+                    &device_cert_path,
+                    &device_key_path,
+                    img,
+                    options,
+                )?;
+
+                if !est.is_empty() {
+                    file::set_est_config(&est, &device_id, img, options)?;
+                }
+
+                Ok(())
+            })?
+        }
+        Command::Identity(RenewCert {
+            intermediate_full_chain_cert,
+            intermediate_key,
+            intermediate_key_pkcs11,
+            key_type,
+            image,
             days,
-            generate_bmap,
-            compress_image,
+            image_options,
         }) => {
-            let intermediate_full_chain_cert_str =
-                std::fs::read_to_string(&intermediate_full_chain_cert)
-                    .context("couldn't read intermediate fullchain cert")?;
-            let intermediate_key_str = std::fs::read_to_string(intermediate_key)
-                .context("couldn't read intermediate key")?;
-            let crypto = omnect_crypto::Crypto::new(
-                intermediate_key_str.as_bytes(),
-                intermediate_full_chain_cert_str.as_bytes(),
+            let issuer = create_issuer(
+                &intermediate_full_chain_cert,
+                intermediate_key,
+                intermediate_key_pkcs11,
+                key_type,
+                days,
             )?;
-            let (device_cert_pem, device_key_pem) = crypto
-                .create_cert_and_key(&device_id, &None, days)
-                .context("couldn't create device cert and key")?;
 
-            let device_cert_path = file::get_file_path(&image, "device_cert_path.pem")?;
-            let device_key_path = file::get_file_path(&image, "device_key_path.key.pem")?;
+            run_image_command(image, image_options, file_options, |img, options| {
+                let device_id = certificate::device_id(img, options)?;
+                let (device_cert_path, device_key_path) = create_device_cert(
+                    &issuer,
+                    &device_id,
+                    days,
+                    img.parent().context("cannot get image directory")?,
+                )?;
 
-            fs::write(&device_cert_path, device_cert_pem)
-                .context("set_device_cert: write device_cert_path")?;
-            fs::write(&device_key_path, device_key_pem)
-                .context("set_device_cert: write device_key_path")?;
+                info!("renew device certificate of {device_id}");
 
-            run_image_command(image, generate_bmap, compress_image, |img| {
                 file::set_device_cert(
                     Some(&intermediate_full_chain_cert),
                     &device_cert_path,
                     &device_key_path,
                     img,
+                    options,
                 )
             })?
         }
@@ -246,10 +1658,9 @@ pub fn run() -> Result<()> {
             device_cert: device_cert_pem,
             device_key: device_key_pem,
             image,
-            generate_bmap,
-            compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img| {
-            file::set_device_cert(None, &device_cert_pem, &device_key_pem, img)
+            image_options,
+        }) => run_image_command(image, image_options, file_options, |img, options| {
+            file::set_device_cert(None, &device_cert_pem, &device_key_pem, img, options)
         })?,
         Command::Identity(SetIotedgeGatewayConfig {
             config,
@@ -257,42 +1668,108 @@ pub fn run() -> Result<()> {
             root_ca,
             device_identity,
             device_identity_key,
-            generate_bmap,
-            compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::set_iotedge_gateway_config(
-                &config,
-                img,
-                &root_ca,
-                &device_identity,
-                &device_identity_key,
-            )
-        })?,
+            image_options,
+        }) => run_image_command(
+            image,
+            image_options,
+            file_options,
+            |img: &PathBuf, options| {
+                file::set_iotedge_gateway_config(
+                    &config,
+                    img,
+                    &root_ca,
+                    &device_identity,
+                    &device_identity_key,
+                    options,
+                )
+            },
+        )?,
         Command::Identity(SetIotLeafSasConfig {
             config,
             image,
             root_ca,
-            generate_bmap,
-            compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::set_iot_leaf_sas_config(&config, img, &root_ca)
-        })?,
+            image_options,
+        }) => run_image_command(
+            image,
+            image_options,
+            file_options,
+            |img: &PathBuf, options| file::set_iot_leaf_sas_config(&config, img, &root_ca, options),
+        )?,
         Command::Ssh(SetCertificate {
             image,
             root_ca,
-            generate_bmap,
-            compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::set_ssh_tunnel_certificate(img, &root_ca)
-        })?,
+            image_options,
+        }) => run_image_command(
+            image,
+            image_options,
+            file_options,
+            |img: &PathBuf, options| file::set_ssh_tunnel_certificate(img, &root_ca, options),
+        )?,
+        Command::Ssh(SetAuthorizedKeys {
+            image,
+            user,
+            keys,
+            image_options,
+        }) => run_image_command(
+            image,
+            image_options,
+            file_options,
+            |img: &PathBuf, options| file::set_authorized_keys(img, &user, &keys, options),
+        )?,
         Command::IotHubDeviceUpdate(IotHubDeviceUpdateSet {
             iot_hub_device_update_config,
+            from_components,
+            manufacturer,
+            model,
+            compatibility_id,
+            agent_name,
+            iot_hub_hostname,
+            device_id,
+            module_id,
+            shared_access_key,
+            partition,
+            path,
             image,
-            generate_bmap,
-            compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::set_iot_hub_device_update_config(&iot_hub_device_update_config, img)
-        })?,
+            image_options,
+        }) => {
+            let components = if from_components {
+                Some(validators::device_update::DuConfigComponents {
+                    manufacturer: manufacturer.context("--manufacturer is required")?,
+                    model: model.context("--model is required")?,
+                    compatibility_id,
+                    agent_name,
+                    iot_hub_hostname,
+                    device_id,
+                    module_id,
+                    shared_access_key,
+                })
+            } else {
+                None
+            };
+
+            run_image_command(
+                image,
+                image_options,
+                file_options,
+                |img: &PathBuf, options| match (components, iot_hub_device_update_config) {
+                    (Some(components), _) => {
+                        file::set_iot_hub_device_update_config_from_components(
+                            &components,
+                            partition,
+                            &path,
+                            img,
+                            options,
+                        )
+                    }
+                    (None, Some(config)) => file::set_iot_hub_device_update_config(
+                        &config, partition, &path, img, options,
+                    ),
+                    (None, None) => {
+                        anyhow::bail!("either --config or --from-components is required")
+                    }
+                },
+            )?
+        }
         Command::IotHubDeviceUpdate(IotHubDeviceUpdate::ImportUpdate {
             import_manifest: import_manifest_path,
             storage_container_name,
@@ -363,6 +1840,9 @@ pub fn run() -> Result<()> {
             priv_key_path,
             config_path,
             env,
+            issuer,
+            client_id,
+            scopes,
         }) => {
             #[tokio::main]
             async fn create_ssh_tunnel(
@@ -373,7 +1853,13 @@ pub fn run() -> Result<()> {
                 config_path: Option<PathBuf>,
                 env_config: config::BackendConfig,
             ) -> Result<()> {
-                let access_token = crate::auth::authorize(env_config.auth)
+                let auth_info = env_config
+                    .auth
+                    .auth_info()
+                    .await
+                    .context("create ssh tunnel")?;
+
+                let access_token = crate::auth::authorize(auth_info)
                     .await
                     .context("create ssh tunnel")?;
 
@@ -382,16 +1868,7 @@ pub fn run() -> Result<()> {
                 ssh::ssh_create_tunnel(device, username, config, access_token).await
             }
 
-            let env_conf: config::BackendConfig = if let Some(env_path) = env {
-                let config_file = std::fs::read_to_string(env_path)?;
-
-                toml::from_str(&config_file)?
-            } else {
-                config::BackendConfig {
-                    backend: url::Url::parse("https://cp.omnect.conplement.cloud")?,
-                    auth: config::AUTH_INFO_PROD.clone(),
-                }
-            };
+            let env_conf = backend_config(env, issuer, client_id, scopes)?;
 
             create_ssh_tunnel(
                 &device,
@@ -403,19 +1880,240 @@ pub fn run() -> Result<()> {
             )?;
         }
         Command::File(CopyToImage {
-            file_copy_params,
+            mut file_copy_params,
+            file,
+            partitions,
+            destination,
             image,
-            generate_bmap,
-            compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::copy_to_image(&file_copy_params, img)
-        })?,
+            dereference: _,
+            no_dereference,
+            mtime,
+            owner,
+            preserve_xattrs,
+            allow_overwrite,
+            incremental,
+            excludes,
+            max_file_size,
+            verify,
+            expect_existing_sha256,
+            expect_absent,
+            keep_going,
+            container_options,
+            image_options,
+        }) => {
+            if let (Some(file), Some(destination)) = (&file, &destination) {
+                file_copy_params.extend(FileCopyToParams::for_partitions(
+                    file,
+                    &partitions,
+                    destination,
+                )?);
+            }
+
+            // without --keep-going missing in-files fail before the image is processed
+            if !keep_going {
+                file_copy_params
+                    .iter()
+                    .try_for_each(FileCopyToParams::check_in_file)?;
+            }
+
+            if container_options.in_container {
+                let mut paths: Vec<&Path> = file_copy_params.iter().map(|p| p.in_file()).collect();
+                paths.push(&image);
+                paths.extend(image_options_paths(&image_options));
+
+                return docker::run_in_container(
+                    container_options.container_image.as_deref(),
+                    &paths,
+                );
+            }
+
+            file_options.allow_overwrite = allow_overwrite;
+            file_options.incremental = incremental;
+            file_options.keep_going = keep_going;
+            file_options.excludes = file::functions::parse_excludes(&excludes)?;
+            file_options.max_file_size = max_file_size;
+
+            anyhow::ensure!(
+                expect_existing_sha256.is_none() || file_copy_params.len() == 1,
+                "--expect-existing-sha256 requires a single copy triple"
+            );
+
+            let expectation = match (expect_existing_sha256, expect_absent) {
+                (Some(sha256), _) => Some(file::functions::Expectation::Sha256(sha256)),
+                (None, true) => Some(file::functions::Expectation::Absent),
+                (None, false) => None,
+            };
+
+            let file_copy_params: Vec<FileCopyToParams> = file_copy_params
+                .into_iter()
+                .map(|p| {
+                    let p = p
+                        .with_dereference(!no_dereference)
+                        .with_preserve_xattrs(preserve_xattrs);
+                    let p = match &expectation {
+                        Some(expectation) => p.with_expectation(expectation.clone()),
+                        None => p,
+                    };
+                    let p = match owner {
+                        Some((uid, gid)) => p.with_owner(uid, gid),
+                        None => p,
+                    };
+                    match mtime {
+                        Some(mtime) => p.with_mtime(mtime),
+                        None => p,
+                    }
+                })
+                .collect();
+
+            let mut failures = vec![];
+
+            run_image_command(
+                image,
+                image_options,
+                file_options,
+                |img: &PathBuf, options| {
+                    file::copy_to_image(&file_copy_params, img, options)?;
+
+                    if verify {
+                        file::verify_copy_to_image(&file_copy_params, img, options)?;
+                    }
+
+                    failures = options.copy_failures();
+
+                    Ok(())
+                },
+            )?;
+
+            // the image is written with the remaining copies anyway
+            anyhow::ensure!(
+                failures.is_empty(),
+                "--keep-going: {} copy triple(s) skipped:\n{}",
+                failures.len(),
+                failures.join("\n")
+            );
+        }
         Command::File(CopyFromImage {
             file_copy_params,
             image,
-        }) => run_image_command(image, false, None, |img: &PathBuf| {
-            file::copy_from_image(&file_copy_params, img)
-        })?,
+            read_options,
+            keep_partitions,
+            container_options,
+        }) => {
+            let image_options = ImageOptions {
+                keep_partitions,
+                ..read_options.into()
+            };
+
+            if container_options.in_container {
+                let mut paths: Vec<&Path> = file_copy_params.iter().map(|p| p.out_file()).collect();
+                paths.push(&image);
+                paths.extend(image_options_paths(&image_options));
+
+                return docker::run_in_container(
+                    container_options.container_image.as_deref(),
+                    &paths,
+                );
+            }
+
+            run_image_command(
+                image,
+                image_options,
+                file_options,
+                |img: &PathBuf, options| file::copy_from_image(&file_copy_params, img, options),
+            )?
+        }
+        Command::File(Append {
+            image,
+            partition,
+            path,
+            content,
+            image_options,
+        }) => {
+            anyhow::ensure!(path.is_absolute(), "path isn't an absolute path");
+
+            let content = match content.strip_prefix('@') {
+                Some(content_file) => fs::read(content_file)
+                    .context(format!("couldn't read content file {content_file}"))?,
+                None => content.into_bytes(),
+            };
+
+            run_image_command(
+                image,
+                image_options,
+                file_options,
+                |img: &PathBuf, options| {
+                    file::append_to_file(partition, &path, &content, img, options)
+                },
+            )?
+        }
+        Command::File(MovePartition {
+            from,
+            to,
+            keep_source,
+            image,
+            image_options,
+        }) => run_image_command(
+            image,
+            image_options,
+            file_options,
+            |img: &PathBuf, options| file::move_partition(&from, &to, keep_source, img, options),
+        )?,
+        Command::File(SetEnv {
+            env_vars,
+            image,
+            image_options,
+        }) => run_image_command(
+            image,
+            image_options,
+            file_options,
+            |img: &PathBuf, options| file::set_env(&env_vars, img, options),
+        )?,
+        Command::Schema { kind } => println!(
+            "{}",
+            serde_json::to_string_pretty(&validators::schema::json_schema(kind))?
+        ),
+        Command::File(SetNetwork {
+            config_files,
+            image,
+            image_options,
+        }) => run_image_command(
+            image,
+            image_options,
+            file_options,
+            |img: &PathBuf, options| file::set_network_config(&config_files, img, options),
+        )?,
+        Command::File(SetTimezone {
+            tz,
+            image,
+            image_options,
+        }) => run_image_command(
+            image,
+            image_options,
+            file_options,
+            |img: &PathBuf, options| file::set_timezone(&tz, img, options),
+        )?,
+        Command::Service(Service::Enable {
+            name,
+            target,
+            image,
+            image_options,
+        }) => run_image_command(
+            image,
+            image_options,
+            file_options,
+            |img: &PathBuf, options| file::enable_service(&name, target.as_deref(), img, options),
+        )?,
+        Command::Service(Service::Disable {
+            name,
+            target,
+            image,
+            image_options,
+        }) => run_image_command(
+            image,
+            image_options,
+            file_options,
+            |img: &PathBuf, options| file::disable_service(&name, target.as_deref(), img, options),
+        )?,
     }
 
     Ok(())
@@ -4,7 +4,7 @@ use std::process;
 
 fn main() {
     // storage_account_client logs cleartext credentials, the others are just unnecessarily verbose.
-    if cfg!(debug_assertions) {
+    let logger = if cfg!(debug_assertions) {
         Builder::from_env(Env::default().default_filter_or(concat!(
             "debug",
             ",azure_core::http_client::reqwest=debug",
@@ -15,7 +15,7 @@ fn main() {
             ",device_update_importer::blob_uploader=info",
             ",reqwest::async_impl::client=debug"
         )))
-        .init();
+        .build()
     } else {
         Builder::from_env(Env::default().default_filter_or(concat!(
             "info",
@@ -27,8 +27,11 @@ fn main() {
             ",device_update_importer::blob_uploader=info",
             ",reqwest::async_impl::client=debug"
         )))
-        .init();
-    }
+        .build()
+    };
+
+    // collects the warnings of omnect-cli, e.g. for --fail-on-warning
+    omnect_cli::diagnostics::init(logger).expect("failed to initialize logger");
 
     info!("version: {}", env!("CARGO_PKG_VERSION"));
 
@@ -0,0 +1,187 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Debug)]
+struct Manifest {
+    path: PathBuf,
+    // files copied into and partitions written of the image currently processed
+    files: Vec<Value>,
+    partitions: Vec<Value>,
+    compressed_delta: Option<Value>,
+    images: Vec<Value>,
+}
+
+/// Json manifest of all files copied into images and of the written images of
+/// an image command. Disabled by default, i.e. nothing is recorded.
+#[derive(Debug, Default)]
+pub struct ChangeManifest {
+    manifest: Option<Mutex<Manifest>>,
+}
+
+impl ChangeManifest {
+    /// Writes the manifest to `path`.
+    pub fn new(path: &Path) -> ChangeManifest {
+        ChangeManifest {
+            manifest: Some(Mutex::new(Manifest {
+                path: path.to_path_buf(),
+                files: vec![],
+                partitions: vec![],
+                compressed_delta: None,
+                images: vec![],
+            })),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.manifest.is_some()
+    }
+
+    fn lock(&self) -> Result<Option<MutexGuard<'_, Manifest>>> {
+        self.manifest
+            .as_ref()
+            .map(|m| {
+                m.lock()
+                    .map_err(|_| anyhow::anyhow!("manifest: change manifest poisoned"))
+            })
+            .transpose()
+    }
+
+    /// Records a file copied to `path` of the partition with number
+    /// `partition_num` with file specific `details`. A previous record of the
+    /// same destination is replaced, since the file was overwritten.
+    pub fn file(
+        &self,
+        partition: &str,
+        partition_num: &str,
+        path: &str,
+        details: Value,
+    ) -> Result<()> {
+        let Some(mut manifest) = self.lock()? else {
+            return Ok(());
+        };
+
+        manifest
+            .files
+            .retain(|f| f["partitionNum"] != partition_num || f["path"] != path);
+
+        let mut entry = json!({
+            "partition": partition,
+            "partitionNum": partition_num,
+            "path": path,
+        });
+
+        if let (Some(entry), Value::Object(details)) = (entry.as_object_mut(), details) {
+            entry.extend(details);
+        }
+
+        manifest.files.push(entry);
+
+        Ok(())
+    }
+
+    /// Records `changed_sectors` of `sector_size` bytes written to the partition
+    /// with number `partition_num`. Sectors of several writes of a partition are
+    /// summed up.
+    pub fn partition(
+        &self,
+        partition_num: &str,
+        changed_sectors: u64,
+        sector_size: u64,
+    ) -> Result<()> {
+        let Some(mut manifest) = self.lock()? else {
+            return Ok(());
+        };
+
+        let changed_bytes = changed_sectors * sector_size;
+
+        match manifest
+            .partitions
+            .iter_mut()
+            .find(|p| p["partitionNum"] == partition_num)
+        {
+            Some(entry) => {
+                entry["writes"] = json!(entry["writes"].as_u64().unwrap_or(0) + 1);
+                entry["changedSectors"] =
+                    json!(entry["changedSectors"].as_u64().unwrap_or(0) + changed_sectors);
+                entry["changedBytes"] =
+                    json!(entry["changedBytes"].as_u64().unwrap_or(0) + changed_bytes);
+            }
+            None => manifest.partitions.push(json!({
+                "partitionNum": partition_num,
+                "writes": 1,
+                "changedSectors": changed_sectors,
+                "changedBytes": changed_bytes,
+            })),
+        }
+
+        Ok(())
+    }
+
+    /// Records the `--compressed-delta` report of the image currently processed.
+    pub fn compressed_delta(&self, delta: Value) -> Result<()> {
+        let Some(mut manifest) = self.lock()? else {
+            return Ok(());
+        };
+
+        manifest.compressed_delta = Some(delta);
+
+        Ok(())
+    }
+
+    /// Records a written image together with the files copied into it, the
+    /// partitions written and the compressed delta since the previous image.
+    pub fn image(
+        &self,
+        image: &Path,
+        compression: Option<String>,
+        bmap: bool,
+        sha256: &str,
+    ) -> Result<()> {
+        let Some(mut manifest) = self.lock()? else {
+            return Ok(());
+        };
+
+        // partitions may be processed in parallel, so sort for a stable order
+        let mut files = std::mem::take(&mut manifest.files);
+        files.sort_by_key(|f| (f["partitionNum"].to_string(), f["path"].to_string()));
+        let mut partitions = std::mem::take(&mut manifest.partitions);
+        partitions.sort_by_key(|p| p["partitionNum"].to_string());
+        let compressed_delta = manifest.compressed_delta.take();
+
+        manifest.images.push(json!({
+            "image": image,
+            "compression": compression,
+            "bmap": bmap,
+            "sha256": sha256,
+            "files": files,
+            "partitions": partitions,
+            "compressedDelta": compressed_delta,
+        }));
+
+        Ok(())
+    }
+
+    /// Writes the manifest, if enabled. Files copied into an image which wasn't
+    /// written, e.g. since its content didn't change, aren't contained.
+    pub fn write(&self) -> Result<()> {
+        let Some(manifest) = self.lock()? else {
+            return Ok(());
+        };
+
+        let content = serde_json::to_string_pretty(&json!({ "images": manifest.images }))
+            .context("manifest: cannot serialize change manifest")?;
+
+        fs::write(&manifest.path, content).context(format!(
+            "manifest: cannot write {}",
+            manifest.path.to_string_lossy()
+        ))
+    }
+}
+
+/// Lowercase hex representation of a hash.
+pub fn hex(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,95 @@
+use anyhow::{Context, Result};
+
+/// Writes `query` to `writer` and reads the answer from `reader` until it is
+/// either yes or no; an empty answer means no.
+pub fn query_yes_no<R, W>(query: impl AsRef<str>, mut reader: R, mut writer: W) -> Result<bool>
+where
+    R: std::io::BufRead,
+    W: std::io::Write,
+{
+    writeln!(writer, "{}", query.as_ref())?;
+
+    loop {
+        let mut buffer = String::new();
+        reader
+            .read_line(&mut buffer)
+            .map(|err| anyhow::anyhow!("Can't read from stdin: {err}"))
+            .context("query_yes_no: cannot read answer")?;
+
+        match buffer.trim() {
+            "y" | "yes" => return Ok(true),
+            "N" | "No" | "" => return Ok(false),
+            _ => {
+                eprintln!("Please specify either y(es) or N(o)");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_query_yes_no_for_result(
+        input: &str,
+        expected_result: bool,
+        expected_output: &str,
+    ) -> bool {
+        let input = std::io::Cursor::new(input);
+        let mut output = vec![];
+
+        let result = query_yes_no("Some test query", input, &mut output).unwrap();
+
+        expected_result == result && expected_output != String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_query_yes_no_true_on_y_input_succeess() {
+        assert!(test_query_yes_no_for_result("y", true, ""));
+    }
+
+    #[test]
+    fn test_query_yes_no_true_on_yes_input_succeess() {
+        assert!(test_query_yes_no_for_result("yes", true, ""));
+    }
+
+    #[allow(non_snake_case)]
+    #[test]
+    fn test_query_yes_no_true_on_N_input_succeess() {
+        assert!(test_query_yes_no_for_result("N", false, ""));
+    }
+
+    #[allow(non_snake_case)]
+    #[test]
+    fn test_query_yes_no_true_on_No_input_succeess() {
+        assert!(test_query_yes_no_for_result("No", false, ""));
+    }
+
+    #[test]
+    fn test_query_yes_no_true_on_default_input_succeess() {
+        assert!(test_query_yes_no_for_result("", false, ""));
+    }
+
+    #[test]
+    fn test_query_yes_no_multiple_input_succeess() {
+        assert!(test_query_yes_no_for_result(
+            "123\n345\nyes",
+            true,
+            "Please specify either y(es) or N(o)\nPlease specify either y(es) or N(o)"
+        ));
+    }
+
+    #[test]
+    fn test_query_yes_no_false_on_missing_correct_input_succeess() {
+        assert!(test_query_yes_no_for_result(
+            "123\n345",
+            false,
+            "Please specify either y(es) or N(o)\nPlease specify either y(es) or N(o)"
+        ));
+    }
+
+    #[test]
+    fn test_query_yes_no_true_on_y_line_input_succeess() {
+        assert!(test_query_yes_no_for_result("y\n", true, ""));
+    }
+}
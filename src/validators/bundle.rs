@@ -0,0 +1,204 @@
+use super::identity::{validate_identity, IdentityType};
+use crate::certificate::common_name;
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+const DEVICE_CERT_URI: &str = "file:///mnt/cert/priv/device_id_cert.pem";
+const DEVICE_KEY_URI: &str = "file:///mnt/cert/priv/device_id_cert_key.pem";
+
+/// Identity configuration together with the files injected along with it.
+pub struct Bundle<'a> {
+    pub config: &'a Path,
+    pub payload: Option<&'a Path>,
+    pub device_cert: Option<&'a Path>,
+    pub device_key: Option<&'a Path>,
+    pub intermediate_full_chain_cert: Option<&'a Path>,
+}
+
+/// Checks `bundle` for internal consistency and returns all problems found:
+/// the config is valid, the device certificate and key it references are
+/// given, the key matches the certificate, the certificate is issued for the
+/// registration id by the intermediate certificate and isn't expired.
+pub fn validate_bundle(bundle: &Bundle) -> Result<Vec<String>> {
+    let mut problems = vec![];
+
+    match validate_identity(IdentityType::Standalone, bundle.config, &bundle.payload) {
+        Ok(warnings) => problems.extend(warnings.iter().map(|w| w.to_string())),
+        Err(e) => problems.push(format!("{e:#}")),
+    }
+
+    let config: toml::Table = match std::fs::read_to_string(bundle.config)
+        .context("validate_bundle: cannot read config")
+        .and_then(|c| toml::from_str(&c).context("validate_bundle: invalid toml"))
+    {
+        Ok(config) => config,
+        // already reported by validate_identity
+        Err(_) => return Ok(problems),
+    };
+    let config = toml::Value::Table(config);
+
+    for (uri, file, option) in [
+        (DEVICE_CERT_URI, bundle.device_cert, "--device-cert"),
+        (DEVICE_KEY_URI, bundle.device_key, "--device-key"),
+    ] {
+        match (references(&config, uri), file) {
+            (true, None) => {
+                problems.push(format!("config references {uri}, but {option} isn't given"))
+            }
+            (false, Some(_)) => problems.push(format!(
+                "{option} is given, but config doesn't reference {uri}"
+            )),
+            _ => {}
+        }
+    }
+
+    let Some(device_cert) = bundle.device_cert else {
+        return Ok(problems);
+    };
+    let device_cert = device_cert.to_string_lossy();
+
+    let cert = match openssl(&[
+        "x509",
+        "-noout",
+        "-pubkey",
+        "-subject",
+        "-nameopt",
+        "RFC2253",
+        "-in",
+        &device_cert,
+    ]) {
+        Ok(cert) => cert,
+        Err(e) => {
+            problems.push(format!("invalid device certificate {device_cert}: {e:#}"));
+            return Ok(problems);
+        }
+    };
+    let (cert_pubkey, subject) = cert
+        .rsplit_once("subject=")
+        .context("validate_bundle: unexpected openssl output")?;
+
+    if openssl(&["x509", "-noout", "-checkend", "0", "-in", &device_cert]).is_err() {
+        problems.push(format!("device certificate {device_cert} is expired"));
+    }
+
+    let registration_id = config
+        .get("provisioning")
+        .and_then(|p| p.get("attestation"))
+        .and_then(|a| a.get("registration_id"))
+        .and_then(|r| r.as_str());
+
+    match (registration_id, common_name(subject.trim())) {
+        (Some(registration_id), Some(cn)) if cn != registration_id => problems.push(format!(
+            "common name {cn} of the device certificate doesn't match registration id {registration_id}"
+        )),
+        (_, None) => problems.push(format!(
+            "device certificate {device_cert} has no common name"
+        )),
+        _ => {}
+    }
+
+    if let Some(device_key) = bundle.device_key {
+        let device_key = device_key.to_string_lossy();
+
+        match openssl(&["pkey", "-pubout", "-in", &device_key]) {
+            Ok(key_pubkey) if key_pubkey.trim() != cert_pubkey.trim() => problems.push(format!(
+                "device key {device_key} doesn't match device certificate {device_cert}"
+            )),
+            Ok(_) => {}
+            Err(e) => problems.push(format!("invalid device key {device_key}: {e:#}")),
+        }
+    }
+
+    if let Some(chain) = bundle.intermediate_full_chain_cert {
+        let chain = chain.to_string_lossy();
+
+        if let Err(e) = openssl(&["verify", "-partial_chain", "-CAfile", &chain, &device_cert]) {
+            problems.push(format!(
+                "device certificate {device_cert} isn't issued by {chain}: {e:#}"
+            ));
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Checks whether any string in `value` equals `uri`.
+fn references(value: &toml::Value, uri: &str) -> bool {
+    match value {
+        toml::Value::String(s) => s == uri,
+        toml::Value::Array(a) => a.iter().any(|v| references(v, uri)),
+        toml::Value::Table(t) => t.values().any(|v| references(v, uri)),
+        _ => false,
+    }
+}
+
+/// Runs openssl with `args` and returns its output; fails with the output on
+/// stderr.
+fn openssl(args: &[&str]) -> Result<String> {
+    let out = Command::new("openssl")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .context("openssl: cannot run openssl")?;
+
+    anyhow::ensure!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+
+    String::from_utf8(out.stdout).context("openssl: get output")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_references() {
+        let config: toml::Value = toml::from_str(&format!(
+            "[provisioning.attestation]\nidentity_cert = \"{DEVICE_CERT_URI}\"\n[cert_issuance.est]\ntrusted_certs = [\"{DEVICE_KEY_URI}\"]\n"
+        ))
+        .unwrap();
+
+        assert!(references(&config, DEVICE_CERT_URI));
+        assert!(references(&config, DEVICE_KEY_URI));
+        assert!(!references(&config, "file:///mnt/cert/ca/ca.crt"));
+    }
+
+    #[test]
+    fn bundle_with_unreferenced_device_cert() {
+        let problems = validate_bundle(&Bundle {
+            config: Path::new("testfiles/identity_config_dps_tpm.toml"),
+            payload: None,
+            device_cert: Some(Path::new("testfiles/test-int-ca.pem")),
+            device_key: Some(Path::new("testfiles/test-int-ca.key")),
+            intermediate_full_chain_cert: Some(Path::new("testfiles/test-ca.pem")),
+        })
+        .unwrap();
+
+        assert!(problems
+            .iter()
+            .any(|p| p.starts_with("--device-cert is given")));
+        // key and certificate match and the certificate is issued by the ca
+        assert!(!problems.iter().any(|p| p.contains("doesn't match")));
+        assert!(!problems.iter().any(|p| p.contains("isn't issued")));
+    }
+
+    #[test]
+    fn bundle_with_mismatching_device_key() {
+        let problems = validate_bundle(&Bundle {
+            config: Path::new("testfiles/identity_config_dps_x509_no_est.toml"),
+            payload: None,
+            device_cert: Some(Path::new("testfiles/test-int-ca.pem")),
+            device_key: Some(Path::new("testfiles/test-ca.key")),
+            intermediate_full_chain_cert: None,
+        })
+        .unwrap();
+
+        assert!(problems
+            .iter()
+            .any(|p| p.starts_with("device key testfiles/test-ca.key doesn't match")));
+    }
+}
@@ -0,0 +1,157 @@
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+
+fn not_after(cert_file: &Path) -> Result<String> {
+    let out = Command::new("openssl")
+        .args([
+            "x509",
+            "-noout",
+            "-enddate",
+            "-in",
+            &cert_file.to_string_lossy(),
+        ])
+        .stderr(Stdio::null())
+        .output()
+        .context("get certificate expiry")?;
+
+    anyhow::ensure!(out.status.success(), "invalid certificate format");
+
+    let out = String::from_utf8(out.stdout).context("get certificate expiry")?;
+
+    out.trim()
+        .strip_prefix("notAfter=")
+        .map(|s| s.to_string())
+        .ok_or_else(|| anyhow::anyhow!("unexpected certificate expiry format: {out}"))
+}
+
+/// Ensures that `cert_file` contains a certificate in pem format.
+pub fn validate_certificate(cert_file: &Path) -> Result<()> {
+    not_after(cert_file).context(format!(
+        "{} isn't a valid certificate",
+        cert_file.to_string_lossy()
+    ))?;
+
+    Ok(())
+}
+
+/// Ensures that a certificate valid for `days` from now and issued by the first
+/// certificate of `issuer_cert_file` doesn't outlive its issuer.
+pub fn validate_validity_period(issuer_cert_file: &Path, days: u32) -> Result<()> {
+    let seconds = u64::from(days) * 24 * 60 * 60;
+
+    // "openssl x509 -checkend" fails if the certificate expires within the given seconds
+    let status = Command::new("openssl")
+        .args([
+            "x509",
+            "-noout",
+            "-checkend",
+            &seconds.to_string(),
+            "-in",
+            &issuer_cert_file.to_string_lossy(),
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .context("validate certificate validity period")?;
+
+    if !status.success() {
+        anyhow::bail!(
+            "validity period of {days} days exceeds expiry of intermediate certificate ({})",
+            not_after(issuer_cert_file)?
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns whether the private key in pem file `key_file` is an Ed25519 key.
+pub fn is_ed25519_key(key_file: &Path) -> Result<bool> {
+    let out = Command::new("openssl")
+        .args([
+            "pkey",
+            "-noout",
+            "-text",
+            "-in",
+            &key_file.to_string_lossy(),
+        ])
+        .stderr(Stdio::null())
+        .output()
+        .context("get key type")?;
+
+    anyhow::ensure!(out.status.success(), "invalid key format");
+
+    Ok(String::from_utf8_lossy(&out.stdout).starts_with("ED25519 "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{path::PathBuf, str::FromStr};
+
+    // self-signed ca expiring in `days`, so that the tests don't depend on the
+    // expiry of the test certificates; integration tests use
+    // `Testrunner::short_lived_ca`, since they can't share test code with the crate
+    fn short_lived_ca(dir: &Path, days: u32) -> PathBuf {
+        let cert = dir.join("ca.pem");
+        let status = Command::new("openssl")
+            .args(["req", "-x509", "-newkey", "ec", "-pkeyopt"])
+            .arg("ec_paramgen_curve:prime256v1")
+            .args(["-nodes", "-subj", "/CN=short-lived-ca", "-days"])
+            .arg(days.to_string())
+            .arg("-keyout")
+            .arg(dir.join("ca.key"))
+            .arg("-out")
+            .arg(&cert)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        cert
+    }
+
+    #[test]
+    fn validate_short_validity_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = short_lived_ca(dir.path(), 30);
+
+        assert!(matches!(validate_validity_period(&cert, 1), Ok(())));
+        assert!(matches!(validate_validity_period(&cert, 29), Ok(())));
+    }
+
+    #[test]
+    fn decline_validity_period_exceeding_intermediate() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = short_lived_ca(dir.path(), 30);
+
+        let err = validate_validity_period(&cert, 31).unwrap_err();
+
+        assert!(err.to_string().contains(&not_after(&cert).unwrap()));
+    }
+
+    #[test]
+    fn decline_invalid_certificate() {
+        let cert = PathBuf::from_str("testfiles/non_ssh_ca").unwrap();
+
+        assert!(matches!(
+            validate_validity_period(&cert, 1),
+            Err(anyhow::Error { .. })
+        ));
+    }
+
+    #[test]
+    fn validate_certificate_format() {
+        assert!(validate_certificate(Path::new("testfiles/rootCA.crt")).is_ok());
+        assert!(validate_certificate(Path::new("testfiles/rootCA.key")).is_err());
+    }
+
+    #[test]
+    fn detect_ed25519_key() {
+        assert!(!is_ed25519_key(Path::new("testfiles/test-int-ca.key")).unwrap());
+        assert!(is_ed25519_key(Path::new("testfiles/test-int-ca.pem")).is_err());
+    }
+}
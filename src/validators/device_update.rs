@@ -1,8 +1,24 @@
 use anyhow::{Context, Result};
+use serde_json::{json, Value};
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
 
+/// Values `du-config.json` is assembled from, see
+/// https://learn.microsoft.com/en-us/azure/iot-hub-device-update/device-update-configuration-file
+pub struct DuConfigComponents {
+    pub manufacturer: String,
+    pub model: String,
+    pub compatibility_id: Option<String>,
+    pub agent_name: String,
+    /// the agent connects via AIS (identity service) unless a hostname is given
+    pub iot_hub_hostname: Option<String>,
+    pub device_id: Option<String>,
+    pub module_id: Option<String>,
+    /// base64 encoded shared access key
+    pub shared_access_key: Option<String>,
+}
+
 pub fn validate_config(device_update_conf_file: &Path) -> Result<()> {
     let file = File::open(device_update_conf_file).context(format!(
         "validate_du_config: failed to open {device_update_conf_file:?}"
@@ -14,3 +30,179 @@ pub fn validate_config(device_update_conf_file: &Path) -> Result<()> {
 
     Ok(())
 }
+
+fn ensure_component(name: &str, value: &str) -> Result<()> {
+    anyhow::ensure!(
+        !value.trim().is_empty(),
+        "config_from_components: {name} must not be empty"
+    );
+    anyhow::ensure!(
+        !value.contains(';'),
+        "config_from_components: {name} must not contain ';'"
+    );
+
+    Ok(())
+}
+
+/// Builds the connection string of the agent, `None` if it connects via AIS.
+fn connection_string(components: &DuConfigComponents) -> Result<Option<String>> {
+    let DuConfigComponents {
+        iot_hub_hostname,
+        device_id,
+        module_id,
+        shared_access_key,
+        ..
+    } = components;
+
+    let Some(hostname) = iot_hub_hostname else {
+        anyhow::ensure!(
+            device_id.is_none() && module_id.is_none() && shared_access_key.is_none(),
+            "config_from_components: connection string requires an iot hub hostname"
+        );
+        return Ok(None);
+    };
+
+    let (Some(device_id), Some(key)) = (device_id, shared_access_key) else {
+        anyhow::bail!(
+            "config_from_components: connection string requires device id and shared access key"
+        );
+    };
+
+    ensure_component("iot hub hostname", hostname)?;
+    ensure_component("device id", device_id)?;
+    ensure_component("shared access key", key)?;
+    anyhow::ensure!(
+        base64::decode(key).is_ok_and(|key| !key.is_empty()),
+        "config_from_components: shared access key isn't base64 encoded"
+    );
+
+    let mut connection_string = format!("HostName={hostname};DeviceId={device_id}");
+    if let Some(module_id) = module_id {
+        ensure_component("module id", module_id)?;
+        connection_string.push_str(&format!(";ModuleId={module_id}"));
+    }
+    connection_string.push_str(&format!(";SharedAccessKey={key}"));
+
+    Ok(Some(connection_string))
+}
+
+/// Assembles `du-config.json` like `conf/du-config.json.template` from
+/// `components`.
+pub fn config_from_components(components: &DuConfigComponents) -> Result<Value> {
+    ensure_component("manufacturer", &components.manufacturer)?;
+    ensure_component("model", &components.model)?;
+    ensure_component("agent name", &components.agent_name)?;
+
+    let (connection_type, connection_data) = match connection_string(components)? {
+        Some(connection_string) => ("string", connection_string),
+        None => ("AIS", String::new()),
+    };
+
+    let mut agent = json!({
+        "name": components.agent_name,
+        "runas": "adu",
+        "connectionSource": {
+            "connectionType": connection_type,
+            "connectionData": connection_data,
+        },
+        "manufacturer": components.manufacturer,
+        "model": components.model,
+    });
+    let mut compat_property_names = "manufacturer,model".to_string();
+
+    if let Some(compatibility_id) = &components.compatibility_id {
+        ensure_component("compatibility id", compatibility_id)?;
+        agent["additionalDeviceProperties"] = json!({ "compatibilityid": compatibility_id });
+        compat_property_names.push_str(",compatibilityid");
+    }
+
+    Ok(json!({
+        "schemaVersion": "1.1",
+        "aduShellTrustedUsers": ["adu", "do"],
+        "iotHubProtocol": "mqtt",
+        "compatPropertyNames": compat_property_names,
+        "manufacturer": components.manufacturer,
+        "model": components.model,
+        "agents": [agent],
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn components() -> DuConfigComponents {
+        DuConfigComponents {
+            manufacturer: "conplement-ag".to_string(),
+            model: "omnect-raspberrypi4-64-gateway-devel".to_string(),
+            compatibility_id: Some("2".to_string()),
+            agent_name: "AducIotAgent".to_string(),
+            iot_hub_hostname: None,
+            device_id: None,
+            module_id: None,
+            shared_access_key: None,
+        }
+    }
+
+    #[test]
+    fn du_config_from_components() {
+        let config = config_from_components(&components()).unwrap();
+        assert_eq!(
+            config["compatPropertyNames"],
+            "manufacturer,model,compatibilityid"
+        );
+        assert_eq!(
+            config["agents"][0]["connectionSource"]["connectionType"],
+            "AIS"
+        );
+        assert_eq!(
+            config["agents"][0]["additionalDeviceProperties"]["compatibilityid"],
+            "2"
+        );
+
+        let config = config_from_components(&DuConfigComponents {
+            compatibility_id: None,
+            iot_hub_hostname: Some("my-hub.azure-devices.net".to_string()),
+            device_id: Some("my-device".to_string()),
+            module_id: Some("omnect-device-service".to_string()),
+            shared_access_key: Some("c2VjcmV0".to_string()),
+            ..components()
+        })
+        .unwrap();
+        assert_eq!(config["compatPropertyNames"], "manufacturer,model");
+        assert!(config["agents"][0]
+            .get("additionalDeviceProperties")
+            .is_none());
+        assert_eq!(
+            config["agents"][0]["connectionSource"],
+            json!({
+                "connectionType": "string",
+                "connectionData": "HostName=my-hub.azure-devices.net;DeviceId=my-device;ModuleId=omnect-device-service;SharedAccessKey=c2VjcmV0",
+            })
+        );
+
+        // incomplete connection string
+        assert!(config_from_components(&DuConfigComponents {
+            iot_hub_hostname: Some("my-hub.azure-devices.net".to_string()),
+            ..components()
+        })
+        .is_err());
+        assert!(config_from_components(&DuConfigComponents {
+            device_id: Some("my-device".to_string()),
+            ..components()
+        })
+        .is_err());
+        assert!(config_from_components(&DuConfigComponents {
+            iot_hub_hostname: Some("my-hub.azure-devices.net".to_string()),
+            device_id: Some("my;device".to_string()),
+            shared_access_key: Some("c2VjcmV0".to_string()),
+            ..components()
+        })
+        .is_err());
+        assert!(config_from_components(&DuConfigComponents {
+            model: " ".to_string(),
+            ..components()
+        })
+        .is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::{anyhow, Context, Result};
 use log::debug;
 use regex::Regex;
+use schemars::JsonSchema;
 use serde::Deserialize;
 use std::collections::BTreeMap;
 use std::path::Path;
@@ -15,7 +16,7 @@ lazy_static! {
     .unwrap();
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
 struct CertAutoRenew {
@@ -24,7 +25,7 @@ struct CertAutoRenew {
     retry: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
 struct IdentityCert {
@@ -33,7 +34,15 @@ struct IdentityCert {
     auto_renew: Option<CertAutoRenew>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct SymmetricKey {
+    value: Option<String>,
+    uri: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
 struct AttestationEst {
@@ -41,9 +50,10 @@ struct AttestationEst {
     registration_id: Option<String>,
     trust_bundle_cert: Option<String>,
     identity_cert: Option<IdentityCert>,
+    symmetric_key: Option<SymmetricKey>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
 struct AttestationNoEst {
@@ -54,7 +64,7 @@ struct AttestationNoEst {
     identity_pk: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[serde(untagged)]
 #[allow(dead_code)]
@@ -63,7 +73,7 @@ enum Attestation {
     Est(AttestationEst),
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
 struct Authentication {
@@ -71,14 +81,14 @@ struct Authentication {
     device_id_pk: Option<BTreeMap<String, String>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
 struct Payload {
     uri: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 struct Provisioning {
     source: String,
@@ -92,7 +102,7 @@ struct Provisioning {
     payload: Option<Payload>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
 struct TpmHierarchyAuthorization {
@@ -100,14 +110,14 @@ struct TpmHierarchyAuthorization {
     owner: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
 struct TpmEndpoints {
     aziot_tpmd: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
 struct Tpm {
@@ -117,7 +127,7 @@ struct Tpm {
     endpoints: Option<TpmEndpoints>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
 struct EdgeCA {
@@ -125,22 +135,36 @@ struct EdgeCA {
     pk: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
 struct Auth {
-    bootstrap_identity_cert: String,
-    bootstrap_identity_pk: String,
+    bootstrap_identity_cert: Option<String>,
+    bootstrap_identity_pk: Option<String>,
+    identity_cert: Option<String>,
+    identity_pk: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+impl Auth {
+    // whether `cert_uri` authenticates to the EST server, either as bootstrap
+    // certificate or directly as EST identity certificate
+    fn uses_cert(&self, cert_uri: &str) -> bool {
+        [&self.bootstrap_identity_cert, &self.identity_cert]
+            .into_iter()
+            .any(|cert| cert.as_deref() == Some(cert_uri))
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
 struct Urls {
     default: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code, clippy::upper_case_acronyms)]
 struct EST {
@@ -149,14 +173,14 @@ struct EST {
     trusted_certs: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
 struct CertIssuance {
     est: Option<EST>,
 }
 
-#[derive(Debug, Validate, Deserialize)]
+#[derive(Debug, Validate, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
 pub struct IdentityConfig {
@@ -165,6 +189,7 @@ pub struct IdentityConfig {
         code = "hostname validation",
         message = "hostname is not compliant with rfc1035"
     ))]
+    #[schemars(regex = "RE_HOSTNAME")]
     pub hostname: String,
     local_gateway_hostname: Option<String>,
     provisioning: Option<Provisioning>,
@@ -307,10 +332,8 @@ pub fn validate_identity(
             .as_ref()
             .and_then(|ci| ci.est.as_ref())
             .map(|est| {
-                est.auth.bootstrap_identity_cert.as_str()
-                    == "file:///mnt/cert/priv/device_id_cert.pem"
-                    && est.auth.bootstrap_identity_cert.as_str()
-                        == "file:///mnt/cert/priv/device_id_cert.pem"
+                est.auth
+                    .uses_cert("file:///mnt/cert/priv/device_id_cert.pem")
                     && est
                         .trusted_certs
                         .iter()
@@ -323,6 +346,45 @@ pub fn validate_identity(
     Ok(out)
 }
 
+/// How a device certificate injected by omnect-cli is used by an identity
+/// config.
+#[derive(Debug, PartialEq)]
+pub enum DeviceCertUsage {
+    /// client certificate authenticating to an EST server, e.g. as bootstrap
+    /// certificate requesting identity certificates
+    EstBootstrap,
+    /// identity certificate presented to DPS for X.509 attestation
+    Identity,
+    /// not referenced
+    Unused,
+}
+
+/// Returns how the identity config `content` uses the certificate referenced
+/// as `device_cert_uri`.
+pub fn device_cert_usage(content: &str, device_cert_uri: &str) -> Result<DeviceCertUsage> {
+    let config: IdentityConfig =
+        toml::from_str(content).context("device_cert_usage: cannot parse identity config")?;
+
+    let identity = config
+        .provisioning
+        .as_ref()
+        .and_then(|p| p.attestation.as_ref())
+        .is_some_and(|a| matches!(a, Attestation::NoEst(a) if a.identity_cert == device_cert_uri));
+    let bootstrap = config
+        .cert_issuance
+        .as_ref()
+        .and_then(|ci| ci.est.as_ref())
+        .is_some_and(|est| est.auth.uses_cert(device_cert_uri));
+
+    Ok(if identity {
+        DeviceCertUsage::Identity
+    } else if bootstrap {
+        DeviceCertUsage::EstBootstrap
+    } else {
+        DeviceCertUsage::Unused
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -485,6 +547,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn identity_config_standalone_dps_symmetric_key() {
+        lazy_static::initialize(&LOG);
+        let result = validate_identity(
+            IdentityType::Standalone,
+            Path::new("testfiles/identity_config_dps_symmetric_key.toml"),
+            &None,
+        )
+        .unwrap();
+        assert_eq!(0, result.len());
+    }
+
     #[test]
     fn identity_config_dps_x509_est() {
         lazy_static::initialize(&LOG);
@@ -707,4 +781,28 @@ mod tests {
             result[0].find("attestation method should be tpm, x509 or symmetric_key")
         );
     }
+
+    #[test]
+    fn identity_config_device_cert_usage() {
+        let usage = |file: &str| {
+            device_cert_usage(
+                &std::fs::read_to_string(file).unwrap(),
+                "file:///mnt/cert/priv/device_id_cert.pem",
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            usage("testfiles/identity_config_dps_x509_est.toml"),
+            DeviceCertUsage::EstBootstrap
+        );
+        assert_eq!(
+            usage("testfiles/identity_config_dps_x509_no_est.toml"),
+            DeviceCertUsage::Identity
+        );
+        assert_eq!(
+            usage("testfiles/identity_config_dps_tpm.toml"),
+            DeviceCertUsage::Unused
+        );
+    }
 }
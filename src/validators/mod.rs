@@ -1,3 +1,7 @@
+pub mod bundle;
+pub mod certificate;
 pub mod device_update;
 pub mod identity;
+pub mod network;
+pub mod schema;
 pub mod ssh;
@@ -0,0 +1,165 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+
+// file types of systemd-networkd, see systemd.network(5), systemd.netdev(5) and systemd.link(5)
+const NETWORK_CONFIG_EXTENSIONS: [&str; 3] = ["network", "netdev", "link"];
+
+/// Checks a systemd-networkd config file for syntax errors, which networkd
+/// would only log on the device while ignoring the file or parts of it. The
+/// settings themselves aren't checked, except that a `.netdev` file has to
+/// name the device and its kind.
+pub fn validate_network_config(config_file: &Path) -> Result<()> {
+    let path = config_file.to_string_lossy();
+    let extension = config_file
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+
+    anyhow::ensure!(
+        NETWORK_CONFIG_EXTENSIONS.contains(&extension),
+        "validate_network_config: {path} must have extension .network, .netdev or .link"
+    );
+
+    let content = std::fs::read_to_string(config_file)
+        .context(format!("validate_network_config: cannot read {path}"))?;
+    let sections =
+        parse_sections(&content).context(format!("validate_network_config: invalid {path}"))?;
+
+    anyhow::ensure!(
+        !sections.is_empty(),
+        "validate_network_config: {path} has no settings"
+    );
+
+    if extension == "netdev" {
+        for key in ["Name", "Kind"] {
+            anyhow::ensure!(
+                sections
+                    .iter()
+                    .any(|(section, keys)| section == "NetDev" && keys.iter().any(|k| k == key)),
+                "validate_network_config: {path} lacks {key}= in section [NetDev]"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses the ini-style syntax of systemd config files and returns the
+/// sections with the keys set in them. Values may be continued on the next
+/// line by a trailing backslash.
+fn parse_sections(content: &str) -> Result<Vec<(String, Vec<String>)>> {
+    let mut sections: Vec<(String, Vec<String>)> = vec![];
+    let mut continued = false;
+
+    for (i, line) in content.lines().enumerate() {
+        let n = i + 1;
+        let line = line.trim();
+
+        if continued {
+            continued = line.ends_with('\\');
+            continue;
+        }
+
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(section) = line.strip_prefix('[') {
+            let section = section
+                .strip_suffix(']')
+                .context(format!("line {n}: unterminated section header {line}"))?;
+
+            anyhow::ensure!(
+                !section.is_empty()
+                    && section
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-'),
+                "line {n}: invalid section name {line}"
+            );
+
+            sections.push((section.to_string(), vec![]));
+            continue;
+        }
+
+        let (key, _) = line
+            .split_once('=')
+            .context(format!("line {n}: expected KEY=VALUE or [Section]: {line}"))?;
+        let key = key.trim();
+
+        anyhow::ensure!(
+            !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'),
+            "line {n}: invalid key {key}"
+        );
+
+        sections
+            .last_mut()
+            .context(format!("line {n}: {key} is set outside of a section"))?
+            .1
+            .push(key.to_string());
+
+        continued = line.ends_with('\\');
+    }
+
+    Ok(sections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_config_sections() {
+        let sections = parse_sections(
+            "# static ip\n[Match]\nName=eth0\n\n[Network]\nAddress=192.168.0.10/24\nDNS=192.168.0.1 \\\n    192.168.0.2\n; comment\nVLAN=vlan10\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            sections,
+            vec![
+                ("Match".to_string(), vec!["Name".to_string()]),
+                (
+                    "Network".to_string(),
+                    vec!["Address".to_string(), "DNS".to_string(), "VLAN".to_string()]
+                ),
+            ]
+        );
+
+        assert!(parse_sections("[SR-IOV]\nVirtualFunction=0\n").is_ok());
+        assert!(parse_sections("Name=eth0\n[Match]\n").is_err());
+        assert!(parse_sections("[Match\nName=eth0\n").is_err());
+        assert!(parse_sections("[Match]\nName eth0\n").is_err());
+        assert!(parse_sections("[Match]\nMy Name=eth0\n").is_err());
+        assert!(parse_sections("[]\n").is_err());
+    }
+
+    #[test]
+    fn network_config_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = |name: &str, content: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            path
+        };
+
+        assert!(validate_network_config(&file(
+            "10-eth0.network",
+            "[Match]\nName=eth0\n[Network]\nDHCP=yes\n"
+        ))
+        .is_ok());
+        assert!(validate_network_config(&file(
+            "20-vlan10.netdev",
+            "[NetDev]\nName=vlan10\nKind=vlan\n[VLAN]\nId=10\n"
+        ))
+        .is_ok());
+
+        // networkd ignores netdevs without name or kind
+        assert!(validate_network_config(&file("vlan.netdev", "[NetDev]\nName=vlan10\n")).is_err());
+        assert!(validate_network_config(&file("empty.network", "# nothing\n")).is_err());
+        assert!(validate_network_config(&file("eth0.yaml", "network:\n  version: 2\n")).is_err());
+        assert!(validate_network_config(&dir.path().join("missing.network")).is_err());
+    }
+}
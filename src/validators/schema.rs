@@ -0,0 +1,39 @@
+use super::identity::IdentityConfig;
+use schemars::schema::RootSchema;
+
+/// Config files validated by omnect-cli a JSON schema can be exported for.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum SchemaKind {
+    /// config.toml of the identity service as validated by `identity set-config`
+    IdentityConfig,
+    /// du-config.json as validated by `iot-hub-device-update set-device-config`,
+    /// currently any valid json
+    DuConfig,
+}
+
+/// Returns the JSON schema of `kind`, generated from the types config files
+/// are deserialized into for validation. Checks beyond these types, e.g. of
+/// the provisioning method's required fields, aren't contained.
+pub fn json_schema(kind: SchemaKind) -> RootSchema {
+    match kind {
+        SchemaKind::IdentityConfig => schemars::schema_for!(IdentityConfig),
+        SchemaKind::DuConfig => schemars::schema_for!(serde_json::Value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_config_schema() {
+        let schema = serde_json::to_value(json_schema(SchemaKind::IdentityConfig)).unwrap();
+
+        assert_eq!(schema["required"], serde_json::json!(["hostname"]));
+        assert_eq!(schema["additionalProperties"], false);
+        assert!(schema["properties"]["hostname"]["pattern"]
+            .as_str()
+            .is_some_and(|p| p.starts_with("^[a-zA-Z]")));
+        assert!(schema["definitions"]["Provisioning"]["properties"]["attestation"].is_object());
+    }
+}
@@ -38,6 +38,16 @@ fn validate_key_format(root_ca_file: &Path) -> Result<()> {
         .ok_or_else(|| anyhow::anyhow!("invalid key format"))
 }
 
+/// Validates a single line of an authorized_keys file, e.g.
+/// "ssh-ed25519 AAAA... user@host". All key types supported by ssh-keygen are
+/// accepted.
+pub fn validate_authorized_key(key: &str) -> Result<()> {
+    let file = tempfile::NamedTempFile::new().context("validate authorized key")?;
+    std::fs::write(file.path(), format!("{key}\n")).context("validate authorized key")?;
+
+    validate_key_format(file.path()).context(format!("invalid ssh public key: {key}"))
+}
+
 pub fn validate_ssh_pub_key(root_ca_file: &Path) -> Result<()> {
     validate_key_type(root_ca_file)?;
 
@@ -102,6 +112,20 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn validate_authorized_keys() {
+        for key in [ed25519_key(), non_ed25519_key()] {
+            let key = std::fs::read_to_string(key).unwrap();
+
+            assert!(matches!(validate_authorized_key(key.trim()), Ok(())));
+        }
+
+        assert!(matches!(
+            validate_authorized_key("ssh-ed25519 invalid"),
+            Err(anyhow::Error { .. })
+        ));
+    }
+
     #[test]
     fn validate_ed25519_ssh_pub_key() {
         let key = ed25519_key();
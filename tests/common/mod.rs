@@ -4,11 +4,34 @@ use ring::digest::{Context, SHA256};
 use std::fs::copy;
 use std::fs::File;
 use std::fs::{create_dir_all, remove_dir_all};
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Write};
 use std::path::PathBuf;
+use std::process::{Command, Stdio};
 
 const TMPDIR_FORMAT_STR: &str = "/tmp/omnect-cli-integration-tests/";
 
+// (name, start sector, sector count) of the partitions of a synthetic image.
+// the order matches the gpt layout of omnect-os images.
+const SYNTHETIC_PARTITIONS: [(&str, u64, u64); 5] = [
+    ("boot", 2048, 8192),
+    ("rootA", 10240, 16384),
+    ("rootB", 26624, 8192),
+    ("factory", 34816, 8192),
+    ("cert", 43008, 8192),
+];
+// sectors following the last partition, e.g. for the backup gpt header
+const SYNTHETIC_IMAGE_TRAILER_SECTORS: u64 = 1024;
+
+const SYNTHETIC_FSTAB: &str = "\
+/dev/omnect/boot   /boot        vfat defaults 0 0
+PARTLABEL=factory  /mnt/factory ext4 defaults 0 2
+/dev/mmcblk0p5     /mnt/cert    ext4 defaults 0 2
+";
+const SYNTHETIC_HOSTS: &str = "127.0.0.1 localhost\n127.0.1.1 omnect-device\n";
+const SYNTHETIC_OS_RELEASE: &str = "ID=omnect-os\nOMNECT_TARGET_ARCH=\"x86_64\"\n";
+const SYNTHETIC_PASSWD: &str =
+    "root:x:0:0:root:/home/root:/bin/sh\nomnect:x:1000:1000::/home/omnect:/bin/sh\n";
+
 lazy_static! {
     static ref LOG: () = if cfg!(debug_assertions) {
         Builder::from_env(Env::default().default_filter_or("debug")).init()
@@ -41,6 +64,196 @@ impl Testrunner {
         copy(file, &path).unwrap();
         path
     }
+
+    /// Creates a self-signed ca certificate expiring in `days` and its key,
+    /// e.g. to test validity periods independently of the current date. Unlike
+    /// the one of the unit tests in src/validators/certificate.rs, which
+    /// integration tests can't use, it is marked as ca (basicConstraints), so
+    /// that it can issue device certificates. Requires openssl.
+    pub fn short_lived_ca(&self, days: u32) -> (PathBuf, PathBuf) {
+        let cert = self.pathbuf().join("short-lived-ca.pem");
+        let key = self.pathbuf().join("short-lived-ca.key");
+
+        let status = Command::new("openssl")
+            .args(["req", "-x509", "-newkey", "ec", "-pkeyopt"])
+            .arg("ec_paramgen_curve:prime256v1")
+            .args(["-nodes", "-subj", "/CN=short-lived-ca", "-days"])
+            .arg(days.to_string())
+            .args(["-addext", "basicConstraints=critical,CA:TRUE", "-keyout"])
+            .arg(&key)
+            .arg("-out")
+            .arg(&cert)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        (cert, key)
+    }
+
+    /// Creates a minimal gpt image named `name` with the partition layout of
+    /// omnect-os, i.e. a vfat boot partition followed by ext4 partitions rootA,
+    /// rootB, factory and cert. rootA contains /etc/hosts, /etc/fstab and
+    /// /usr/lib/os-release. Requires sfdisk, mkfs.vfat and mkfs.ext4.
+    pub fn synthetic_image(&self, name: &str) -> PathBuf {
+        self.synthetic_image_without(name, &[])
+    }
+
+    /// Same as `synthetic_image`, but without the partitions in `omit`. Since
+    /// partitions are numbered consecutively, only trailing partitions should
+    /// be omitted to keep the numbering of the remaining ones.
+    pub fn synthetic_image_without(&self, name: &str, omit: &[&str]) -> PathBuf {
+        let partitions: Vec<_> = SYNTHETIC_PARTITIONS
+            .into_iter()
+            .filter(|(partition, _, _)| !omit.contains(partition))
+            .collect();
+
+        self.synthetic_image_with(name, &partitions)
+    }
+
+    /// Same as `synthetic_image`, but with an additional ext4 partition
+    /// `extra` inserted before `cert`, which shifts the number of `cert`.
+    pub fn synthetic_image_with_extra_partition(&self, name: &str, extra: &str) -> PathBuf {
+        let mut partitions = SYNTHETIC_PARTITIONS.to_vec();
+        let (_, start, size) = partitions.pop().unwrap();
+        partitions.push((extra, start, size));
+        partitions.push(("cert", start + size, size));
+
+        self.synthetic_image_with(name, &partitions)
+    }
+
+    fn synthetic_image_with(&self, name: &str, partitions: &[(&str, u64, u64)]) -> PathBuf {
+        let image = self.pathbuf().join(name);
+        let parts_dir = self.pathbuf().join(format!("{name}.parts"));
+        let rootfs_dir = parts_dir.join("rootA");
+
+        create_dir_all(rootfs_dir.join("etc")).unwrap();
+        create_dir_all(rootfs_dir.join("usr/lib")).unwrap();
+        std::fs::write(rootfs_dir.join("etc/fstab"), SYNTHETIC_FSTAB).unwrap();
+        std::fs::write(rootfs_dir.join("etc/hosts"), SYNTHETIC_HOSTS).unwrap();
+        std::fs::write(rootfs_dir.join("usr/lib/os-release"), SYNTHETIC_OS_RELEASE).unwrap();
+        std::fs::write(rootfs_dir.join("etc/passwd"), SYNTHETIC_PASSWD).unwrap();
+
+        let end = partitions
+            .iter()
+            .map(|(_, start, size)| start + size)
+            .max()
+            .unwrap();
+        File::create(&image)
+            .unwrap()
+            .set_len((end + SYNTHETIC_IMAGE_TRAILER_SECTORS) * 512)
+            .unwrap();
+
+        // partition table
+        let mut sfdisk_script = String::from("label: gpt\nunit: sectors\n");
+        for (name, start, size) in partitions {
+            sfdisk_script.push_str(&format!("start={start}, size={size}, name={name}\n"));
+        }
+        let mut sfdisk = Command::new("sfdisk")
+            .arg("--quiet")
+            .arg(&image)
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        sfdisk
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(sfdisk_script.as_bytes())
+            .unwrap();
+        assert!(sfdisk.wait().unwrap().success());
+
+        // file systems
+        for &(name, start, size) in partitions {
+            let part = parts_dir.join(format!("{name}.img"));
+
+            File::create(&part).unwrap().set_len(size * 512).unwrap();
+
+            let status = if name == "boot" {
+                Command::new("mkfs.vfat")
+                    .arg("-n")
+                    .arg("BOOT")
+                    .arg(&part)
+                    .stdout(Stdio::null())
+                    .status()
+            } else {
+                let mut mkfs = Command::new("mkfs.ext4");
+                mkfs.arg("-q").arg("-F").arg("-L").arg(name);
+                if name == "rootA" {
+                    mkfs.arg("-d").arg(&rootfs_dir);
+                }
+                mkfs.arg(&part).status()
+            };
+            assert!(status.unwrap().success());
+
+            let status = Command::new("dd")
+                .arg(format!("if={}", part.to_str().unwrap()))
+                .arg(format!("of={}", image.to_str().unwrap()))
+                .arg("bs=512")
+                .arg(format!("seek={start}"))
+                .arg("conv=notrunc,sparse")
+                .arg("status=none")
+                .status();
+            assert!(status.unwrap().success());
+        }
+
+        remove_dir_all(parts_dir).unwrap();
+
+        image
+    }
+
+    /// Creates an empty vfat (`vfat == true`) or ext4 file system image of
+    /// 4MiB named `name`, i.e. a bare partition without partition table.
+    pub fn raw_partition_image(&self, name: &str, vfat: bool) -> PathBuf {
+        let image = self.pathbuf().join(name);
+
+        File::create(&image)
+            .unwrap()
+            .set_len(4 * 1024 * 1024)
+            .unwrap();
+
+        let status = if vfat {
+            Command::new("mkfs.vfat")
+                .arg(&image)
+                .stdout(Stdio::null())
+                .status()
+        } else {
+            Command::new("mkfs.ext4")
+                .arg("-q")
+                .arg("-F")
+                .arg(&image)
+                .status()
+        };
+        assert!(status.unwrap().success());
+
+        image
+    }
+
+    /// Returns the file system UUID of `partition` of an image created by
+    /// `synthetic_image`. Requires blkid.
+    pub fn synthetic_fs_uuid(image: &PathBuf, partition: &str) -> String {
+        let (_, start, _) = SYNTHETIC_PARTITIONS
+            .into_iter()
+            .find(|(name, _, _)| *name == partition)
+            .unwrap();
+
+        let output = Command::new("blkid")
+            .arg("-p")
+            .arg("-o")
+            .arg("value")
+            .arg("-s")
+            .arg("UUID")
+            .arg("-O")
+            .arg((start * 512).to_string())
+            .arg(image)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
     pub fn file_hash(path: &PathBuf) -> String {
         let mut context = Context::new(&SHA256);
         let mut buffer = [0; 1024];
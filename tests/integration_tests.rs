@@ -197,6 +197,47 @@ fn check_set_identity_leaf_config() {
         .contains("my-omnect-iot-leaf-device"));
 }
 
+#[test]
+fn check_identity_validate() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+
+    let config_file_path = tr.to_pathbuf("testfiles/identity_config_dps_x509_no_est.toml");
+    let device_cert_path = tr.to_pathbuf("testfiles/test-int-ca.pem");
+    let device_key_path = tr.to_pathbuf("testfiles/test-ca.key");
+
+    // device key missing and device cert not issued for registration id
+    let mut identity_validate = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = identity_validate
+        .arg("identity")
+        .arg("validate")
+        .arg("-c")
+        .arg(&config_file_path)
+        .arg("--device-cert")
+        .arg(&device_cert_path)
+        .assert();
+    let assert = assert.failure();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+    assert!(stdout.contains("--device-key isn't given"));
+    assert!(stdout.contains("doesn't match registration id test-omnect-no-est"));
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr).contains("2 problem(s) found"));
+
+    // device key doesn't match device cert
+    let mut identity_validate = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = identity_validate
+        .arg("identity")
+        .arg("validate")
+        .arg("-c")
+        .arg(&config_file_path)
+        .arg("--device-cert")
+        .arg(&device_cert_path)
+        .arg("--device-key")
+        .arg(&device_key_path)
+        .assert();
+    let assert = assert.failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stdout)
+        .contains("doesn't match device certificate"));
+}
+
 #[test]
 fn check_set_identity_config_est_template() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
@@ -398,48 +439,49 @@ fn check_set_identity_config_tpm_template() {
 }
 
 #[test]
-fn check_set_device_cert_est() {
+fn check_set_identity_config_merge() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+
+    let config_file_path = tr.to_pathbuf("conf/config.toml.est.template");
     let image_path = tr.to_pathbuf("testfiles/image.wic");
-    let intermediate_full_chain_crt_path = tr.to_pathbuf("testfiles/test-int-ca_fullchain.pem");
-    let intermediate_full_chain_crt_key_path = tr.to_pathbuf("testfiles/test-int-ca.key");
+    let mut overlay_path = tr.pathbuf();
+    overlay_path.push("overlay.toml");
+    std::fs::write(
+        &overlay_path,
+        "hostname = \"test-omnect-merged\"\n\n[provisioning]\nid_scope = \"0neYYYYYYYY\"\n",
+    )
+    .unwrap();
 
-    let mut set_device_certificate = Command::cargo_bin("omnect-cli").unwrap();
-    let assert = set_device_certificate
+    let mut set_identity_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_identity_config
         .arg("identity")
-        .arg("set-device-certificate")
+        .arg("set-config")
         .arg("-c")
-        .arg(&intermediate_full_chain_crt_path)
-        .arg("-k")
-        .arg(&intermediate_full_chain_crt_key_path)
+        .arg(&config_file_path)
         .arg("-i")
         .arg(&image_path)
-        .arg("-d")
-        .arg("my-device-id")
-        .arg("-D")
-        .arg("1")
         .assert();
     assert.success();
 
-    let mut device_id_cert_out_path = tr.pathbuf();
-    device_id_cert_out_path.push("dir1");
-    create_dir_all(device_id_cert_out_path.clone()).unwrap();
-
-    let mut device_id_cert_key_out_path = device_id_cert_out_path.clone();
-    let mut ca_crt_pem_out_path = device_id_cert_out_path.clone();
-    let mut ca_pem_out_path = device_id_cert_out_path.clone();
-
-    device_id_cert_out_path.push("device_id_cert_out_path");
-    let device_id_cert_out_path = device_id_cert_out_path.to_str().unwrap();
-
-    device_id_cert_key_out_path.push("device_id_cert_key_out_path");
-    let device_id_cert_key_out_path = device_id_cert_key_out_path.to_str().unwrap();
-
-    ca_crt_pem_out_path.push("ca_crt_pem_out_path");
-    let ca_crt_pem_out_path = ca_crt_pem_out_path.to_str().unwrap();
+    // merging twice yields the same result
+    for _ in 0..2 {
+        let mut set_identity_config = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = set_identity_config
+            .arg("identity")
+            .arg("set-config")
+            .arg("--merge")
+            .arg("-c")
+            .arg(&overlay_path)
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+    }
 
-    ca_pem_out_path.push("ca_pem_out_path");
-    let ca_pem_out_path = ca_pem_out_path.to_str().unwrap();
+    let mut config_file_out_path = tr.pathbuf();
+    config_file_out_path.push("config_file_out_path");
+    let mut hostname_file_out_path = tr.pathbuf();
+    hostname_file_out_path.push("hostname_file_out_path");
 
     let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
     let assert = copy_from_img
@@ -447,58 +489,130 @@ fn check_set_device_cert_est() {
         .arg("copy-from-image")
         .arg("-f")
         .arg(format!(
-            "cert:/priv/device_id_cert.pem,{device_id_cert_out_path}"
+            "factory:/etc/aziot/config.toml,{}",
+            config_file_out_path.to_str().unwrap()
         ))
         .arg("-f")
         .arg(format!(
-            "cert:/priv/device_id_cert_key.pem,{device_id_cert_key_out_path}"
+            "factory:/etc/hostname,{}",
+            hostname_file_out_path.to_str().unwrap()
         ))
-        .arg("-f")
-        .arg(format!("cert:/priv/ca.crt.pem,{ca_crt_pem_out_path}"))
-        .arg("-f")
-        .arg(format!("cert:/ca/ca.crt,{ca_pem_out_path}"))
         .arg("-i")
         .arg(&image_path)
         .assert();
     assert.success();
 
-    assert!(file_diff::diff(
-        intermediate_full_chain_crt_path.to_str().unwrap(),
-        ca_crt_pem_out_path
-    ));
+    let config = std::fs::read_to_string(&config_file_out_path).unwrap();
+    assert!(config.contains("hostname = \"test-omnect-merged\""));
+    assert!(config.contains("id_scope = \"0neYYYYYYYY\""));
+    assert!(config.contains("registration_id = \"test-omnect-est\""));
+    assert!(config.contains("rotate_key = true"));
+
+    assert!(std::fs::read_to_string(hostname_file_out_path)
+        .unwrap()
+        .contains("test-omnect-merged"));
 }
 
 #[test]
-fn check_set_device_cert_no_est() {
+fn check_identity_show() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+
+    let config_file_path = tr.to_pathbuf("testfiles/identity_config_dps_symmetric_key.toml");
     let image_path = tr.to_pathbuf("testfiles/image.wic");
-    let device_crt_path = tr.to_pathbuf("testfiles/test-int-ca_fullchain.pem");
-    let device_crt_key_path = tr.to_pathbuf("testfiles/test-int-ca.key");
 
-    let mut set_device_certificate_no_est = Command::cargo_bin("omnect-cli").unwrap();
-    let assert = set_device_certificate_no_est
+    let mut set_identity_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_identity_config
         .arg("identity")
-        .arg("set-device-certificate-no-est")
+        .arg("set-config")
         .arg("-c")
-        .arg(&device_crt_path)
-        .arg("-k")
-        .arg(&device_crt_key_path)
+        .arg(&config_file_path)
         .arg("-i")
         .arg(&image_path)
         .assert();
     assert.success();
 
-    let mut device_cert_out_path = tr.pathbuf();
-    device_cert_out_path.push("dir1");
-    create_dir_all(device_cert_out_path.clone()).unwrap();
+    let mut identity_show = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = identity_show
+        .arg("identity")
+        .arg("show")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    let assert = assert.success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+    assert!(stdout.contains("YWJjZGVmZ2hpamtsbW5vcHFyc3R1dnd4eXo="));
 
-    let mut device_cert_key_out_path = device_cert_out_path.clone();
+    let mut identity_show = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = identity_show
+        .arg("identity")
+        .arg("show")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--redact")
+        .assert();
+    let assert = assert.success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+    let config: toml::Table = toml::from_str(&stdout).unwrap();
+
+    assert!(!stdout.contains("YWJjZGVmZ2hpamtsbW5vcHFyc3R1dnd4eXo="));
+    assert_eq!(
+        config["provisioning"]["attestation"]["symmetric_key"].as_str(),
+        Some("<redacted>")
+    );
+    assert_eq!(
+        config["provisioning"]["attestation"]["method"].as_str(),
+        Some("symmetric_key")
+    );
+}
 
-    device_cert_out_path.push("device_id_cert_out_path");
-    let device_cert_out_path = device_cert_out_path.to_str().unwrap();
+#[test]
+fn check_set_provisioning() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
 
-    device_cert_key_out_path.push("device_id_cert_key_out_path");
-    let device_cert_key_out_path = device_cert_key_out_path.to_str().unwrap();
+    let config_file_path = tr.to_pathbuf("conf/config.toml.tpm.template");
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let config_file_out_path = tr.pathbuf().join("config_file_out_path");
+
+    let mut set_identity_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_identity_config
+        .arg("identity")
+        .arg("set-config")
+        .arg("-c")
+        .arg(&config_file_path)
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    // symmetric key and x509 are mutually exclusive
+    let mut set_provisioning = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_provisioning
+        .arg("identity")
+        .arg("set-provisioning")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-s")
+        .arg("0neYYYYYYYY")
+        .arg("-r")
+        .arg("my-new-reg-id")
+        .arg("--symmetric-key")
+        .arg("a2V5")
+        .arg("--x509")
+        .assert();
+    assert.failure();
+
+    let mut set_provisioning = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_provisioning
+        .arg("identity")
+        .arg("set-provisioning")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-s")
+        .arg("0neYYYYYYYY")
+        .arg("-r")
+        .arg("my-new-reg-id")
+        .assert();
+    assert.success();
 
     let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
     let assert = copy_from_img
@@ -506,51 +620,139 @@ fn check_set_device_cert_no_est() {
         .arg("copy-from-image")
         .arg("-f")
         .arg(format!(
-            "cert:/priv/device_id_cert.pem,{device_cert_out_path}"
-        ))
-        .arg("-f")
-        .arg(format!(
-            "cert:/priv/device_id_cert_key.pem,{device_cert_key_out_path}"
+            "factory:/etc/aziot/config.toml,{}",
+            config_file_out_path.to_str().unwrap()
         ))
         .arg("-i")
         .arg(&image_path)
         .assert();
     assert.success();
 
-    assert!(file_diff::diff(
-        device_crt_path.to_str().unwrap(),
-        device_cert_out_path
-    ));
+    // the tpm attestation and the hostname are kept
+    let config = std::fs::read_to_string(&config_file_out_path).unwrap();
+    assert!(config.contains("hostname = \"my-omnect-iot-tpm-device\""));
+    assert!(config.contains("id_scope = \"0neYYYYYYYY\""));
+    assert!(config.contains("method = \"tpm\""));
+    assert!(config.contains("registration_id = \"my-new-reg-id\""));
+}
 
-    assert!(file_diff::diff(
-        device_crt_key_path.to_str().unwrap(),
-        device_cert_key_out_path
-    ));
+#[test]
+fn check_image_dump_restore_table() {
+    use std::os::unix::fs::FileExt;
+
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let gpt_image_path = tr.synthetic_image("image.wic");
+    let dos_image_path = tr.to_pathbuf("testfiles/image.wic");
+    let table_path = tr.pathbuf().join("table.bin");
+
+    // (image, damaged sectors counted from the end (negative) or the start)
+    for (image, damaged) in [
+        (&gpt_image_path, vec![0i64, 1, 2, -1, -2]),
+        (&dos_image_path, vec![0]),
+    ] {
+        let image_hash = Testrunner::file_hash(image);
+
+        let mut dump_table = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = dump_table
+            .arg("image")
+            .arg("dump-table")
+            .arg("-i")
+            .arg(image)
+            .arg("-o")
+            .arg(&table_path)
+            .assert();
+        assert.success();
+
+        let file = std::fs::OpenOptions::new().write(true).open(image).unwrap();
+        let sectors = (file.metadata().unwrap().len() / 512) as i64;
+        for sector in damaged {
+            let sector = if sector < 0 { sectors + sector } else { sector };
+            file.write_all_at(&[0u8; 512], sector as u64 * 512).unwrap();
+        }
+        drop(file);
+        assert_ne!(image_hash, Testrunner::file_hash(image));
+
+        let mut restore_table = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = restore_table
+            .arg("image")
+            .arg("restore-table")
+            .arg("-i")
+            .arg(image)
+            .arg("-t")
+            .arg(&table_path)
+            .assert();
+        assert.success();
+
+        assert_eq!(image_hash, Testrunner::file_hash(image));
+    }
+
+    // an inconsistent backup gpt is detected
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&gpt_image_path)
+        .unwrap();
+    let len = file.metadata().unwrap().len();
+    file.write_all_at(b"X", len - 512 + 60).unwrap();
+    drop(file);
+
+    let mut dump_table = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = dump_table
+        .arg("image")
+        .arg("dump-table")
+        .arg("-i")
+        .arg(&gpt_image_path)
+        .arg("-o")
+        .arg(&table_path)
+        .assert();
+    let assert = assert.failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(stderr.contains("invalid backup gpt header"));
 }
 
 #[test]
-fn check_set_iot_hub_device_update_template() {
+fn check_image_verity() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let cmdline_path = tr.pathbuf().join("cmdline.txt");
+    let cmdline_out_path = tr.pathbuf().join("cmdline_out.txt");
+    let hash_tree_path = tr.pathbuf().join("rootA.hashtree");
+    let root_hash_path = tr.pathbuf().join("rootA.roothash");
 
-    let adu_config_file_path = tr.to_pathbuf("conf/du-config.json.template");
-    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    std::fs::write(&cmdline_path, "console=ttyS0 roothash=outdated\n").unwrap();
 
-    let mut set_iot_hub_device_update_config = Command::cargo_bin("omnect-cli").unwrap();
-    let assert = set_iot_hub_device_update_config
-        .arg("iot-hub-device-update")
-        .arg("set-device-config")
-        .arg("-c")
-        .arg(&adu_config_file_path)
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!(
+            "{},boot:/cmdline.txt",
+            cmdline_path.to_str().unwrap()
+        ))
         .arg("-i")
         .arg(&image_path)
         .assert();
     assert.success();
 
-    let mut adu_config_file_out_path = tr.pathbuf();
-    adu_config_file_out_path.push("dir1");
-    create_dir_all(adu_config_file_out_path.clone()).unwrap();
-    adu_config_file_out_path.push("adu_config_file_out_path");
-    let adu_config_file_out_path = adu_config_file_out_path.to_str().unwrap();
+    let mut verity = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = verity
+        .arg("image")
+        .arg("verity")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-t")
+        .arg(&hash_tree_path)
+        .arg("-r")
+        .arg(&root_hash_path)
+        .arg("-c")
+        .arg("/cmdline.txt")
+        .assert();
+    assert.success();
+
+    let root_hash = std::fs::read_to_string(&root_hash_path).unwrap();
+    let root_hash = root_hash.trim();
+    assert_eq!(root_hash.len(), 64);
+    assert!(std::fs::metadata(&hash_tree_path).unwrap().len() > 0);
 
     let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
     let assert = copy_from_img
@@ -558,88 +760,1876 @@ fn check_set_iot_hub_device_update_template() {
         .arg("copy-from-image")
         .arg("-f")
         .arg(format!(
-            "factory:/etc/adu/du-config.json,{adu_config_file_out_path}"
+            "boot:/cmdline.txt,{}",
+            cmdline_out_path.to_str().unwrap()
         ))
         .arg("-i")
         .arg(&image_path)
         .assert();
     assert.success();
 
-    assert!(file_diff::diff(
-        adu_config_file_path.to_str().unwrap(),
-        adu_config_file_out_path
-    ));
+    assert_eq!(
+        std::fs::read_to_string(&cmdline_out_path).unwrap(),
+        format!("console=ttyS0 roothash={root_hash}\n")
+    );
 }
 
 #[test]
-fn check_set_iot_hub_device_update_create_import_manifest() {
+fn check_image_detect() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let compressed_image_path = tr.to_pathbuf("testfiles/image.wic.xz");
+
+    for (image, compression, decompressed_image) in [
+        (
+            &image_path,
+            serde_json::Value::Null,
+            serde_json::Value::Null,
+        ),
+        (
+            &compressed_image_path,
+            serde_json::json!("xz"),
+            serde_json::json!(image_path.to_str().unwrap()),
+        ),
+    ] {
+        let mut detect = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = detect
+            .arg("image")
+            .arg("detect")
+            .arg("-i")
+            .arg(image)
+            .arg("--json")
+            .assert();
+        let info: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+        assert.success();
+
+        assert_eq!(info["compression"], compression);
+        assert_eq!(info["decompressedImage"], decompressed_image);
+    }
+
+    // nothing is decompressed
+    assert_eq!(
+        std::fs::read_dir(tr.pathbuf()).unwrap().count(),
+        2,
+        "unexpected files in {}",
+        tr.pathbuf().to_str().unwrap()
+    );
+}
 
-    let image_path = tr.to_pathbuf("testfiles/image.swu");
-    let script_path = tr.to_pathbuf("testfiles/image.swu.sh");
-    let manifest_created = script_path.with_extension("importManifest.json");
-    let manifest_original = tr.to_pathbuf("testfiles/image.swu.importManifest.json.orig");
+#[test]
+fn check_image_size_report() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic.xz");
+    let image_hash = Testrunner::file_hash(&image_path);
 
-    let mut set_iot_hub_device_update_config = Command::cargo_bin("omnect-cli").unwrap();
-    let assert = set_iot_hub_device_update_config
-        .current_dir(tr.pathbuf())
-        .arg("iot-hub-device-update")
-        .arg("create-import-manifest")
-        .arg("-d")
-        .arg("OMNECT-gateway-devel")
-        .arg("-v")
-        .arg("4.0.15.0")
+    let mut size_report = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = size_report
+        .arg("image")
+        .arg("size-report")
         .arg("-i")
         .arg(&image_path)
-        .arg("-s")
-        .arg(&script_path)
-        .arg("-n")
-        .arg("omnect-raspberrypi4-64-gateway-devel")
-        .arg("-c")
-        .arg("2")
+        .arg("--trial-compress")
+        .arg("--json")
         .assert();
+    let report: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
     assert.success();
 
-    let mut manifest_created: serde_json::Value = serde_json::from_reader(
-        std::fs::OpenOptions::new()
-            .read(true)
-            .create(false)
-            .open(manifest_created)
-            .unwrap(),
-    )
-    .unwrap();
-
-    manifest_created["createdDateTime"] = serde_json::json!("removed");
-
-    let manifest_original: serde_json::Value = serde_json::from_reader(
-        std::fs::OpenOptions::new()
-            .read(true)
-            .create(false)
-            .open(manifest_original)
-            .unwrap(),
-    )
-    .unwrap();
+    assert_eq!(report["compression"], "xz");
+    assert_eq!(
+        report["imageSize"],
+        std::fs::metadata(&image_path).unwrap().len()
+    );
+    let uncompressed_size = report["uncompressedSize"].as_u64().unwrap();
+    assert!(uncompressed_size > report["imageSize"].as_u64().unwrap());
+
+    let partitions = report["partitions"].as_array().unwrap();
+    for name in ["boot", "rootA"] {
+        let partition = partitions
+            .iter()
+            .find(|p| p["partition"] == name)
+            .unwrap_or_else(|| panic!("no usage of {name} reported"));
+        let size = partition["size"].as_u64().unwrap();
+
+        assert!(size > 0 && size < uncompressed_size);
+        assert_eq!(
+            partition["used"].as_u64().unwrap() + partition["free"].as_u64().unwrap(),
+            size
+        );
+    }
+
+    let compressed = report["compressed"].as_array().unwrap();
+    assert_eq!(
+        compressed
+            .iter()
+            .map(|c| c["compression"].as_str().unwrap())
+            .collect::<Vec<_>>(),
+        ["xz", "bz2", "gz"]
+    );
+    assert!(compressed
+        .iter()
+        .all(|c| c["ratio"].as_f64().unwrap() < 1.0));
 
-    assert_json_eq!(manifest_created, manifest_original);
+    // the image is only read
+    assert_eq!(Testrunner::file_hash(&image_path), image_hash);
 }
 
 #[test]
-fn check_file_copy_dos_partition() {
+fn check_image_offsets() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
-    check_file_copy(tr, "boot");
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let image_hash = Testrunner::file_hash(&image_path);
+
+    let mut offsets = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = offsets
+        .arg("image")
+        .arg("offsets")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--json")
+        .assert();
+    let report: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    assert.success();
+
+    let partitions = report["partitions"].as_array().unwrap();
+    for name in ["boot", "rootA"] {
+        let partition = partitions
+            .iter()
+            .find(|p| p["partition"] == name)
+            .unwrap_or_else(|| panic!("no offset of {name} reported"));
+        let start = partition["startSector"].as_u64().unwrap();
+        let end = partition["endSector"].as_u64().unwrap();
+
+        assert!(start > 0 && end > start);
+        assert_eq!(partition["offset"].as_u64().unwrap(), start * 512);
+        assert_eq!(partition["size"].as_u64().unwrap(), (end + 1 - start) * 512);
+    }
+
+    // rootA is ext4, i.e. its superblock magic 0xef53 is at offset 1080
+    let mut offsets = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = offsets
+        .arg("image")
+        .arg("offsets")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-a")
+        .arg("rootA")
+        .arg("-j")
+        .assert();
+    let report: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    assert.success();
+
+    let partitions = report["partitions"].as_array().unwrap();
+    assert_eq!(partitions.len(), 1);
+    assert_eq!(partitions[0]["partition"], "rootA");
+
+    let offset = partitions[0]["offset"].as_u64().unwrap() as usize;
+    let image = std::fs::read(&image_path).unwrap();
+    assert_eq!(image[offset + 1080..offset + 1082], [0x53, 0xef]);
+
+    // the image is only read
+    assert_eq!(Testrunner::file_hash(&image_path), image_hash);
 }
 
 #[test]
-fn check_file_copy_ext4() {
+fn check_image_build_info() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
-    check_file_copy(tr, "factory");
-}
+    let image_path = tr.synthetic_image("image.wic");
+    let os_release_path = tr.pathbuf().join("os-release");
+    std::fs::write(
+        &os_release_path,
+        "ID=omnect-os\nNAME=\"OMNECT-gateway-devel\"\nVERSION_ID=4.0.17\nBUILD_ID=\"20261016\"\nOMNECT_TARGET_ARCH=\"x86_64\"\n",
+    )
+    .unwrap();
 
-fn check_file_copy(tr: Testrunner, partition: &str) {
-    let in_file1 = tr.to_pathbuf("testfiles/boot.scr");
-    let in_file1 = in_file1.to_str().unwrap();
-    let in_file2 = tr.to_pathbuf("testfiles/dps-payload.json");
-    let in_file2 = in_file2.to_str().unwrap();
+    // synthetic os-release lacks name, version and build id
+    let mut build_info = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = build_info
+        .arg("image")
+        .arg("build-info")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-j")
+        .assert();
+    let info: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    assert.success();
+
+    assert_eq!(info["distro"], "omnect-os");
+    assert_eq!(info["arch"], "x86_64");
+    assert!(info["version"].is_null());
+    assert!(info["label"].is_null());
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!(
+            "{},rootA:/usr/lib/os-release",
+            os_release_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--label")
+        .arg("build-4711")
+        .assert();
+    assert.success();
+
+    let image_hash = Testrunner::file_hash(&image_path);
+
+    let mut build_info = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = build_info
+        .arg("image")
+        .arg("build-info")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--json")
+        .assert();
+    let info: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    assert.success();
+
+    assert_eq!(info["distro"], "OMNECT-gateway-devel");
+    assert_eq!(info["version"], "4.0.17");
+    assert_eq!(info["buildId"], "20261016");
+    assert_eq!(info["label"], "build-4711");
+    assert_eq!(info["osRelease"]["ID"], "omnect-os");
+
+    let mut build_info = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = build_info
+        .arg("image")
+        .arg("build-info")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).to_string();
+    assert.success();
+    assert!(stdout.contains("version:  4.0.17"));
+    assert!(stdout.contains("label:    build-4711"));
+
+    // the image is only read
+    assert_eq!(Testrunner::file_hash(&image_path), image_hash);
+}
+
+#[test]
+fn check_set_device_cert_est() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let intermediate_full_chain_crt_path = tr.to_pathbuf("testfiles/test-int-ca_fullchain.pem");
+    let intermediate_full_chain_crt_key_path = tr.to_pathbuf("testfiles/test-int-ca.key");
+
+    let mut set_device_certificate = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_device_certificate
+        .arg("identity")
+        .arg("set-device-certificate")
+        .arg("-c")
+        .arg(&intermediate_full_chain_crt_path)
+        .arg("-k")
+        .arg(&intermediate_full_chain_crt_key_path)
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-d")
+        .arg("my-device-id")
+        .arg("-D")
+        .arg("1")
+        .assert();
+    assert.success();
+
+    let mut device_id_cert_out_path = tr.pathbuf();
+    device_id_cert_out_path.push("dir1");
+    create_dir_all(device_id_cert_out_path.clone()).unwrap();
+
+    let mut device_id_cert_key_out_path = device_id_cert_out_path.clone();
+    let mut ca_crt_pem_out_path = device_id_cert_out_path.clone();
+    let mut ca_pem_out_path = device_id_cert_out_path.clone();
+
+    device_id_cert_out_path.push("device_id_cert_out_path");
+    let device_id_cert_out_path = device_id_cert_out_path.to_str().unwrap();
+
+    device_id_cert_key_out_path.push("device_id_cert_key_out_path");
+    let device_id_cert_key_out_path = device_id_cert_key_out_path.to_str().unwrap();
+
+    ca_crt_pem_out_path.push("ca_crt_pem_out_path");
+    let ca_crt_pem_out_path = ca_crt_pem_out_path.to_str().unwrap();
+
+    ca_pem_out_path.push("ca_pem_out_path");
+    let ca_pem_out_path = ca_pem_out_path.to_str().unwrap();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "cert:/priv/device_id_cert.pem,{device_id_cert_out_path}"
+        ))
+        .arg("-f")
+        .arg(format!(
+            "cert:/priv/device_id_cert_key.pem,{device_id_cert_key_out_path}"
+        ))
+        .arg("-f")
+        .arg(format!("cert:/priv/ca.crt.pem,{ca_crt_pem_out_path}"))
+        .arg("-f")
+        .arg(format!("cert:/ca/ca.crt,{ca_pem_out_path}"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(
+        intermediate_full_chain_crt_path.to_str().unwrap(),
+        ca_crt_pem_out_path
+    ));
+}
+
+#[test]
+fn check_set_device_cert_est_endpoint() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let config_file_path = tr.to_pathbuf("conf/config.toml.est.template");
+    let intermediate_full_chain_crt_path = tr.to_pathbuf("testfiles/test-int-ca_fullchain.pem");
+    let intermediate_full_chain_crt_key_path = tr.to_pathbuf("testfiles/test-int-ca.key");
+    let trusted_ca_path = tr.to_pathbuf("testfiles/rootCA.crt");
+    let config_file_out_path = tr.pathbuf().join("config_file_out_path");
+    let trusted_ca_out_path = tr.pathbuf().join("trusted_ca_out_path");
+
+    let mut set_identity_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_identity_config
+        .arg("identity")
+        .arg("set-config")
+        .arg("-c")
+        .arg(&config_file_path)
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let set_device_certificate = |url: &str, trusted_ca: &PathBuf| {
+        let mut set_device_certificate = Command::cargo_bin("omnect-cli").unwrap();
+        set_device_certificate
+            .arg("identity")
+            .arg("set-device-certificate")
+            .arg("-c")
+            .arg(&intermediate_full_chain_crt_path)
+            .arg("-k")
+            .arg(&intermediate_full_chain_crt_key_path)
+            .arg("-i")
+            .arg(&image_path)
+            .arg("-d")
+            .arg("my-device-id")
+            .arg("-D")
+            .arg("1")
+            .arg("--est-url")
+            .arg(url)
+            .arg("--est-trusted-ca")
+            .arg(trusted_ca)
+            .arg("--est-auth")
+            .arg("x509")
+            .assert()
+    };
+
+    // the endpoint must be an https URL and the trusted CA a certificate
+    let image_hash = Testrunner::file_hash(&image_path);
+    set_device_certificate("http://est.example.com/.well-known/est", &trusted_ca_path).failure();
+    set_device_certificate(
+        "https://est.example.com/.well-known/est",
+        &tr.to_pathbuf("testfiles/rootCA.key"),
+    )
+    .failure();
+    assert_eq!(image_hash, Testrunner::file_hash(&image_path));
+
+    set_device_certificate("https://est.example.com/.well-known/est", &trusted_ca_path).success();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/aziot/config.toml,{}",
+            config_file_out_path.to_str().unwrap()
+        ))
+        .arg("-f")
+        .arg(format!(
+            "cert:/ca/est_ca.crt,{}",
+            trusted_ca_out_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let config: toml::Table =
+        toml::from_str(&std::fs::read_to_string(&config_file_out_path).unwrap()).unwrap();
+    let est = &config["cert_issuance"]["est"];
+
+    assert_eq!(
+        est["urls"]["default"].as_str(),
+        Some("https://est.example.com/.well-known/est")
+    );
+    assert_eq!(
+        est["trusted_certs"],
+        toml::Value::from(vec![
+            "file:///mnt/cert/ca/ca.crt",
+            "file:///mnt/cert/ca/est_ca.crt"
+        ])
+    );
+    assert_eq!(
+        est["auth"]["identity_cert"].as_str(),
+        Some("file:///mnt/cert/priv/device_id_cert.pem")
+    );
+    assert_eq!(
+        config["provisioning"]["attestation"]["identity_cert"]["common_name"].as_str(),
+        Some("my-device-id")
+    );
+    assert!(file_diff::diff(
+        trusted_ca_path.to_str().unwrap(),
+        trusted_ca_out_path.to_str().unwrap()
+    ));
+}
+
+#[test]
+fn check_set_device_cert_ed25519() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let intermediate_full_chain_crt_path = tr.to_pathbuf("testfiles/test-int-ca_fullchain.pem");
+    let intermediate_full_chain_crt_key_path = tr.to_pathbuf("testfiles/test-int-ca.key");
+    let key_out_path = tr.pathbuf().join("device_id_cert_key.pem");
+
+    let set_device_certificate = |config: &str| {
+        let mut set_identity_config = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = set_identity_config
+            .arg("identity")
+            .arg("set-config")
+            .arg("-c")
+            .arg(tr.to_pathbuf(config))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        let mut set_device_certificate = Command::cargo_bin("omnect-cli").unwrap();
+        set_device_certificate
+            .arg("identity")
+            .arg("set-device-certificate")
+            .arg("-c")
+            .arg(&intermediate_full_chain_crt_path)
+            .arg("-k")
+            .arg(&intermediate_full_chain_crt_key_path)
+            .arg("-i")
+            .arg(&image_path)
+            .arg("-d")
+            .arg("test-omnect-est")
+            .arg("-D")
+            .arg("1")
+            .arg("--key-type")
+            .arg("ed25519")
+            .assert()
+    };
+
+    // DPS doesn't accept Ed25519 certificates for X.509 attestation
+    let assert = set_device_certificate("testfiles/identity_config_dps_x509_no_est.toml").failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr)
+        .contains("Ed25519 device keys aren't supported"));
+
+    // the device certificate only authenticates to the EST server
+    set_device_certificate("testfiles/identity_config_dps_x509_est.toml").success();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "cert:/priv/device_id_cert_key.pem,{}",
+            key_out_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let key = Command::new("openssl")
+        .arg("pkey")
+        .arg("-noout")
+        .arg("-text")
+        .arg("-in")
+        .arg(&key_out_path)
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&key.stdout).starts_with("ED25519 "));
+}
+
+#[test]
+fn check_set_device_cert_est_exceeding_intermediate_validity() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let (intermediate_full_chain_crt_path, intermediate_full_chain_crt_key_path) =
+        tr.short_lived_ca(30);
+    let image_path_hash1 = Testrunner::file_hash(&image_path);
+
+    let mut set_device_certificate = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_device_certificate
+        .arg("identity")
+        .arg("set-device-certificate")
+        .arg("-c")
+        .arg(&intermediate_full_chain_crt_path)
+        .arg("-k")
+        .arg(&intermediate_full_chain_crt_key_path)
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-d")
+        .arg("my-device-id")
+        .arg("-D")
+        .arg("60")
+        .assert();
+    assert.failure();
+
+    let image_path_hash2 = Testrunner::file_hash(&image_path);
+
+    assert_eq!(image_path_hash1, image_path_hash2);
+}
+
+#[test]
+fn check_set_device_cert_est_batch() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let intermediate_full_chain_crt_path = tr.to_pathbuf("testfiles/test-int-ca_fullchain.pem");
+    let intermediate_full_chain_crt_key_path = tr.to_pathbuf("testfiles/test-int-ca.key");
+    let image_path_hash1 = Testrunner::file_hash(&image_path);
+
+    let mut out_dir = tr.pathbuf();
+    out_dir.push("dir1");
+    create_dir_all(out_dir.clone()).unwrap();
+    let device_b_image = out_dir.join("device-b.wic");
+
+    let csv_path = tr.pathbuf().join("devices.csv");
+    std::fs::write(
+        &csv_path,
+        format!(
+            "# device_id,output_image\ndevice-a\n\ndevice-b,{}\n",
+            device_b_image.to_str().unwrap()
+        ),
+    )
+    .unwrap();
+
+    let mut set_device_certificate = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_device_certificate
+        .arg("identity")
+        .arg("set-device-certificate")
+        .arg("-c")
+        .arg(&intermediate_full_chain_crt_path)
+        .arg("-k")
+        .arg(&intermediate_full_chain_crt_key_path)
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--device-ids-csv")
+        .arg(&csv_path)
+        .arg("-D")
+        .arg("1")
+        .assert();
+    assert.success();
+
+    // base image stays untouched
+    assert_eq!(image_path_hash1, Testrunner::file_hash(&image_path));
+
+    let device_a_image = tr.pathbuf().join("image_device-a.wic");
+    let mut certs = vec![];
+
+    for (name, image) in [("a", &device_a_image), ("b", &device_b_image)] {
+        let cert_out_path = out_dir.join(format!("device_{name}_cert.pem"));
+        let ca_out_path = out_dir.join(format!("device_{name}_ca.crt.pem"));
+
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!(
+                "cert:/priv/device_id_cert.pem,{}",
+                cert_out_path.to_str().unwrap()
+            ))
+            .arg("-f")
+            .arg(format!(
+                "cert:/priv/ca.crt.pem,{}",
+                ca_out_path.to_str().unwrap()
+            ))
+            .arg("-i")
+            .arg(image)
+            .assert();
+        assert.success();
+
+        assert!(file_diff::diff(
+            intermediate_full_chain_crt_path.to_str().unwrap(),
+            ca_out_path.to_str().unwrap()
+        ));
+
+        certs.push(std::fs::read(cert_out_path).unwrap());
+    }
+
+    assert_ne!(certs[0], certs[1]);
+}
+
+#[test]
+fn check_cert_list() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let root_ca_path = tr.to_pathbuf("testfiles/test-ca.pem");
+
+    let cert_list = || {
+        Command::cargo_bin("omnect-cli")
+            .unwrap()
+            .arg("cert")
+            .arg("list")
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--json")
+            .assert()
+    };
+
+    // absent certificates are skipped
+    let assert = cert_list();
+    let certs: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    assert.success();
+    assert!(certs.as_array().unwrap().is_empty());
+
+    let mut copy_to_image = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_image
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!(
+            "{},rootA:/usr/local/share/ca-certificates/test-ca.crt",
+            root_ca_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let assert = cert_list();
+    let certs: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    assert.success();
+
+    let certs = certs.as_array().unwrap();
+    assert_eq!(certs.len(), 1);
+    assert_eq!(certs[0]["partition"], "rootA");
+    assert_eq!(
+        certs[0]["path"],
+        "/usr/local/share/ca-certificates/test-ca.crt"
+    );
+    assert!(certs[0]["subject"].as_str().unwrap().contains("CN=test-ca"));
+}
+
+#[test]
+fn check_cert_verify() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let intermediate_full_chain_crt_path = tr.to_pathbuf("testfiles/test-int-ca_fullchain.pem");
+    let intermediate_full_chain_crt_key_path = tr.to_pathbuf("testfiles/test-int-ca.key");
+    let root_ca_path = tr.to_pathbuf("testfiles/test-ca.pem");
+    let foreign_root_ca_path = tr.to_pathbuf("testfiles/rootCA.crt");
+
+    let mut set_device_certificate = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_device_certificate
+        .arg("identity")
+        .arg("set-device-certificate")
+        .arg("-c")
+        .arg(&intermediate_full_chain_crt_path)
+        .arg("-k")
+        .arg(&intermediate_full_chain_crt_key_path)
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-d")
+        .arg("my-device-id")
+        .arg("-D")
+        .arg("1")
+        .assert();
+    assert.success();
+
+    let cert_verify = |ca: &PathBuf| {
+        Command::cargo_bin("omnect-cli")
+            .unwrap()
+            .arg("cert")
+            .arg("verify")
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--ca")
+            .arg(ca)
+            .arg("--json")
+            .assert()
+    };
+
+    let assert = cert_verify(&root_ca_path);
+    let certs: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    assert.success();
+
+    let certs = certs.as_array().unwrap();
+    assert!(certs
+        .iter()
+        .all(|c| c["verified"] == true && c["reason"].is_null()));
+    for (path, subject) in [
+        ("/priv/device_id_cert.pem", "CN=my-device-id"),
+        ("/priv/ca.crt.pem", "CN=test-int-ca"),
+        ("/ca/ca.crt", "CN=test-ca"),
+    ] {
+        assert!(
+            certs
+                .iter()
+                .any(|c| c["path"] == path && c["subject"].as_str().unwrap().contains(subject)),
+            "{subject} of {path} not verified"
+        );
+    }
+
+    // none of the certificates chains to a foreign root
+    let assert = cert_verify(&foreign_root_ca_path);
+    let certs: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    let assert = assert.failure();
+    assert!(
+        String::from_utf8_lossy(&assert.get_output().stderr).contains(&format!(
+            "{n} of {n} certificates don't chain to",
+            n = certs.as_array().unwrap().len()
+        ))
+    );
+
+    assert!(certs
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|c| c["verified"] == false && c["reason"].is_string()));
+}
+
+#[test]
+fn check_renew_cert() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let intermediate_full_chain_crt_path = tr.to_pathbuf("testfiles/test-int-ca_fullchain.pem");
+    let intermediate_full_chain_crt_key_path = tr.to_pathbuf("testfiles/test-int-ca.key");
+
+    let renew_cert = |image: &PathBuf| {
+        Command::cargo_bin("omnect-cli")
+            .unwrap()
+            .arg("identity")
+            .arg("renew-cert")
+            .arg("-c")
+            .arg(&intermediate_full_chain_crt_path)
+            .arg("-k")
+            .arg(&intermediate_full_chain_crt_key_path)
+            .arg("-i")
+            .arg(image)
+            .arg("-D")
+            .arg("100")
+            .assert()
+    };
+
+    let device_cert = |image: &PathBuf| {
+        let mut cert_list = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = cert_list
+            .arg("cert")
+            .arg("list")
+            .arg("-i")
+            .arg(image)
+            .arg("--json")
+            .assert();
+        let certs: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+
+        certs
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["path"] == "/priv/device_id_cert.pem")
+            .cloned()
+            .unwrap()
+    };
+
+    // there is no device certificate to renew yet
+    let assert = renew_cert(&image_path);
+    let assert = assert.failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr)
+        .contains("no device certificate found to renew"));
+
+    let mut set_device_certificate = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_device_certificate
+        .arg("identity")
+        .arg("set-device-certificate")
+        .arg("-c")
+        .arg(&intermediate_full_chain_crt_path)
+        .arg("-k")
+        .arg(&intermediate_full_chain_crt_key_path)
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-d")
+        .arg("my-device-id")
+        .arg("-D")
+        .arg("1")
+        .assert();
+    assert.success();
+
+    let cert = device_cert(&image_path);
+    assert!(cert["daysUntilExpiry"].as_i64().unwrap() <= 1);
+
+    renew_cert(&image_path).success();
+
+    let renewed_cert = device_cert(&image_path);
+    assert_eq!(renewed_cert["subject"], cert["subject"]);
+    assert!(renewed_cert["subject"]
+        .as_str()
+        .unwrap()
+        .contains("CN=my-device-id"));
+    assert!(renewed_cert["daysUntilExpiry"].as_i64().unwrap() >= 99);
+}
+
+#[test]
+fn check_set_device_cert_no_est() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let device_crt_path = tr.to_pathbuf("testfiles/test-int-ca_fullchain.pem");
+    let device_crt_key_path = tr.to_pathbuf("testfiles/test-int-ca.key");
+
+    let mut set_device_certificate_no_est = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_device_certificate_no_est
+        .arg("identity")
+        .arg("set-device-certificate-no-est")
+        .arg("-c")
+        .arg(&device_crt_path)
+        .arg("-k")
+        .arg(&device_crt_key_path)
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut device_cert_out_path = tr.pathbuf();
+    device_cert_out_path.push("dir1");
+    create_dir_all(device_cert_out_path.clone()).unwrap();
+
+    let mut device_cert_key_out_path = device_cert_out_path.clone();
+
+    device_cert_out_path.push("device_id_cert_out_path");
+    let device_cert_out_path = device_cert_out_path.to_str().unwrap();
+
+    device_cert_key_out_path.push("device_id_cert_key_out_path");
+    let device_cert_key_out_path = device_cert_key_out_path.to_str().unwrap();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "cert:/priv/device_id_cert.pem,{device_cert_out_path}"
+        ))
+        .arg("-f")
+        .arg(format!(
+            "cert:/priv/device_id_cert_key.pem,{device_cert_key_out_path}"
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(
+        device_crt_path.to_str().unwrap(),
+        device_cert_out_path
+    ));
+
+    assert!(file_diff::diff(
+        device_crt_key_path.to_str().unwrap(),
+        device_cert_key_out_path
+    ));
+}
+
+#[test]
+fn check_set_iot_hub_device_update_template() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+
+    let adu_config_file_path = tr.to_pathbuf("conf/du-config.json.template");
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+
+    let mut set_iot_hub_device_update_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_iot_hub_device_update_config
+        .arg("iot-hub-device-update")
+        .arg("set-device-config")
+        .arg("-c")
+        .arg(&adu_config_file_path)
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut adu_config_file_out_path = tr.pathbuf();
+    adu_config_file_out_path.push("dir1");
+    create_dir_all(adu_config_file_out_path.clone()).unwrap();
+    adu_config_file_out_path.push("adu_config_file_out_path");
+    let adu_config_file_out_path = adu_config_file_out_path.to_str().unwrap();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/adu/du-config.json,{adu_config_file_out_path}"
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(
+        adu_config_file_path.to_str().unwrap(),
+        adu_config_file_out_path
+    ));
+}
+
+#[test]
+fn check_set_iot_hub_device_update_from_components() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let out_file = tr.pathbuf().join("du-config.json");
+
+    let mut set_iot_hub_device_update_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_iot_hub_device_update_config
+        .arg("iot-hub-device-update")
+        .arg("set-device-config")
+        .arg("--from-components")
+        .arg("--manufacturer")
+        .arg("conplement-ag")
+        .arg("--model")
+        .arg("omnect-raspberrypi4-64-gateway-devel")
+        .arg("--compatibility-id")
+        .arg("2")
+        .arg("--iot-hub-hostname")
+        .arg("my-hub.azure-devices.net")
+        .arg("--device-id")
+        .arg("my-device")
+        .arg("--shared-access-key")
+        .arg("c2VjcmV0")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/adu/du-config.json,{}",
+            out_file.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let config: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&out_file).unwrap()).unwrap();
+    assert_eq!(config["manufacturer"], "conplement-ag");
+    assert_eq!(
+        config["agents"][0]["model"],
+        "omnect-raspberrypi4-64-gateway-devel"
+    );
+    assert_eq!(
+        config["agents"][0]["connectionSource"]["connectionData"],
+        "HostName=my-hub.azure-devices.net;DeviceId=my-device;SharedAccessKey=c2VjcmV0"
+    );
+
+    // config file and components are exclusive, a model is required
+    let mut set_iot_hub_device_update_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_iot_hub_device_update_config
+        .arg("iot-hub-device-update")
+        .arg("set-device-config")
+        .arg("-c")
+        .arg(tr.to_pathbuf("conf/du-config.json.template"))
+        .arg("--from-components")
+        .arg("--manufacturer")
+        .arg("conplement-ag")
+        .arg("--model")
+        .arg("omnect-raspberrypi4-64-gateway-devel")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.failure();
+
+    let mut set_iot_hub_device_update_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_iot_hub_device_update_config
+        .arg("iot-hub-device-update")
+        .arg("set-device-config")
+        .arg("--from-components")
+        .arg("--manufacturer")
+        .arg("conplement-ag")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.failure();
+}
+
+#[test]
+fn check_set_iot_hub_device_update_create_import_manifest() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+
+    let image_path = tr.to_pathbuf("testfiles/image.swu");
+    let script_path = tr.to_pathbuf("testfiles/image.swu.sh");
+    let manifest_created = script_path.with_extension("importManifest.json");
+    let manifest_original = tr.to_pathbuf("testfiles/image.swu.importManifest.json.orig");
+
+    let mut set_iot_hub_device_update_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_iot_hub_device_update_config
+        .current_dir(tr.pathbuf())
+        .arg("iot-hub-device-update")
+        .arg("create-import-manifest")
+        .arg("-d")
+        .arg("OMNECT-gateway-devel")
+        .arg("-v")
+        .arg("4.0.15.0")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-s")
+        .arg(&script_path)
+        .arg("-n")
+        .arg("omnect-raspberrypi4-64-gateway-devel")
+        .arg("-c")
+        .arg("2")
+        .assert();
+    assert.success();
+
+    let mut manifest_created: serde_json::Value = serde_json::from_reader(
+        std::fs::OpenOptions::new()
+            .read(true)
+            .create(false)
+            .open(manifest_created)
+            .unwrap(),
+    )
+    .unwrap();
+
+    manifest_created["createdDateTime"] = serde_json::json!("removed");
+
+    let manifest_original: serde_json::Value = serde_json::from_reader(
+        std::fs::OpenOptions::new()
+            .read(true)
+            .create(false)
+            .open(manifest_original)
+            .unwrap(),
+    )
+    .unwrap();
+
+    assert_json_eq!(manifest_created, manifest_original);
+}
+
+#[test]
+fn check_file_copy_dos_partition() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    check_file_copy(tr, &image_path, "boot");
+}
+
+#[test]
+fn check_file_copy_ext4() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    check_file_copy(tr, &image_path, "factory");
+}
+
+#[test]
+fn check_file_copy_synthetic_gpt_image() {
+    for partition in ["boot", "rootA", "factory", "cert"] {
+        let tr = Testrunner::new(&format!(
+            "{}_{partition}",
+            function_name!().split("::").last().unwrap()
+        ));
+        let image_path = tr.synthetic_image("image.wic");
+        check_file_copy(tr, &image_path, partition);
+    }
+}
+
+#[test]
+fn check_file_copy_mountpoint() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let mut out_file = tr.pathbuf();
+    out_file.push("boot.scr.out");
+    let out_file = out_file.to_str().unwrap();
+
+    // see SYNTHETIC_FSTAB for the mountpoint to partition mapping
+    for (mountpoint, partition) in [
+        ("/boot", "boot"),
+        ("/mnt/factory", "factory"),
+        ("/mnt/cert", "cert"),
+    ] {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},{mountpoint}:/my-file"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("{partition}:/my-file,{out_file}"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        assert!(file_diff::diff(in_file, out_file));
+        std::fs::remove_file(out_file).unwrap();
+    }
+}
+
+#[test]
+fn check_file_copy_multiple_partitions() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let mut out_file = tr.pathbuf();
+    out_file.push("boot.scr.out");
+    let out_file = out_file.to_str().unwrap();
+
+    // comma-separated and repeated partitions, combined with a copy triple
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("--file")
+        .arg(in_file)
+        .arg("--partition")
+        .arg("rootA,cert")
+        .arg("--partition")
+        .arg("/mnt/factory")
+        .arg("--destination")
+        .arg("/my-file")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    for partition in ["boot", "rootA", "cert", "factory"] {
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("{partition}:/my-file,{out_file}"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        assert!(file_diff::diff(in_file, out_file));
+        std::fs::remove_file(out_file).unwrap();
+    }
+
+    // a partition given multiple times is rejected
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("--file")
+        .arg(in_file)
+        .arg("--partition")
+        .arg("rootA,rootA")
+        .arg("--destination")
+        .arg("/my-file")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    let assert = assert.failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr)
+        .contains("out-partition rootA is given multiple times"));
+}
+
+#[test]
+fn check_file_copy_fs_uuid() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let mut out_file = tr.pathbuf();
+    out_file.push("boot.scr.out");
+    let out_file = out_file.to_str().unwrap();
+
+    for partition in ["boot", "factory"] {
+        let uuid = Testrunner::synthetic_fs_uuid(&image_path, partition);
+
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},UUID={uuid}:/my-file"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("{partition}:/my-file,{out_file}"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        assert!(file_diff::diff(in_file, out_file));
+        std::fs::remove_file(out_file).unwrap();
+    }
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!(
+            "{in_file},UUID=00000000-0000-0000-0000-000000000000:/my-file"
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    let assert = assert.failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(stderr.contains("no partition with file system UUID"));
+}
+
+#[test]
+fn check_file_copy_partition_layout() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let layout_path = tr.to_pathbuf("testfiles/partition_layout.toml");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let mut out_file = tr.pathbuf();
+    out_file.push("boot.scr.out");
+    let out_file = out_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--layout")
+        .arg(&layout_path)
+        .assert();
+    assert.success();
+
+    // without layout the file is expected in the default cert partition
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("cert:/my-file,{out_file}"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(in_file, out_file));
+}
+
+#[test]
+fn check_file_copy_extra_partition() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image_with_extra_partition("image.wic", "var");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let mut out_file = tr.pathbuf();
+    out_file.push("boot.scr.out");
+    let out_file = out_file.to_str().unwrap();
+
+    // "var" is partition 5, i.e. the default index of cert
+    let layout_path = tr.pathbuf().join("layout.toml");
+    std::fs::write(&layout_path, "[factory]\nindex = 4\n\n[cert]\nindex = 6\n").unwrap();
+
+    for partition in ["factory", "cert"] {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},{partition}:/my-{partition}-file"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        // the explicit layout proves the file went to the shifted partition
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("{partition}:/my-{partition}-file,{out_file}"))
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--layout")
+            .arg(&layout_path)
+            .assert();
+        assert.success();
+
+        assert!(file_diff::diff(in_file, out_file));
+        std::fs::remove_file(out_file).unwrap();
+    }
+}
+
+#[test]
+fn check_file_copy_raw_partition() {
+    for (raw_partition, vfat) in [("boot", true), ("ext", false)] {
+        let tr = Testrunner::new(&format!(
+            "{}_{raw_partition}",
+            function_name!().split("::").last().unwrap()
+        ));
+        let image_path = tr.raw_partition_image("rootA.img", vfat);
+        let in_file = tr.to_pathbuf("testfiles/boot.scr");
+        let in_file = in_file.to_str().unwrap();
+        let mut out_file = tr.pathbuf();
+        out_file.push("boot.scr.out");
+        let out_file = out_file.to_str().unwrap();
+
+        // the partition is ignored for raw partition images
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},rootA:/dir/my-file"))
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--raw-partition")
+            .arg(raw_partition)
+            .assert();
+        assert.success();
+
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("rootA:/dir/my-file,{out_file}"))
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--raw-partition")
+            .arg(raw_partition)
+            .assert();
+        assert.success();
+
+        assert!(file_diff::diff(in_file, out_file));
+    }
+}
+
+#[test]
+fn check_file_copy_fsck() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let raw_image_path = tr.raw_partition_image("rootA.img", false);
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--fsck")
+        .assert();
+    assert.success();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},rootA:/my-file"))
+        .arg("-i")
+        .arg(&raw_image_path)
+        .arg("--raw-partition")
+        .arg("ext")
+        .assert();
+    assert.success();
+
+    // a wrong link count is an inconsistency e2fsck detects
+    let status = std::process::Command::new("debugfs")
+        .arg("-w")
+        .arg("-R")
+        .arg("sif /my-file links_count 5")
+        .arg(&raw_image_path)
+        .status();
+    assert!(status.unwrap().success());
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},rootA:/my-other-file"))
+        .arg("-i")
+        .arg(&raw_image_path)
+        .arg("--raw-partition")
+        .arg("ext")
+        .arg("--fsck")
+        .assert();
+    let assert = assert.failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(stderr.contains("file system of partition #raw is inconsistent"));
+}
+
+#[test]
+fn check_file_copy_no_dereference() {
+    for (raw_partition, vfat) in [("ext", false), ("boot", true)] {
+        let tr = Testrunner::new(&format!(
+            "{}_{raw_partition}",
+            function_name!().split("::").last().unwrap()
+        ));
+        let image_path = tr.raw_partition_image("partition.img", vfat);
+        let in_file = tr.to_pathbuf("testfiles/boot.scr");
+        let mut link = tr.pathbuf();
+        link.push("boot.scr.link");
+        std::os::unix::fs::symlink(&in_file, &link).unwrap();
+        let link = link.to_str().unwrap();
+
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{link},factory:/dir/my-link"))
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--raw-partition")
+            .arg(raw_partition)
+            .arg("--no-dereference")
+            .assert();
+
+        // symlinks cannot be preserved on vfat
+        if vfat {
+            assert.failure();
+            continue;
+        }
+        assert.success();
+
+        let stat = std::process::Command::new("debugfs")
+            .arg("-R")
+            .arg("stat /dir/my-link")
+            .arg(&image_path)
+            .output()
+            .unwrap();
+        let stat = String::from_utf8(stat.stdout).unwrap();
+        assert!(stat.contains("Type: symlink"));
+    }
+}
+
+#[test]
+fn check_file_copy_mtime() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.raw_partition_image("partition.img", false);
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},rootA:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--raw-partition")
+        .arg("ext")
+        .arg("--mtime")
+        .arg("@1700000000")
+        .assert();
+    assert.success();
+
+    let stat = std::process::Command::new("debugfs")
+        .arg("-R")
+        .arg("stat /my-file")
+        .arg(&image_path)
+        .output()
+        .unwrap();
+    let stat = String::from_utf8(stat.stdout).unwrap();
+    assert!(stat.contains(" mtime: 0x6553f100:"));
+    assert!(stat.contains(" ctime: 0x6553f100:"));
+}
+
+#[test]
+fn check_file_copy_preserve_mtime() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.raw_partition_image("partition.img", false);
+    let in_file = tr.pathbuf().join("my file");
+    std::fs::write(&in_file, "content").unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&in_file)
+        .unwrap()
+        .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1700000000))
+        .unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{},rootA:/my file", in_file.to_str().unwrap()))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--raw-partition")
+        .arg("ext")
+        .assert();
+    assert.success();
+
+    let stat = std::process::Command::new("debugfs")
+        .arg("-R")
+        .arg("stat \"/my file\"")
+        .arg(&image_path)
+        .output()
+        .unwrap();
+    let stat = String::from_utf8(stat.stdout).unwrap();
+    assert!(stat.contains(" mtime: 0x6553f100:"));
+}
+
+#[test]
+fn check_file_copy_chown() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.raw_partition_image("partition.img", false);
+    let tree = tr.pathbuf().join("tree");
+    let tree = tree.to_str().unwrap();
+
+    for file in ["a.conf", "sub/b.conf"] {
+        let path = PathBuf::from(tree).join(file);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, file).unwrap();
+    }
+
+    // owners must be numeric
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{tree},rootA:/tree"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--raw-partition")
+        .arg("ext")
+        .arg("--chown")
+        .arg("omnect:omnect")
+        .assert();
+    assert.failure();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{tree},rootA:/tree"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--raw-partition")
+        .arg("ext")
+        .arg("--chown")
+        .arg("1000:1001")
+        .assert();
+    assert.success();
+
+    for path in ["/tree", "/tree/a.conf", "/tree/sub", "/tree/sub/b.conf"] {
+        let stat = std::process::Command::new("debugfs")
+            .arg("-R")
+            .arg(format!("stat {path}"))
+            .arg(&image_path)
+            .output()
+            .unwrap();
+        let stat = String::from_utf8(stat.stdout).unwrap();
+        let fields: Vec<&str> = stat.split_whitespace().collect();
+        let field = |name| fields[fields.iter().position(|f| *f == name).unwrap() + 1];
+
+        assert_eq!(field("User:"), "1000", "owner of {path}");
+        assert_eq!(field("Group:"), "1001", "group of {path}");
+    }
+
+    // ownership can't be set on vfat
+    let image_path = tr.synthetic_image("image.wic");
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{tree},boot:/tree"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--chown")
+        .arg("1000:1001")
+        .assert();
+    let assert = assert.failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr).contains("cannot set owner of"));
+}
+
+#[test]
+fn check_file_copy_preserve_xattrs() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.raw_partition_image("partition.img", false);
+    let in_file = tr.pathbuf().join("in-file");
+    std::fs::write(&in_file, "content").unwrap();
+
+    let in_file = in_file.to_str().unwrap();
+    let path = std::ffi::CString::new(in_file).unwrap();
+    let name = std::ffi::CString::new("user.omnect").unwrap();
+    // SAFETY: path and name are nul terminated, the value is valid for its length
+    let res = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            b"my-value".as_ptr().cast(),
+            8,
+            0,
+        )
+    };
+    assert_eq!(res, 0, "test dir doesn't support user xattrs");
+
+    for (preserve_xattrs, expected) in [(false, false), (true, true)] {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},rootA:/my-file"))
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--raw-partition")
+            .arg("ext");
+        if preserve_xattrs {
+            copy_to_img.arg("--preserve-xattrs");
+        }
+        copy_to_img.assert().success();
+
+        let ea_list = std::process::Command::new("debugfs")
+            .arg("-R")
+            .arg("ea_list /my-file")
+            .arg(&image_path)
+            .output()
+            .unwrap();
+        let ea_list = String::from_utf8(ea_list.stdout).unwrap();
+        assert_eq!(
+            ea_list.contains("user.omnect (8) = \"my-value\""),
+            expected,
+            "{ea_list}"
+        );
+    }
+
+    // vfat can't carry extended attributes
+    let image_path = tr.synthetic_image("image.wic");
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--preserve-xattrs")
+        .assert();
+    let assert = assert.failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr)
+        .contains("cannot preserve extended attributes"));
+}
+
+#[test]
+fn check_missing_partitions() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let intermediate_full_chain_crt_path = tr.to_pathbuf("testfiles/test-int-ca_fullchain.pem");
+    let intermediate_full_chain_crt_key_path = tr.to_pathbuf("testfiles/test-int-ca.key");
+
+    for (image_name, omit, cert_expected, factory_expected) in [
+        ("complete.wic", vec![], true, true),
+        ("no-cert.wic", vec!["cert"], false, true),
+        (
+            "no-cert-no-factory.wic",
+            vec!["factory", "cert"],
+            false,
+            false,
+        ),
+    ] {
+        let image_path = tr.synthetic_image_without(image_name, &omit);
+
+        let mut set_device_certificate = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = set_device_certificate
+            .arg("identity")
+            .arg("set-device-certificate")
+            .arg("-c")
+            .arg(&intermediate_full_chain_crt_path)
+            .arg("-k")
+            .arg(&intermediate_full_chain_crt_key_path)
+            .arg("-i")
+            .arg(&image_path)
+            .arg("-d")
+            .arg("my-device-id")
+            .arg("-D")
+            .arg("1")
+            .assert();
+        if cert_expected {
+            assert.success();
+        } else {
+            let assert = assert.failure();
+            let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+            assert!(stderr
+                .contains("this image has no 'cert' partition; cannot set device certificate."));
+        }
+
+        let mut set_env = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = set_env
+            .arg("file")
+            .arg("set-env")
+            .arg("-v")
+            .arg("MY_VAR=1")
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        if factory_expected {
+            assert.success();
+        } else {
+            let assert = assert.failure();
+            let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+            assert!(
+                stderr.contains("this image has no 'factory' partition; cannot set environment.")
+            );
+        }
+    }
+}
+
+#[test]
+fn check_file_copy_from_image_dir() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/dir/sub/boot.scr"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    for (partition, dir, expected_file) in
+        [("rootA", "/etc", "fstab"), ("boot", "/dir", "sub/boot.scr")]
+    {
+        // destination tree doesn't exist yet and is created
+        let out_dir = tr.pathbuf().join(format!("{partition}/out"));
+
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("{partition}:{dir},{}", out_dir.to_str().unwrap()))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        assert!(out_dir.join(expected_file).is_file());
+    }
+
+    assert!(file_diff::diff(
+        in_file,
+        tr.pathbuf().join("boot/out/sub/boot.scr").to_str().unwrap()
+    ));
+}
+
+#[test]
+fn check_file_copy_from_image_into_dir() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/boot.scr"))
+        .arg("-f")
+        .arg(format!("{in_file},rootA:/boot.scr"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    for partition in ["boot", "rootA"] {
+        let out_dir = tr.pathbuf().join(partition);
+        create_dir_all(&out_dir).unwrap();
+        let out_dir = out_dir.to_str().unwrap();
+
+        // existing directory, directory with trailing slash and file path
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("{partition}:/boot.scr,{out_dir}"))
+            .arg("-f")
+            .arg(format!("{partition}:/boot.scr,{out_dir}/sub/"))
+            .arg("-f")
+            .arg(format!("{partition}:/boot.scr,{out_dir}/renamed.scr"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        // the trailing slash requires the directory to exist, like cp does
+        assert.failure();
+
+        create_dir_all(format!("{out_dir}/sub")).unwrap();
+
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("{partition}:/boot.scr,{out_dir}"))
+            .arg("-f")
+            .arg(format!("{partition}:/boot.scr,{out_dir}/sub/"))
+            .arg("-f")
+            .arg(format!("{partition}:/boot.scr,{out_dir}/renamed.scr"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        for out_file in ["boot.scr", "sub/boot.scr", "renamed.scr"] {
+            assert!(file_diff::diff(in_file, &format!("{out_dir}/{out_file}")));
+        }
+    }
+}
+
+fn check_file_copy(tr: Testrunner, image_path: &PathBuf, partition: &str) {
+    let in_file1 = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file1 = in_file1.to_str().unwrap();
+    let in_file2 = tr.to_pathbuf("testfiles/dps-payload.json");
+    let in_file2 = in_file2.to_str().unwrap();
     let mut out_file1 = tr.pathbuf();
     out_file1.push("test/test1.scr");
     create_dir_all(out_file1.parent().unwrap()).unwrap();
@@ -647,102 +2637,2302 @@ fn check_file_copy(tr: Testrunner, partition: &str) {
     let mut out_file2 = tr.pathbuf();
     out_file2.push("test2.json");
     let out_file2 = out_file2.to_str().unwrap();
+    let mut out_file3 = tr.pathbuf();
+    out_file3.push("dir1");
+    out_file3.push("outfile3.scr");
+    create_dir_all(out_file3.parent().unwrap()).unwrap();
+    let out_file3 = out_file3.to_str().unwrap();
+    let mut out_file4 = tr.pathbuf();
+    out_file4.push("outfile4.json");
+    let out_file4 = out_file4.to_str().unwrap();
+
+    let image_path_hash1 = Testrunner::file_hash(&image_path);
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file1},{partition}:{out_file1}"))
+        .arg("-f")
+        .arg(format!("{in_file2},{partition}:{out_file2}"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let image_path_hash2 = Testrunner::file_hash(&image_path);
+
+    assert_ne!(image_path_hash1, image_path_hash2);
+
+    let image_path_hash1 = Testrunner::file_hash(&image_path);
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("{partition}:{out_file1},{out_file3}"))
+        .arg("-f")
+        .arg(format!("{partition}:{out_file2},{out_file4}"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let image_path_hash2 = Testrunner::file_hash(&image_path);
+
+    assert_eq!(image_path_hash1, image_path_hash2);
+
+    assert!(file_diff::diff(in_file1, out_file3));
+    assert!(file_diff::diff(in_file2, out_file4));
+
+    // test if we can overwrite files
+    let in_file3 = tr.to_pathbuf("testfiles/identity_config_dps_payload.toml");
+    let in_file3 = in_file3.to_str().unwrap();
+    let in_file4 = tr.to_pathbuf("testfiles/identity_config_hostname_valid.toml");
+    let in_file4 = in_file4.to_str().unwrap();
+
+    let image_path_hash1 = Testrunner::file_hash(&image_path);
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file3},{partition}:{out_file1}"))
+        .arg("-f")
+        .arg(format!("{in_file4},{partition}:{out_file2}"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let image_path_hash2 = Testrunner::file_hash(&image_path);
+
+    assert_ne!(image_path_hash1, image_path_hash2);
+
+    let image_path_hash1 = Testrunner::file_hash(&image_path);
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("{partition}:{out_file1},{out_file3}"))
+        .arg("-f")
+        .arg(format!("{partition}:{out_file2},{out_file4}"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let image_path_hash2 = Testrunner::file_hash(&image_path);
+
+    assert_eq!(image_path_hash1, image_path_hash2);
+    assert!(file_diff::diff(in_file3, out_file3));
+    assert!(file_diff::diff(in_file4, out_file4));
+}
+
+#[test]
+fn check_file_append() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let mut out_file = tr.pathbuf();
+    out_file.push("appended.list");
+
+    for content in ["line1", "line2\n"] {
+        let mut append = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = append
+            .arg("file")
+            .arg("append")
+            .arg("-a")
+            .arg("factory")
+            .arg("-f")
+            .arg("/etc/my/appended.list")
+            .arg("-c")
+            .arg(content)
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+    }
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/my/appended.list,{}",
+            out_file.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert_eq!(std::fs::read_to_string(out_file).unwrap(), "line1\nline2\n");
+}
+
+#[test]
+fn check_invalid_image_path() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let missing_path = tr.pathbuf().join("missing.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let out_file = tr.pathbuf().join("hosts");
+
+    for (image, expected_err) in [
+        (tr.pathbuf(), "isn't a regular file"),
+        (missing_path.clone(), "doesn't exist or isn't accessible"),
+    ] {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},boot:/my-file"))
+            .arg("-i")
+            .arg(&image)
+            .assert();
+        let assert = assert.failure();
+        let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+        assert!(stderr.contains(&format!("image {} {expected_err}", image.to_str().unwrap())));
+    }
+
+    // read-only images can be read but not modified
+    let mut permissions = std::fs::metadata(&image_path).unwrap().permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(&image_path, permissions).unwrap();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("rootA:/etc/hosts,{}", out_file.to_str().unwrap()))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    // root may write to read-only files anyway
+    if std::fs::OpenOptions::new()
+        .write(true)
+        .open(&image_path)
+        .is_err()
+    {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},boot:/my-file"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        let assert = assert.failure();
+        let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+        assert!(stderr.contains("isn't writable"));
+    }
+}
+
+#[test]
+fn check_json_errors() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let missing_path = tr.pathbuf().join("missing.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&missing_path)
+        .arg("--json-errors")
+        .assert();
+    let assert = assert.failure();
+
+    let json: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    let chain = json["error"]["chain"].as_array().unwrap();
+    assert!(chain.iter().any(|c| c
+        .as_str()
+        .unwrap()
+        .contains("doesn't exist or isn't accessible")));
+    assert_eq!(json["error"]["source"]["type"], "io");
+    assert_eq!(json["error"]["source"]["kind"], "NotFound");
+}
+
+#[test]
+fn check_fail_on_warning() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    // lacks a provisioning section, which is only logged as warning
+    let config_file_path = tr.to_pathbuf("testfiles/identity_config_minimal.toml");
+    let image_path = tr.synthetic_image("image.wic");
+
+    let mut set_identity_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_identity_config
+        .arg("identity")
+        .arg("set-config")
+        .arg("-c")
+        .arg(&config_file_path)
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut set_identity_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_identity_config
+        .env("RUST_LOG", "error")
+        .arg("--fail-on-warning")
+        .arg("identity")
+        .arg("set-config")
+        .arg("-c")
+        .arg(&config_file_path)
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    let assert = assert.failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(stderr.contains("--fail-on-warning"), "{stderr}");
+    assert!(stderr.contains("provisioning section should be specified"));
+}
+
+#[test]
+fn check_file_copy_to_dir() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let out_file = tr.pathbuf().join("out");
+    let out_file = out_file.to_str().unwrap();
+
+    // (destination, expected path in image)
+    for (partition, destination, expected) in [
+        ("rootA", "/etc/", "/etc/boot.scr"),
+        ("rootA", "/etc/foo.conf", "/etc/foo.conf"),
+        // existing directory without trailing slash
+        ("rootA", "/usr/lib", "/usr/lib/boot.scr"),
+        ("boot", "/dir/", "/dir/boot.scr"),
+        ("boot", "/dir", "/dir/boot.scr"),
+        ("boot", "/dir/foo.conf", "/dir/foo.conf"),
+    ] {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},{partition}:{destination}"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("{partition}:{expected},{out_file}"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        assert!(file_diff::diff(in_file, out_file));
+        std::fs::remove_file(out_file).unwrap();
+    }
+}
+
+#[test]
+fn check_file_copy_parallel() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let out_file = tr.pathbuf().join("out");
+    let out_file = out_file.to_str().unwrap();
+    let partitions = ["boot", "rootA", "factory", "cert", "/mnt/factory"];
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    copy_to_img.arg("file").arg("copy-to-image");
+    for (i, partition) in partitions.iter().enumerate() {
+        copy_to_img
+            .arg("-f")
+            .arg(format!("{in_file},{partition}:/dir/file{i}"))
+            .arg("-f")
+            .arg(format!("{in_file},{partition}:/file{i}"));
+    }
+    let assert = copy_to_img
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--parallel")
+        .arg("4")
+        .assert();
+    assert.success();
+
+    for (i, partition) in partitions.iter().enumerate() {
+        for path in [format!("/dir/file{i}"), format!("/file{i}")] {
+            let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+            let assert = copy_from_img
+                .arg("file")
+                .arg("copy-from-image")
+                .arg("-f")
+                .arg(format!("{partition}:{path},{out_file}"))
+                .arg("-i")
+                .arg(&image_path)
+                .assert();
+            assert.success();
+
+            assert!(file_diff::diff(in_file, out_file));
+            std::fs::remove_file(out_file).unwrap();
+        }
+    }
+}
+
+#[test]
+fn check_file_copy_no_sync() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let out_file = tr.pathbuf().join("out");
+    let out_file = out_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--no-sync")
+        .assert();
+    assert.success();
+
+    for partition in ["boot", "factory"] {
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("{partition}:/my-file,{out_file}"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        assert!(file_diff::diff(in_file, out_file));
+        std::fs::remove_file(out_file).unwrap();
+    }
+}
+
+#[test]
+fn check_file_copy_verify() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let other_in_file = tr.to_pathbuf("testfiles/test-ca.pem");
+    let other_in_file = other_in_file.to_str().unwrap();
+
+    // the last copy to a destination is verified
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-f")
+        .arg(format!("{other_in_file},factory:/my-file"))
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("--allow-overwrite")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--verify")
+        .assert();
+    let assert = assert.success();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr).contains("verified 2 files"));
+
+    // directory destinations are resolved like on copying
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},rootA:/etc/"))
+        .arg("-f")
+        .arg(format!("{other_in_file},rootA:/etc"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--verify")
+        .assert();
+    let assert = assert.success();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr).contains("verified 2 files"));
+
+    // destinations are compared normalized, so only the last copy is verified
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{other_in_file},factory:/etc//./my-file"))
+        .arg("-f")
+        .arg(format!("{in_file},factory:/etc/my-file"))
+        .arg("--allow-overwrite")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--verify")
+        .assert();
+    let assert = assert.success();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr).contains("verified 1 files"));
+
+    // files are verified concurrently, also within a partition
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/a"))
+        .arg("-f")
+        .arg(format!("{other_in_file},boot:/b"))
+        .arg("-f")
+        .arg(format!("{in_file},factory:/dir/a"))
+        .arg("-f")
+        .arg(format!("{other_in_file},factory:/dir/b"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--parallel")
+        .arg("3")
+        .arg("--verify")
+        .assert();
+    let assert = assert.success();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr).contains("verified 4 files"));
+}
+
+#[test]
+fn check_file_copy_tar_archive() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let readme_path = tr.to_pathbuf("testfiles/test-ca.pem");
+    let archive_path = tr.pathbuf().join("bundle.tar");
+    let extract_dir = tr.pathbuf().join("extracted");
+    let out_file = tr.pathbuf().join("out");
+    let out_file = out_file.to_str().unwrap();
+
+    let mut builder = tar::Builder::new(std::fs::File::create(&archive_path).unwrap());
+    builder
+        .append_path_with_name(&readme_path, "README")
+        .unwrap();
+    builder
+        .append_path_with_name(&image_path, "image.wic")
+        .unwrap();
+    builder.finish().unwrap();
+    drop(builder);
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&archive_path)
+        .assert();
+    assert.success();
+
+    // the archive is repacked with its members in order
+    tar::Archive::new(std::fs::File::open(&archive_path).unwrap())
+        .unpack(&extract_dir)
+        .unwrap();
+    let members: Vec<String> = tar::Archive::new(std::fs::File::open(&archive_path).unwrap())
+        .entries()
+        .unwrap()
+        .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(members, ["README", "image.wic"]);
+    assert!(file_diff::diff(
+        readme_path.to_str().unwrap(),
+        extract_dir.join("README").to_str().unwrap()
+    ));
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("boot:/my-file,{out_file}"))
+        .arg("-i")
+        .arg(extract_dir.join("image.wic"))
+        .assert();
+    assert.success();
+    assert!(file_diff::diff(in_file, out_file));
+
+    // several wic images are ambiguous
+    let mut builder = tar::Builder::new(std::fs::File::create(&archive_path).unwrap());
+    for name in ["a.wic", "b.wic"] {
+        builder.append_path_with_name(&image_path, name).unwrap();
+    }
+    builder.finish().unwrap();
+    drop(builder);
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&archive_path)
+        .assert();
+    let assert = assert.failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr)
+        .contains("contains multiple .wic images: a.wic, b.wic"));
+}
+
+#[test]
+fn check_list_partitions() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let image_hash = Testrunner::file_hash(&image_path);
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--list-partitions")
+        .assert();
+    let assert = assert.success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+
+    let boot = stdout
+        .lines()
+        .find(|l| l.ends_with(" boot"))
+        .expect("boot partition listed");
+    assert!(boot.contains(" vfat "));
+    assert!(stdout.lines().any(|l| l.ends_with(" rootA")));
+
+    // the command isn't performed
+    assert_eq!(image_hash, Testrunner::file_hash(&image_path));
+}
+
+#[test]
+fn check_file_copy_expectations() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file_hash = Testrunner::file_hash(&in_file);
+    let in_file = in_file.to_str().unwrap();
+    let other_in_file = tr.to_pathbuf("testfiles/test-ca.pem");
+    let other_in_file = other_in_file.to_str().unwrap();
+
+    let copy_to_img = |in_file: &str, expectation: &[&str]| {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},factory:/etc/my-file"))
+            .arg("-i")
+            .arg(&image_path)
+            .args(expectation)
+            .assert()
+    };
+
+    copy_to_img(in_file, &["--expect-absent"]).success();
+
+    let assert = copy_to_img(other_in_file, &["--expect-absent"]).failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr)
+        .contains("/etc/my-file (factory) already exists"));
+
+    // the actual hash is reported
+    let image_hash = Testrunner::file_hash(&image_path);
+    let assert = copy_to_img(
+        other_in_file,
+        &["--expect-existing-sha256", &"0".repeat(64)],
+    )
+    .failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr)
+        .contains(&format!("has sha256 {}", in_file_hash.to_lowercase())));
+    assert_eq!(image_hash, Testrunner::file_hash(&image_path));
+
+    copy_to_img(other_in_file, &["--expect-existing-sha256", &in_file_hash]).success();
+}
+
+#[test]
+fn check_partition_format() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let out_file = tr.pathbuf().join("out");
+    let out_file = out_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    // declining the confirmation leaves the partition untouched
+    let mut format = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = format
+        .arg("partition")
+        .arg("format")
+        .arg("-a")
+        .arg("factory")
+        .arg("-i")
+        .arg(&image_path)
+        .write_stdin("N\n")
+        .assert();
+    assert.failure();
+
+    let mut format = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = format
+        .arg("partition")
+        .arg("format")
+        .arg("-a")
+        .arg("factory")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--yes")
+        .assert();
+    assert.success();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("factory:/my-file,{out_file}"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.failure();
+
+    // the recreated file system is usable
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+}
+
+#[test]
+fn check_set_authorized_keys() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let ed25519_key = tr.to_pathbuf("testfiles/ssh_ca_ed25519.pub");
+    let rsa_key = tr.to_pathbuf("testfiles/ssh_ca_rsa.pub");
+    let rsa_key = std::fs::read_to_string(rsa_key).unwrap();
+    let out_file = tr.pathbuf().join("authorized_keys");
+
+    let mut set_keys = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_keys
+        .arg("ssh")
+        .arg("set-authorized-keys")
+        .arg("-u")
+        .arg("omnect")
+        .arg("-k")
+        .arg(format!("@{}", ed25519_key.to_str().unwrap()))
+        .arg("-k")
+        .arg(rsa_key.trim())
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "rootA:/home/omnect/.ssh/authorized_keys,{}",
+            out_file.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert_eq!(
+        std::fs::read_to_string(&out_file).unwrap(),
+        format!(
+            "{}\n{}\n",
+            std::fs::read_to_string(&ed25519_key).unwrap().trim(),
+            rsa_key.trim()
+        )
+    );
+
+    // invalid keys and unknown users are rejected
+    for (user, key) in [
+        ("omnect", "ssh-ed25519 invalid"),
+        ("unknown", rsa_key.trim()),
+    ] {
+        let mut set_keys = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = set_keys
+            .arg("ssh")
+            .arg("set-authorized-keys")
+            .arg("-u")
+            .arg(user)
+            .arg("-k")
+            .arg(key)
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.failure();
+    }
+}
+
+#[test]
+fn check_image_lock_no_wait() {
+    use std::os::unix::io::AsRawFd;
+
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let copy_to_img = || {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},factory:/my-file"))
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--no-wait")
+            .assert()
+    };
+
+    // lock the image as another omnect-cli process would
+    let lock = std::fs::File::open(&image_path).unwrap();
+    assert_eq!(
+        unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) },
+        0
+    );
+
+    let assert = copy_to_img();
+    assert.failure();
+
+    drop(lock);
+
+    let assert = copy_to_img();
+    assert.success();
+}
+
+#[test]
+fn check_file_copy_duplicate_destination() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file1 = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file1 = in_file1.to_str().unwrap();
+    let in_file2 = tr.pathbuf().join("dir1").join("file");
+    create_dir_all(in_file2.parent().unwrap()).unwrap();
+    std::fs::write(&in_file2, "overwritten").unwrap();
+    let in_file2 = in_file2.to_str().unwrap();
+    let out_file = tr.pathbuf().join("out");
+    let out_file = out_file.to_str().unwrap();
+    let image_path_hash1 = Testrunner::file_hash(&image_path);
+
+    let copy_to_img = |allow_overwrite: bool| {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file1},factory:/etc/file"))
+            // same partition given by mountpoint
+            .arg("-f")
+            .arg(format!("{in_file2},/mnt/factory:/etc/"))
+            .arg("-i")
+            .arg(&image_path);
+        if allow_overwrite {
+            copy_to_img.arg("--allow-overwrite");
+        }
+        copy_to_img.assert()
+    };
+
+    let assert = copy_to_img(false).failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr).contains("is target of"));
+    assert_eq!(image_path_hash1, Testrunner::file_hash(&image_path));
+
+    copy_to_img(true).success();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("factory:/etc/file,{out_file}"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(in_file2, out_file));
+}
+
+#[test]
+fn check_file_copy_output() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let out_file = tr.pathbuf().join("out");
+    let out_file = out_file.to_str().unwrap();
+    let output = tr.pathbuf().join("output.wic");
+    let image_path_hash1 = Testrunner::file_hash(&image_path);
+
+    // the source image doesn't need to be writable
+    let mut permissions = std::fs::metadata(&image_path).unwrap().permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(&image_path, permissions).unwrap();
+
+    for (compression, expected) in [
+        (None, output.clone()),
+        (Some("xz"), tr.pathbuf().join("output.wic.xz")),
+    ] {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},factory:/my-file"))
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--output")
+            .arg(&output);
+        if let Some(compression) = compression {
+            copy_to_img.arg("-p").arg(compression);
+        }
+        copy_to_img.assert().success();
+
+        assert_eq!(image_path_hash1, Testrunner::file_hash(&image_path));
+
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("factory:/my-file,{out_file}"))
+            .arg("-i")
+            .arg(&expected)
+            .assert();
+        assert.success();
+
+        assert!(file_diff::diff(in_file, out_file));
+        std::fs::remove_file(out_file).unwrap();
+    }
+}
+
+#[test]
+fn check_file_copy_output_compression() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic.xz");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let out_file = tr.pathbuf().join("out");
+    let out_file = out_file.to_str().unwrap();
+
+    // the output codec is independent of the one of the source image
+    for (source, compression, expected) in [
+        (&image_path, "gz", tr.pathbuf().join("image.wic.gz")),
+        (
+            &tr.pathbuf().join("image.wic.gz"),
+            "bzip2",
+            tr.pathbuf().join("image.wic.bz2"),
+        ),
+        (
+            &tr.pathbuf().join("image.wic.bz2"),
+            "none",
+            tr.pathbuf().join("image.wic"),
+        ),
+    ] {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},factory:/my-file"))
+            .arg("-i")
+            .arg(source)
+            .arg("--output-compression")
+            .arg(compression)
+            .assert();
+        assert.success();
+
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("factory:/my-file,{out_file}"))
+            .arg("-i")
+            .arg(&expected)
+            .assert();
+        assert.success();
+
+        assert!(file_diff::diff(in_file, out_file));
+        std::fs::remove_file(out_file).unwrap();
+    }
+
+    // zstd isn't supported
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--output-compression")
+        .arg("zst")
+        .assert();
+    assert.failure();
+}
+
+#[test]
+fn check_file_copy_vfat_long_names() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.pathbuf().join("a file with a long name.txt");
+    let out_file = tr.pathbuf().join("out-file");
+    std::fs::write(&in_file, "content").unwrap();
+
+    for dest in [
+        "/a directory with spaces/a file with a long name.txt",
+        "/MixedCase/averylongfilenamewithoutspaces.configuration",
+    ] {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{},boot:{dest}", in_file.to_str().unwrap()))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        // mtools only finds the file by its long name if it was stored as such
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("boot:{dest},{}", out_file.to_str().unwrap()))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "content");
+        std::fs::remove_file(&out_file).unwrap();
+    }
+
+    // the long names are listed as such
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "boot:/a directory with spaces,{}",
+            out_file.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert!(out_file.join("a file with a long name.txt").is_file());
+}
+
+#[test]
+fn check_file_copy_dir_exclude() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let tree = tr.pathbuf().join("tree");
+    let out_dir = tr.pathbuf().join("out");
+
+    for file in ["a.conf", "sub/b.conf", "sub/b.tmp", ".git/config"] {
+        let path = tree.join(file);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, file).unwrap();
+    }
+
+    for partition in ["boot", "rootA"] {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{},{partition}:/tree", tree.to_str().unwrap()))
+            .arg("--exclude")
+            .arg(".git")
+            .arg("--exclude")
+            .arg("*.tmp")
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        let out_dir = out_dir.join(partition);
+
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("{partition}:/tree,{}", out_dir.to_str().unwrap()))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        assert_eq!(
+            std::fs::read_to_string(out_dir.join("sub/b.conf")).unwrap(),
+            "sub/b.conf"
+        );
+        assert!(out_dir.join("a.conf").is_file());
+        assert!(!out_dir.join("sub/b.tmp").exists());
+        assert!(!out_dir.join(".git").exists());
+    }
+}
+
+#[test]
+fn check_file_copy_incremental() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.pathbuf().join("in-file");
+    let out_file = tr.pathbuf().join("out-file");
+
+    let copy_to_img = || {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{},boot:/my-file", in_file.to_str().unwrap()))
+            .arg("-f")
+            .arg(format!("{},factory:/my-file", in_file.to_str().unwrap()))
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--incremental")
+            .arg("--only-if-changed")
+            .assert();
+        assert.success();
+    };
+
+    std::fs::write(&in_file, "content 1").unwrap();
+    copy_to_img();
+    let image_hash = Testrunner::file_hash(&image_path);
+
+    // unchanged files are skipped, thus the image isn't written at all
+    copy_to_img();
+    assert_eq!(image_hash, Testrunner::file_hash(&image_path));
+
+    std::fs::write(&in_file, "content 2").unwrap();
+    copy_to_img();
+    assert_ne!(image_hash, Testrunner::file_hash(&image_path));
+
+    for partition in ["boot", "factory"] {
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!(
+                "{partition}:/my-file,{}",
+                out_file.to_str().unwrap()
+            ))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "content 2");
+        std::fs::remove_file(&out_file).unwrap();
+    }
+}
+
+#[test]
+fn check_keep_decompressed() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let out_file = tr.pathbuf().join("out");
+    let out_file = out_file.to_str().unwrap();
+    let output = tr.pathbuf().join("output.wic");
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--output")
+        .arg(&output)
+        .arg("-p")
+        .arg("gzip")
+        .arg("--keep-decompressed")
+        .assert();
+    assert.success();
+
+    // both the packed and the decompressed image contain the copied file
+    for image in [tr.pathbuf().join("output.wic.gz"), output] {
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("factory:/my-file,{out_file}"))
+            .arg("-i")
+            .arg(&image)
+            .assert();
+        assert.success();
+
+        assert!(file_diff::diff(in_file, out_file));
+        std::fs::remove_file(out_file).unwrap();
+    }
+
+    // packing is required
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--keep-decompressed")
+        .assert();
+    assert.failure();
+}
+
+#[test]
+fn check_file_copy_file_size() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let big_file = tr.pathbuf().join("big-file");
+    std::fs::File::create(&big_file)
+        .unwrap()
+        .set_len(8 * 1024 * 1024)
+        .unwrap();
+    let big_file = big_file.to_str().unwrap();
+    let image_path_hash1 = Testrunner::file_hash(&image_path);
+
+    for (file, partition, max_file_size, expected_err) in [
+        (
+            in_file,
+            "factory",
+            Some("16"),
+            Some("exceeds --max-file-size"),
+        ),
+        (big_file, "cert", None, Some("have")),
+        (big_file, "boot", None, Some("have")),
+        (in_file, "factory", Some("1K"), None),
+    ] {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{file},{partition}:/my-file"))
+            .arg("-i")
+            .arg(&image_path);
+        if let Some(max_file_size) = max_file_size {
+            copy_to_img.arg("--max-file-size").arg(max_file_size);
+        }
+        let assert = copy_to_img.assert();
+
+        match expected_err {
+            Some(expected_err) => {
+                let assert = assert.failure();
+                let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+                assert!(stderr.contains(expected_err));
+                assert!(stderr.contains("need"));
+                assert_eq!(image_path_hash1, Testrunner::file_hash(&image_path));
+            }
+            None => {
+                assert.success();
+            }
+        }
+    }
+}
+
+#[test]
+fn check_file_copy_transactional() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let big_file = tr.pathbuf().join("big-file");
+    std::fs::File::create(&big_file)
+        .unwrap()
+        .set_len(8 * 1024 * 1024)
+        .unwrap();
+    let big_file = big_file.to_str().unwrap();
+    let work_dir = tr.pathbuf().join("work");
+    std::fs::create_dir(&work_dir).unwrap();
+    let image_path_hash1 = Testrunner::file_hash(&image_path);
+
+    for parallel in ["1", "2"] {
+        // factory is staged successfully before cert fails, since the file doesn't fit
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},factory:/my-file"))
+            .arg("-f")
+            .arg(format!("{big_file},cert:/my-file"))
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--parallel")
+            .arg(parallel)
+            .arg("--work-dir")
+            .arg(&work_dir)
+            .arg("--no-recompress-on-error")
+            .assert();
+        let assert = assert.failure();
+        let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+        let kept_image = stdout
+            .lines()
+            .find_map(|l| l.strip_prefix("kept image for debugging: "))
+            .unwrap();
+
+        // no partition was written back into the processed image
+        assert_eq!(
+            image_path_hash1,
+            Testrunner::file_hash(&PathBuf::from(kept_image))
+        );
+        assert_eq!(image_path_hash1, Testrunner::file_hash(&image_path));
+
+        std::fs::remove_dir_all(PathBuf::from(kept_image).parent().unwrap()).unwrap();
+    }
+}
+
+#[test]
+fn check_threads() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    // --threads sets the number of workers, unless --parallel is given
+    for (parallel, workers) in [(None, 2), (Some("1"), 1)] {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        copy_to_img
+            .env("RUST_LOG", "debug")
+            .arg("--threads")
+            .arg("2")
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},boot:/my-file"))
+            .arg("-f")
+            .arg(format!("{in_file},factory:/my-file"))
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--verify");
+        if let Some(parallel) = parallel {
+            copy_to_img.arg("--parallel").arg(parallel);
+        }
+        let assert = copy_to_img.assert();
+        let assert = assert.success();
+        assert!(String::from_utf8_lossy(&assert.get_output().stderr)
+            .contains(&format!("verify 2 files with {workers} workers")));
+    }
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-p")
+        .arg("xz")
+        .arg("--threads")
+        .arg("1")
+        .assert();
+    assert.success();
+    assert!(tr.pathbuf().join("image.wic.xz").exists());
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("--threads")
+        .arg("0")
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.failure();
+}
+
+#[test]
+fn check_read_only() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let image_hash = Testrunner::file_hash(&image_path);
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("--read-only")
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    let assert = assert.failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr).contains("--read-only"));
+    assert_eq!(image_hash, Testrunner::file_hash(&image_path));
+
+    // the global flag may also follow the subcommand
+    let mut size_report = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = size_report
+        .arg("image")
+        .arg("size-report")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--read-only")
+        .assert();
+    assert.success();
+
+    let mut list_partitions = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = list_partitions
+        .arg("--read-only")
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--list-partitions")
+        .assert();
+    assert.success();
+    assert_eq!(image_hash, Testrunner::file_hash(&image_path));
+}
+
+#[test]
+fn check_keep_going() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let missing_file = tr.pathbuf().join("missing-file");
+    let missing_file = missing_file.to_str().unwrap();
+    let image_hash = Testrunner::file_hash(&image_path);
+
+    let copy_to_img = |keep_going: bool| {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{missing_file},rootA:/missing-file"))
+            .arg("-f")
+            .arg(format!("{in_file},factory:/my-file"))
+            .arg("-f")
+            .arg(format!("{in_file},boot:/my-file"))
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--verify");
+        if keep_going {
+            copy_to_img.arg("--keep-going");
+        }
+        copy_to_img.assert()
+    };
+
+    // by default a missing in-file fails before the image is processed
+    let assert = copy_to_img(false);
+    let assert = assert.failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr).contains(missing_file));
+    assert_eq!(image_hash, Testrunner::file_hash(&image_path));
+
+    // the other files are copied, the command fails afterwards
+    let assert = copy_to_img(true);
+    let assert = assert.failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(stderr.contains("--keep-going: 1 copy triple(s) skipped"));
+    assert!(stderr.contains(&format!("{missing_file},rootA:/missing-file")));
+    assert_ne!(image_hash, Testrunner::file_hash(&image_path));
+
+    for partition in ["factory", "boot"] {
+        let out_file = tr.pathbuf().join(format!("{partition}-my-file"));
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!(
+                "{partition}:/my-file,{}",
+                out_file.to_str().unwrap()
+            ))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        assert_eq!(
+            Testrunner::file_hash(&out_file),
+            Testrunner::file_hash(&PathBuf::from(in_file))
+        );
+    }
+}
+
+#[test]
+fn check_audit_log() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let audit_log = tr.pathbuf().join("audit.log");
+
+    for _ in 0..2 {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},factory:/my-file"))
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--audit-log")
+            .arg(&audit_log)
+            .assert();
+        assert.success();
+    }
+
+    let entries: Vec<serde_json::Value> = std::fs::read_to_string(&audit_log)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let operations = |operation: &str| {
+        entries
+            .iter()
+            .filter(|e| e["type"] == "operation" && e["operation"] == operation)
+            .collect::<Vec<_>>()
+    };
+
+    // the audit log is appended to
+    let copies = operations("copy-to-image");
+    assert_eq!(copies.len(), 2);
+    assert_eq!(copies[0]["partition"], "factory");
+    assert_eq!(copies[0]["outFile"], "/my-file");
+
+    assert!(entries.iter().all(|e| e["timestamp"].is_string()));
+    assert!(entries.iter().any(|e| e["type"] == "command"
+        && e["argv"][0] == "e2cp"
+        && e["exitStatus"] == 0
+        && e["durationMs"].is_u64()));
+
+    let writes = operations("write-image");
+    assert_eq!(writes.len(), 2);
+    assert_eq!(
+        writes[1]["sha256"].as_str().unwrap().to_uppercase(),
+        Testrunner::file_hash(&image_path)
+    );
+}
+
+#[test]
+fn check_change_manifest() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_path = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_path.to_str().unwrap();
+    let manifest_path = tr.pathbuf().join("manifest.json");
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--change-manifest")
+        .arg(&manifest_path)
+        .assert();
+    assert.success();
+
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&manifest_path).unwrap()).unwrap();
+    let images = manifest["images"].as_array().unwrap();
+    assert_eq!(images.len(), 1);
+    assert_eq!(images[0]["bmap"], false);
+    assert_eq!(
+        images[0]["sha256"].as_str().unwrap().to_uppercase(),
+        Testrunner::file_hash(&image_path)
+    );
+
+    let files = images[0]["files"].as_array().unwrap();
+    let in_file_size = std::fs::metadata(&in_path).unwrap().len();
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0]["partition"], "boot");
+    assert_eq!(files[1]["partition"], "factory");
+
+    for file in files {
+        assert_eq!(file["path"], "/my-file");
+        assert_eq!(file["size"], in_file_size);
+        assert_eq!(
+            file["sha256"].as_str().unwrap().to_uppercase(),
+            Testrunner::file_hash(&in_path)
+        );
+    }
+
+    let partitions = images[0]["partitions"].as_array().unwrap();
+    assert_eq!(partitions.len(), 2);
+    assert!(partitions
+        .iter()
+        .all(|p| p["writes"] == 1 && p["changedSectors"].as_u64().unwrap() > 0));
+}
+
+#[test]
+fn check_compressed_delta() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let manifest_path = tr.pathbuf().join("manifest.json");
+    let baseline = tr.pathbuf().join("baseline.wic.gz");
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--compressed-delta")
+        .arg("gzip")
+        .arg("--change-manifest")
+        .arg(&manifest_path)
+        .assert();
+    let assert = assert.success();
+    assert!(String::from_utf8_lossy(&assert.get_output().stdout).contains("changed blocks: "));
+
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&manifest_path).unwrap()).unwrap();
+    let delta = &manifest["images"][0]["compressedDelta"];
+    assert_eq!(delta["compression"], "gz");
+    assert!(delta["changedBlocks"].as_u64().unwrap() > 0);
+
+    // compressed like images packed via '-p gzip'
+    let mut encoder = flate2::write::GzEncoder::new(
+        std::fs::File::create(&baseline).unwrap(),
+        flate2::Compression::best(),
+    );
+    std::io::copy(&mut std::fs::File::open(&image_path).unwrap(), &mut encoder).unwrap();
+    encoder.finish().unwrap();
+
+    // copying the same file again incrementally doesn't change the image
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("--incremental")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--compressed-delta")
+        .arg("gzip")
+        .arg("--delta-baseline")
+        .arg(&baseline)
+        .assert();
+    let assert = assert.success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+    assert!(stdout.contains("delta:          +0"));
+    assert!(stdout.contains("changed blocks: 0 of"));
+}
+
+#[test]
+fn check_keep_partitions() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let keep_dir = tr.pathbuf().join("partitions");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--keep-partitions")
+        .arg(&keep_dir)
+        .assert();
+    assert.success();
+
+    // factory is the 4th partition of synthetic images
+    let stat = std::process::Command::new("debugfs")
+        .arg("-R")
+        .arg("stat /my-file")
+        .arg(keep_dir.join("4.img"))
+        .output()
+        .unwrap();
+    let stat = String::from_utf8(stat.stdout).unwrap();
+    assert!(stat.contains("Type: regular"));
+}
+
+#[test]
+fn check_sparse_check() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+
+    // the tmp dir of the test environment is expected to support sparse files
+    let mut set_env = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_env
+        .arg("file")
+        .arg("set-env")
+        .arg("-v")
+        .arg("FOO=bar")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--sparse-check")
+        .arg("--strict")
+        .assert();
+    assert.success();
+
+    // --strict requires --sparse-check
+    let mut set_env = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_env
+        .arg("file")
+        .arg("set-env")
+        .arg("-v")
+        .arg("FOO=bar")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--strict")
+        .assert();
+    assert.failure();
+}
+
+#[test]
+fn check_label() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
     let image_path = tr.to_pathbuf("testfiles/image.wic");
-    let mut out_file3 = tr.pathbuf();
-    out_file3.push("dir1");
-    out_file3.push("outfile3.scr");
-    create_dir_all(out_file3.parent().unwrap()).unwrap();
-    let out_file3 = out_file3.to_str().unwrap();
-    let mut out_file4 = tr.pathbuf();
-    out_file4.push("outfile4.json");
-    let out_file4 = out_file4.to_str().unwrap();
+    let mut build_info_out_path = tr.pathbuf();
+    build_info_out_path.push("build-info");
+
+    let mut set_env = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_env
+        .arg("file")
+        .arg("set-env")
+        .arg("-v")
+        .arg("FOO=bar")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--label")
+        .arg("build-4711")
+        .assert();
+    assert.success();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/omnect/build-info,{}",
+            build_info_out_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert_eq!(
+        std::fs::read_to_string(&build_info_out_path).unwrap(),
+        "LABEL=\"build-4711\"\n"
+    );
+}
+
+#[test]
+fn check_label_only_if_changed() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.pathbuf().join("in-file");
+    std::fs::write(&in_file, "content").unwrap();
+
+    let copy_to_img = || {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{},factory:/my-file", in_file.to_str().unwrap()))
+            .arg("-i")
+            .arg(&image_path)
+            .arg("--incremental")
+            .arg("--label")
+            .arg("build-4711")
+            .arg("--only-if-changed")
+            .assert();
+        assert.success();
+    };
+
+    copy_to_img();
+    let image_hash = Testrunner::file_hash(&image_path);
+
+    // neither file nor label change, thus the image isn't written at all
+    copy_to_img();
+    assert_eq!(image_hash, Testrunner::file_hash(&image_path));
+}
+
+#[test]
+fn check_set_timezone() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let timezone_out_path = tr.pathbuf().join("timezone");
+    let image_hash = Testrunner::file_hash(&image_path);
+
+    let mut set_timezone = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_timezone
+        .arg("file")
+        .arg("set-timezone")
+        .arg("-t")
+        .arg("Mars/Olympus_Mons")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    let assert = assert.failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr)
+        .contains("unknown timezone Mars/Olympus_Mons"));
+    assert_eq!(image_hash, Testrunner::file_hash(&image_path));
+
+    let mut set_timezone = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_timezone
+        .arg("file")
+        .arg("set-timezone")
+        .arg("-t")
+        .arg("UTC")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "rootA:/etc/timezone,{}",
+            timezone_out_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert_eq!(std::fs::read_to_string(timezone_out_path).unwrap(), "UTC\n");
+}
+
+#[test]
+fn check_set_network() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let network = tr.pathbuf().join("10-eth0.network");
+    let netdev = tr.pathbuf().join("20-vlan10.netdev");
+    let invalid = tr.pathbuf().join("30-invalid.network");
+    let out_path = tr.pathbuf().join("out");
+    let image_hash = Testrunner::file_hash(&image_path);
+
+    std::fs::write(
+        &network,
+        "[Match]\nName=eth0\n\n[Network]\nAddress=192.168.0.10/24\nGateway=192.168.0.1\nVLAN=vlan10\n",
+    )
+    .unwrap();
+    std::fs::write(
+        &netdev,
+        "[NetDev]\nName=vlan10\nKind=vlan\n\n[VLAN]\nId=10\n",
+    )
+    .unwrap();
+    std::fs::write(&invalid, "[Match]\nName eth0\n").unwrap();
+
+    let mut set_network = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_network
+        .arg("file")
+        .arg("set-network")
+        .arg("-c")
+        .arg(&network)
+        .arg("-c")
+        .arg(&invalid)
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    let assert = assert.failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr).contains("line 2"));
+    assert_eq!(image_hash, Testrunner::file_hash(&image_path));
+
+    let mut set_network = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_network
+        .arg("file")
+        .arg("set-network")
+        .arg("-c")
+        .arg(&network)
+        .arg("-c")
+        .arg(&netdev)
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    for config_file in [&network, &netdev] {
+        let name = config_file.file_name().unwrap().to_str().unwrap();
+
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!(
+                "rootA:/etc/systemd/network/{name},{}",
+                out_path.to_str().unwrap()
+            ))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        assert_eq!(
+            Testrunner::file_hash(config_file),
+            Testrunner::file_hash(&out_path)
+        );
+    }
+}
+
+#[test]
+fn check_move_partition() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_path = tr.to_pathbuf("testfiles/boot.scr");
+    let out_path = tr.pathbuf().join("out");
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!(
+            "{},factory:/etc/my-file",
+            in_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let move_partition = |from: &str, to: &str, keep_source: bool| {
+        let mut move_partition = Command::cargo_bin("omnect-cli").unwrap();
+        move_partition
+            .arg("file")
+            .arg("move-partition")
+            .arg("--from")
+            .arg(from)
+            .arg("--to")
+            .arg(to)
+            .arg("-i")
+            .arg(&image_path);
+        if keep_source {
+            move_partition.arg("--keep-source");
+        }
+        move_partition.assert()
+    };
+    let copy_from_img = |from: &str| {
+        let _ = std::fs::remove_file(&out_path);
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("{from},{}", out_path.to_str().unwrap()))
+            .arg("-i")
+            .arg(&image_path)
+            .assert()
+    };
+
+    // ext to ext, ext to vfat and vfat to ext
+    for (from, to, keep_source) in [
+        ("factory:/etc/my-file", "rootA:/etc/my-file", false),
+        ("rootA:/etc/my-file", "boot:/dir/my-file", false),
+        ("boot:/dir/my-file", "cert:/my-file", true),
+    ] {
+        move_partition(from, to, keep_source).success();
+
+        copy_from_img(to).success();
+        assert_eq!(
+            Testrunner::file_hash(&in_path),
+            Testrunner::file_hash(&out_path)
+        );
+
+        let assert = copy_from_img(from);
+        if keep_source {
+            assert.success();
+        } else {
+            assert.failure();
+        }
+    }
+
+    let image_hash = Testrunner::file_hash(&image_path);
+    move_partition("boot:/dir", "factory:/dir", false).failure();
+    move_partition("boot:/missing", "factory:/missing", false).failure();
+    assert_eq!(image_hash, Testrunner::file_hash(&image_path));
+}
+
+#[test]
+fn check_boot_references() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let extlinux = tr.pathbuf().join("extlinux.conf");
+    let kernel = tr.pathbuf().join("Image");
+    let initrd = tr.pathbuf().join("initrd");
+
+    std::fs::write(
+        &extlinux,
+        "DEFAULT omnect\nLABEL omnect\n  KERNEL ../Image\n  INITRD /boot/initrd\n  APPEND root=/dev/mmcblk0p2\n",
+    )
+    .unwrap();
+    std::fs::write(&kernel, "kernel").unwrap();
+    std::fs::write(&initrd, "initrd").unwrap();
+
+    // the kernel is resolved in boot relative to extlinux.conf, the initrd in rootA
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!(
+            "{},boot:/extlinux/extlinux.conf",
+            extlinux.to_str().unwrap()
+        ))
+        .arg("-f")
+        .arg(format!("{},boot:/Image", kernel.to_str().unwrap()))
+        .arg("-f")
+        .arg(format!("{},rootA:/boot/initrd", initrd.to_str().unwrap()))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--check-boot")
+        .assert();
+    assert.success();
 
-    let image_path_hash1 = Testrunner::file_hash(&image_path);
+    let image_hash = Testrunner::file_hash(&image_path);
+
+    std::fs::write(
+        &extlinux,
+        "LABEL omnect\n  KERNEL /Image\n  FDT /board.dtb\n  INITRD /boot/initrd\n",
+    )
+    .unwrap();
 
     let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
     let assert = copy_to_img
         .arg("file")
         .arg("copy-to-image")
         .arg("-f")
-        .arg(format!("{in_file1},{partition}:{out_file1}"))
+        .arg(format!(
+            "{},boot:/extlinux/extlinux.conf",
+            extlinux.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--check-boot")
+        .assert();
+    let assert = assert.failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(stderr.contains("boot:/extlinux/extlinux.conf: /board.dtb"));
+    assert!(!stderr.contains("/boot/initrd"));
+    assert_eq!(image_hash, Testrunner::file_hash(&image_path));
+}
+
+#[test]
+fn check_set_cmdline() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let extlinux = tr.pathbuf().join("extlinux.conf");
+    let cmdline = tr.pathbuf().join("cmdline.txt");
+    let out_path = tr.pathbuf().join("out");
+
+    let set_cmdline = |args: &[&str]| {
+        let mut set_cmdline = Command::cargo_bin("omnect-cli").unwrap();
+        set_cmdline
+            .arg("image")
+            .arg("set-cmdline")
+            .args(args)
+            .arg("-i")
+            .arg(&image_path)
+            .assert()
+    };
+    let read_back = |config: &str| {
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("boot:{config},{}", out_path.to_str().unwrap()))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+        std::fs::read_to_string(&out_path).unwrap()
+    };
+
+    // nothing to edit yet
+    let assert = set_cmdline(&["--append", "quiet"]).failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr).contains("--config"));
+
+    std::fs::write(
+        &extlinux,
+        "LABEL omnect\n  KERNEL /Image\n  APPEND root=/dev/mmcblk0p2 rootwait\n",
+    )
+    .unwrap();
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
         .arg("-f")
-        .arg(format!("{in_file2},{partition}:{out_file2}"))
+        .arg(format!(
+            "{},boot:/extlinux/extlinux.conf",
+            extlinux.to_str().unwrap()
+        ))
         .arg("-i")
         .arg(&image_path)
         .assert();
     assert.success();
 
-    let image_path_hash2 = Testrunner::file_hash(&image_path);
-
-    assert_ne!(image_path_hash1, image_path_hash2);
+    set_cmdline(&["--append", "console=ttyS0,115200 rootwait"]).success();
+    assert_eq!(
+        read_back("/extlinux/extlinux.conf"),
+        "LABEL omnect\n  KERNEL /Image\n  APPEND root=/dev/mmcblk0p2 rootwait console=ttyS0,115200\n"
+    );
 
-    let image_path_hash1 = Testrunner::file_hash(&image_path);
+    // an absurdly long command line is rejected
+    let image_hash = Testrunner::file_hash(&image_path);
+    let assert = set_cmdline(&["--append", &"x".repeat(4096)]).failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr).contains("exceeds"));
+    assert_eq!(image_hash, Testrunner::file_hash(&image_path));
 
-    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
-    let assert = copy_from_img
+    // a plain command line file is selected explicitly
+    std::fs::write(&cmdline, "quiet\n").unwrap();
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
         .arg("file")
-        .arg("copy-from-image")
-        .arg("-f")
-        .arg(format!("{partition}:{out_file1},{out_file3}"))
+        .arg("copy-to-image")
         .arg("-f")
-        .arg(format!("{partition}:{out_file2},{out_file4}"))
+        .arg(format!("{},boot:/cmdline.txt", cmdline.to_str().unwrap()))
         .arg("-i")
         .arg(&image_path)
         .assert();
     assert.success();
 
-    let image_path_hash2 = Testrunner::file_hash(&image_path);
-
-    assert_eq!(image_path_hash1, image_path_hash2);
-
-    assert!(file_diff::diff(in_file1, out_file3));
-    assert!(file_diff::diff(in_file2, out_file4));
+    let assert = set_cmdline(&["--set", "root=/dev/sda2"]).failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr).contains("several"));
 
-    // test if we can overwrite files
-    let in_file3 = tr.to_pathbuf("testfiles/identity_config_dps_payload.toml");
-    let in_file3 = in_file3.to_str().unwrap();
-    let in_file4 = tr.to_pathbuf("testfiles/identity_config_hostname_valid.toml");
-    let in_file4 = in_file4.to_str().unwrap();
+    set_cmdline(&["--set", "root=/dev/sda2", "-c", "/cmdline.txt"]).success();
+    assert_eq!(read_back("/cmdline.txt"), "root=/dev/sda2\n");
+}
 
-    let image_path_hash1 = Testrunner::file_hash(&image_path);
+#[test]
+fn check_service_enable_disable() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let unit_file = tr.pathbuf().join("my-app.service");
+    let manifest = tr.pathbuf().join("manifest.json");
+    let link = "/etc/systemd/system/multi-user.target.wants/my-app.service";
+    std::fs::write(
+        &unit_file,
+        "[Service]\nExecStart=/usr/bin/my-app\n\n[Install]\nWantedBy=multi-user.target\n",
+    )
+    .unwrap();
 
     let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
     let assert = copy_to_img
         .arg("file")
         .arg("copy-to-image")
         .arg("-f")
-        .arg(format!("{in_file3},{partition}:{out_file1}"))
-        .arg("-f")
-        .arg(format!("{in_file4},{partition}:{out_file2}"))
+        .arg(format!(
+            "{},rootA:/usr/lib/systemd/system/my-app.service",
+            unit_file.to_str().unwrap()
+        ))
         .arg("-i")
         .arg(&image_path)
         .assert();
     assert.success();
 
-    let image_path_hash2 = Testrunner::file_hash(&image_path);
+    // the unit file must exist
+    let mut enable = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = enable
+        .arg("service")
+        .arg("enable")
+        .arg("-n")
+        .arg("unknown")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    let assert = assert.failure();
+    assert!(String::from_utf8_lossy(&assert.get_output().stderr)
+        .contains("unit file unknown.service not found"));
+
+    let mut enable = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = enable
+        .arg("service")
+        .arg("enable")
+        .arg("-n")
+        .arg("my-app")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--change-manifest")
+        .arg(&manifest)
+        .assert();
+    assert.success();
 
-    assert_ne!(image_path_hash1, image_path_hash2);
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap();
+    let files = &manifest["images"][0]["files"];
+    assert_eq!(files[0]["path"], link);
+    assert_eq!(
+        files[0]["symlink"],
+        "/usr/lib/systemd/system/my-app.service"
+    );
 
-    let image_path_hash1 = Testrunner::file_hash(&image_path);
+    let mut disable = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = disable
+        .arg("service")
+        .arg("disable")
+        .arg("-n")
+        .arg("my-app.service")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
 
     let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
     let assert = copy_from_img
         .arg("file")
         .arg("copy-from-image")
         .arg("-f")
-        .arg(format!("{partition}:{out_file1},{out_file3}"))
+        .arg(format!(
+            "rootA:{link},{}",
+            tr.pathbuf().join("link").to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.failure();
+}
+
+#[test]
+fn check_set_env() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let mut env_file_out_path = tr.pathbuf();
+    env_file_out_path.push("environment");
+
+    let mut set_env = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_env
+        .arg("file")
+        .arg("set-env")
+        .arg("-v")
+        .arg("FOO=bar")
+        .arg("-v")
+        .arg("GREETING=hello world")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut set_env = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_env
+        .arg("file")
+        .arg("set-env")
+        .arg("-v")
+        .arg("FOO=baz")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
         .arg("-f")
-        .arg(format!("{partition}:{out_file2},{out_file4}"))
+        .arg(format!(
+            "factory:/etc/environment,{}",
+            env_file_out_path.to_str().unwrap()
+        ))
         .arg("-i")
         .arg(&image_path)
         .assert();
     assert.success();
 
-    let image_path_hash2 = Testrunner::file_hash(&image_path);
+    let content = std::fs::read_to_string(env_file_out_path).unwrap();
+    assert!(content.contains("FOO=baz\n"));
+    assert!(!content.contains("FOO=bar"));
+    assert!(content.contains("GREETING=\"hello world\"\n"));
 
-    assert_eq!(image_path_hash1, image_path_hash2);
-    assert!(file_diff::diff(in_file3, out_file3));
-    assert!(file_diff::diff(in_file4, out_file4));
+    let mut set_env = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_env
+        .arg("file")
+        .arg("set-env")
+        .arg("-v")
+        .arg("1FOO=bar")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.failure();
 }
 
 #[test]
@@ -826,6 +5016,82 @@ fn check_bmap_generation_wic_xz() {
     assert.success();
 }
 
+#[test]
+fn check_bmap_verify() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let bmap_path = PathBuf::from(format!("{}.bmap", image_path.to_str().unwrap()));
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let other_in_file = tr.to_pathbuf("testfiles/rootCA.crt");
+    let other_in_file = other_in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-b")
+        .assert();
+    assert.success();
+
+    let mut bmap_verify = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = bmap_verify
+        .arg("bmap")
+        .arg("verify")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--bmap")
+        .arg(&bmap_path)
+        .arg("--json")
+        .assert();
+    let verification: serde_json::Value =
+        serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    assert.success();
+
+    assert_eq!(verification["bmapChecksumValid"], true);
+    assert_eq!(
+        verification["imageSize"],
+        std::fs::metadata(&image_path).unwrap().len()
+    );
+    assert!(verification["mappedBlocks"].as_u64().unwrap() > 0);
+    assert!(verification["mismatchedRanges"]
+        .as_array()
+        .unwrap()
+        .is_empty());
+
+    // modify the image without regenerating the bmap
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{other_in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut bmap_verify = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = bmap_verify
+        .arg("bmap")
+        .arg("verify")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--bmap")
+        .arg(&bmap_path)
+        .assert();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).to_string();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert.failure();
+
+    assert!(!stdout.contains("mismatched ranges: none"));
+    assert!(stderr.contains("doesn't match"));
+}
+
 #[test]
 fn check_image_compression() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
@@ -907,6 +5173,198 @@ fn check_image_decompression() {
     assert_eq!(image_path_wic_xz_hash1, image_path_wic_xz_hash2);
 }
 
+#[test]
+fn check_image_decompression_resume() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path_wic_xz = tr.to_pathbuf("testfiles/image.wic.xz");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let out_file = tr.pathbuf().join("out");
+    let work_dir = tr.pathbuf().join("work");
+    let cache_dir = work_dir.join("omnect-cli-resume");
+    std::fs::create_dir(&work_dir).unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic_xz)
+        .arg("-p")
+        .arg("xz")
+        .arg("--resume")
+        .arg("--work-dir")
+        .arg(&work_dir)
+        .assert();
+    assert.success();
+
+    // the cached decompressed image is removed on success
+    assert_eq!(std::fs::read_dir(&cache_dir).unwrap().count(), 0);
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("boot:/my-file,{}", out_file.to_str().unwrap()))
+        .arg("-i")
+        .arg(&image_path_wic_xz)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(in_file, out_file.to_str().unwrap()));
+}
+
+#[test]
+fn check_image_decompression_space() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.pathbuf().join("huge.wic.xz");
+    let work_dir = tr.pathbuf().join("work");
+    std::fs::create_dir(&work_dir).unwrap();
+
+    // xz stream whose index records a single block of 1 PiB uncompressed
+    let mut index = vec![0x00, 0x01, 0x04];
+    let mut size: u64 = 1 << 50;
+    while size >= 0x80 {
+        index.push((size as u8 & 0x7f) | 0x80);
+        size >>= 7;
+    }
+    index.push(size as u8);
+    index.resize(index.len().next_multiple_of(4) + 4, 0);
+    let backward_size = (index.len() as u32 / 4 - 1).to_le_bytes();
+    let stream = [
+        &[0xfd, b'7', b'z', b'X', b'Z', 0x00, 0x00, 0x04, 0, 0, 0, 0][..],
+        &[0; 4],
+        &index,
+        &[0; 4],
+        &backward_size,
+        &[0x00, 0x04, b'Y', b'Z'],
+    ]
+    .concat();
+    std::fs::write(&image_path, stream).unwrap();
+
+    let mut size_report = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = size_report
+        .arg("image")
+        .arg("size-report")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--work-dir")
+        .arg(&work_dir)
+        .assert();
+    let assert = assert.failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(stderr.contains("need ~1048576.0 GiB free"), "{stderr}");
+
+    // nothing was decompressed
+    assert_eq!(std::fs::read_dir(&work_dir).unwrap().count(), 0);
+}
+
+#[test]
+fn check_image_misdetected_compression() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.synthetic_image("image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let out_file = tr.pathbuf().join("out-file");
+
+    // boot code of the mbr starting with the xz magic: libmagic takes the image for xz compressed data
+    let mut image = std::fs::read(&image_path).unwrap();
+    image[..6].copy_from_slice(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]);
+    std::fs::write(&image_path, image).unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{},factory:/my-file", in_file.to_str().unwrap()))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("factory:/my-file,{}", out_file.to_str().unwrap()))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert_eq!(
+        Testrunner::file_hash(&in_file),
+        Testrunner::file_hash(&out_file)
+    );
+
+    // a forced format isn't second-guessed
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{},factory:/my-file", in_file.to_str().unwrap()))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--image-format")
+        .arg("xz")
+        .assert();
+    assert.failure();
+}
+
+#[test]
+fn check_auth_status() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let env_path = tr.pathbuf().join("env.toml");
+    std::fs::write(
+        &env_path,
+        r#"backend = 'https://cp.test.example.com'
+
+[auth.Oidc]
+issuer = 'https://idp.example.com/realms/omnect'
+client_id = 'omnect-cli-check-auth-status'
+"#,
+    )
+    .unwrap();
+
+    let mut auth_status = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = auth_status
+        .arg("auth")
+        .arg("status")
+        .arg("--env")
+        .arg(&env_path)
+        .arg("--json")
+        .assert();
+    let assert = assert.success();
+
+    // no login is cached for this client and no request is sent to the issuer
+    let status: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    assert_eq!(status["backend"], "https://cp.test.example.com/");
+    assert_eq!(status["issuer"], "https://idp.example.com/realms/omnect");
+    assert_eq!(status["clientId"], "omnect-cli-check-auth-status");
+    assert_eq!(status["tokenCached"], false);
+    assert_eq!(status["loggedIn"], false);
+
+    let mut auth_status = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = auth_status
+        .arg("auth")
+        .arg("status")
+        .arg("--issuer")
+        .arg("https://idp.example.com/realms/other")
+        .arg("--client-id")
+        .arg("omnect-cli-check-auth-status")
+        .assert();
+    let assert = assert.success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+    assert!(stdout.contains("https://cp.omnect.conplement.cloud/"));
+    assert!(stdout.contains("https://idp.example.com/realms/other"));
+    assert!(stdout.contains("not logged in"));
+}
+
 #[tokio::test]
 async fn check_ssh_tunnel_setup() {
     let tr = Testrunner::new("check_ssh_tunnel_setup");